@@ -0,0 +1,603 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use tokio::sync::{Notify, watch};
+use tracing::{debug, info};
+use webrtc::{
+    interceptor::{
+        Attributes, Error, Interceptor, InterceptorBuilder, RTCPReader, RTCPWriter, RTPReader,
+        RTPWriter, registry::Registry, stream_info::StreamInfo, twcc::sender::Sender as TwccSender,
+    },
+    rtcp::transport_feedbacks::transport_layer_cc::{
+        PacketStatusChunk, SymbolTypeTcc, TransportLayerCc,
+    },
+    rtp::packet::Packet,
+    sdp::extmap::TRANSPORT_CC_URI,
+    util::MarshalSize,
+};
+
+use crate::fanout::FanoutTrack;
+
+// Sent packets remembered until the viewer's feedback covers them
+const SENT_HISTORY: usize = 1 << 12;
+// Bounds of the estimate, in bits per second
+const MIN_ESTIMATE: f64 = 50_000.0;
+const MAX_ESTIMATE: f64 = 100_000_000.0;
+// Share of packets lost, and queueing delay built up within one feedback,
+// beyond which the link is taken as congested, and below which as clear
+const CONGESTED_LOSS: f64 = 0.1;
+const CLEAR_LOSS: f64 = 0.02;
+const CONGESTED_DELAY: Duration = Duration::from_millis(10);
+const CLEAR_DELAY: Duration = Duration::from_millis(5);
+// On congestion, the estimate falls to this share of the received rate; on a
+// clear link it grows by this share per second
+const DECREASE: f64 = 0.85;
+const INCREASE_PER_SEC: f64 = 0.08;
+
+// How often each viewer's controller looks at its estimate
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+// The estimate must stay below the main stream's bitrate this long before
+// switching down, and above it with headroom at least `MIN_UP_HOLD` before
+// switching back up; up-switches that don't last double the wait
+const DOWN_HOLD: Duration = Duration::from_secs(1);
+const UP_HEADROOM: f64 = 1.25;
+const MIN_UP_HOLD: Duration = Duration::from_secs(8);
+const MAX_UP_HOLD: Duration = Duration::from_secs(120);
+const FAILED_UP_SWITCH: Duration = Duration::from_secs(30);
+// Weight of the newest sample in the main stream's bitrate
+const RATE_SMOOTHING: f64 = 0.3;
+
+/// Adds transport-wide sequence numbers to outgoing packets, so viewers send
+/// TWCC feedback, and estimates each viewer's bandwidth from that feedback
+/// into `estimates`.
+///
+/// The header extension and feedback type are registered by
+/// `configure_twcc_receiver_only`.
+pub fn configure_estimator(mut registry: Registry, estimates: Arc<Estimates>) -> Registry {
+    // Added before the sender so it sees the sequence numbers the sender sets
+    registry.add(Box::new(EstimatorBuilder { estimates }));
+    registry.add(Box::new(TwccSender::builder()));
+    registry
+}
+
+/// Bandwidth estimates of all viewer connections, by the SSRCs they send.
+#[derive(Default)]
+pub struct Estimates(Mutex<HashMap<u32, Arc<Estimator>>>);
+
+impl Estimates {
+    pub fn get(&self, ssrc: u32) -> Option<Arc<Estimator>> {
+        self.0.lock().unwrap().get(&ssrc).cloned()
+    }
+}
+
+/// One viewer connection's bandwidth estimate, in the spirit of Google
+/// Congestion Control: it drops below the rate that got through when
+/// feedback shows loss or growing queues, and creeps up while neither does.
+#[derive(Default)]
+pub struct Estimator(Mutex<EstimatorState>);
+
+#[derive(Default)]
+struct EstimatorState {
+    // Send time and size by transport-wide sequence number
+    sent: HashMap<u16, (Instant, usize)>,
+    estimate: Option<f64>,
+    updated: Option<Instant>,
+}
+
+impl Estimator {
+    /// Bits per second the connection is estimated to carry, once the viewer
+    /// has sent feedback.
+    pub fn estimate(&self) -> Option<u64> {
+        self.0
+            .lock()
+            .unwrap()
+            .estimate
+            .map(|estimate| estimate as u64)
+    }
+
+    fn sent(&self, sequence_number: u16, size: usize) {
+        let mut state = self.0.lock().unwrap();
+        if state.sent.len() >= SENT_HISTORY {
+            // Feedback for these is overdue; it won't count them anyway
+            let oldest = sequence_number.wrapping_sub(SENT_HISTORY as u16 / 2);
+            state
+                .sent
+                .retain(|&sent, _| sent.wrapping_sub(oldest) < SENT_HISTORY as u16);
+        }
+        state.sent.insert(sequence_number, (Instant::now(), size));
+    }
+
+    fn feedback(&self, feedback: &TransportLayerCc) {
+        let mut state = self.0.lock().unwrap();
+        let mut deltas = feedback.recv_deltas.iter();
+        // Arrival times relative to the feedback's reference time, in µs
+        let mut arrival = 0i64;
+        let mut received = Vec::new();
+        let mut lost = 0usize;
+        for (offset, status) in statuses(feedback).enumerate() {
+            let sequence_number = feedback.base_sequence_number.wrapping_add(offset as u16);
+            let sent = state.sent.remove(&sequence_number);
+            match status {
+                SymbolTypeTcc::PacketNotReceived => lost += 1,
+                SymbolTypeTcc::PacketReceivedWithoutDelta => {}
+                SymbolTypeTcc::PacketReceivedSmallDelta
+                | SymbolTypeTcc::PacketReceivedLargeDelta => {
+                    arrival += deltas.next().map_or(0, |delta| delta.delta);
+                    if let Some((at, size)) = sent {
+                        received.push((at, arrival, size));
+                    }
+                }
+            }
+        }
+        let total = received.len() + lost;
+        if total == 0 {
+            return;
+        }
+
+        let loss = lost as f64 / total as f64;
+        // Queueing delay built up: how much more the arrivals spread out
+        // than the sends did
+        let (rate, queueing) = match (received.first(), received.last()) {
+            (Some(first), Some(last)) if received.len() > 1 => {
+                let sending = last.0.duration_since(first.0).as_micros() as i64;
+                let arriving = (last.1 - first.1).max(1);
+                let bytes: usize = received[1..].iter().map(|(_, _, size)| size).sum();
+                let rate = bytes as f64 * 8.0 * 1_000_000.0 / arriving as f64;
+                let queueing = Duration::from_micros((arriving - sending).max(0) as u64);
+                (Some(rate), queueing)
+            }
+            _ => (None, Duration::ZERO),
+        };
+
+        let now = Instant::now();
+        let elapsed = state
+            .updated
+            .map_or(0.0, |updated| now.duration_since(updated).as_secs_f64());
+        state.updated = Some(now);
+        let estimate = match (state.estimate, rate) {
+            (None, Some(rate)) => rate,
+            (None, None) => return,
+            (Some(estimate), rate) => {
+                if loss > CONGESTED_LOSS || queueing > CONGESTED_DELAY {
+                    rate.map_or(estimate, |rate| estimate.min(rate)) * DECREASE
+                } else if loss < CLEAR_LOSS && queueing < CLEAR_DELAY {
+                    let base = rate.map_or(estimate, |rate| estimate.max(rate));
+                    base * (1.0 + INCREASE_PER_SEC * elapsed.min(1.0))
+                } else {
+                    estimate
+                }
+            }
+        };
+        state.estimate = Some(estimate.clamp(MIN_ESTIMATE, MAX_ESTIMATE));
+    }
+}
+
+// The feedback's packet status symbols, one per packet from the base sequence
+// number on
+fn statuses(feedback: &TransportLayerCc) -> impl Iterator<Item = SymbolTypeTcc> + '_ {
+    feedback
+        .packet_chunks
+        .iter()
+        .flat_map(|chunk| match chunk {
+            PacketStatusChunk::RunLengthChunk(run) => {
+                vec![run.packet_status_symbol; run.run_length as usize]
+            }
+            PacketStatusChunk::StatusVectorChunk(vector) => vector.symbol_list.clone(),
+        })
+        .take(feedback.packet_status_count as usize)
+}
+
+struct EstimatorBuilder {
+    estimates: Arc<Estimates>,
+}
+
+impl InterceptorBuilder for EstimatorBuilder {
+    fn build(&self, _id: &str) -> Result<Arc<dyn Interceptor + Send + Sync>, Error> {
+        Ok(Arc::new(EstimatorInterceptor {
+            estimates: self.estimates.clone(),
+            estimator: Arc::default(),
+            ssrcs: Mutex::default(),
+        }))
+    }
+}
+
+/// Feeds one connection's sent packets and TWCC feedback to its [`Estimator`].
+struct EstimatorInterceptor {
+    estimates: Arc<Estimates>,
+    estimator: Arc<Estimator>,
+    // Local streams filed in `estimates`
+    ssrcs: Mutex<Vec<u32>>,
+}
+
+#[async_trait]
+impl Interceptor for EstimatorInterceptor {
+    async fn bind_rtcp_reader(
+        &self,
+        reader: Arc<dyn RTCPReader + Send + Sync>,
+    ) -> Arc<dyn RTCPReader + Send + Sync> {
+        Arc::new(FeedbackReader {
+            estimator: self.estimator.clone(),
+            reader,
+        })
+    }
+
+    async fn bind_rtcp_writer(
+        &self,
+        writer: Arc<dyn RTCPWriter + Send + Sync>,
+    ) -> Arc<dyn RTCPWriter + Send + Sync> {
+        writer
+    }
+
+    async fn bind_local_stream(
+        &self,
+        info: &StreamInfo,
+        writer: Arc<dyn RTPWriter + Send + Sync>,
+    ) -> Arc<dyn RTPWriter + Send + Sync> {
+        let Some(extension) = info
+            .rtp_header_extensions
+            .iter()
+            .find(|extension| extension.uri == TRANSPORT_CC_URI)
+        else {
+            return writer;
+        };
+        self.estimates
+            .0
+            .lock()
+            .unwrap()
+            .insert(info.ssrc, self.estimator.clone());
+        self.ssrcs.lock().unwrap().push(info.ssrc);
+        Arc::new(SentRecorder {
+            extension: extension.id as u8,
+            estimator: self.estimator.clone(),
+            writer,
+        })
+    }
+
+    async fn unbind_local_stream(&self, info: &StreamInfo) {
+        self.estimates.0.lock().unwrap().remove(&info.ssrc);
+        self.ssrcs.lock().unwrap().retain(|&ssrc| ssrc != info.ssrc);
+    }
+
+    async fn bind_remote_stream(
+        &self,
+        _info: &StreamInfo,
+        reader: Arc<dyn RTPReader + Send + Sync>,
+    ) -> Arc<dyn RTPReader + Send + Sync> {
+        reader
+    }
+
+    async fn unbind_remote_stream(&self, _info: &StreamInfo) {}
+
+    async fn close(&self) -> Result<(), Error> {
+        let mut estimates = self.estimates.0.lock().unwrap();
+        for ssrc in self.ssrcs.lock().unwrap().drain(..) {
+            estimates.remove(&ssrc);
+        }
+        Ok(())
+    }
+}
+
+/// Passes RTCP on, handing TWCC feedback to the estimator.
+struct FeedbackReader {
+    estimator: Arc<Estimator>,
+    reader: Arc<dyn RTCPReader + Send + Sync>,
+}
+
+#[async_trait]
+impl RTCPReader for FeedbackReader {
+    async fn read(
+        &self,
+        buf: &mut [u8],
+        attributes: &Attributes,
+    ) -> Result<
+        (
+            Vec<Box<dyn webrtc::rtcp::packet::Packet + Send + Sync>>,
+            Attributes,
+        ),
+        Error,
+    > {
+        let (packets, attributes) = self.reader.read(buf, attributes).await?;
+        for packet in &packets {
+            if let Some(feedback) = packet.as_any().downcast_ref::<TransportLayerCc>() {
+                self.estimator.feedback(feedback);
+            }
+        }
+        Ok((packets, attributes))
+    }
+}
+
+/// Notes the transport-wide sequence number and size of every packet sent.
+struct SentRecorder {
+    extension: u8,
+    estimator: Arc<Estimator>,
+    writer: Arc<dyn RTPWriter + Send + Sync>,
+}
+
+#[async_trait]
+impl RTPWriter for SentRecorder {
+    async fn write(&self, packet: &Packet, attributes: &Attributes) -> Result<usize, Error> {
+        if let Some(value) = packet.header.get_extension(self.extension)
+            && let [high, low, ..] = value[..]
+        {
+            self.estimator
+                .sent(u16::from_be_bytes([high, low]), packet.marshal_size());
+        }
+        self.writer.write(packet, attributes).await
+    }
+}
+
+/// One of the two streams a viewer can be switched between.
+pub struct Layer {
+    pub name: String,
+    pub track: Arc<FanoutTrack>,
+    pub keyframe_requests: Arc<Notify>,
+}
+
+/// Moves a viewer between the `main` and `low` layers as its bandwidth
+/// estimate allows, by updating `selected` (`true` for `low`), until the
+/// viewer's track stops listening.
+///
+/// The estimator is found by `ssrc`, the SSRC of the viewer's video track,
+/// once the connection is up. The viewer's keyframe requests, notified on
+/// `keyframe_requests`, are passed on to whichever layer it is watching.
+pub fn spawn_controller(
+    session: String,
+    estimates: Arc<Estimates>,
+    ssrc: u32,
+    [main, low]: [Layer; 2],
+    selected: watch::Sender<bool>,
+    keyframe_requests: Arc<Notify>,
+) {
+    tokio::spawn(async move {
+        let mut check = tokio::time::interval(CHECK_INTERVAL);
+        let mut estimator = None;
+        let mut main_rate = Bitrate::new(&main.track);
+        // Since when the estimate has favoured the other layer
+        let mut favoured_since: Option<Instant> = None;
+        let mut up_hold = MIN_UP_HOLD;
+        let mut last_up_switch: Option<Instant> = None;
+        loop {
+            tokio::select! {
+                _ = selected.closed() => break,
+                _ = keyframe_requests.notified() => {
+                    let layer = if *selected.borrow() { &low } else { &main };
+                    layer.keyframe_requests.notify_one();
+                    continue;
+                }
+                _ = check.tick() => {}
+            }
+
+            let main_bps = main_rate.sample();
+            if estimator.is_none() {
+                estimator = estimates.get(ssrc);
+            }
+            let (Some(estimate), Some(main_bps)) = (
+                estimator
+                    .as_ref()
+                    .and_then(|estimator| estimator.estimate()),
+                main_bps,
+            ) else {
+                continue;
+            };
+
+            let on_low = *selected.borrow();
+            let (favours_other, hold) = if on_low {
+                (estimate as f64 > main_bps * UP_HEADROOM, up_hold)
+            } else {
+                ((estimate as f64) < main_bps, DOWN_HOLD)
+            };
+            if !favours_other {
+                favoured_since = None;
+                continue;
+            }
+            let since = *favoured_since.get_or_insert_with(Instant::now);
+            if since.elapsed() < hold {
+                continue;
+            }
+
+            favoured_since = None;
+            let target = if on_low {
+                last_up_switch = Some(Instant::now());
+                &main
+            } else {
+                // Back off from probing a link that couldn't keep up
+                up_hold = if last_up_switch.is_some_and(|at| at.elapsed() < FAILED_UP_SWITCH) {
+                    (up_hold * 2).min(MAX_UP_HOLD)
+                } else {
+                    MIN_UP_HOLD
+                };
+                &low
+            };
+            info!(
+                "🎚️ Session {} switching to '{}' (estimate {} kbps, main stream {} kbps)",
+                &session[..8],
+                target.name,
+                estimate / 1000,
+                main_bps as u64 / 1000
+            );
+            // The switch happens at the target's next keyframe
+            target.keyframe_requests.notify_one();
+            selected.send_replace(!on_low);
+        }
+        debug!("Bandwidth controller of session {} ended", &session[..8]);
+    });
+}
+
+/// The smoothed bitrate a fanout track forwards.
+struct Bitrate<'a> {
+    track: &'a FanoutTrack,
+    bytes: u64,
+    at: Instant,
+    rate: Option<f64>,
+}
+
+impl<'a> Bitrate<'a> {
+    fn new(track: &'a FanoutTrack) -> Self {
+        Self {
+            track,
+            bytes: track.bytes_sent(),
+            at: Instant::now(),
+            rate: None,
+        }
+    }
+
+    /// Bits per second since the last sample, smoothed; `None` until the
+    /// track has forwarded anything.
+    fn sample(&mut self) -> Option<f64> {
+        let (bytes, at) = (self.track.bytes_sent(), Instant::now());
+        let elapsed = at.duration_since(self.at).as_secs_f64();
+        if elapsed > 0.0 {
+            // The counter starts over if the track's transport restarts
+            let sample = bytes.saturating_sub(self.bytes) as f64 * 8.0 / elapsed;
+            self.rate = Some(match self.rate {
+                Some(rate) => rate + RATE_SMOOTHING * (sample - rate),
+                None => sample,
+            });
+        }
+        (self.bytes, self.at) = (bytes, at);
+        self.rate.filter(|&rate| rate > 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::{
+        rtcp::transport_feedbacks::transport_layer_cc::{
+            RecvDelta, RunLengthChunk, StatusVectorChunk,
+        },
+        rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    };
+
+    use super::*;
+
+    fn run(symbol: SymbolTypeTcc, length: u16) -> PacketStatusChunk {
+        PacketStatusChunk::RunLengthChunk(RunLengthChunk {
+            packet_status_symbol: symbol,
+            run_length: length,
+            ..Default::default()
+        })
+    }
+
+    // Feedback on packets from `base` on, each received `spacing` after the
+    // one before unless lost
+    fn feedback(base: u16, received: &[bool], spacing: i64) -> TransportLayerCc {
+        let symbol = |received: bool| {
+            if received {
+                SymbolTypeTcc::PacketReceivedSmallDelta
+            } else {
+                SymbolTypeTcc::PacketNotReceived
+            }
+        };
+        TransportLayerCc {
+            base_sequence_number: base,
+            packet_status_count: received.len() as u16,
+            packet_chunks: received.iter().map(|&r| run(symbol(r), 1)).collect(),
+            recv_deltas: received
+                .iter()
+                .filter(|&&r| r)
+                .map(|_| RecvDelta {
+                    type_tcc_packet: SymbolTypeTcc::PacketReceivedSmallDelta,
+                    delta: spacing,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn expands_status_chunks() {
+        let feedback = TransportLayerCc {
+            packet_status_count: 5,
+            packet_chunks: vec![
+                run(SymbolTypeTcc::PacketReceivedSmallDelta, 2),
+                PacketStatusChunk::StatusVectorChunk(StatusVectorChunk {
+                    symbol_list: vec![
+                        SymbolTypeTcc::PacketNotReceived,
+                        SymbolTypeTcc::PacketReceivedLargeDelta,
+                        SymbolTypeTcc::PacketNotReceived,
+                        SymbolTypeTcc::PacketNotReceived,
+                    ],
+                    ..Default::default()
+                }),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            statuses(&feedback).collect::<Vec<_>>(),
+            [
+                SymbolTypeTcc::PacketReceivedSmallDelta,
+                SymbolTypeTcc::PacketReceivedSmallDelta,
+                SymbolTypeTcc::PacketNotReceived,
+                SymbolTypeTcc::PacketReceivedLargeDelta,
+                SymbolTypeTcc::PacketNotReceived,
+            ]
+        );
+    }
+
+    #[test]
+    fn estimates_from_feedback() {
+        let estimator = Estimator::default();
+        assert_eq!(estimator.estimate(), None);
+
+        // Eleven 1000 byte packets arriving 1 ms apart: 10 kB in 10 ms
+        for sequence_number in 0..11 {
+            estimator.sent(sequence_number, 1000);
+        }
+        estimator.feedback(&feedback(0, &[true; 11], 1000));
+        assert_eq!(estimator.estimate(), Some(8_000_000));
+
+        // Half of them lost cuts the estimate below what got through
+        for sequence_number in 11..21 {
+            estimator.sent(sequence_number, 1000);
+        }
+        let half: Vec<bool> = (0..10).map(|i| i % 2 == 0).collect();
+        estimator.feedback(&feedback(11, &half, 2000));
+        assert_eq!(estimator.estimate(), Some(3_400_000));
+
+        // Feedback on packets it never heard of changes nothing
+        estimator.feedback(&feedback(500, &[true; 4], 1000));
+        assert_eq!(estimator.estimate(), Some(3_400_000));
+        assert!(estimator.0.lock().unwrap().sent.is_empty());
+    }
+
+    #[test]
+    fn forgets_packets_without_feedback() {
+        let estimator = Estimator::default();
+        for sequence_number in 0..SENT_HISTORY as u16 + 1 {
+            estimator.sent(sequence_number, 100);
+        }
+        let sent = estimator.0.lock().unwrap().sent.len();
+        assert!(sent <= SENT_HISTORY / 2 + 1, "{} packets kept", sent);
+    }
+
+    #[test]
+    fn measures_the_main_bitrate() {
+        let track = FanoutTrack::new(
+            RTCRtpCodecCapability {
+                mime_type: "video/H264".to_owned(),
+                clock_rate: 90000,
+                ..Default::default()
+            },
+            "video".to_owned(),
+            "main".to_owned(),
+        );
+        let mut rate = Bitrate::new(&track);
+        rate.at -= Duration::from_secs(1);
+        track.send(Packet {
+            payload: vec![0; 1000].into(),
+            ..Default::default()
+        });
+        let bps = rate.sample().unwrap();
+        assert!((7000.0..=8000.0).contains(&bps), "{} bps", bps);
+
+        // A counter that starts over reads as nothing sent, not as a panic
+        rate.bytes = u64::MAX;
+        rate.at -= Duration::from_secs(1);
+        let bps = rate.sample().unwrap();
+        assert!(bps < 8000.0);
+    }
+}
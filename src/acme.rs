@@ -0,0 +1,586 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::http::header;
+use axum_server::tls_rustls::RustlsConfig;
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use ring::{
+    rand::SystemRandom,
+    signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::{info, warn};
+
+const ACCOUNT_KEY: &str = "acme-account.key";
+const CERT: &str = "acme.crt";
+const KEY: &str = "acme.key";
+// Certificates are renewed once this old, well before the 90 days (and the
+// shorter lifetimes to come) of Let's Encrypt's run out
+const RENEW_AFTER: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+// How often the certificate's age is checked, and failed renewals retried
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+// Polling of authorizations and orders the CA is still working on
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: u32 = 30;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+
+#[derive(Debug, thiserror::Error)]
+pub enum AcmeError {
+    #[error("ACME request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("ACME server refused {url}: {status} {body}")]
+    Refused {
+        url: String,
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    #[error("ACME {0} failed: {1}")]
+    Invalid(&'static str, String),
+    #[error("ACME server sent no {0}")]
+    Missing(&'static str),
+    #[error("timed out waiting for the ACME {0}")]
+    Timeout(&'static str),
+    #[error("failed to keep the ACME files: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to create the certificate request: {0}")]
+    Csr(#[from] rcgen::Error),
+    #[error("invalid ACME account key")]
+    AccountKey,
+}
+
+/// Where to get a certificate from and for which names.
+#[derive(Clone)]
+pub struct AcmeOptions {
+    pub directory: url::Url,
+    pub domains: Vec<String>,
+    pub email: Option<String>,
+    /// Holds the account key, the certificate and its key.
+    pub dir: PathBuf,
+}
+
+impl AcmeOptions {
+    /// The certificate chain and key files.
+    pub fn files(&self) -> (PathBuf, PathBuf) {
+        (self.dir.join(CERT), self.dir.join(KEY))
+    }
+}
+
+/// Key authorizations of the pending HTTP-01 challenges, by token, served
+/// under `/.well-known/acme-challenge/` for the CA to fetch.
+#[derive(Default)]
+pub struct Challenges(Mutex<HashMap<String, String>>);
+
+impl Challenges {
+    pub fn get(&self, token: &str) -> Option<String> {
+        self.0.lock().unwrap().get(token).cloned()
+    }
+}
+
+/// The certificate and key files in `--tls-dir`, first issued if missing or
+/// due for renewal. The CA's HTTP-01 challenges are answered from
+/// `challenges`, which must be served on port 80 by then.
+pub async fn certificate(
+    options: &AcmeOptions,
+    challenges: &Challenges,
+) -> Result<(PathBuf, PathBuf), AcmeError> {
+    if needs_renewal(options) {
+        issue(options, challenges).await?;
+    }
+    Ok(options.files())
+}
+
+/// Renews the certificate when due, for as long as the gateway runs, and has
+/// `config` serve the new one.
+pub fn spawn_renewal(options: AcmeOptions, challenges: Arc<Challenges>, config: RustlsConfig) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(RENEW_CHECK_INTERVAL).await;
+            if !needs_renewal(&options) {
+                continue;
+            }
+            if let Err(e) = issue(&options, &challenges).await {
+                warn!("ACME renewal failed, retrying later: {}", e);
+                continue;
+            }
+            let (cert, key) = options.files();
+            if let Err(e) = config.reload_from_pem_file(&cert, &key).await {
+                warn!("Failed to load the renewed certificate: {}", e);
+            }
+        }
+    });
+}
+
+// Missing, or older than RENEW_AFTER
+fn needs_renewal(options: &AcmeOptions) -> bool {
+    let (cert, key) = options.files();
+    !key.exists()
+        || std::fs::metadata(&cert)
+            .and_then(|metadata| metadata.modified())
+            .map_or(true, |modified| {
+                modified.elapsed().unwrap_or_default() > RENEW_AFTER
+            })
+}
+
+/// Orders a certificate for every domain (RFC 8555), proving control of each
+/// with an HTTP-01 challenge, and writes it to `--tls-dir`.
+async fn issue(options: &AcmeOptions, challenges: &Challenges) -> Result<(), AcmeError> {
+    std::fs::create_dir_all(&options.dir)?;
+    info!(
+        "🔐 Requesting a certificate for {} from {}",
+        options.domains.join(", "),
+        options.directory
+    );
+    let mut client = Client::new(options).await?;
+    client.register(options.email.as_deref()).await?;
+
+    let identifiers: Vec<_> = options
+        .domains
+        .iter()
+        .map(|domain| json!({"type": "dns", "value": domain}))
+        .collect();
+    let new_order = client.directory.new_order.clone();
+    let response = client
+        .post(&new_order, Some(json!({"identifiers": identifiers})))
+        .await?;
+    let order_url = location(&response).ok_or(AcmeError::Missing("order URL"))?;
+    let order: Order = response.json().await?;
+
+    for url in &order.authorizations {
+        let authorization: Authorization = client.post(url, None).await?.json().await?;
+        if authorization.status == "valid" {
+            continue;
+        }
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.kind == "http-01")
+            .ok_or(AcmeError::Missing("HTTP-01 challenge"))?;
+        challenges.0.lock().unwrap().insert(
+            challenge.token.clone(),
+            format!("{}.{}", challenge.token, client.thumbprint()),
+        );
+        let validated = client.validate(url, &challenge.url).await;
+        challenges.0.lock().unwrap().remove(&challenge.token);
+        validated?;
+    }
+
+    let key_pair = rcgen::KeyPair::generate()?;
+    let csr =
+        rcgen::CertificateParams::new(options.domains.clone())?.serialize_request(&key_pair)?;
+    client
+        .post(
+            &order.finalize,
+            Some(json!({"csr": BASE64_URL_SAFE_NO_PAD.encode(csr.der())})),
+        )
+        .await?;
+    let certificate_url = client.issued(&order_url).await?;
+    let chain = client.post(&certificate_url, None).await?.text().await?;
+
+    // The key goes first: a crash in between leaves the old certificate,
+    // whose age has it reissued on the next start
+    let (cert, key) = options.files();
+    write(&key, &key_pair.serialize_pem())?;
+    write(&cert, &chain)?;
+    info!("🔐 Certificate issued, stored in {}", options.dir.display());
+    Ok(())
+}
+
+// Replaces `path` as a whole, so a crash never leaves half a file
+fn write(path: &Path, contents: &str) -> std::io::Result<()> {
+    let part = path.with_extension("part");
+    std::fs::write(&part, contents)?;
+    std::fs::rename(&part, path)
+}
+
+fn location(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .map(str::to_owned)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+    error: Option<Value>,
+}
+
+/// An ACME account's session with the CA: requests signed with its key as
+/// JWS, each carrying a fresh nonce.
+struct Client {
+    http: reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    /// The account URL, once registered.
+    account: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Client {
+    /// Fetches the CA's directory, with the account key from `--tls-dir`, or
+    /// a new one kept there.
+    async fn new(options: &AcmeOptions) -> Result<Self, AcmeError> {
+        let rng = SystemRandom::new();
+        let path = options.dir.join(ACCOUNT_KEY);
+        let pkcs8 = match std::fs::read(&path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(|_| AcmeError::AccountKey)?;
+                std::fs::write(&path, pkcs8.as_ref())?;
+                pkcs8.as_ref().to_vec()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|_| AcmeError::AccountKey)?;
+
+        let http = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        let directory = http
+            .get(options.directory.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(Self {
+            http,
+            directory,
+            key,
+            rng,
+            account: None,
+            nonce: None,
+        })
+    }
+
+    /// Creates the account, or finds the one the key already has.
+    async fn register(&mut self, email: Option<&str>) -> Result<(), AcmeError> {
+        let mut payload = json!({"termsOfServiceAgreed": true});
+        if let Some(email) = email {
+            payload["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let new_account = self.directory.new_account.clone();
+        let response = self.post(&new_account, Some(payload)).await?;
+        self.account = Some(location(&response).ok_or(AcmeError::Missing("account URL"))?);
+        Ok(())
+    }
+
+    /// Tells the CA the challenge at `challenge_url` is ready, then waits for
+    /// the authorization at `url` to be granted.
+    async fn validate(&mut self, url: &str, challenge_url: &str) -> Result<(), AcmeError> {
+        self.post(challenge_url, Some(json!({}))).await?;
+        for _ in 0..POLL_ATTEMPTS {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let authorization: Authorization = self.post(url, None).await?.json().await?;
+            match authorization.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" | "processing" => {}
+                status => {
+                    let reason = authorization
+                        .challenges
+                        .into_iter()
+                        .find_map(|challenge| challenge.error)
+                        .map_or_else(|| status.to_owned(), |error| error.to_string());
+                    return Err(AcmeError::Invalid("authorization", reason));
+                }
+            }
+        }
+        Err(AcmeError::Timeout("authorization"))
+    }
+
+    /// Waits for the order at `url` to be issued, returning its certificate
+    /// URL.
+    async fn issued(&mut self, url: &str) -> Result<String, AcmeError> {
+        for _ in 0..POLL_ATTEMPTS {
+            let order: Order = self.post(url, None).await?.json().await?;
+            match order.status.as_str() {
+                "valid" => return order.certificate.ok_or(AcmeError::Missing("certificate")),
+                "processing" | "ready" | "pending" => {}
+                status => {
+                    let reason = order
+                        .error
+                        .map_or_else(|| status.to_owned(), |error| error.to_string());
+                    return Err(AcmeError::Invalid("order", reason));
+                }
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(AcmeError::Timeout("order"))
+    }
+
+    /// POSTs `payload` to `url` as a JWS, or an empty one for POST-as-GET.
+    /// A rejected nonce is retried once with the fresh one the CA sent.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<Value>,
+    ) -> Result<reqwest::Response, AcmeError> {
+        let payload = payload.map_or_else(String::new, |payload| {
+            BASE64_URL_SAFE_NO_PAD.encode(payload.to_string())
+        });
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let mut protected = json!({"alg": "ES256", "nonce": nonce, "url": url});
+            match &self.account {
+                Some(account) => protected["kid"] = json!(account),
+                None => protected["jwk"] = self.jwk(),
+            }
+            let protected = BASE64_URL_SAFE_NO_PAD.encode(protected.to_string());
+            let signature = self
+                .key
+                .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+                .map_err(|_| AcmeError::AccountKey)?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": BASE64_URL_SAFE_NO_PAD.encode(signature.as_ref()),
+            });
+
+            let response = self
+                .http
+                .post(url)
+                .header(header::CONTENT_TYPE, "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await?;
+            self.nonce = response
+                .headers()
+                .get("replay-nonce")
+                .and_then(|nonce| nonce.to_str().ok())
+                .map(str::to_owned);
+            let status = response.status();
+            if status.is_success() {
+                return Ok(response);
+            }
+            let body = response.text().await?;
+            if !retried && body.contains(BAD_NONCE) {
+                retried = true;
+                continue;
+            }
+            return Err(AcmeError::Refused {
+                url: url.to_owned(),
+                status,
+                body,
+            });
+        }
+    }
+
+    async fn new_nonce(&self) -> Result<String, AcmeError> {
+        let response = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await?
+            .error_for_status()?;
+        response
+            .headers()
+            .get("replay-nonce")
+            .and_then(|nonce| nonce.to_str().ok())
+            .map(str::to_owned)
+            .ok_or(AcmeError::Missing("nonce"))
+    }
+
+    /// The account's public key (RFC 7517), members in the order RFC 7638
+    /// hashes them.
+    fn jwk(&self) -> Value {
+        // An uncompressed point: 0x04, then x and y
+        let point = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": BASE64_URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": BASE64_URL_SAFE_NO_PAD.encode(&point[33..65]),
+        })
+    }
+
+    /// The JWK thumbprint (RFC 7638) that key authorizations end with.
+    fn thumbprint(&self) -> String {
+        let digest = ring::digest::digest(&ring::digest::SHA256, self.jwk().to_string().as_bytes());
+        BASE64_URL_SAFE_NO_PAD.encode(digest.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, time::SystemTime};
+
+    use axum::{
+        Json, Router,
+        http::{HeaderMap, StatusCode},
+        response::IntoResponse,
+        routing::{get, head, post},
+    };
+    use ring::signature::{ECDSA_P256_SHA256_FIXED, UnparsedPublicKey};
+
+    use super::*;
+
+    fn options(name: &str, directory: &str) -> AcmeOptions {
+        let dir = std::env::temp_dir().join(format!("acme-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        AcmeOptions {
+            directory: directory.parse().unwrap(),
+            domains: vec!["gateway.example.com".to_owned()],
+            email: None,
+            dir,
+        }
+    }
+
+    #[test]
+    fn renews_missing_and_old_certificates() {
+        let options = options("renewal", "https://ca.example.com/directory");
+        let (cert, key) = options.files();
+        assert!(needs_renewal(&options));
+
+        write(&cert, "cert").unwrap();
+        write(&key, "key").unwrap();
+        assert!(!cert.with_extension("part").exists());
+        assert!(!needs_renewal(&options));
+
+        File::options()
+            .write(true)
+            .open(&cert)
+            .unwrap()
+            .set_modified(SystemTime::now() - RENEW_AFTER - Duration::from_secs(60))
+            .unwrap();
+        assert!(needs_renewal(&options));
+        std::fs::remove_dir_all(&options.dir).unwrap();
+    }
+
+    // Checks a JWS as the CA would, returning its protected header and
+    // payload
+    fn verify_jws(body: &Value) -> (Value, String) {
+        let decode = |field: &str| BASE64_URL_SAFE_NO_PAD.decode(body[field].as_str().unwrap());
+        let protected: Value = serde_json::from_slice(&decode("protected").unwrap()).unwrap();
+        let jwk = &protected["jwk"];
+        let mut point = vec![4];
+        for coordinate in ["x", "y"] {
+            point.extend(
+                BASE64_URL_SAFE_NO_PAD
+                    .decode(jwk[coordinate].as_str().unwrap())
+                    .unwrap(),
+            );
+        }
+        let signed = format!(
+            "{}.{}",
+            body["protected"].as_str().unwrap(),
+            body["payload"].as_str().unwrap()
+        );
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+            .verify(signed.as_bytes(), &decode("signature").unwrap())
+            .unwrap();
+        let payload = String::from_utf8(decode("payload").unwrap()).unwrap();
+        (protected, payload)
+    }
+
+    #[tokio::test]
+    async fn signs_requests_and_retries_bad_nonces() {
+        async fn account(body: String) -> axum::response::Response {
+            let (protected, payload) = verify_jws(&serde_json::from_str(&body).unwrap());
+            assert_eq!(protected["alg"], "ES256");
+            assert!(protected["url"].as_str().unwrap().ends_with("/account"));
+            assert_eq!(
+                serde_json::from_str::<Value>(&payload).unwrap()["termsOfServiceAgreed"],
+                true
+            );
+            match protected["nonce"].as_str() {
+                // The first nonce is turned down with a new one
+                Some("first") => (
+                    StatusCode::BAD_REQUEST,
+                    [("replay-nonce", "second")],
+                    format!("{{\"type\":\"{}\"}}", BAD_NONCE),
+                )
+                    .into_response(),
+                Some("second") => (
+                    StatusCode::CREATED,
+                    [("replay-nonce", "third"), ("location", "/account/1")],
+                )
+                    .into_response(),
+                _ => StatusCode::UNAUTHORIZED.into_response(),
+            }
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let directory = json!({
+            "newNonce": format!("{}/nonce", base),
+            "newAccount": format!("{}/account", base),
+            "newOrder": format!("{}/order", base),
+        });
+        let app = Router::new()
+            .route("/directory", get(move || async move { Json(directory) }))
+            .route(
+                "/nonce",
+                head(|| async {
+                    let mut headers = HeaderMap::new();
+                    headers.insert("replay-nonce", "first".parse().unwrap());
+                    headers
+                }),
+            )
+            .route("/account", post(account));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let options = options("client", &format!("{}/directory", base));
+        let mut client = Client::new(&options).await.unwrap();
+        client.register(None).await.unwrap();
+        assert_eq!(client.account.as_deref(), Some("/account/1"));
+        assert_eq!(client.nonce.as_deref(), Some("third"));
+
+        // The account key is kept and used again
+        let again = Client::new(&options).await.unwrap();
+        assert_eq!(again.thumbprint(), client.thumbprint());
+
+        // RFC 7638: the required members in order, without whitespace
+        let jwk = client.jwk();
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#,
+            jwk["x"], jwk["y"]
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, canonical.as_bytes());
+        assert_eq!(
+            client.thumbprint(),
+            BASE64_URL_SAFE_NO_PAD.encode(digest.as_ref())
+        );
+        std::fs::remove_dir_all(&options.dir).unwrap();
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, atomic::Ordering},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::{state::AppState, stats::PipelineStats};
+
+// Notifier requests that take longer than this are abandoned
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Quantity an alert rule watches, measured per source over one evaluation
+/// interval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Metric {
+    /// Percentage of RTP packets received from the camera that were dropped.
+    Loss,
+    /// Viewer connections that failed.
+    ViewerErrors,
+    /// Attempts to reconnect to a camera that dropped its session.
+    Reconnects,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum AlertRuleParseError {
+    #[error("alert rule must look like 'metric>threshold'")]
+    Syntax,
+    #[error("unknown metric '{0}', expected 'loss', 'viewer-errors' or 'reconnects'")]
+    UnknownMetric(String),
+    #[error("invalid threshold '{0}'")]
+    Threshold(String),
+}
+
+/// A threshold, written as `metric>value`, e.g. `loss>5` (percent),
+/// `viewer-errors>3` or `reconnects>2` (per interval).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlertRule {
+    pub metric: Metric,
+    pub threshold: f64,
+}
+
+impl std::str::FromStr for AlertRule {
+    type Err = AlertRuleParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (metric, threshold) = s.split_once('>').ok_or(AlertRuleParseError::Syntax)?;
+        let metric = match metric.trim() {
+            "loss" => Metric::Loss,
+            "viewer-errors" => Metric::ViewerErrors,
+            "reconnects" => Metric::Reconnects,
+            other => return Err(AlertRuleParseError::UnknownMetric(other.to_owned())),
+        };
+        let threshold = threshold
+            .trim()
+            .parse()
+            .map_err(|_| AlertRuleParseError::Threshold(threshold.to_owned()))?;
+        Ok(AlertRule { metric, threshold })
+    }
+}
+
+impl std::fmt::Display for AlertRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let metric = match self.metric {
+            Metric::Loss => "loss",
+            Metric::ViewerErrors => "viewer-errors",
+            Metric::Reconnects => "reconnects",
+        };
+        write!(f, "{}>{}", metric, self.threshold)
+    }
+}
+
+/// A rule starting or stopping to match on a source.
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub source: String,
+    pub rule: String,
+    pub value: f64,
+    /// `false` once the value is back under the threshold.
+    pub firing: bool,
+}
+
+/// Delivers alerts somewhere outside the process.
+pub trait Notifier: Send + Sync {
+    fn notify<'a>(
+        &'a self,
+        alert: &'a Alert,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+}
+
+/// POSTs each alert as JSON.
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+    url: url::Url,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: url::Url) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(NOTIFY_TIMEOUT)
+                .build()
+                .expect("failed to build alert HTTP client"),
+            url,
+        }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify<'a>(
+        &'a self,
+        alert: &'a Alert,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            self.client
+                .post(self.url.clone())
+                .json(alert)
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok(())
+        })
+    }
+}
+
+/// Counter values at the previous evaluation, to measure one interval.
+#[derive(Default, Clone, Copy)]
+struct Baseline {
+    received: u64,
+    dropped: u64,
+    viewer_failures: u64,
+    reconnects: u64,
+}
+
+impl Baseline {
+    fn read(stats: &PipelineStats) -> Self {
+        Self {
+            received: stats.video.received.load(Ordering::Relaxed)
+                + stats.audio.received.load(Ordering::Relaxed),
+            dropped: stats.video.dropped.load(Ordering::Relaxed)
+                + stats.audio.dropped.load(Ordering::Relaxed),
+            viewer_failures: stats.viewer_failures.load(Ordering::Relaxed),
+            reconnects: stats.reconnects.load(Ordering::Relaxed),
+        }
+    }
+
+    fn value(&self, previous: &Baseline, metric: Metric) -> f64 {
+        match metric {
+            Metric::Loss => {
+                let received = self.received.saturating_sub(previous.received);
+                let dropped = self.dropped.saturating_sub(previous.dropped);
+                if received == 0 {
+                    0.0
+                } else {
+                    dropped as f64 * 100.0 / received as f64
+                }
+            }
+            Metric::ViewerErrors => {
+                self.viewer_failures
+                    .saturating_sub(previous.viewer_failures) as f64
+            }
+            Metric::Reconnects => self.reconnects.saturating_sub(previous.reconnects) as f64,
+        }
+    }
+}
+
+/// Evaluates `rules` against every source each `interval` and tells the
+/// notifiers when a rule starts or stops matching.
+pub fn spawn_alerts(
+    state: AppState,
+    rules: Vec<AlertRule>,
+    interval: Duration,
+    notifiers: Vec<Arc<dyn Notifier>>,
+) {
+    if rules.is_empty() || interval.is_zero() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut baselines: HashMap<String, Baseline> = state
+            .all_streams()
+            .iter()
+            .map(|stream| (stream.info.name.clone(), Baseline::read(&stream.stats)))
+            .collect();
+        let mut firing: HashMap<(String, usize), bool> = HashMap::new();
+
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+
+            for stream in state.all_streams() {
+                let name = &stream.info.name;
+                let current = Baseline::read(&stream.stats);
+                let previous = baselines.insert(name.clone(), current).unwrap_or_default();
+
+                for (index, rule) in rules.iter().enumerate() {
+                    let value = current.value(&previous, rule.metric);
+                    let matches = value > rule.threshold;
+                    let was_firing = firing.insert((name.clone(), index), matches);
+                    if was_firing.unwrap_or(false) == matches {
+                        continue;
+                    }
+
+                    let alert = Alert {
+                        source: name.clone(),
+                        rule: rule.to_string(),
+                        value,
+                        firing: matches,
+                    };
+                    if matches {
+                        warn!("🚨 [{}] Alert {} firing: {:.2}", name, alert.rule, value);
+                    } else {
+                        info!("✅ [{}] Alert {} resolved: {:.2}", name, alert.rule, value);
+                    }
+
+                    for notifier in &notifiers {
+                        match notifier.notify(&alert).await {
+                            Ok(()) => debug!("Alert {:?} delivered", alert),
+                            Err(e) => warn!("Alert notifier failed: {}", e),
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
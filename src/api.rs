@@ -0,0 +1,531 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    Extension, Json,
+    extract::{Path, RawQuery, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{
+        IntoResponse,
+        sse::{self, KeepAlive, Sse},
+    },
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+use tokio_stream::{Stream, StreamExt, wrappers::BroadcastStream};
+use tracing::{info, warn};
+use webrtc::stats::{ICECandidateStats, StatsReportType};
+
+use crate::{
+    auth::Principal,
+    cli::{SourceSpec, Tag},
+    events::Event,
+    ingest, pairing, shutdown,
+    startup::StartupReport,
+    state::{AppState, Capabilities, SourceInfo},
+    stats::{MetricLabels, Snapshot},
+    store::{SessionInfo, SessionStore, StoredSession},
+};
+
+// Viewers get this long to receive `source_disabled` before being hung up on
+const DISABLE_NOTICE: Duration = Duration::from_millis(500);
+
+/// A source as listed by `GET /api/sources`.
+#[derive(Debug, Serialize)]
+pub struct ListedSource {
+    #[serde(flatten)]
+    pub info: SourceInfo,
+    /// Disabled through `POST /api/sources/{name}/disable`.
+    pub disabled: bool,
+    /// Target seconds of video per HLS segment, with `--hls`.
+    pub hls_segment: Option<u64>,
+    /// Target seconds of video per recording segment, with `--record-dir`.
+    pub record_segment: Option<u64>,
+}
+
+/// `GET /api/sources`, optionally filtered by `?tag=key:value` (repeatable, all
+/// must match; a bare `?tag=key` matches any value).
+pub async fn list_sources(
+    State(state): State<AppState>,
+    RawQuery(query): RawQuery,
+) -> Json<Vec<ListedSource>> {
+    let filters: Vec<(String, Option<String>)> =
+        url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+            .filter(|(key, _)| key == "tag")
+            .map(|(_, tag)| match tag.split_once(':') {
+                Some((key, value)) => (key.to_owned(), Some(value.to_owned())),
+                None => (tag.into_owned(), None),
+            })
+            .collect();
+
+    let sources = state
+        .all_streams()
+        .iter()
+        .filter(|stream| {
+            filters.iter().all(|(key, value)| match value {
+                Some(value) => stream.info.tags.get(key) == Some(value),
+                None => stream.info.tags.contains_key(key),
+            })
+        })
+        .map(|stream| ListedSource {
+            info: stream.info.clone(),
+            disabled: stream.control.is_suspended(),
+            hls_segment: stream.hls.as_ref().map(|_| state.options.hls_segment),
+            // WHIP publishers aren't recorded
+            record_segment: state
+                .options
+                .record_dir
+                .as_ref()
+                .filter(|_| stream.info.url.scheme() != "whip")
+                .map(|_| state.options.record_segment),
+        })
+        .collect();
+
+    Json(sources)
+}
+
+/// A source as listed by `GET /api/catalog`.
+#[derive(Debug, Serialize)]
+pub struct CatalogEntry {
+    pub name: String,
+    /// Display name, falling back to `name`.
+    pub title: String,
+    pub description: Option<String>,
+    #[serde(flatten)]
+    pub capabilities: Capabilities,
+    pub has_audio: bool,
+    pub has_metadata: bool,
+    pub whep_url: String,
+}
+
+/// `GET /api/catalog`: the sources the caller may watch, with what a player
+/// needs to know before offering (codecs, resolutions, audio, WHEP URL).
+pub async fn catalog(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+) -> Json<Vec<CatalogEntry>> {
+    let entries = state
+        .all_streams()
+        .iter()
+        .filter(|stream| {
+            principal
+                .as_ref()
+                .is_none_or(|Extension(principal)| principal.can_view(&stream.info.name))
+        })
+        .map(|stream| {
+            let path = format!("whep/{}", stream.info.name);
+            let whep_url = match &state.options.public_url {
+                Some(base) => base
+                    .join(&path)
+                    .map_or_else(|_| format!("/{}", path), String::from),
+                None => format!("/{}", path),
+            };
+            CatalogEntry {
+                name: stream.info.name.clone(),
+                title: stream.info.title().to_owned(),
+                description: stream.info.description.clone(),
+                capabilities: stream.capabilities.clone(),
+                has_audio: stream.audio_track.is_some(),
+                has_metadata: stream.info.metadata_codec.is_some(),
+                whep_url,
+            }
+        })
+        .collect();
+
+    Json(entries)
+}
+
+/// Body of `POST /api/sources`, the JSON form of `--source`.
+#[derive(Debug, Deserialize)]
+pub struct NewSource {
+    pub name: String,
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default)]
+    pub relay_only: bool,
+    pub substream: Option<String>,
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+    pub interface: Option<String>,
+}
+
+/// `POST /api/sources`: connects to a camera and serves it like a `--source`.
+pub async fn add_source(
+    State(state): State<AppState>,
+    Json(source): Json<NewSource>,
+) -> Result<(StatusCode, Json<SourceInfo>), (StatusCode, String)> {
+    if source.name.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "name must not be empty".to_owned()));
+    }
+    if state.stream(&source.name).is_some() {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("source '{}' already exists", source.name),
+        ));
+    }
+    let spec = SourceSpec {
+        url: source
+            .url
+            .parse()
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("{}", e)))?,
+        name: source.name,
+        username: source.username,
+        password: source.password,
+        tags: source
+            .tags
+            .into_iter()
+            .map(|(key, value)| Tag { key, value })
+            .collect(),
+        relay_only: source.relay_only,
+        substream: source.substream,
+        display_name: source.display_name,
+        description: source.description,
+        priority: 0,
+        attempts: None,
+        wait_for_dns: false,
+        no_metadata: false,
+        gain: 0,
+        interface: source.interface,
+    };
+
+    let stream = ingest::start(&spec, &state.options).await.map_err(|e| {
+        warn!("Failed to start source '{}': {:#}", spec.name, e);
+        (StatusCode::BAD_GATEWAY, format!("{:#}", e))
+    })?;
+    let info = stream.info.clone();
+    let control = stream.control.clone();
+    if !state.add_stream(stream) {
+        control.stop.notify_one();
+        return Err((
+            StatusCode::CONFLICT,
+            format!("source '{}' already exists", info.name),
+        ));
+    }
+
+    ingest::spawn_rebuilder(&state, spec);
+    info!("➕ Source '{}' added", info.name);
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+/// `DELETE /api/sources/{name}`: hangs up on the source's viewers, stops it
+/// and disconnects from it.
+pub async fn delete_source(State(state): State<AppState>, Path(name): Path<String>) -> StatusCode {
+    let Some(stream) = state.remove_stream(&name) else {
+        return StatusCode::NOT_FOUND;
+    };
+    let viewers = shutdown::close_source(&state, &stream).await;
+    info!(
+        "➖ Source '{}' removed, {} viewer(s) disconnected",
+        name, viewers
+    );
+    StatusCode::NO_CONTENT
+}
+
+/// `POST /api/sources/{name}/disable`: closes the source's RTSP session and
+/// hangs up on its viewers, who are told with a `source_disabled` event first.
+/// New viewers are turned away until it is enabled again.
+pub async fn disable_source(State(state): State<AppState>, Path(name): Path<String>) -> StatusCode {
+    let Some(stream) = state.stream(&name) else {
+        return StatusCode::NOT_FOUND;
+    };
+    if stream.control.suspended.send_replace(true) {
+        return StatusCode::NO_CONTENT;
+    }
+    if stream.events.send(Event::SourceDisabled).is_ok() {
+        tokio::time::sleep(DISABLE_NOTICE).await;
+    }
+    let viewers = shutdown::close_viewers(&state, &name).await;
+    info!(
+        "⏸️  Source '{}' disabled, {} viewer(s) disconnected",
+        name, viewers
+    );
+    StatusCode::NO_CONTENT
+}
+
+/// `POST /api/sources/{name}/enable`: reconnects a disabled source (on demand
+/// sources once a viewer joins) and lets viewers in again. Enabling a source
+/// whose reconnect failed tries again.
+pub async fn enable_source(State(state): State<AppState>, Path(name): Path<String>) -> StatusCode {
+    let Some(stream) = state.stream(&name) else {
+        return StatusCode::NOT_FOUND;
+    };
+    if stream.control.suspended.send_replace(false) {
+        let _ = stream.events.send(Event::SourceEnabled);
+        info!("▶️  Source '{}' enabled", name);
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// `GET /api/sources/{name}/metadata`: the source's metadata documents (e.g.
+/// ONVIF analytics) as Server-Sent Events, one `metadata` event per document.
+pub async fn source_metadata(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, StatusCode> {
+    let stream = state.stream(&name).ok_or(StatusCode::NOT_FOUND)?;
+    if stream.info.metadata_codec.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    // Documents missed by a slow client are skipped, not queued
+    let documents = BroadcastStream::new(stream.metadata.subscribe())
+        .filter_map(|document| document.ok())
+        .map(|document| Ok(sse::Event::default().event("metadata").data(document)));
+
+    Ok(Sse::new(documents).keep_alive(KeepAlive::default()))
+}
+
+/// `GET /api/sources/{name}/startup`: where the source's latest (re)connect
+/// and its last viewer spent their time to first frame.
+pub async fn source_startup(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<StartupReport>, StatusCode> {
+    let stream = state.stream(&name).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(stream.startup.report()))
+}
+
+/// A link a phone can watch a source with for a while, e.g. to check a
+/// camera's aim while installing it.
+#[derive(Debug, Serialize)]
+pub struct PairingLink {
+    /// The player, opened on the source with a pairing token.
+    pub url: String,
+    /// Unix time after which the token is no longer accepted.
+    pub expires_at: u64,
+    /// `url` as a QR code, base64 encoded PNG.
+    pub qr_png: String,
+}
+
+/// `GET /api/sources/{name}/pairing`: a short-lived signed viewing link for
+/// the source, and its QR code. Links point at `--public-url`, or else at the
+/// host the request was made to.
+pub async fn source_pairing(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<PairingLink>, StatusCode> {
+    let stream = state.stream(&name).ok_or(StatusCode::NOT_FOUND)?;
+    let mut url = match &state.options.public_url {
+        Some(public_url) => public_url.clone(),
+        None => {
+            let host = headers
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .ok_or(StatusCode::BAD_REQUEST)?;
+            let scheme = if state.options.tls() { "https" } else { "http" };
+            url::Url::parse(&format!("{}://{}/", scheme, host))
+                .map_err(|_| StatusCode::BAD_REQUEST)?
+        }
+    };
+
+    let (token, expires_at) = state.pairing.issue(&stream.info.name);
+    url.query_pairs_mut()
+        .clear()
+        .append_pair("stream", &stream.info.name)
+        .append_pair("access_token", &token);
+    let qr_png = pairing::qr_png(url.as_str()).map_err(|e| {
+        warn!("No pairing QR code for source '{}': {}", name, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    info!("📱 Pairing link issued for source '{}'", name);
+    Ok(Json(PairingLink {
+        url: url.into(),
+        expires_at,
+        qr_png: BASE64_STANDARD.encode(qr_png),
+    }))
+}
+
+/// `GET /api/stats/snapshot`: sources, sessions and pipeline counters as JSON.
+pub async fn stats_snapshot(State(state): State<AppState>) -> Json<Snapshot> {
+    Json(Snapshot::take(&state))
+}
+
+/// Viewer sessions, as counted against `--max-viewers`.
+#[derive(Debug, Serialize)]
+pub struct ViewerCount {
+    /// Admitted viewer sessions, including ones still negotiating.
+    pub viewers: usize,
+    pub max_viewers: Option<usize>,
+    /// Connected viewers per source.
+    pub sources: BTreeMap<String, usize>,
+}
+
+/// `GET /api/viewers`
+pub async fn viewer_count(State(state): State<AppState>) -> Json<ViewerCount> {
+    Json(ViewerCount {
+        viewers: state.viewer_limit.count(),
+        max_viewers: state.viewer_limit.max(),
+        sources: state
+            .all_streams()
+            .iter()
+            .map(|stream| (stream.info.name.clone(), *stream.viewers.borrow()))
+            .collect(),
+    })
+}
+
+/// `GET /metrics`: the same counters in the OpenMetrics text format.
+pub async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let labels = MetricLabels {
+        dropped: state.options.metrics_drop_label.clone(),
+        source_tag: state.options.metrics_source_tag.clone(),
+    };
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        Snapshot::take(&state).to_openmetrics(&labels),
+    )
+}
+
+/// Whether a session watches a source (WHEP) or publishes one (WHIP).
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionKind {
+    Viewer,
+    Publisher,
+}
+
+/// One end of a session's selected ICE candidate pair.
+#[derive(Debug, Serialize)]
+pub struct Candidate {
+    pub address: String,
+    pub port: u16,
+    /// `host`, `srflx`, `prflx` or `relay`.
+    #[serde(rename = "type")]
+    pub candidate_type: String,
+    /// e.g. `udp4`.
+    pub network: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CandidatePair {
+    pub local: Candidate,
+    pub remote: Candidate,
+}
+
+/// A peer connection as reported by `GET /api/sessions`.
+#[derive(Debug, Serialize)]
+pub struct SessionDetails {
+    #[serde(flatten)]
+    pub info: SessionInfo,
+    pub kind: SessionKind,
+    pub ice_state: String,
+    /// `None` until ICE has nominated a pair.
+    pub candidate_pair: Option<CandidatePair>,
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    pub uptime_secs: u64,
+}
+
+impl SessionDetails {
+    async fn collect(kind: SessionKind, session: StoredSession) -> Self {
+        let report = session.pc.get_stats().await.reports;
+
+        let candidate_pair = nominated_pair(&report);
+        let (bytes_sent, bytes_received) = report
+            .values()
+            .find_map(|stats| match stats {
+                StatsReportType::Transport(transport) => {
+                    Some((transport.bytes_sent, transport.bytes_received))
+                }
+                _ => None,
+            })
+            .unwrap_or_default();
+
+        Self {
+            info: session.info,
+            kind,
+            ice_state: session.pc.ice_connection_state().to_string(),
+            candidate_pair,
+            bytes_sent,
+            bytes_received,
+            uptime_secs: session.uptime.as_secs(),
+        }
+    }
+}
+
+/// The candidate pair ICE nominated, from a connection's stats.
+pub fn nominated_pair(report: &HashMap<String, StatsReportType>) -> Option<CandidatePair> {
+    let candidate = |id: &str| match report.get(id) {
+        Some(
+            StatsReportType::LocalCandidate(candidate)
+            | StatsReportType::RemoteCandidate(candidate),
+        ) => Some(Candidate::from(candidate)),
+        _ => None,
+    };
+    report.values().find_map(|stats| match stats {
+        StatsReportType::CandidatePair(pair) if pair.nominated => Some(CandidatePair {
+            local: candidate(&pair.local_candidate_id)?,
+            remote: candidate(&pair.remote_candidate_id)?,
+        }),
+        _ => None,
+    })
+}
+
+impl From<&ICECandidateStats> for Candidate {
+    fn from(stats: &ICECandidateStats) -> Self {
+        Self {
+            address: stats.ip.clone(),
+            port: stats.port,
+            candidate_type: stats.candidate_type.to_string(),
+            network: stats.network_type.to_string(),
+        }
+    }
+}
+
+// Viewer sessions first, then publishers
+fn stores(state: &AppState) -> [(SessionKind, &Arc<dyn SessionStore>); 2] {
+    [
+        (SessionKind::Viewer, &state.sessions),
+        (SessionKind::Publisher, &state.publishers),
+    ]
+}
+
+/// `GET /api/sessions`: every viewer and publisher connection.
+pub async fn list_sessions(State(state): State<AppState>) -> Json<Vec<SessionDetails>> {
+    let mut sessions = Vec::new();
+    for (kind, store) in stores(&state) {
+        for info in store.list() {
+            // Skip sessions that ended while we were collecting
+            if let Some(session) = store.get(&info.id) {
+                sessions.push(SessionDetails::collect(kind, session).await);
+            }
+        }
+    }
+    Json(sessions)
+}
+
+/// `GET /api/sessions/{id}`
+pub async fn get_session(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<SessionDetails>, StatusCode> {
+    for (kind, store) in stores(&state) {
+        if let Some(session) = store.get(&id) {
+            return Ok(Json(SessionDetails::collect(kind, session).await));
+        }
+    }
+    Err(StatusCode::NOT_FOUND)
+}
+
+/// `DELETE /api/sessions/{id}`: kicks a viewer or publisher.
+pub async fn delete_session(State(state): State<AppState>, Path(id): Path<String>) -> StatusCode {
+    for (_, store) in stores(&state) {
+        if let Some(pc) = store.remove(&id) {
+            let _ = pc.close().await;
+            info!("👢 Session {} closed by operator", id);
+            return StatusCode::NO_CONTENT;
+        }
+    }
+    StatusCode::NOT_FOUND
+}
@@ -0,0 +1,21 @@
+use axum::http::{HeaderValue, Response, header};
+
+/// `Cache-Control` for a static file response. The player's HTML is
+/// revalidated on every load, so upgrades reach viewers right away; other
+/// assets (scripts, styles, wasm) are cached for `max_age` seconds.
+pub fn cache_control<B>(response: &Response<B>, max_age: u64) -> Option<HeaderValue> {
+    // A 304 keeps what the cached response said
+    if !response.status().is_success() {
+        return None;
+    }
+    let html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/html"));
+    if html || max_age == 0 {
+        Some(HeaderValue::from_static("no-cache"))
+    } else {
+        HeaderValue::from_str(&format!("public, max-age={}", max_age)).ok()
+    }
+}
@@ -0,0 +1,291 @@
+use bytes::Bytes;
+use webrtc::rtp::packet::Packet;
+
+// Gains beyond this only add clipping
+const MAX_GAIN_DB: i32 = 40;
+// G.711 carries one 8 kHz sample per byte
+const G711_SAMPLE_RATE: u32 = 8000;
+// Sound must last this long to count, so clicks and pops don't
+const SOUND_ONSET_MS: u32 = 200;
+// Sound has stopped once it has been quiet this long, bridging pauses in speech
+const SOUND_HANGOVER_MS: u32 = 2000;
+
+/// A fixed gain applied to a source's audio before it is relayed.
+///
+/// Only G.711 (PCMU/PCMA) can be amplified: its samples are re-encoded one by
+/// one through a table, while Opus and G.722 are relayed as they come. Channel
+/// mapping is deferred: of the relayed codecs only Opus has channels, and
+/// remapping them takes an Opus decoder and encoder.
+pub struct Gain {
+    table: [u8; 256],
+}
+
+impl Gain {
+    /// Returns `None` for 0 dB and for encodings that can't be amplified.
+    pub fn new(encoding_name: &str, db: i32) -> Option<Self> {
+        if db == 0 {
+            return None;
+        }
+        let factor = 10f64.powf(db.clamp(-MAX_GAIN_DB, MAX_GAIN_DB) as f64 / 20.0);
+        let amplify =
+            |sample: i16| (sample as f64 * factor).clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+
+        let table = match encoding_name {
+            "pcmu" => {
+                std::array::from_fn(|code| linear_to_ulaw(amplify(ulaw_to_linear(code as u8))))
+            }
+            "pcma" => {
+                std::array::from_fn(|code| linear_to_alaw(amplify(alaw_to_linear(code as u8))))
+            }
+            _ => return None,
+        };
+        Some(Self { table })
+    }
+
+    pub fn apply(&self, mut pkt: Packet) -> Packet {
+        pkt.payload = pkt
+            .payload
+            .iter()
+            .map(|&code| self.table[code as usize])
+            .collect::<Bytes>();
+        pkt
+    }
+}
+
+/// Tells when a source's audio starts and stops being louder than a
+/// threshold, from the RMS level of each packet. Like [`Gain`], it only
+/// decodes G.711.
+pub struct SoundDetector {
+    decode: fn(u8) -> i16,
+    threshold_dbfs: f64,
+    active: bool,
+    /// Consecutive samples on the other side of the threshold than `active`.
+    crossing: u32,
+    /// The loudest level while crossing, in dBFS.
+    peak_dbfs: f64,
+}
+
+impl SoundDetector {
+    /// Returns `None` for encodings that can't be decoded.
+    pub fn new(encoding_name: &str, threshold_dbfs: f64) -> Option<Self> {
+        let decode = match encoding_name {
+            "pcmu" => ulaw_to_linear,
+            "pcma" => alaw_to_linear,
+            _ => return None,
+        };
+        Some(Self {
+            decode,
+            threshold_dbfs,
+            active: false,
+            crossing: 0,
+            peak_dbfs: f64::NEG_INFINITY,
+        })
+    }
+
+    /// Measures the packet's `payload`, returning whether there is sound, and
+    /// the loudest packet level (in dBFS) since it began to change, when it
+    /// does.
+    pub fn push(&mut self, payload: &[u8]) -> Option<(bool, f64)> {
+        if payload.is_empty() {
+            return None;
+        }
+        let level = rms_dbfs(payload.iter().map(|&code| (self.decode)(code)));
+        if (level >= self.threshold_dbfs) == self.active {
+            self.crossing = 0;
+            self.peak_dbfs = f64::NEG_INFINITY;
+            return None;
+        }
+
+        self.crossing += payload.len() as u32;
+        self.peak_dbfs = self.peak_dbfs.max(level);
+        let needed = if self.active {
+            SOUND_HANGOVER_MS
+        } else {
+            SOUND_ONSET_MS
+        };
+        if self.crossing * 1000 < needed * G711_SAMPLE_RATE {
+            return None;
+        }
+        self.active = !self.active;
+        self.crossing = 0;
+        let peak = std::mem::replace(&mut self.peak_dbfs, f64::NEG_INFINITY);
+        Some((self.active, peak))
+    }
+}
+
+/// The RMS level of `samples` relative to full scale, in dB.
+fn rms_dbfs(samples: impl Iterator<Item = i16>) -> f64 {
+    let (sum, count) = samples.fold((0.0, 0u32), |(sum, count), sample| {
+        (sum + (sample as f64).powi(2), count + 1)
+    });
+    let rms = (sum / count.max(1) as f64).sqrt();
+    20.0 * (rms / i16::MAX as f64).max(f64::MIN_POSITIVE).log10()
+}
+
+/// Decodes a G.711 μ-law sample.
+fn ulaw_to_linear(code: u8) -> i16 {
+    let code = !code;
+    let exponent = (code >> 4) & 0x07;
+    let mantissa = (code & 0x0f) as i16;
+    let magnitude = (((mantissa << 3) + 0x84) << exponent) - 0x84;
+    if code & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// Decodes a G.711 A-law sample.
+fn alaw_to_linear(code: u8) -> i16 {
+    let code = code ^ 0x55;
+    let exponent = (code >> 4) & 0x07;
+    let mantissa = (code & 0x0f) as i16;
+    let magnitude = match exponent {
+        0 => (mantissa << 4) + 8,
+        _ => ((mantissa << 4) + 0x108) << (exponent - 1),
+    };
+    if code & 0x80 != 0 {
+        magnitude
+    } else {
+        -magnitude
+    }
+}
+
+fn linear_to_ulaw(sample: i16) -> u8 {
+    const BIAS: i32 = 0x84;
+    const CLIP: i32 = 32635;
+
+    let sign = if sample < 0 { 0x80 } else { 0 };
+    let magnitude = (sample as i32).abs().min(CLIP) + BIAS;
+    let exponent = 31 - (magnitude >> 7).leading_zeros() as i32;
+    let mantissa = (magnitude >> (exponent + 3)) & 0x0f;
+    !(sign | (exponent << 4) as u8 | mantissa as u8)
+}
+
+fn linear_to_alaw(sample: i16) -> u8 {
+    let sign = if sample < 0 { 0 } else { 0x80 };
+    // A-law works on 13-bit samples
+    let magnitude = ((sample as i32).abs() >> 3).min(0x0fff);
+    let code = if magnitude < 32 {
+        (magnitude >> 1) as u8
+    } else {
+        let exponent = 31 - (magnitude as u32).leading_zeros() - 4;
+        ((exponent << 4) as u8) | ((magnitude >> exponent) & 0x0f) as u8
+    };
+    (sign | code) ^ 0x55
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One 20 ms packet of G.711
+    const PACKET_SAMPLES: usize = 160;
+
+    #[test]
+    fn decodes_g711() {
+        assert_eq!(ulaw_to_linear(0x00), -32124);
+        assert_eq!(ulaw_to_linear(0x80), 32124);
+        assert_eq!(ulaw_to_linear(0xff), 0);
+        assert_eq!(alaw_to_linear(0xd5), 8);
+        assert_eq!(alaw_to_linear(0x55), -8);
+        assert_eq!(alaw_to_linear(0x2a), -32256);
+        assert_eq!(alaw_to_linear(0xaa), 32256);
+    }
+
+    #[test]
+    fn encodes_g711() {
+        assert_eq!(linear_to_ulaw(0), 0xff);
+        assert_eq!(linear_to_ulaw(32767), 0x80);
+        assert_eq!(linear_to_ulaw(-32768), 0x00);
+        assert_eq!(linear_to_alaw(0), 0xd5);
+        assert_eq!(linear_to_alaw(32767), 0xaa);
+        assert_eq!(linear_to_alaw(-32768), 0x2a);
+    }
+
+    #[test]
+    fn g711_round_trips() {
+        for code in 0..=255 {
+            // Negative zero encodes as positive zero
+            if code != 0x7f {
+                assert_eq!(
+                    linear_to_ulaw(ulaw_to_linear(code)),
+                    code,
+                    "μ-law {code:#x}"
+                );
+            }
+            assert_eq!(
+                linear_to_alaw(alaw_to_linear(code)),
+                code,
+                "A-law {code:#x}"
+            );
+        }
+    }
+
+    #[test]
+    fn gain_needs_g711_and_a_change() {
+        assert!(Gain::new("pcmu", 0).is_none());
+        assert!(Gain::new("opus", 6).is_none());
+        assert!(Gain::new("pcma", -6).is_some());
+    }
+
+    #[test]
+    fn gain_scales_samples() {
+        let pkt = |payload: Vec<u8>| Packet {
+            payload: payload.into(),
+            ..Default::default()
+        };
+        let quiet = linear_to_ulaw(1000);
+        let loud = Gain::new("pcmu", 20).unwrap().apply(pkt(vec![quiet, 0xff]));
+        // 20 dB is 10 times the amplitude, within a step of μ-law
+        let amplified = ulaw_to_linear(loud.payload[0]);
+        assert!((9500..=10500).contains(&amplified), "{amplified}");
+        assert_eq!(loud.payload[1], 0xff);
+
+        // Clipped rather than wrapped around
+        let clipped = Gain::new("pcma", 40).unwrap().apply(pkt(vec![0xaa, 0x2a]));
+        assert_eq!(&clipped.payload[..], [0xaa, 0x2a]);
+        let attenuated = Gain::new("pcma", -40).unwrap().apply(pkt(vec![0xaa]));
+        assert!(alaw_to_linear(attenuated.payload[0]).abs() < 400);
+    }
+
+    #[test]
+    fn detects_sound_after_onset() {
+        let mut detector = SoundDetector::new("pcmu", -30.0).unwrap();
+        let loud = [0x80; PACKET_SAMPLES];
+        let quiet = [0xff; PACKET_SAMPLES];
+
+        // 200 ms of sound is 10 packets
+        for _ in 0..9 {
+            assert_eq!(detector.push(&loud), None);
+        }
+        let (active, level) = detector.push(&loud).unwrap();
+        assert!(active);
+        assert!((-0.2..0.0).contains(&level), "{level}");
+
+        // A short pause doesn't end it, 2 s of quiet do
+        for _ in 0..50 {
+            assert_eq!(detector.push(&quiet), None);
+        }
+        assert_eq!(detector.push(&loud), None);
+        for _ in 0..99 {
+            assert_eq!(detector.push(&quiet), None);
+        }
+        let (active, level) = detector.push(&quiet).unwrap();
+        assert!(!active);
+        assert!(level < -100.0, "{level}");
+    }
+
+    #[test]
+    fn ignores_clicks() {
+        let mut detector = SoundDetector::new("pcma", -30.0).unwrap();
+        let click = [0xaa; PACKET_SAMPLES];
+        let quiet = [0xd5; PACKET_SAMPLES];
+        for _ in 0..100 {
+            assert_eq!(detector.push(&click), None);
+            assert_eq!(detector.push(&quiet), None);
+        }
+        assert!(detector.push(&[]).is_none());
+        assert!(SoundDetector::new("opus", -30.0).is_none());
+    }
+}
@@ -0,0 +1,447 @@
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{
+    callback::AuthCallback,
+    jwt::JwtValidator,
+    pairing::Pairing,
+    tokens::{self, TokenFile},
+};
+
+/// Access level of an API token; each role includes the ones below it.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// May watch streams through WHEP.
+    Viewer,
+    /// May also read sources and statistics.
+    Operator,
+    /// May also manage sources and recordings.
+    Admin,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ApiTokenParseError {
+    #[error("API token must not be empty")]
+    Empty,
+    #[error("unknown API token role '{0}'")]
+    UnknownRole(String),
+}
+
+/// A bearer token, written as `[role[+relay][+sub][+av1]:]token`; tokens
+/// without a role are admins. `+relay` forces the token's WHEP sessions to use
+/// TURN relays only; `+sub` serves them a source's substream where it has one;
+/// `+av1` transcodes their video to AV1 in builds with the `av1` feature.
+///
+/// A prefix of lowercase letters, digits and `+` is always read as a role, so
+/// a misspelled one is an error rather than part of an admin token; tokens
+/// that contain such a prefix themselves need an explicit role, e.g.
+/// `admin:abc:def`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiToken {
+    pub role: Role,
+    /// No role was given, so the token is an admin by default.
+    pub default_role: bool,
+    pub relay_only: bool,
+    pub substream_only: bool,
+    pub av1: bool,
+    pub token: String,
+}
+
+impl std::str::FromStr for ApiToken {
+    type Err = ApiTokenParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_role = |role: &str| match role {
+            "viewer" => Some(Role::Viewer),
+            "operator" => Some(Role::Operator),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        };
+
+        let parse_prefix = |prefix: &str| {
+            let mut modifiers = prefix.split('+');
+            let role = parse_role(modifiers.next()?)?;
+            let (mut relay_only, mut substream_only, mut av1) = (false, false, false);
+            for modifier in modifiers {
+                match modifier {
+                    "relay" => relay_only = true,
+                    "sub" => substream_only = true,
+                    "av1" => av1 = true,
+                    _ => return None,
+                }
+            }
+            Some((role, relay_only, substream_only, av1))
+        };
+
+        let looks_like_role = |prefix: &str| {
+            !prefix.is_empty()
+                && prefix
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'+')
+        };
+
+        let default = (Role::Admin, false, false, false);
+        let (((role, relay_only, substream_only, av1), token), default_role) =
+            match s.split_once(':') {
+                Some((prefix, token)) => match parse_prefix(prefix) {
+                    Some(prefix) => ((prefix, token), false),
+                    None if looks_like_role(prefix) => {
+                        return Err(ApiTokenParseError::UnknownRole(prefix.to_owned()));
+                    }
+                    None => ((default, s), true),
+                },
+                None => ((default, s), true),
+            };
+
+        if token.is_empty() {
+            return Err(ApiTokenParseError::Empty);
+        }
+        Ok(ApiToken {
+            role,
+            default_role,
+            relay_only,
+            substream_only,
+            av1,
+            token: token.to_owned(),
+        })
+    }
+}
+
+/// The authenticated caller, stored in request extensions.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    /// Stable identity the caller's sessions are filed under.
+    pub id: String,
+    pub role: Role,
+    /// Sources the caller may watch; `None` allows all of them.
+    pub sources: Option<Vec<String>>,
+    /// Restrict the caller's WHEP sessions to TURN relay candidates.
+    pub relay_only: bool,
+    /// Serve the caller a source's substream instead of the source where one
+    /// is configured.
+    pub substream_only: bool,
+    /// Transcode the caller's video to AV1, with the `av1` feature.
+    pub av1: bool,
+}
+
+impl Principal {
+    pub fn can_view(&self, source: &str) -> bool {
+        match &self.sources {
+            Some(sources) => sources.iter().any(|s| s == source || s == "*"),
+            None => true,
+        }
+    }
+}
+
+/// Configured credentials; authentication is disabled when there are none.
+#[derive(Default)]
+pub struct Auth {
+    // Keyed by token, with the id of its principal
+    tokens: HashMap<String, (String, ApiToken)>,
+    jwt: Option<JwtValidator>,
+    callback: Option<AuthCallback>,
+    pairing: Option<Arc<Pairing>>,
+    token_file: Option<TokenFile>,
+}
+
+impl Auth {
+    pub fn new(
+        tokens: impl IntoIterator<Item = ApiToken>,
+        jwt: Option<JwtValidator>,
+        callback: Option<AuthCallback>,
+        pairing: Option<Arc<Pairing>>,
+        token_file: Option<TokenFile>,
+    ) -> Self {
+        Self {
+            tokens: tokens
+                .into_iter()
+                .map(|t| (t.token.clone(), (static_token_id(&t.token), t)))
+                .collect(),
+            jwt,
+            callback,
+            pairing,
+            token_file,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+            || self.jwt.is_some()
+            || self.callback.is_some()
+            || self.token_file.is_some()
+    }
+
+    /// Static tokens are checked first, then `--token-file`, pairing tokens,
+    /// JWTs and finally the callback.
+    async fn authenticate(&self, token: &str, method: &str, path: &str) -> Option<Principal> {
+        if let Some((id, token)) = self.tokens.get(token) {
+            return Some(Principal {
+                id: id.clone(),
+                role: token.role,
+                sources: None,
+                relay_only: token.relay_only,
+                substream_only: token.substream_only,
+                av1: token.av1,
+            });
+        }
+
+        if let Some(principal) = self
+            .token_file
+            .as_ref()
+            .and_then(|token_file| token_file.authenticate(token))
+        {
+            return Some(principal);
+        }
+
+        if let Some(principal) = self
+            .pairing
+            .as_ref()
+            .and_then(|pairing| pairing.verify(token))
+        {
+            return Some(principal);
+        }
+
+        if let Some(jwt) = &self.jwt {
+            match jwt.validate(token).await {
+                Ok(principal) => return Some(principal),
+                Err(e) => debug!("JWT rejected: {}", e),
+            }
+        }
+
+        let callback = self.callback.as_ref()?;
+        callback.verify(token, method, path).await
+    }
+}
+
+// Derived from the token rather than its place on the command line, so
+// reordering --api-token flags doesn't hand sessions to another token
+fn static_token_id(token: &str) -> String {
+    format!("token:{}", &tokens::hash(token)[..16])
+}
+
+fn bearer_token(req: &Request) -> Option<Cow<'_, str>> {
+    let header = req
+        .headers()
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| Cow::Borrowed(token.trim()));
+    // HLS players, browser WebSockets and the bundled player's WHEP client
+    // can't add headers to the requests they make, so the token may come as
+    // `?access_token=` there
+    header.or_else(|| {
+        let path = req.uri().path();
+        if !path.starts_with("/hls/") && !path.starts_with("/whep") && path != "/ws" {
+            return None;
+        }
+        url::form_urlencoded::parse(req.uri().query()?.as_bytes())
+            .find_map(|(name, token)| (name == "access_token").then_some(token))
+    })
+}
+
+/// Middleware rejecting requests whose bearer token lacks the required role.
+pub async fn require_role(
+    State((auth, required)): State<(Arc<Auth>, Role)>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if !auth.is_enabled() {
+        return next.run(req).await;
+    }
+
+    let principal = match bearer_token(&req) {
+        Some(token) => {
+            let (method, path) = (req.method().to_string(), req.uri().path().to_owned());
+            auth.authenticate(&token, &method, &path).await
+        }
+        None => None,
+    };
+
+    match principal {
+        Some(principal) if principal.role >= required => {
+            req.extensions_mut().insert(principal);
+            next.run(req).await
+        }
+        Some(principal) => {
+            warn!(
+                "Token with role {:?} denied {} (requires {:?})",
+                principal.role,
+                req.uri().path(),
+                required
+            );
+            axum::http::StatusCode::FORBIDDEN.into_response()
+        }
+        None => (
+            axum::http::StatusCode::UNAUTHORIZED,
+            [(axum::http::header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+
+    use super::*;
+
+    fn request(uri: &str, authorization: Option<&str>) -> Request {
+        let mut request = Request::builder().uri(uri);
+        if let Some(authorization) = authorization {
+            request = request.header(axum::http::header::AUTHORIZATION, authorization);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    fn token(s: &str) -> Result<ApiToken, ApiTokenParseError> {
+        s.parse()
+    }
+
+    #[test]
+    fn tokens_without_role_are_admins() {
+        let parsed = token("s3cret").unwrap();
+        assert_eq!(parsed.role, Role::Admin);
+        assert!(parsed.default_role);
+        assert_eq!(parsed.token, "s3cret");
+
+        // Prefixes that can't be roles belong to the token
+        let parsed = token("Abc:def").unwrap();
+        assert_eq!(
+            (parsed.role, parsed.token.as_str()),
+            (Role::Admin, "Abc:def")
+        );
+        assert!(parsed.default_role);
+
+        let parsed = token("admin:abc:def").unwrap();
+        assert_eq!(
+            (parsed.role, parsed.token.as_str()),
+            (Role::Admin, "abc:def")
+        );
+        assert!(!parsed.default_role);
+    }
+
+    #[test]
+    fn parses_roles_and_modifiers() {
+        let parsed = token("viewer:s3cret").unwrap();
+        assert_eq!(parsed.role, Role::Viewer);
+        assert!(!parsed.default_role);
+        assert!(!parsed.relay_only && !parsed.substream_only && !parsed.av1);
+
+        let parsed = token("viewer+relay+sub+av1:s3cret").unwrap();
+        assert!(parsed.relay_only && parsed.substream_only && parsed.av1);
+        assert_eq!(parsed.token, "s3cret");
+        assert_eq!(token("operator:x").unwrap().role, Role::Operator);
+    }
+
+    #[test]
+    fn rejects_misspelled_roles_and_empty_tokens() {
+        assert_eq!(
+            token("viwer:s3cret"),
+            Err(ApiTokenParseError::UnknownRole("viwer".to_owned()))
+        );
+        assert_eq!(
+            token("viewer+relya:s3cret"),
+            Err(ApiTokenParseError::UnknownRole("viewer+relya".to_owned()))
+        );
+        assert_eq!(token(""), Err(ApiTokenParseError::Empty));
+        assert_eq!(token("viewer:"), Err(ApiTokenParseError::Empty));
+    }
+
+    #[test]
+    fn takes_token_from_header() {
+        let req = request("/api/sources", Some("Bearer  s3cret "));
+        assert_eq!(bearer_token(&req).as_deref(), Some("s3cret"));
+        assert_eq!(
+            bearer_token(&request("/api/sources", Some("Basic abc"))),
+            None
+        );
+        assert_eq!(bearer_token(&request("/api/sources", None)), None);
+
+        // The header wins over the query
+        let req = request("/whep?access_token=other", Some("Bearer s3cret"));
+        assert_eq!(bearer_token(&req).as_deref(), Some("s3cret"));
+    }
+
+    #[test]
+    fn takes_token_from_query_where_headers_cant_be_set() {
+        for uri in [
+            "/whep?access_token=s3cret",
+            "/whep/front?stream=x&access_token=s3cret",
+            "/hls/front/index.m3u8?access_token=s3cret",
+            "/ws?access_token=s3cret",
+        ] {
+            assert_eq!(
+                bearer_token(&request(uri, None)).as_deref(),
+                Some("s3cret"),
+                "{uri}"
+            );
+        }
+        // Percent-encoded like any query value
+        let req = request("/whep?access_token=viewer%3As3cret%2Bx%20y", None);
+        assert_eq!(bearer_token(&req).as_deref(), Some("viewer:s3cret+x y"));
+        let req = request("/whep?my_access_token=s3cret", None);
+        assert_eq!(bearer_token(&req), None);
+    }
+
+    #[test]
+    fn ignores_query_token_elsewhere() {
+        for uri in ["/api/sources?access_token=s3cret", "/?access_token=s3cret"] {
+            assert_eq!(bearer_token(&request(uri, None)), None, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn authenticates_static_tokens() {
+        let auth = Auth::new(
+            [token("viewer+relay:watch").unwrap(), token("root").unwrap()],
+            None,
+            None,
+            None,
+            None,
+        );
+        assert!(auth.is_enabled());
+
+        let viewer = auth.authenticate("watch", "POST", "/whep").await.unwrap();
+        assert_eq!(viewer.role, Role::Viewer);
+        assert!(viewer.relay_only);
+        assert!(viewer.can_view("front"));
+        let admin = auth
+            .authenticate("root", "GET", "/api/sources")
+            .await
+            .unwrap();
+        assert_eq!(admin.role, Role::Admin);
+        assert_ne!(viewer.id, admin.id);
+        assert!(
+            auth.authenticate("viewer+relay:watch", "POST", "/whep")
+                .await
+                .is_none()
+        );
+        assert!(!Auth::default().is_enabled());
+    }
+
+    #[tokio::test]
+    async fn static_token_ids_survive_reordering() {
+        let tokens = [token("viewer:watch").unwrap(), token("root").unwrap()];
+        let id = |auth: Auth| async move {
+            auth.authenticate("watch", "POST", "/whep")
+                .await
+                .unwrap()
+                .id
+        };
+        let reordered = Auth::new(tokens.iter().rev().cloned(), None, None, None, None);
+        assert_eq!(
+            id(Auth::new(tokens.clone(), None, None, None, None)).await,
+            id(reordered).await
+        );
+    }
+}
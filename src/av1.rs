@@ -0,0 +1,429 @@
+use std::{
+    collections::HashMap,
+    pin::pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Waker},
+};
+
+use bytes::{Bytes, BytesMut};
+use ffmpeg_next as ffmpeg;
+use tokio::sync::{Notify, broadcast};
+use tracing::{debug, info, warn};
+use webrtc::{
+    api::media_engine::{MIME_TYPE_AV1, MIME_TYPE_H264},
+    rtp::{
+        codecs::{av1::Av1Payloader, h264::H264Packet},
+        header::Header,
+        packet::Packet,
+        packetizer::{Depacketizer, Payloader},
+    },
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+};
+
+use crate::{fanout::FanoutTrack, state::Stream};
+
+// AV1 encoders tried in order, with the option that makes each fast enough
+// for live video; SVT-AV1 keeps up at far higher resolutions than rav1e
+const ENCODERS: &[(&str, &str, &str)] =
+    &[("libsvtav1", "preset", "10"), ("librav1e", "speed", "10")];
+const CLOCK_RATE: u32 = 90_000;
+// Keyframes follow the source's; this only bounds sources that send few
+const KEYFRAME_INTERVAL: u32 = 300;
+// Payload size of the AV1 RTP packets, leaving room for SRTP and extensions
+const MTU: usize = 1200;
+// Picture heights of the rungs below the source's resolution, with
+// `--av1-ladder`
+const LADDER_HEIGHTS: [u32; 3] = [1080, 720, 360];
+// Bits per pixel shrink as pictures do: a rung of a quarter of the pixels
+// gets 0.25^0.75 ≈ 35% of the bitrate
+const BITRATE_EXPONENT: f64 = 0.75;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TranscodeError {
+    #[error("ffmpeg failed: {0}")]
+    Ffmpeg(#[from] ffmpeg::Error),
+    #[error("ffmpeg was built without an AV1 encoder (libsvtav1 or librav1e)")]
+    NoEncoder,
+    #[error("failed to packetize AV1: {0}")]
+    Rtp(#[from] webrtc::rtp::Error),
+}
+
+/// The AV1 transcodes of sources' video for viewers of the `+av1` tier, one
+/// per source shared by all its AV1 viewers. A transcode starts with its first
+/// viewer and stops once the last one has left.
+pub struct Transcoders {
+    // Bits per second at the source's resolution
+    bitrate: usize,
+    ladder: bool,
+    running: Mutex<HashMap<String, Arc<Transcode>>>,
+}
+
+/// One picture size a source is transcoded to.
+#[derive(Debug, Clone, Copy)]
+struct Rung {
+    /// Picture height; the source's when `None`.
+    height: Option<u32>,
+    /// Bits per second.
+    bitrate: usize,
+}
+
+struct Transcode {
+    source: Weak<FanoutTrack>,
+    /// Best first.
+    rungs: Vec<Rung>,
+    /// Each rung's track while it has viewers; `None` once the transcode has
+    /// stopped.
+    tracks: Mutex<Option<Vec<Weak<FanoutTrack>>>>,
+    keyframe_requests: Arc<Notify>,
+}
+
+impl Transcoders {
+    pub fn new(bitrate_kbps: u32, ladder: bool) -> Self {
+        Self {
+            bitrate: bitrate_kbps as usize * 1000,
+            ladder,
+            running: Mutex::default(),
+        }
+    }
+
+    /// Tracks of up to `rungs` rungs of the AV1 transcode of `stream`'s video,
+    /// best first, and where their viewers ask for keyframes; the transcode is
+    /// started unless it runs already. `None` if the video is not H.264, the
+    /// only codec decoded.
+    ///
+    /// Without `--av1-ladder` there is one rung, at the source's resolution.
+    pub fn subscribe(
+        &self,
+        stream: &Stream,
+        rungs: usize,
+    ) -> Option<(Vec<Arc<FanoutTrack>>, Arc<Notify>)> {
+        let (_, source) = stream.video_track.as_ref()?;
+        if !source
+            .codec()
+            .mime_type
+            .eq_ignore_ascii_case(MIME_TYPE_H264)
+        {
+            return None;
+        }
+
+        let mut running = self.running.lock().unwrap();
+        // A source restarted under the same name has a new track
+        let (transcode, started) = match running.get(&stream.info.name) {
+            Some(transcode)
+                if Weak::ptr_eq(&transcode.source, &Arc::downgrade(source))
+                    && transcode.tracks.lock().unwrap().is_some() =>
+            {
+                (transcode.clone(), false)
+            }
+            _ => {
+                let size = stream
+                    .capabilities
+                    .video
+                    .as_ref()
+                    .and_then(|video| video.height);
+                let ladder = ladder(size.filter(|_| self.ladder), self.bitrate);
+                let transcode = Arc::new(Transcode {
+                    source: Arc::downgrade(source),
+                    tracks: Mutex::new(Some(vec![Weak::new(); ladder.len()])),
+                    rungs: ladder,
+                    keyframe_requests: Arc::new(Notify::new()),
+                });
+                running.insert(stream.info.name.clone(), transcode.clone());
+                (transcode, true)
+            }
+        };
+
+        let subscribed: Vec<_> = {
+            let mut tracks = transcode.tracks.lock().unwrap();
+            tracks
+                .as_mut()?
+                .iter_mut()
+                .take(rungs.max(1))
+                .map(|rung| {
+                    rung.upgrade().unwrap_or_else(|| {
+                        let track = Arc::new(source.transcoded(RTCRtpCodecCapability {
+                            mime_type: MIME_TYPE_AV1.to_owned(),
+                            clock_rate: CLOCK_RATE,
+                            ..Default::default()
+                        }));
+                        *rung = Arc::downgrade(&track);
+                        track
+                    })
+                })
+                .collect()
+        };
+
+        if started {
+            info!(
+                "🎞️ Transcoding '{}' to AV1 in {} rung(s)",
+                stream.info.name,
+                transcode.rungs.len()
+            );
+            let (name, packets, transcode) =
+                (stream.info.name.clone(), source.listen(), transcode.clone());
+            std::thread::spawn(move || {
+                match run(packets, &transcode) {
+                    Ok(()) => info!("🎞️ Stopped transcoding '{}' to AV1", name),
+                    Err(e) => warn!("AV1 transcode of '{}' failed: {}", name, e),
+                }
+                transcode.tracks.lock().unwrap().take();
+            });
+            // Decoding can only start at a keyframe
+            stream.keyframe_requests.notify_one();
+        }
+
+        Some((subscribed, transcode.keyframe_requests.clone()))
+    }
+}
+
+/// The rungs for a source `height` pixels high: the source's resolution, then
+/// each of [`LADDER_HEIGHTS`] below it, with bitrates shrinking with their
+/// pixel counts. Just the first without a height.
+fn ladder(height: Option<u32>, bitrate: usize) -> Vec<Rung> {
+    let mut rungs = vec![Rung {
+        height: None,
+        bitrate,
+    }];
+    let Some(height) = height else {
+        return rungs;
+    };
+    for rung_height in LADDER_HEIGHTS.into_iter().filter(|&h| h < height) {
+        let pixels = (rung_height as f64 / height as f64).powi(2);
+        rungs.push(Rung {
+            height: Some(rung_height),
+            bitrate: (bitrate as f64 * pixels.powf(BITRATE_EXPONENT)) as usize,
+        });
+    }
+    rungs
+}
+
+/// Decodes the H.264 `packets` and sends them re-encoded as AV1 on the
+/// tracks of `transcode`'s rungs, until the source ends or no rung has
+/// viewers left.
+///
+/// Every rung puts a keyframe where the source has one, so rungs can be
+/// switched between at the source's GOP boundaries.
+fn run(
+    mut packets: broadcast::Receiver<Packet>,
+    transcode: &Transcode,
+) -> Result<(), TranscodeError> {
+    ffmpeg::init()?;
+    let codec =
+        ffmpeg::decoder::find(ffmpeg::codec::Id::H264).ok_or(ffmpeg::Error::DecoderNotFound)?;
+    let mut decoder = ffmpeg::codec::Context::new_with_codec(codec)
+        .decoder()
+        .video()?;
+    // Opened while a rung has viewers, and again when the picture size changes
+    let mut encoders: Vec<Option<RungEncoder>> = transcode.rungs.iter().map(|_| None).collect();
+    let mut sequence_numbers = vec![0u16; transcode.rungs.len()];
+
+    let mut depacketizer = H264Packet::default();
+    let mut access_unit = BytesMut::new();
+    let mut timestamps = Timestamps::default();
+    let mut decoded = ffmpeg::frame::Video::empty();
+
+    loop {
+        let pkt = match packets.blocking_recv() {
+            Ok(pkt) => pkt,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                debug!("AV1 transcode lagged, {} packets skipped", skipped);
+                access_unit.clear();
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+
+        match depacketizer.depacketize(&pkt.payload) {
+            Ok(nalus) => access_unit.extend_from_slice(&nalus),
+            Err(e) => {
+                debug!("Dropping an H.264 access unit: {}", e);
+                access_unit.clear();
+                continue;
+            }
+        }
+        if !pkt.header.marker || access_unit.is_empty() {
+            continue;
+        }
+
+        let mut input = ffmpeg::Packet::copy(&access_unit);
+        access_unit.clear();
+        input.set_pts(Some(timestamps.unwrap(pkt.header.timestamp)));
+        // A damaged access unit; the decoder recovers at the next keyframe
+        if let Err(e) = decoder.send_packet(&input) {
+            debug!("H.264 decoder rejected an access unit: {}", e);
+            continue;
+        }
+
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            let outputs: Vec<Option<Arc<FanoutTrack>>> = {
+                let tracks = transcode.tracks.lock().unwrap();
+                let outputs: Vec<_> = tracks.iter().flatten().map(Weak::upgrade).collect();
+                if outputs.iter().all(Option::is_none) {
+                    return Ok(());
+                }
+                outputs
+            };
+            let keyframe = decoded.is_key() || keyframe_requested(&transcode.keyframe_requests);
+
+            for (index, output) in outputs.into_iter().enumerate() {
+                let Some(output) = output else {
+                    // Nobody watches this rung
+                    encoders[index] = None;
+                    continue;
+                };
+                if encoders[index]
+                    .as_ref()
+                    .is_none_or(|encoder| !encoder.fits(&decoded))
+                {
+                    encoders[index] = Some(RungEncoder::open(&decoded, transcode.rungs[index])?);
+                }
+                let Some(encoder) = encoders[index].as_mut() else {
+                    continue;
+                };
+                for (payload, marker, timestamp) in encoder.encode(&decoded, keyframe)? {
+                    output.send(Packet {
+                        header: Header {
+                            version: 2,
+                            marker,
+                            sequence_number: sequence_numbers[index],
+                            timestamp,
+                            ..Default::default()
+                        },
+                        payload,
+                    });
+                    sequence_numbers[index] = sequence_numbers[index].wrapping_add(1);
+                }
+            }
+        }
+    }
+}
+
+/// The encoder of one rung, with the converter from decoded pictures to its
+/// size and the planar 4:2:0 the encoders take.
+struct RungEncoder {
+    encoder: ffmpeg::encoder::Video,
+    scaler: ffmpeg::software::scaling::Context,
+    scaled: ffmpeg::frame::Video,
+    encoded: ffmpeg::Packet,
+    payloader: Av1Payloader,
+}
+
+impl RungEncoder {
+    fn open(frame: &ffmpeg::frame::Video, rung: Rung) -> Result<Self, TranscodeError> {
+        let format = ffmpeg::format::Pixel::YUV420P;
+        let (height, width) = match rung.height {
+            // Even sizes, as 4:2:0 needs
+            Some(height) => (height, (frame.width() * height / frame.height()) & !1),
+            None => (frame.height(), frame.width()),
+        };
+        let scaler = ffmpeg::software::scaling::Context::get(
+            frame.format(),
+            frame.width(),
+            frame.height(),
+            format,
+            width,
+            height,
+            ffmpeg::software::scaling::Flags::BILINEAR,
+        )?;
+
+        for (name, option, value) in ENCODERS {
+            let Some(codec) = ffmpeg::encoder::find_by_name(name) else {
+                continue;
+            };
+            let mut encoder = ffmpeg::codec::Context::new_with_codec(codec)
+                .encoder()
+                .video()?;
+            encoder.set_width(width);
+            encoder.set_height(height);
+            encoder.set_format(format);
+            encoder.set_time_base((1, CLOCK_RATE as i32));
+            encoder.set_bit_rate(rung.bitrate);
+            encoder.set_gop(KEYFRAME_INTERVAL);
+            encoder.set_max_b_frames(0);
+            let mut options = ffmpeg::Dictionary::new();
+            options.set(option, value);
+            debug!(
+                "Encoding {}x{} AV1 at {} kbit/s with {}",
+                width,
+                height,
+                rung.bitrate / 1000,
+                name
+            );
+            return Ok(Self {
+                encoder: encoder.open_with(options)?,
+                scaler,
+                scaled: ffmpeg::frame::Video::empty(),
+                encoded: ffmpeg::Packet::empty(),
+                payloader: Av1Payloader::default(),
+            });
+        }
+        Err(TranscodeError::NoEncoder)
+    }
+
+    /// Whether `frame` is what the encoder was opened for.
+    fn fits(&self, frame: &ffmpeg::frame::Video) -> bool {
+        let input = self.scaler.input();
+        (input.format, input.width, input.height) == (frame.format(), frame.width(), frame.height())
+    }
+
+    /// Encodes `frame`, as a keyframe if asked to, returning the RTP payloads
+    /// of what the encoder put out with their marker bits and timestamps.
+    fn encode(
+        &mut self,
+        frame: &ffmpeg::frame::Video,
+        keyframe: bool,
+    ) -> Result<Vec<(Bytes, bool, u32)>, TranscodeError> {
+        self.scaler.run(frame, &mut self.scaled)?;
+        self.scaled.set_pts(frame.pts());
+        self.scaled.set_kind(if keyframe {
+            ffmpeg::picture::Type::I
+        } else {
+            ffmpeg::picture::Type::None
+        });
+        self.encoder.send_frame(&self.scaled)?;
+
+        let mut payloads = Vec::new();
+        while self.encoder.receive_packet(&mut self.encoded).is_ok() {
+            let Some(data) = self.encoded.data() else {
+                continue;
+            };
+            let timestamp = self.encoded.pts().unwrap_or_default() as u32;
+            let packets = self.payloader.payload(MTU, &Bytes::copy_from_slice(data))?;
+            let last = packets.len().saturating_sub(1);
+            payloads.extend(
+                packets
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, payload)| (payload, index == last, timestamp)),
+            );
+        }
+        Ok(payloads)
+    }
+}
+
+/// Whether a viewer asked for a keyframe since the last frame, without
+/// waiting for one to.
+fn keyframe_requested(keyframe_requests: &Notify) -> bool {
+    let requested = pin!(keyframe_requests.notified());
+    requested
+        .poll(&mut Context::from_waker(Waker::noop()))
+        .is_ready()
+}
+
+/// RTP timestamps extended to 64 bits, so they keep growing across wraps as
+/// the encoder wants.
+#[derive(Default)]
+struct Timestamps {
+    last: Option<u32>,
+    extended: i64,
+}
+
+impl Timestamps {
+    fn unwrap(&mut self, timestamp: u32) -> i64 {
+        if let Some(last) = self.last {
+            self.extended += timestamp.wrapping_sub(last) as i32 as i64;
+        }
+        self.last = Some(timestamp);
+        self.extended
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use retina::rtp::ReceivedPacket;
+use tokio::{sync::mpsc, time::Instant};
+use tracing::debug;
+
+// Latencies are the lowest seen over windows this long, so network jitter
+// isn't taken for skew
+const WINDOW: Duration = Duration::from_secs(5);
+// NTP timestamps are seconds in 32.32 fixed point
+const NTP_FRACTION: f64 = 4_294_967_296.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Media {
+    Video = 0,
+    Audio = 1,
+}
+
+impl Media {
+    fn other(self) -> Self {
+        match self {
+            Media::Video => Media::Audio,
+            Media::Audio => Media::Video,
+        }
+    }
+}
+
+/// Lines up a source's video and audio by holding back whichever the camera
+/// delivers earlier, so what was captured together reaches viewers together.
+///
+/// Rewriting RTP timestamps wouldn't help: the Sender Reports viewers sync on
+/// are generated by webrtc-rs from when each packet is sent, so only the send
+/// time can move one track against the other. The camera's Sender Reports give
+/// each packet's capture time; arrival minus capture is the track's latency,
+/// and the track with less of it waits for the other.
+pub struct AvSync {
+    name: String,
+    epoch: Instant,
+    max_delay: Duration,
+    tracks: Mutex<[Track; 2]>,
+}
+
+#[derive(Default)]
+struct Track {
+    // The camera's latest Sender Report: capture time in seconds, and the RTP
+    // timestamp it corresponds to
+    report: Option<(f64, u32)>,
+    // Lowest latency, in seconds, of the current window
+    window_min: Option<f64>,
+    window_start: Option<Instant>,
+    // Lowest latency of the last complete window
+    latency: Option<f64>,
+}
+
+impl AvSync {
+    /// Holds a track of source `name` back by at most `max_delay`.
+    pub fn new(name: &str, max_delay: Duration) -> Self {
+        Self {
+            name: name.to_owned(),
+            epoch: Instant::now(),
+            max_delay,
+            tracks: Mutex::default(),
+        }
+    }
+
+    /// Records a camera Sender Report mapping `ntp` to `rtp_timestamp`.
+    pub fn sender_report(&self, media: Media, ntp: u64, rtp_timestamp: u32) {
+        let capture = (ntp >> 32) as f64 + (ntp & 0xffff_ffff) as f64 / NTP_FRACTION;
+        self.tracks.lock().unwrap()[media as usize].report = Some((capture, rtp_timestamp));
+    }
+
+    /// Forgets everything learned, e.g. when a new RTSP session starts with
+    /// new timestamps.
+    pub fn reset(&self) {
+        *self.tracks.lock().unwrap() = Default::default();
+    }
+
+    /// How long to hold a packet of `media` that arrived at `arrival`.
+    fn delay(&self, media: Media, pkt: &ReceivedPacket, arrival: Instant) -> Duration {
+        let mut tracks = self.tracks.lock().unwrap();
+        let track = &mut tracks[media as usize];
+        if let Some((report_capture, report_timestamp)) = track.report {
+            let timestamp = pkt.timestamp();
+            let since_report = (timestamp.timestamp() as u32).wrapping_sub(report_timestamp) as i32;
+            let capture =
+                report_capture + since_report as f64 / timestamp.clock_rate().get() as f64;
+            let latency = arrival.duration_since(self.epoch).as_secs_f64() - capture;
+
+            track.window_min = Some(track.window_min.map_or(latency, |min| min.min(latency)));
+            let window_start = *track.window_start.get_or_insert(arrival);
+            if arrival.duration_since(window_start) >= WINDOW {
+                track.latency = track.window_min.take();
+                track.window_start = Some(arrival);
+                if let (Some(latency), Some(other)) =
+                    (track.latency, tracks[media.other() as usize].latency)
+                {
+                    debug!(
+                        "[{}] {:?} arrives {:.1} ms after {:?}",
+                        self.name,
+                        media,
+                        (latency - other) * 1000.0,
+                        media.other()
+                    );
+                }
+            }
+        }
+
+        match (
+            tracks[media as usize].latency,
+            tracks[media.other() as usize].latency,
+        ) {
+            (Some(latency), Some(other)) => Duration::try_from_secs_f64(other - latency)
+                .unwrap_or_default()
+                .min(self.max_delay),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Passes the packets of `media` from `packets` on as they become due.
+    pub fn spawn_delay(
+        self: &Arc<Self>,
+        media: Media,
+        mut packets: mpsc::Receiver<ReceivedPacket>,
+    ) -> mpsc::Receiver<ReceivedPacket> {
+        let (tx, rx) = mpsc::channel(packets.max_capacity());
+        let sync = self.clone();
+        tokio::spawn(async move {
+            let mut held: VecDeque<(Instant, ReceivedPacket)> = VecDeque::new();
+            loop {
+                let due = held.front().map(|(due, _)| *due);
+                tokio::select! {
+                    pkt = packets.recv() => {
+                        let Some(pkt) = pkt else {
+                            break;
+                        };
+                        let arrival = Instant::now();
+                        let delay = sync.delay(media, &pkt, arrival);
+                        held.push_back((arrival + delay, pkt));
+                    }
+                    _ = tokio::time::sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {}
+                }
+
+                // In order, so a shrinking delay never reorders packets
+                let now = Instant::now();
+                while held.front().is_some_and(|(due, _)| *due <= now) {
+                    let Some((_, pkt)) = held.pop_front() else {
+                        break;
+                    };
+                    if tx.send(pkt).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        rx
+    }
+}
@@ -0,0 +1,364 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, atomic::Ordering},
+};
+
+use retina::rtp::ReceivedPacket;
+use tokio::sync::{Notify, mpsc};
+
+use crate::{gop::Codec, stats::PipelineStats};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum FrameKind {
+    NonReference,
+    Reference,
+    Keyframe,
+}
+
+struct Frame {
+    timestamp: i64,
+    kind: FrameKind,
+    packets: VecDeque<ReceivedPacket>,
+    // Some of its packets were passed on already, so dropping the rest would
+    // leave a partial frame
+    started: bool,
+}
+
+#[derive(Default)]
+struct Backlog {
+    frames: VecDeque<Frame>,
+    packets: usize,
+    // RTP timestamp of a dropped frame whose remaining packets are discarded
+    discarding: Option<i64>,
+    // A reference frame was dropped, so nothing decodes until a keyframe
+    awaiting_keyframe: bool,
+    closed: bool,
+}
+
+struct Shared {
+    backlog: Mutex<Backlog>,
+    ready: Notify,
+}
+
+/// The reading end of a source's video backlog, which drops whole frames
+/// rather than arbitrary packets when the video writer falls behind.
+pub struct FrameSender {
+    codec: Option<Codec>,
+    capacity: usize,
+    shared: Arc<Shared>,
+    stats: Arc<PipelineStats>,
+}
+
+/// A video backlog of `capacity` packets for frames of `codec`, and the
+/// channel its packets come out of in order.
+///
+/// When the backlog is full, whole non-reference frames are dropped first;
+/// then frames queued ahead of a new keyframe; then the newest frame, after
+/// which everything up to the next keyframe is dropped as undecodable. A
+/// keyframe is never dropped in part, even if that overfills the backlog.
+/// Without a known codec every frame counts as a reference frame, and the
+/// newest one is dropped without waiting for a keyframe.
+pub fn frame_channel(
+    codec: Option<Codec>,
+    capacity: usize,
+    stats: Arc<PipelineStats>,
+) -> (FrameSender, mpsc::Receiver<ReceivedPacket>) {
+    let shared = Arc::new(Shared {
+        backlog: Mutex::default(),
+        ready: Notify::new(),
+    });
+    // Packets only leave the backlog when the writer takes them, so the
+    // backlog is where a slow writer shows
+    let (tx, rx) = mpsc::channel(1);
+    let output = shared.clone();
+    tokio::spawn(async move {
+        loop {
+            let pkt = {
+                let mut backlog = output.backlog.lock().unwrap();
+                let pkt = backlog.pop();
+                if pkt.is_none() && backlog.closed {
+                    return;
+                }
+                pkt
+            };
+            match pkt {
+                Some(pkt) => {
+                    if tx.send(pkt).await.is_err() {
+                        return;
+                    }
+                }
+                None => output.ready.notified().await,
+            }
+        }
+    });
+
+    (
+        FrameSender {
+            codec,
+            capacity,
+            shared,
+            stats,
+        },
+        rx,
+    )
+}
+
+impl FrameSender {
+    /// Queues a packet, dropping frames if the backlog is full; `true` if a
+    /// reference frame was dropped and a keyframe should be requested.
+    pub fn push(&self, pkt: ReceivedPacket) -> bool {
+        let timestamp = pkt.timestamp().timestamp();
+        let kind = match self.codec {
+            Some(codec) if codec.starts_keyframe_or_parameter_set(pkt.payload()) => {
+                FrameKind::Keyframe
+            }
+            Some(codec) if !codec.is_reference(pkt.payload()) => FrameKind::NonReference,
+            _ => FrameKind::Reference,
+        };
+
+        let mut backlog = self.shared.backlog.lock().unwrap();
+        if backlog.discarding == Some(timestamp) {
+            self.stats.video.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        backlog.discarding = None;
+        if backlog.awaiting_keyframe {
+            if kind != FrameKind::Keyframe {
+                self.stats.video.dropped.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            backlog.awaiting_keyframe = false;
+        }
+
+        match backlog.frames.back_mut() {
+            Some(frame) if frame.timestamp == timestamp => {
+                frame.kind = frame.kind.max(kind);
+                frame.packets.push_back(pkt);
+            }
+            _ => backlog.frames.push_back(Frame {
+                timestamp,
+                kind,
+                packets: VecDeque::from([pkt]),
+                started: false,
+            }),
+        }
+        backlog.packets += 1;
+
+        let needs_keyframe = self.make_room(&mut backlog);
+        drop(backlog);
+        self.shared.ready.notify_one();
+        needs_keyframe
+    }
+
+    fn make_room(&self, backlog: &mut Backlog) -> bool {
+        let drops = &self.stats.video_frames_dropped;
+        let mut needs_keyframe = false;
+        while backlog.packets > self.capacity {
+            if let Some(index) = backlog
+                .frames
+                .iter()
+                .position(|frame| frame.kind == FrameKind::NonReference && !frame.started)
+            {
+                backlog.drop_frame(index, &self.stats);
+                drops.non_reference.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+
+            let Some(newest) = backlog.frames.back() else {
+                break;
+            };
+            if newest.kind == FrameKind::Keyframe {
+                // Nothing before a keyframe is needed to decode what follows
+                let superseded = backlog.frames.len() - 1;
+                let before = backlog.frames.len();
+                for index in (0..superseded).rev() {
+                    if !backlog.frames[index].started {
+                        backlog.drop_frame(index, &self.stats);
+                        drops.superseded.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                if backlog.frames.len() == before {
+                    break;
+                }
+                continue;
+            }
+            if newest.started {
+                break;
+            }
+
+            backlog.drop_frame(backlog.frames.len() - 1, &self.stats);
+            drops.reference.fetch_add(1, Ordering::Relaxed);
+            if self.codec.is_some() {
+                backlog.awaiting_keyframe = true;
+                needs_keyframe = true;
+            }
+        }
+        needs_keyframe
+    }
+}
+
+impl Drop for FrameSender {
+    fn drop(&mut self) {
+        self.shared.backlog.lock().unwrap().closed = true;
+        self.shared.ready.notify_one();
+    }
+}
+
+impl Backlog {
+    /// The next packet to pass on, if any.
+    fn pop(&mut self) -> Option<ReceivedPacket> {
+        loop {
+            let front = self.frames.front_mut()?;
+            if let Some(pkt) = front.packets.pop_front() {
+                front.started = true;
+                self.packets -= 1;
+                return Some(pkt);
+            }
+            // The newest frame may still get packets, which must follow
+            if self.frames.len() == 1 {
+                return None;
+            }
+            self.frames.pop_front();
+        }
+    }
+
+    fn drop_frame(&mut self, index: usize, stats: &PipelineStats) {
+        let Some(frame) = self.frames.remove(index) else {
+            return;
+        };
+        if index == self.frames.len() {
+            self.discarding = Some(frame.timestamp);
+        }
+        self.packets -= frame.packets.len();
+        stats
+            .video
+            .dropped
+            .fetch_add(frame.packets.len() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use retina::{PacketContext, Timestamp, rtp::ReceivedPacketBuilder};
+
+    use super::*;
+
+    const IDR: &[u8] = &[0x65, 0x88];
+    const P: &[u8] = &[0x41, 0x9a];
+    const B: &[u8] = &[0x01, 0x9e];
+
+    fn packet(sequence_number: u16, timestamp: i64, payload: &[u8]) -> ReceivedPacket {
+        ReceivedPacketBuilder {
+            ctx: PacketContext::dummy(),
+            stream_id: 0,
+            sequence_number,
+            timestamp: Timestamp::new(timestamp, NonZeroU32::new(90_000).unwrap(), 0).unwrap(),
+            payload_type: 96,
+            ssrc: 1,
+            mark: true,
+            loss: 0,
+        }
+        .build(payload.iter().copied())
+        .unwrap()
+    }
+
+    /// Pushes everything before the output task gets to run, so the backlog
+    /// is as full as a stalled writer leaves it, and returns the sequence
+    /// numbers that come out.
+    async fn run(
+        codec: Option<Codec>,
+        capacity: usize,
+        packets: Vec<ReceivedPacket>,
+    ) -> (Vec<u16>, Vec<bool>, Arc<PipelineStats>) {
+        let stats = Arc::new(PipelineStats::default());
+        let (tx, mut rx) = frame_channel(codec, capacity, stats.clone());
+        let needs_keyframe = packets.into_iter().map(|pkt| tx.push(pkt)).collect();
+        drop(tx);
+        let mut out = Vec::new();
+        while let Some(pkt) = rx.recv().await {
+            out.push(pkt.sequence_number());
+        }
+        (out, needs_keyframe, stats)
+    }
+
+    #[tokio::test]
+    async fn passes_everything_with_room() {
+        let packets = vec![packet(0, 0, IDR), packet(1, 3000, P), packet(2, 6000, B)];
+        let (out, needs_keyframe, stats) = run(Some(Codec::H264), 8, packets).await;
+        assert_eq!(out, [0, 1, 2]);
+        assert!(!needs_keyframe.contains(&true));
+        assert_eq!(stats.video.dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn drops_non_reference_frames_first() {
+        let packets = vec![
+            packet(0, 0, IDR),
+            packet(1, 3000, P),
+            packet(2, 6000, B),
+            packet(3, 9000, P),
+            packet(4, 12000, P),
+        ];
+        let (out, needs_keyframe, stats) = run(Some(Codec::H264), 4, packets).await;
+        assert_eq!(out, [0, 1, 3, 4]);
+        assert!(!needs_keyframe.contains(&true));
+        let drops = stats.video_frames_dropped.snapshot();
+        assert_eq!(drops.non_reference, 1);
+        assert_eq!(drops.reference, 0);
+    }
+
+    #[tokio::test]
+    async fn drops_to_next_keyframe_after_reference_frame() {
+        let packets = vec![
+            packet(0, 0, IDR),
+            packet(1, 3000, P),
+            packet(2, 6000, P),
+            // Overfills the backlog, so this frame goes...
+            packet(3, 9000, P),
+            // ...with the rest of its packets...
+            packet(4, 9000, P),
+            // ...and what can't be decoded without it
+            packet(5, 12000, P),
+            packet(6, 15000, B),
+            packet(7, 18000, IDR),
+            packet(8, 21000, P),
+        ];
+        let (out, needs_keyframe, stats) = run(Some(Codec::H264), 3, packets).await;
+        // The new keyframe made the frames queued ahead of it unnecessary
+        assert_eq!(out, [7, 8]);
+        assert_eq!(
+            needs_keyframe,
+            [false, false, false, true, false, false, false, false, false]
+        );
+        let drops = stats.video_frames_dropped.snapshot();
+        assert_eq!(drops.reference, 1);
+        assert_eq!(drops.superseded, 3);
+        assert_eq!(stats.video.dropped.load(Ordering::Relaxed), 7);
+    }
+
+    #[tokio::test]
+    async fn never_drops_part_of_keyframe() {
+        let packets = (0..5).map(|seq| packet(seq, 0, IDR)).collect();
+        let (out, needs_keyframe, stats) = run(Some(Codec::H264), 2, packets).await;
+        assert_eq!(out, [0, 1, 2, 3, 4]);
+        assert!(!needs_keyframe.contains(&true));
+        assert_eq!(stats.video.dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn drops_newest_frame_of_unknown_codec() {
+        let packets = vec![
+            packet(0, 0, P),
+            packet(1, 3000, P),
+            packet(2, 6000, P),
+            packet(3, 9000, P),
+        ];
+        let (out, needs_keyframe, stats) = run(None, 2, packets).await;
+        assert_eq!(out, [0, 1]);
+        // Nothing waits for a keyframe that can't be recognized
+        assert!(!needs_keyframe.contains(&true));
+        assert_eq!(stats.video_frames_dropped.snapshot().reference, 2);
+    }
+}
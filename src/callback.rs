@@ -0,0 +1,180 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::auth::{Principal, Role};
+
+// Callbacks taking longer than this deny the request
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct Verification<'a> {
+    token: &'a str,
+    method: &'a str,
+    path: &'a str,
+}
+
+/// What the callback may grant; everything is optional.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Grant {
+    id: Option<String>,
+    role: Option<Role>,
+    sources: Option<Vec<String>>,
+    relay_only: bool,
+    substream_only: bool,
+    av1: bool,
+}
+
+/// Asks an external service whether a bearer token may make a request, so
+/// tokens can be issued and revoked without restarting the gateway.
+///
+/// The token, method and path are POSTed as JSON; any 2xx response grants the
+/// request as a viewer unless its JSON body says otherwise.
+pub struct AuthCallback {
+    url: url::Url,
+    client: reqwest::Client,
+}
+
+impl AuthCallback {
+    pub fn new(url: url::Url) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::builder()
+                .timeout(CALLBACK_TIMEOUT)
+                .build()
+                .expect("failed to build auth callback HTTP client"),
+        }
+    }
+
+    pub async fn verify(&self, token: &str, method: &str, path: &str) -> Option<Principal> {
+        let response = match self
+            .client
+            .post(self.url.clone())
+            .json(&Verification {
+                token,
+                method,
+                path,
+            })
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Auth callback {} failed: {}", self.url, e);
+                return None;
+            }
+        };
+
+        if !response.status().is_success() {
+            debug!(
+                "Auth callback denied {} {}: {}",
+                method,
+                path,
+                response.status()
+            );
+            return None;
+        }
+
+        // An empty or non-JSON body is a plain "yes"
+        let grant: Grant = response.json().await.unwrap_or_default();
+        Some(Principal {
+            id: format!(
+                "callback:{}",
+                grant.id.unwrap_or_else(|| token_fingerprint(token))
+            ),
+            role: grant.role.unwrap_or(Role::Viewer),
+            sources: grant.sources,
+            relay_only: grant.relay_only,
+            substream_only: grant.substream_only,
+            av1: grant.av1,
+        })
+    }
+}
+
+// Sessions are filed under the token without keeping the secret itself around
+fn token_fingerprint(token: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    token.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Json, http::StatusCode, response::IntoResponse, routing::post};
+    use serde_json::json;
+
+    use super::*;
+
+    // A callback on a local port that knows three tokens
+    async fn callback() -> AuthCallback {
+        async fn verify(Json(request): Json<serde_json::Value>) -> axum::response::Response {
+            assert_eq!(request["method"], "POST");
+            assert_eq!(request["path"], "/whep/front");
+            match request["token"].as_str() {
+                Some("plain") => StatusCode::NO_CONTENT.into_response(),
+                Some("operator") => Json(json!({
+                    "id": "ops",
+                    "role": "operator",
+                    "sources": ["front"],
+                    "relay_only": true,
+                }))
+                .into_response(),
+                _ => StatusCode::FORBIDDEN.into_response(),
+            }
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/verify", listener.local_addr().unwrap());
+        let app = axum::Router::new().route("/verify", post(verify));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        AuthCallback::new(url.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn takes_the_grant() {
+        let callback = callback().await;
+
+        let plain = callback
+            .verify("plain", "POST", "/whep/front")
+            .await
+            .unwrap();
+        assert_eq!(plain.role, Role::Viewer);
+        assert_eq!(plain.id, format!("callback:{}", token_fingerprint("plain")));
+        assert!(!plain.id.contains("plain"));
+        assert!(plain.can_view("back"));
+
+        let operator = callback
+            .verify("operator", "POST", "/whep/front")
+            .await
+            .unwrap();
+        assert_eq!(operator.id, "callback:ops");
+        assert_eq!(operator.role, Role::Operator);
+        assert!(operator.relay_only);
+        assert!(!operator.can_view("back"));
+
+        assert!(
+            callback
+                .verify("guess", "POST", "/whep/front")
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn denies_when_unreachable() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/verify", listener.local_addr().unwrap());
+        drop(listener);
+        let callback = AuthCallback::new(url.parse().unwrap());
+        assert!(
+            callback
+                .verify("plain", "POST", "/whep/front")
+                .await
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use std::str::FromStr;
+
+/// ICE candidate type as it appears after `typ` in a candidate line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CandidateType {
+    Host,
+    Srflx,
+    Prflx,
+    Relay,
+}
+
+impl CandidateType {
+    fn as_str(self) -> &'static str {
+        match self {
+            CandidateType::Host => "host",
+            CandidateType::Srflx => "srflx",
+            CandidateType::Prflx => "prflx",
+            CandidateType::Relay => "relay",
+        }
+    }
+}
+
+/// IP address family of an ICE candidate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum IpFamily {
+    Ipv4,
+    Ipv6,
+}
+
+/// Rewrites candidate priorities in an answer to steer which route a client picks.
+///
+/// Priorities follow RFC 8445: `type preference << 24 | local preference << 8 |
+/// (256 - component)`. Candidate types are ranked in the configured order (unlisted
+/// types rank last) and the preferred IP family gets the higher local preference.
+/// Candidate lines are reordered by the new priorities.
+pub struct CandidatePreference {
+    pub types: Vec<CandidateType>,
+    pub family: Option<IpFamily>,
+}
+
+impl CandidatePreference {
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty() && self.family.is_none()
+    }
+
+    pub fn apply(&self, sdp: &str) -> String {
+        let mut out: Vec<String> = Vec::new();
+        let mut run: Vec<(u32, String)> = Vec::new();
+
+        for line in sdp.lines() {
+            match self.reprioritize(line) {
+                Some(candidate) => run.push(candidate),
+                None => {
+                    flush(&mut run, &mut out);
+                    out.push(line.to_owned());
+                }
+            }
+        }
+        flush(&mut run, &mut out);
+
+        let mut sdp = out.join("\r\n");
+        sdp.push_str("\r\n");
+        sdp
+    }
+
+    fn reprioritize(&self, line: &str) -> Option<(u32, String)> {
+        let attr = line.strip_prefix("a=candidate:")?;
+        let mut fields: Vec<&str> = attr.split(' ').collect();
+        // foundation component transport priority address port "typ" type ...
+        if fields.len() < 8 || fields[6] != "typ" {
+            return None;
+        }
+
+        let component = u32::from_str(fields[1]).ok()?;
+        let original = u32::from_str(fields[3]).ok()?;
+
+        let type_preference = if self.types.is_empty() {
+            original >> 24
+        } else {
+            match self.types.iter().position(|t| t.as_str() == fields[7]) {
+                Some(rank) => 126 - 20 * rank as u32,
+                None => 0,
+            }
+        };
+
+        let local_preference = match (self.family, family_of(fields[4])) {
+            (Some(preferred), Some(family)) if preferred == family => 65535,
+            (Some(_), Some(_)) => 32767,
+            _ => (original >> 8) & 0xffff,
+        };
+
+        let priority =
+            (type_preference << 24) | (local_preference << 8) | (256 - component.min(256));
+        let priority_str = priority.to_string();
+        fields[3] = &priority_str;
+
+        Some((priority, format!("a=candidate:{}", fields.join(" "))))
+    }
+}
+
+fn flush(run: &mut Vec<(u32, String)>, out: &mut Vec<String>) {
+    run.sort_by(|(a, _), (b, _)| b.cmp(a));
+    out.extend(run.drain(..).map(|(_, line)| line));
+}
+
+fn family_of(address: &str) -> Option<IpFamily> {
+    match address.parse::<std::net::IpAddr>().ok()? {
+        std::net::IpAddr::V4(_) => Some(IpFamily::Ipv4),
+        std::net::IpAddr::V6(_) => Some(IpFamily::Ipv6),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST_V6: &str = "a=candidate:1 1 udp 2122262783 2001:db8::1 5000 typ host";
+    const HOST_V4: &str = "a=candidate:2 1 udp 2122194687 192.0.2.1 5000 typ host";
+    const SRFLX: &str =
+        "a=candidate:3 1 udp 1686052607 198.51.100.7 5000 typ srflx raddr 0.0.0.0 rport 0";
+    const RELAY: &str =
+        "a=candidate:4 1 udp 41885439 203.0.113.9 3478 typ relay raddr 0.0.0.0 rport 0";
+
+    fn sdp() -> String {
+        [
+            "v=0",
+            "m=video 9 UDP/TLS/RTP/SAVPF 96",
+            HOST_V6,
+            HOST_V4,
+            SRFLX,
+            RELAY,
+            "a=end-of-candidates",
+        ]
+        .join("\r\n")
+    }
+
+    fn candidates(sdp: &str) -> Vec<(String, u32)> {
+        sdp.lines()
+            .filter_map(|line| line.strip_prefix("a=candidate:"))
+            .map(|candidate| {
+                let fields: Vec<&str> = candidate.split(' ').collect();
+                (fields[0].to_owned(), fields[3].parse().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ranks_types_in_order() {
+        let preference = CandidatePreference {
+            types: vec![CandidateType::Relay, CandidateType::Srflx],
+            family: None,
+        };
+        let answer = preference.apply(&sdp());
+        let ranked = candidates(&answer);
+        let foundations: Vec<&str> = ranked.iter().map(|(f, _)| f.as_str()).collect();
+        assert_eq!(foundations, ["4", "3", "1", "2"]);
+        assert_eq!(ranked[0].1 >> 24, 126);
+        assert_eq!(ranked[1].1 >> 24, 106);
+        assert_eq!(ranked[2].1 >> 24, 0);
+        // Everything but the candidates stays put
+        assert!(answer.starts_with("v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=candidate:4 "));
+        assert!(answer.ends_with("a=end-of-candidates\r\n"));
+    }
+
+    #[test]
+    fn prefers_the_ip_family() {
+        let preference = CandidatePreference {
+            types: Vec::new(),
+            family: Some(IpFamily::Ipv4),
+        };
+        let ranked = candidates(&preference.apply(&sdp()));
+        let foundations: Vec<&str> = ranked.iter().map(|(f, _)| f.as_str()).collect();
+        assert_eq!(foundations, ["2", "1", "3", "4"]);
+        // Type preferences are kept, local preferences follow the family
+        assert_eq!(ranked[0].1, 126 << 24 | 65535 << 8 | 255);
+        assert_eq!(ranked[1].1, 126 << 24 | 32767 << 8 | 255);
+    }
+
+    #[test]
+    fn skips_what_it_cannot_parse() {
+        let preference = CandidatePreference {
+            types: vec![CandidateType::Host],
+            family: None,
+        };
+        let odd = "a=candidate:1 1 udp high 192.0.2.1 5000 typ host";
+        assert_eq!(preference.apply(odd), format!("{}\r\n", odd));
+        assert!(preference.reprioritize("a=candidate:1 1 udp").is_none());
+        assert!(!preference.is_empty());
+        assert!(
+            CandidatePreference {
+                types: Vec::new(),
+                family: None
+            }
+            .is_empty()
+        );
+    }
+}
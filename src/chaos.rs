@@ -0,0 +1,113 @@
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde::Deserialize;
+use tokio::{sync::Notify, time::Instant};
+use tracing::warn;
+use webrtc::rtp::packet::Packet;
+
+use crate::state::AppState;
+
+// Longest stall that can be injected at once
+const MAX_STALL: Duration = Duration::from_secs(600);
+
+/// A failure to inject into a source, as posted to
+/// `POST /api/sources/{name}/faults`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "fault", rename_all = "snake_case")]
+pub enum Fault {
+    /// Drops the RTSP session, as a lost connection to the camera would; the
+    /// source then reconnects with backoff.
+    Disconnect,
+    /// Discards everything the camera sends for `secs` seconds, keeping the
+    /// session up.
+    Stall { secs: u64 },
+    /// Garbles the payload of the next `packets` video and audio packets.
+    Corrupt { packets: u64 },
+}
+
+/// Failures injected into a source's pipeline, with `--chaos`.
+#[derive(Default)]
+pub struct Faults {
+    /// Signalled to drop the RTSP session.
+    pub disconnect: Notify,
+    stalled_until: Mutex<Option<Instant>>,
+    corrupt: AtomicU64,
+}
+
+impl Faults {
+    pub fn inject(&self, fault: &Fault) {
+        match fault {
+            Fault::Disconnect => self.disconnect.notify_one(),
+            Fault::Stall { secs } => {
+                let stall = Duration::from_secs(*secs).min(MAX_STALL);
+                *self.stalled_until.lock().unwrap() = Some(Instant::now() + stall);
+            }
+            Fault::Corrupt { packets } => {
+                self.corrupt.fetch_add(*packets, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Whether packets from the camera are being discarded.
+    pub fn is_stalled(&self) -> bool {
+        let mut stalled_until = self.stalled_until.lock().unwrap();
+        match *stalled_until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                *stalled_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// `pkt`, with its payload inverted past the first two bytes (the codec
+    /// header stays, so it decodes as damage rather than as another packet
+    /// type) while corrupt packets are still owed.
+    pub fn corrupt(&self, mut pkt: Packet) -> Packet {
+        let owed = self
+            .corrupt
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |owed| {
+                owed.checked_sub(1)
+            })
+            .is_ok();
+        if owed {
+            let mut payload = pkt.payload.to_vec();
+            for byte in payload.iter_mut().skip(2) {
+                *byte = !*byte;
+            }
+            pkt.payload = payload.into();
+        }
+        pkt
+    }
+}
+
+/// `POST /api/sources/{name}/faults`: injects a failure into an RTSP source,
+/// e.g. `{"fault": "stall", "secs": 10}`.
+pub async fn inject_fault(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(fault): Json<Fault>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    let stream = state
+        .stream(&name)
+        .ok_or((StatusCode::NOT_FOUND, "no such source"))?;
+    let faults = stream
+        .faults
+        .as_ref()
+        .ok_or((StatusCode::CONFLICT, "faults only apply to RTSP sources"))?;
+    warn!("💥 [{}] Injecting fault {:?}", name, fault);
+    faults.inject(&fault);
+    Ok(StatusCode::ACCEPTED)
+}
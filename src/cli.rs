@@ -1,5 +1,17 @@
 use clap::Parser;
 
+use crate::{
+    alerts::AlertRule,
+    auth::{ApiToken, Role},
+    candidates::{CandidateType, IpFamily},
+    ids::{SessionIdFormat, SessionIdPolicy},
+    logging::{self, LogFormat},
+    mqtt::MqttUrl,
+    onvif::OnvifCamera,
+    rtx,
+    stats::{self, MetricLabel},
+};
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RTSPUrl(pub url::Url);
 
@@ -43,11 +55,417 @@ impl std::fmt::Display for RTSPUrl {
     }
 }
 
-#[derive(Parser)]
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum CpuListParseError {
+    #[error("invalid CPU range '{0}'")]
+    InvalidRange(String),
+    #[error(transparent)]
+    ParseIntError(#[from] std::num::ParseIntError),
+}
+
+/// A list of CPU ids, written like `0,2,4-7`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CpuList(pub Vec<usize>);
+
+impl std::ops::Deref for CpuList {
+    type Target = [usize];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl std::str::FromStr for CpuList {
+    type Err = CpuListParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut cpus = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match part.split_once('-') {
+                Some((start, end)) => {
+                    let (start, end) = (start.parse::<usize>()?, end.parse::<usize>()?);
+                    if start > end {
+                        return Err(CpuListParseError::InvalidRange(part.to_owned()));
+                    }
+                    cpus.extend(start..=end);
+                }
+                None => cpus.push(part.parse()?),
+            }
+        }
+        Ok(CpuList(cpus))
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum DscpParseError {
+    #[error("unknown DSCP class '{0}'")]
+    UnknownClass(String),
+    #[error("DSCP value {0} out of range, expected 0-63")]
+    OutOfRange(u8),
+}
+
+/// A DSCP code point, written as a number (`46`) or a class name (`ef`, `af41`, `cs5`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dscp(pub u8);
+
+impl std::str::FromStr for Dscp {
+    type Err = DscpParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_ascii_lowercase();
+        let value = if let Ok(value) = name.parse::<u8>() {
+            value
+        } else if name == "ef" {
+            46
+        } else if let Some(class) = name.strip_prefix("cs") {
+            match class.parse::<u8>() {
+                Ok(class @ 0..=7) => class << 3,
+                _ => return Err(DscpParseError::UnknownClass(s.to_owned())),
+            }
+        } else if let Some(af) = name.strip_prefix("af") {
+            // AFxy = 8x + 2y for classes 1-4 and drop precedences 1-3
+            match af.as_bytes() {
+                [x @ b'1'..=b'4', y @ b'1'..=b'3'] => 8 * (x - b'0') + 2 * (y - b'0'),
+                _ => return Err(DscpParseError::UnknownClass(s.to_owned())),
+            }
+        } else {
+            return Err(DscpParseError::UnknownClass(s.to_owned()));
+        };
+
+        if value > 63 {
+            return Err(DscpParseError::OutOfRange(value));
+        }
+        Ok(Dscp(value))
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum TagParseError {
+    #[error("invalid tag '{0}', expected key=value")]
+    MissingValue(String),
+}
+
+/// A `key=value` label attached to a source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub key: String,
+    pub value: String,
+}
+
+impl std::str::FromStr for Tag {
+    type Err = TagParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok(Tag {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            }),
+            _ => Err(TagParseError::MissingValue(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum SourceSpecParseError {
+    #[error("unknown source option '{0}'")]
+    UnknownOption(String),
+    #[error("source is missing '{0}'")]
+    Missing(&'static str),
+    #[error(transparent)]
+    RTSPUrlParseError(#[from] RTSPUrlParseError),
+    #[error(transparent)]
+    TagParseError(#[from] TagParseError),
+    #[error("invalid source option '{0}', expected a number")]
+    InvalidNumber(String),
+}
+
+/// One upstream camera, written as comma-separated options:
+/// `name=front,url=rtsp://...[,username=..,password=..,tag=key=value,relay-only,
+/// substream=..,display-name=..,description=..,priority=..,attempts=..,wait-for-dns,
+/// no-metadata,gain=..,interface=..]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceSpec {
+    pub name: String,
+    pub url: RTSPUrl,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tags: Vec<Tag>,
+    pub relay_only: bool,
+    /// Source that substream-only viewers watch instead of this one.
+    pub substream: Option<String>,
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+    /// Sources with a higher priority are started first at boot.
+    pub priority: i32,
+    /// Connection attempts at boot, instead of `--startup-attempts`.
+    pub attempts: Option<u32>,
+    /// Wait for the camera's host name to resolve before connecting.
+    pub wait_for_dns: bool,
+    /// Leave the camera's metadata stream unplayed.
+    pub no_metadata: bool,
+    /// Gain in dB applied to the camera's audio.
+    pub gain: i32,
+    /// Local network interface (e.g. `eth1`) or address that viewers' ICE
+    /// runs on, instead of `--ice-interface` / `--ice-ip`. The RTSP connection
+    /// to the camera isn't bound to it.
+    pub interface: Option<String>,
+}
+
+impl std::str::FromStr for SourceSpec {
+    type Err = SourceSpecParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut name, mut url) = (None, None);
+        let (mut username, mut password) = (None, None);
+        let mut tags = Vec::new();
+        let (mut relay_only, mut substream) = (false, None);
+        let (mut display_name, mut description) = (None, None);
+        let (mut priority, mut attempts, mut wait_for_dns) = (0, None, false);
+        let (mut no_metadata, mut gain, mut interface) = (false, 0, None);
+
+        for option in s.split(',').filter(|o| !o.is_empty()) {
+            match option.split_once('=') {
+                Some(("name", value)) => name = Some(value.to_owned()),
+                Some(("url", value)) => url = Some(value.parse()?),
+                Some(("username", value)) => username = Some(value.to_owned()),
+                Some(("password", value)) => password = Some(value.to_owned()),
+                Some(("tag", value)) => tags.push(value.parse()?),
+                Some(("substream", value)) => substream = Some(value.to_owned()),
+                Some(("interface", value)) => interface = Some(value.to_owned()),
+                Some(("display-name", value)) => display_name = Some(value.to_owned()),
+                Some(("description", value)) => description = Some(value.to_owned()),
+                Some(("priority", value)) => {
+                    priority = value
+                        .parse()
+                        .map_err(|_| SourceSpecParseError::InvalidNumber(option.to_owned()))?
+                }
+                Some(("gain", value)) => {
+                    gain = value
+                        .parse()
+                        .map_err(|_| SourceSpecParseError::InvalidNumber(option.to_owned()))?
+                }
+                Some(("attempts", value)) => {
+                    attempts = Some(
+                        value
+                            .parse()
+                            .map_err(|_| SourceSpecParseError::InvalidNumber(option.to_owned()))?,
+                    )
+                }
+                None if option == "relay-only" => relay_only = true,
+                None if option == "wait-for-dns" => wait_for_dns = true,
+                None if option == "no-metadata" => no_metadata = true,
+                _ => return Err(SourceSpecParseError::UnknownOption(option.to_owned())),
+            }
+        }
+
+        Ok(SourceSpec {
+            name: name
+                .filter(|name| !name.is_empty())
+                .ok_or(SourceSpecParseError::Missing("name"))?,
+            url: url.ok_or(SourceSpecParseError::Missing("url"))?,
+            username,
+            password,
+            tags,
+            relay_only,
+            substream,
+            display_name,
+            description,
+            priority,
+            attempts,
+            wait_for_dns,
+            no_metadata,
+            gain,
+            interface,
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum IceServerParseError {
+    #[error("unknown ICE server option '{0}'")]
+    UnknownOption(String),
+    #[error("invalid ICE server URL '{0}', expected stun:, stuns:, turn: or turns:")]
+    InvalidUrl(String),
+    #[error("ICE server has no URL")]
+    MissingUrl,
+}
+
+/// A STUN or TURN server, written as comma-separated options:
+/// `turn:turn.example.com:3478[,username=..,credential=..]`; `url=` may be
+/// spelled out and repeated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    pub username: String,
+    pub credential: String,
+}
+
+impl std::str::FromStr for IceServer {
+    type Err = IceServerParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let is_ice_url = |url: &str| {
+            ["stun:", "stuns:", "turn:", "turns:"]
+                .iter()
+                .any(|scheme| url.starts_with(scheme))
+        };
+
+        let mut server = IceServer {
+            urls: Vec::new(),
+            username: String::new(),
+            credential: String::new(),
+        };
+        for option in s.split(',').filter(|o| !o.is_empty()) {
+            match option.split_once('=') {
+                Some(("url", url)) if is_ice_url(url) => server.urls.push(url.to_owned()),
+                Some(("url", url)) => return Err(IceServerParseError::InvalidUrl(url.to_owned())),
+                Some(("username", value)) => server.username = value.to_owned(),
+                Some(("credential", value)) => server.credential = value.to_owned(),
+                // URLs may carry `?transport=udp`
+                _ if is_ice_url(option) => server.urls.push(option.to_owned()),
+                _ => return Err(IceServerParseError::UnknownOption(option.to_owned())),
+            }
+        }
+
+        if server.urls.is_empty() {
+            return Err(IceServerParseError::MissingUrl);
+        }
+        Ok(server)
+    }
+}
+
+/// Tasks run instead of the gateway.
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum Command {
+    /// Manage the API tokens of `--token-file`.
+    #[command(subcommand)]
+    Token(TokenCommand),
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum TokenCommand {
+    /// Generate a token and print it; only its hash is stored.
+    Create {
+        #[arg(default_value = "viewer", long, value_enum)]
+        role: Role,
+        /// Restrict the token's WHEP sessions to TURN relays.
+        #[arg(long)]
+        relay_only: bool,
+        /// Serve the token's viewers sources' substreams.
+        #[arg(long)]
+        substream_only: bool,
+        /// Transcode the token's viewers' video to AV1, with the `av1` feature.
+        #[arg(long)]
+        av1: bool,
+        /// Seconds until the token expires; without it, it never does.
+        #[arg(long)]
+        expires_in: Option<u64>,
+        /// Reminder of who or what the token is for.
+        #[arg(long)]
+        note: Option<String>,
+    },
+    /// Revoke a token by its id.
+    Revoke { id: String },
+    /// List the tokens, without their secrets.
+    List,
+}
+
+#[derive(Parser, Clone)]
 pub struct Source {
-    /// `rtsp://` URL to connect to.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// TOML file setting any of these options; flags given here override it.
+    #[arg(long)]
+    pub config: Option<std::path::PathBuf>,
+
+    /// Print the JSON Schema of the `--config` file and exit.
+    #[arg(long)]
+    pub print_config_schema: bool,
+
+    /// Check `/readyz` of the gateway running with the same `--listen` and TLS
+    /// options, exit 0 when it is ready and 1 otherwise (for Docker `HEALTHCHECK`).
+    #[arg(long)]
+    pub healthcheck: bool,
+
+    /// `rtsp://` URL to connect to. Without it or `--source` the server starts
+    /// with no sources; add them through `POST /api/sources`.
     #[clap(long)]
-    pub url: RTSPUrl,
+    pub url: Option<RTSPUrl>,
+
+    /// Name the source is listed under in the API.
+    #[arg(default_value = "default", long)]
+    pub name: String,
+
+    /// `key=value` label for the source (e.g. `site=hq`); may be repeated.
+    #[arg(long)]
+    pub tag: Vec<Tag>,
+
+    /// Only let viewers of this source connect through TURN relays.
+    #[arg(long)]
+    pub relay_only: bool,
+
+    /// `--source` that substream-only (`+sub`) viewers watch instead of this one.
+    #[arg(long)]
+    pub substream: Option<String>,
+
+    /// Gain in dB applied to the source's audio, for quiet camera mics; only
+    /// G.711 (PCMU/PCMA) audio can be amplified.
+    #[arg(
+        long,
+        value_name = "DB",
+        default_value_t = 0,
+        allow_negative_numbers = true
+    )]
+    pub audio_gain: i32,
+
+    /// Human-readable name of the source, announced to players in the SDP.
+    #[arg(long)]
+    pub display_name: Option<String>,
+
+    /// Longer description of the source, announced to players in the SDP.
+    #[arg(long)]
+    pub description: Option<String>,
+
+    /// Additional camera as `name=..,url=..[,username=..,password=..,tag=k=v,relay-only,substream=..,display-name=..,description=..,priority=..,attempts=..,wait-for-dns,no-metadata,gain=..]`,
+    /// served at `/whep/{name}`; may be repeated.
+    #[arg(long)]
+    pub source: Vec<SourceSpec>,
+
+    /// Camera whose RTSP URL is looked up over ONVIF at boot, as
+    /// `host=..[:port],username=..,password=..[,name=..,profile=..]`; streams
+    /// the named profile (token or name), else the first H.264/H.265 one; may
+    /// be repeated.
+    #[arg(long)]
+    pub onvif: Vec<OnvifCamera>,
+
+    /// Times to try connecting to each camera at boot before giving up and
+    /// exiting; `0` keeps trying. Sources start one at a time, highest
+    /// `priority=` first.
+    #[arg(default_value_t = 1, long)]
+    pub startup_attempts: u32,
+
+    /// Seconds between connection attempts, and DNS lookups with
+    /// `--wait-for-dns`, at boot.
+    #[arg(default_value_t = 5, long)]
+    pub startup_retry_interval: u64,
+
+    /// Wait at boot until every camera's host name resolves before connecting
+    /// to it, without using up `--startup-attempts`.
+    #[arg(long)]
+    pub wait_for_dns: bool,
+
+    /// Serve HTTP right away and connect to cameras in the background, all at
+    /// once and retrying each until it is up; `/whep` answers `503` for those
+    /// not up yet. Ignores `--startup-attempts` and `attempts=`.
+    #[arg(long)]
+    pub background_startup: bool,
+
+    /// Don't play cameras' metadata streams (ONVIF analytics), sparing the
+    /// bandwidth when nothing consumes them; `no-metadata` does so per camera.
+    #[arg(long)]
+    pub no_metadata: bool,
 
     /// Username to send if the server requires authentication.
     #[clap(long)]
@@ -64,4 +482,478 @@ pub struct Source {
     /// The transport to use: `tcp` or `udp` (experimental).
     #[arg(default_value_t, long)]
     pub transport: retina::client::Transport,
+
+    /// Most milliseconds a camera's video or audio is held back to line it up
+    /// with the other, per the camera's RTCP Sender Reports; `0` forwards both
+    /// as they arrive.
+    #[arg(default_value_t = 500, long)]
+    pub av_sync_max_delay: u64,
+
+    /// Milliseconds without audio, while video keeps flowing, before silence is
+    /// inserted on the audio track; `0` disables.
+    #[arg(default_value_t = 500, long)]
+    pub audio_stall_timeout: u64,
+
+    /// RMS level in dBFS (e.g. `-40`) above which sources' audio counts as
+    /// sound, reported as `sound` events; only G.711 (PCMU/PCMA) audio is
+    /// measured.
+    #[arg(long, value_name = "DBFS", allow_negative_numbers = true)]
+    pub sound_threshold: Option<f64>,
+
+    /// Seconds without video frames before viewers are told the video froze;
+    /// `0` disables.
+    #[arg(default_value_t = 5, long)]
+    pub freeze_timeout: u64,
+
+    /// Seconds a viewer may go without a keepalive (`PATCH` or `HEAD` on its
+    /// session resource) before the session is closed; `0` disables.
+    #[arg(default_value_t = 0, long)]
+    pub session_keepalive: u64,
+
+    /// Seconds a viewer or publisher connection may take to connect before
+    /// the session is closed; `0` disables.
+    #[arg(default_value_t = 30, long)]
+    pub connect_timeout: u64,
+
+    /// Enable `POST /api/sources/{name}/faults` to inject failures (dropped
+    /// connection, stalled or corrupted packets) into RTSP sources, for
+    /// resilience testing in staging.
+    #[arg(long)]
+    pub chaos: bool,
+
+    /// Most viewer sessions at once, across all sources; further offers get
+    /// `503 Service Unavailable`. Unlimited by default.
+    #[arg(long)]
+    pub max_viewers: Option<usize>,
+
+    /// Alert rule as `metric>threshold`, with metric `loss` (percent of packets
+    /// dropped), `viewer-errors` (failed viewer connections) or `reconnects`
+    /// (attempts to reconnect to a camera); may be repeated.
+    #[arg(long)]
+    pub alert: Vec<AlertRule>,
+
+    /// Seconds over which alert rules are measured and evaluated.
+    #[arg(default_value_t = 60, long)]
+    pub alert_interval: u64,
+
+    /// URL to POST alerts to as JSON when a rule starts or stops matching; may
+    /// be repeated.
+    #[arg(long)]
+    pub alert_webhook: Vec<url::Url>,
+
+    /// MQTT broker to publish events and alerts to and take commands from, as
+    /// `mqtt://[user:password@]host[:port]`.
+    #[arg(long)]
+    pub mqtt_url: Option<MqttUrl>,
+
+    /// Topic prefix for MQTT messages.
+    #[arg(default_value = "rtsp-to-webrtc", long, requires = "mqtt_url")]
+    pub mqtt_topic_prefix: String,
+
+    /// Announce sources to Home Assistant under this MQTT discovery prefix
+    /// (usually `homeassistant`).
+    #[arg(long, requires_all = ["mqtt_url", "public_url"])]
+    pub mqtt_discovery_prefix: Option<String>,
+
+    /// Base URL clients reach the gateway at, e.g. `https://cams.example.com/`.
+    #[arg(long)]
+    pub public_url: Option<url::Url>,
+
+    /// Directory to periodically write JSON stats snapshots to, for postmortem
+    /// analysis.
+    #[arg(long)]
+    pub stats_snapshot_dir: Option<std::path::PathBuf>,
+
+    /// Seconds between stats snapshots written to `--stats-snapshot-dir`.
+    #[arg(default_value_t = 60, long, requires = "stats_snapshot_dir")]
+    pub stats_snapshot_interval: u64,
+
+    /// InfluxDB line protocol write URL (e.g.
+    /// `http://influxdb:8086/api/v2/write?org=home&bucket=gateway`) to send
+    /// per-source and per-session stats rows to.
+    #[arg(long, value_name = "URL")]
+    pub stats_sink: Option<url::Url>,
+
+    /// Token for `--stats-sink`, sent as `Authorization: Token <TOKEN>`.
+    #[arg(long, value_name = "TOKEN", requires = "stats_sink")]
+    pub stats_sink_token: Option<String>,
+
+    /// Seconds between writes to `--stats-sink`.
+    #[arg(default_value_t = 10, long, requires = "stats_sink")]
+    pub stats_sink_interval: u64,
+
+    /// Leave this label (`source`, `track` or `reason`) out of `/metrics`,
+    /// summing the series that differed only in it; may be repeated.
+    #[arg(long, value_enum)]
+    pub metrics_drop_label: Vec<MetricLabel>,
+
+    /// Label `/metrics` series with this source tag (e.g. `site="hq"`) instead
+    /// of `source`, summing the sources that share a value.
+    #[arg(long, value_name = "KEY", value_parser = stats::parse_label_name)]
+    pub metrics_source_tag: Option<String>,
+
+    /// Write each viewer session's timeline (join, state changes, route,
+    /// periodic quality, leave) as JSON Lines into `<DIR>/`.
+    #[arg(long, value_name = "DIR")]
+    pub session_log_dir: Option<std::path::PathBuf>,
+
+    /// Seconds between quality samples in session timelines; `0` logs only
+    /// join, state changes and leave.
+    #[arg(default_value_t = 5, long, requires = "session_log_dir")]
+    pub session_log_interval: u64,
+
+    /// Keep the ids of open viewer sessions in this file, so after a restart
+    /// requests for their resources get `410 Gone` rather than `404 Not Found`.
+    #[arg(long, value_name = "FILE")]
+    pub session_state_file: Option<std::path::PathBuf>,
+
+    /// Minimum seconds between RTSP session restarts done to get a keyframe
+    /// for viewers that joined or reported picture loss; requests in between
+    /// wait for the next one. `0` disables them.
+    #[arg(default_value_t = 10, long)]
+    pub keyframe_request_interval: u64,
+
+    /// Only pull from cameras while someone is watching: connect when the first
+    /// viewer arrives and disconnect once the last one has left.
+    #[arg(long)]
+    pub on_demand: bool,
+
+    /// Seconds an on-demand source stays connected after its last viewer left.
+    #[arg(default_value_t = 10, long)]
+    pub on_demand_grace: u64,
+
+    /// URL to POST source events (e.g. video freezes) to as JSON; may be repeated.
+    #[arg(long)]
+    pub webhook: Vec<url::Url>,
+
+    /// Record every camera as fragmented MP4 segments (H.264 video, Opus or AAC
+    /// audio) into `<DIR>/<source>/`.
+    #[arg(long)]
+    pub record_dir: Option<std::path::PathBuf>,
+
+    /// Seconds of video per recording segment; segments start at a keyframe.
+    #[arg(default_value_t = 60, long, requires = "record_dir")]
+    pub record_segment: u64,
+
+    /// Hours to keep recordings for; `0` keeps them forever.
+    #[arg(default_value_t = 0, long, requires = "record_dir")]
+    pub record_retention: u64,
+
+    /// Record H.264 video transcoded to this picture height instead of the
+    /// camera's, e.g. `720`.
+    #[cfg(feature = "transcode")]
+    #[arg(long, value_name = "PIXELS", requires = "record_dir")]
+    pub record_height: Option<u32>,
+
+    /// Record H.264 video transcoded to this bitrate in kbit/s instead of the
+    /// camera's [default: 1000 with `--record-height`].
+    #[cfg(feature = "transcode")]
+    #[arg(long, value_name = "KBPS", requires = "record_dir")]
+    pub record_bitrate: Option<u32>,
+
+    /// Also serve every source as HLS (H.264 video, Opus audio in fMP4) at
+    /// `/hls/<source>/index.m3u8`, for players without WebRTC.
+    #[arg(long)]
+    pub hls: bool,
+
+    /// Seconds of video per HLS segment; segments start at a keyframe.
+    #[arg(default_value_t = 2, long, requires = "hls")]
+    pub hls_segment: u64,
+
+    /// Ask cameras for a keyframe when a recording or HLS segment reaches its
+    /// length without one, instead of letting long GOPs stretch it. Each
+    /// request restarts the RTSP session, at most every
+    /// `--keyframe-request-interval`.
+    #[arg(long)]
+    pub segment_keyframes: bool,
+
+    /// Log line format.
+    #[arg(default_value = "pretty", long, value_enum)]
+    pub log_format: LogFormat,
+
+    /// Log filter, a level (`debug`) or per-module directives like
+    /// `info,webrtc=warn,rtsp_to_webrtc::whep=debug`.
+    #[arg(default_value = "info", long, env = "RUST_LOG", value_parser = logging::parse_filter)]
+    pub log_level: String,
+
+    /// Number of tokio worker threads [default: one per CPU core, or the
+    /// container's CPU limit].
+    #[arg(long)]
+    pub worker_threads: Option<usize>,
+
+    /// CPUs to pin runtime threads to, e.g. `0,2,4-7`.
+    #[arg(long)]
+    pub cpu_affinity: Option<CpuList>,
+
+    /// Serve all WebRTC media from this single UDP port instead of one
+    /// ephemeral port per session.
+    #[arg(long)]
+    pub ice_udp_port: Option<u16>,
+
+    /// Packets of each viewer track kept to answer NACKs, as RTX where the
+    /// viewer supports it; a power of two up to 32768.
+    #[arg(long, value_name = "PACKETS", default_value_t = rtx::DEFAULT_NACK_HISTORY, value_parser = rtx::parse_nack_history)]
+    pub nack_history: usize,
+
+    /// Switch viewers of sources with a `substream=` to the substream and back
+    /// as the bandwidth their TWCC feedback shows allows.
+    #[arg(long)]
+    pub abr: bool,
+
+    /// Send viewers whose offer takes simulcast (typically SFUs) sources with a
+    /// `substream=` and the substream as two rid encodings of one video track.
+    #[arg(long)]
+    pub simulcast: bool,
+
+    /// Bitrate in kbit/s of the AV1 video transcoded for `+av1` viewers, at
+    /// the source's resolution.
+    #[cfg(feature = "av1")]
+    #[arg(long, value_name = "KBPS", default_value_t = 600)]
+    pub av1_bitrate: u32,
+
+    /// Also transcode sources to AV1 at 1080p, 720p and 360p, those below their
+    /// resolution, for `+av1` viewers whose offer takes simulcast.
+    #[cfg(feature = "av1")]
+    #[arg(long, requires = "simulcast")]
+    pub av1_ladder: bool,
+
+    /// Bitrate in kbit/s of the H.264 video transcoded from MJPEG cameras.
+    #[cfg(feature = "transcode")]
+    #[arg(long, value_name = "KBPS", default_value_t = 2000)]
+    pub mjpeg_bitrate: u32,
+
+    /// Draw a code identifying each viewer's session on the H.264 video it
+    /// gets, transcoded for it alone, so leaked footage can be traced; the
+    /// code is logged with the session id. Turns off AV1, simulcast and ABR.
+    #[cfg(feature = "transcode")]
+    #[arg(long)]
+    pub watermark: bool,
+
+    /// Bitrate in kbit/s of the watermarked video, at the source's
+    /// resolution.
+    #[cfg(feature = "transcode")]
+    #[arg(
+        long,
+        value_name = "KBPS",
+        default_value_t = 2000,
+        requires = "watermark"
+    )]
+    pub watermark_bitrate: u32,
+
+    /// DSCP marking for outgoing WebRTC media, as a number or class name
+    /// (`ef`, `af41`, `cs5`); implies a single UDP port.
+    #[arg(long)]
+    pub dscp: Option<Dscp>,
+
+    /// STUN/TURN server for viewer connections, as
+    /// `turn:host:3478[,username=..,credential=..]`; may be repeated.
+    #[arg(long)]
+    pub ice_server: Vec<IceServer>,
+
+    /// Only offer ICE candidates on this network interface (e.g. `eth1`).
+    #[arg(long)]
+    pub ice_interface: Option<String>,
+
+    /// Only offer ICE candidates on this local IP address.
+    #[arg(long)]
+    pub ice_ip: Option<std::net::IpAddr>,
+
+    /// Bearer token required by the HTTP API, as `[role[+relay][+sub][+av1]:]token`
+    /// with role `viewer`, `operator` or `admin` (the default), `+relay` forcing
+    /// TURN-only sessions, `+sub` serving sources' substreams and `+av1`
+    /// transcoding video to AV1 (with the `av1` feature); may be repeated.
+    /// Without tokens the API is open.
+    #[arg(long, env = "RTSP_TO_WEBRTC_API_TOKEN", value_delimiter = ',')]
+    pub api_token: Vec<ApiToken>,
+
+    /// JSON file of hashed API tokens, managed with `rtsp-to-webrtc token`;
+    /// changes apply without a restart.
+    #[arg(long, value_name = "FILE", global = true)]
+    pub token_file: Option<std::path::PathBuf>,
+
+    /// URL to verify unknown bearer tokens with: the token, method and path are
+    /// POSTed as JSON and a 2xx response grants access (as `viewer`, unless the
+    /// JSON response sets `role`, `sources`, `relay_only`, `substream_only`, `av1`
+    /// or `id`).
+    #[arg(long)]
+    pub auth_callback: Option<url::Url>,
+
+    /// OpenID Connect issuer whose JWTs are accepted as viewer tokens.
+    #[arg(long)]
+    pub jwt_issuer: Option<url::Url>,
+
+    /// JWKS URL to load signing keys from instead of using OIDC discovery.
+    #[arg(long, requires = "jwt_issuer")]
+    pub jwt_jwks_url: Option<url::Url>,
+
+    /// Accepted JWT audience; may be repeated. Without it the audience is not checked.
+    #[arg(long, requires = "jwt_issuer")]
+    pub jwt_audience: Vec<String>,
+
+    /// JWT claim listing the sources a viewer may watch (array or space-separated
+    /// string, `*` for all). Without it every source is allowed.
+    #[arg(long, requires = "jwt_issuer")]
+    pub jwt_source_claim: Option<String>,
+
+    /// Seconds the viewing links handed out by `GET /api/sources/{name}/pairing`
+    /// stay valid.
+    #[arg(default_value_t = 600, long)]
+    pub pairing_ttl: u64,
+
+    /// Encrypt every WHEP session's frames end to end with SFrame; nothing is
+    /// sent until the session's key is set with `PUT /whep/resource/{id}/key`.
+    #[arg(long)]
+    pub e2ee: bool,
+
+    /// Also serve a subset of the go2rtc and MediaMTX HTTP APIs (stream lists,
+    /// WebRTC/WHEP paths) for frontends written against those servers.
+    #[arg(long)]
+    pub compat_api: bool,
+
+    /// Address and port the HTTP(S) server listens on.
+    #[arg(default_value = "0.0.0.0:8080", long)]
+    pub listen: std::net::SocketAddr,
+
+    /// Port to listen on instead of the one in `--listen`, e.g. from `$PORT`.
+    #[arg(long, env = "PORT")]
+    pub port: Option<u16>,
+
+    /// Directory the web player and other static files are served from.
+    #[arg(default_value = "static", long, value_name = "DIR")]
+    pub static_dir: std::path::PathBuf,
+
+    /// Seconds browsers may cache static assets other than HTML pages, which
+    /// are revalidated on every load; `0` revalidates everything.
+    #[arg(default_value_t = 3600, long, value_name = "SECS")]
+    pub static_max_age: u64,
+
+    /// Don't serve static files (the web player); only the APIs answer, e.g.
+    /// behind a reverse proxy that serves its own frontend.
+    #[arg(long, conflicts_with_all = ["static_dir", "static_max_age"])]
+    pub no_static: bool,
+
+    /// PEM certificate chain to serve HTTPS with; requires `--tls-key`.
+    #[arg(long, requires = "tls_key", conflicts_with = "tls_self_signed")]
+    pub tls_cert: Option<std::path::PathBuf>,
+
+    /// PEM private key for `--tls-cert`.
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<std::path::PathBuf>,
+
+    /// Serve HTTPS with a self-signed certificate, generated on first start.
+    #[arg(long)]
+    pub tls_self_signed: bool,
+
+    /// Also listen for plain HTTP on this address (e.g. `0.0.0.0:80`) and
+    /// redirect every request to HTTPS; requires `--tls-cert`,
+    /// `--tls-self-signed` or `--acme-domain`.
+    #[arg(long)]
+    pub http_redirect: Option<std::net::SocketAddr>,
+
+    /// Directory the self-signed or ACME certificate and key are kept in.
+    #[arg(default_value = "tls", long)]
+    pub tls_dir: std::path::PathBuf,
+
+    /// Extra host name or IP address for the self-signed certificate; may be repeated.
+    #[arg(long)]
+    pub tls_name: Vec<String>,
+
+    /// Domain to serve HTTPS for with a certificate from an ACME CA (Let's
+    /// Encrypt by default), issued and renewed automatically; may be repeated.
+    /// The CA's HTTP-01 challenges are answered on `--http-redirect`, which
+    /// must be reachable on port 80.
+    #[arg(
+        long,
+        requires = "http_redirect",
+        conflicts_with_all = ["tls_cert", "tls_self_signed"]
+    )]
+    pub acme_domain: Vec<String>,
+
+    /// Contact email for the ACME account, for the CA's expiry notices.
+    #[arg(long, requires = "acme_domain")]
+    pub acme_email: Option<String>,
+
+    /// Directory URL of the ACME CA, e.g. Let's Encrypt's staging one for
+    /// tests.
+    #[arg(
+        long,
+        default_value = "https://acme-v02.api.letsencrypt.org/directory",
+        requires = "acme_domain"
+    )]
+    pub acme_directory: url::Url,
+
+    /// `Content-Security-Policy` for the web player and static assets.
+    #[arg(long)]
+    pub csp: Option<String>,
+
+    /// Origins allowed to embed the player in a frame, as a CSP source list
+    /// (e.g. `'self' https://portal.example.com` or `'none'`).
+    #[arg(long)]
+    pub frame_ancestors: Option<String>,
+
+    /// `Referrer-Policy` for the web player and static assets; empty to omit.
+    #[arg(default_value = "no-referrer", long)]
+    pub referrer_policy: String,
+
+    /// Log every SDP offer and answer for troubleshooting, with ICE passwords and
+    /// credentials redacted.
+    #[arg(long)]
+    pub log_sdp: bool,
+
+    /// Candidate types in order of preference for the answer, e.g. `relay,srflx,host`;
+    /// unlisted types are ranked last.
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub candidate_type_preference: Vec<CandidateType>,
+
+    /// IP family to rank first in the answer's candidates.
+    #[arg(long, value_enum)]
+    pub ip_family_preference: Option<IpFamily>,
+
+    /// Spelling of viewer and publisher session ids (and so of their resource URLs).
+    #[arg(default_value = "uuid", long, value_enum)]
+    pub session_id_format: SessionIdFormat,
+
+    /// `derivable` prefixes session ids with their source name, `opaque` keeps
+    /// them free of any meaning.
+    #[arg(default_value = "opaque", long, value_enum)]
+    pub session_id_policy: SessionIdPolicy,
+}
+
+impl Source {
+    /// The address to listen on: `--listen`, with `--port` if given.
+    pub fn listen_addr(&self) -> std::net::SocketAddr {
+        let mut addr = self.listen;
+        if let Some(port) = self.port {
+            addr.set_port(port);
+        }
+        addr
+    }
+
+    /// Whether HTTPS is served, with any kind of certificate.
+    pub fn tls(&self) -> bool {
+        self.tls_cert.is_some() || self.tls_self_signed || !self.acme_domain.is_empty()
+    }
+
+    /// All configured cameras: the one given by `--url`, then every `--source`.
+    pub fn sources(&self) -> Vec<SourceSpec> {
+        let primary = self.url.clone().map(|url| SourceSpec {
+            name: self.name.clone(),
+            url,
+            username: self.username.clone(),
+            password: self.password.clone(),
+            tags: self.tag.clone(),
+            relay_only: self.relay_only,
+            substream: self.substream.clone(),
+            display_name: self.display_name.clone(),
+            description: self.description.clone(),
+            priority: 0,
+            attempts: None,
+            wait_for_dns: false,
+            no_metadata: false,
+            gain: self.audio_gain,
+            interface: None,
+        });
+
+        primary.into_iter().chain(self.source.clone()).collect()
+    }
 }
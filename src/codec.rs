@@ -1,3 +1,11 @@
+use webrtc::{
+    api::media_engine::{MIME_TYPE_HEVC, MediaEngine},
+    rtp_transceiver::{
+        RTCPFeedback,
+        rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType},
+    },
+};
+
 // Video codec priorities (lower number = higher priority)
 pub const VIDEO_CODEC_PRIORITY: &[(&str, u32)] =
     &[("h265", 1), ("h264", 2), ("vp9", 3), ("vp8", 4)];
@@ -14,3 +22,107 @@ pub fn get_codec_priority(encoding_name: &str, priority_list: &[(&str, u32)]) ->
         .map(|(_, priority)| *priority)
         .unwrap_or(100)
 }
+
+// Dynamic payload types for the H.265 profiles below, unused by the defaults
+const H265_PAYLOAD_TYPES: [(u8, u8); 2] = [(1, 118), (2, 119)];
+
+/// Advertises H.265 Main (profile 1) and Main 10 (profile 2) with the fmtp
+/// browsers offer, besides the bare H.265 entry of the default codecs.
+pub fn register_h265(m: &mut MediaEngine) -> Result<(), webrtc::Error> {
+    for (profile_id, payload_type) in H265_PAYLOAD_TYPES {
+        m.register_codec(
+            RTCRtpCodecParameters {
+                capability: RTCRtpCodecCapability {
+                    mime_type: MIME_TYPE_HEVC.to_owned(),
+                    clock_rate: 90000,
+                    channels: 0,
+                    sdp_fmtp_line: format!("profile-id={};tier-flag=0;tx-mode=SRST", profile_id),
+                    rtcp_feedback: vec![
+                        RTCPFeedback {
+                            typ: "goog-remb".to_owned(),
+                            parameter: String::new(),
+                        },
+                        RTCPFeedback {
+                            typ: "ccm".to_owned(),
+                            parameter: "fir".to_owned(),
+                        },
+                        RTCPFeedback {
+                            typ: "nack".to_owned(),
+                            parameter: String::new(),
+                        },
+                        RTCPFeedback {
+                            typ: "nack".to_owned(),
+                            parameter: "pli".to_owned(),
+                        },
+                    ],
+                },
+                payload_type,
+                ..Default::default()
+            },
+            RTPCodecType::Video,
+        )?;
+    }
+    Ok(())
+}
+
+// RTX payload types for each video payload type of the default codecs and the
+// H.265 profiles above, all unused otherwise
+const RTX_PAYLOAD_TYPES: [(u8, u8); 12] = [
+    (96, 97),
+    (98, 99),
+    (100, 101),
+    (102, 103),
+    (127, 104),
+    (125, 107),
+    (108, 109),
+    (123, 122),
+    (41, 42),
+    (126, 124),
+    (118, 120),
+    (119, 121),
+];
+
+/// Advertises RTX (RFC 4588) for every video codec, so viewers can take
+/// retransmissions on a repair stream of its own.
+pub fn register_rtx(m: &mut MediaEngine) -> Result<(), webrtc::Error> {
+    for (apt, payload_type) in RTX_PAYLOAD_TYPES {
+        m.register_codec(
+            RTCRtpCodecParameters {
+                capability: RTCRtpCodecCapability {
+                    mime_type: "video/rtx".to_owned(),
+                    clock_rate: 90000,
+                    channels: 0,
+                    sdp_fmtp_line: format!("apt={}", apt),
+                    rtcp_feedback: Vec::new(),
+                },
+                payload_type,
+                ..Default::default()
+            },
+            RTPCodecType::Video,
+        )?;
+    }
+    Ok(())
+}
+
+/// The fmtp of an H.265 stream, from its RFC 6381 codec string (e.g.
+/// `hvc1.1.6.L153.B0`), so the track binds to the viewer's payload type for
+/// the same profile and tier.
+///
+/// The level is left out: viewers announce the highest level they decode,
+/// which rarely equals the camera's.
+pub fn h265_fmtp(rfc6381_codec: &str) -> Option<String> {
+    let mut parts = rfc6381_codec.strip_prefix("hvc1.")?.split('.');
+    // An optional profile space letter precedes the profile
+    let profile_id: u8 = parts
+        .next()?
+        .trim_start_matches(['A', 'B', 'C'])
+        .parse()
+        .ok()?;
+    let _compatibility = parts.next()?;
+    let tier_flag = match parts.next()?.chars().next()? {
+        'L' => 0,
+        'H' => 1,
+        _ => return None,
+    };
+    Some(format!("profile-id={};tier-flag={}", profile_id, tier_flag))
+}
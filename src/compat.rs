@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Query, State},
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::Principal,
+    state::{AppState, SourceInfo},
+    store::SessionInfo,
+    whep::{SDPAnswer, SDPOffer, offer_stream},
+};
+
+// Subsets of the go2rtc and MediaMTX HTTP APIs, so frontends and Home Assistant
+// integrations written against those servers can talk to this gateway.
+
+#[derive(Serialize)]
+pub struct Go2rtcStream {
+    producers: Vec<Go2rtcProducer>,
+    consumers: Vec<SessionInfo>,
+}
+
+#[derive(Serialize)]
+struct Go2rtcProducer {
+    url: url::Url,
+}
+
+/// go2rtc `GET /api/streams`.
+pub async fn go2rtc_streams(State(state): State<AppState>) -> Json<BTreeMap<String, Go2rtcStream>> {
+    let sessions = state.sessions.list();
+    let streams = state
+        .all_streams()
+        .iter()
+        .map(|stream| {
+            let info = &stream.info;
+            let stream = Go2rtcStream {
+                producers: vec![Go2rtcProducer {
+                    url: info.url.clone(),
+                }],
+                consumers: readers(&sessions, &info.name),
+            };
+            (info.name.clone(), stream)
+        })
+        .collect();
+
+    Json(streams)
+}
+
+#[derive(Deserialize)]
+pub struct Go2rtcWebrtcQuery {
+    src: String,
+}
+
+/// go2rtc `POST /api/webrtc?src={stream}` with an `application/sdp` offer.
+pub async fn go2rtc_webrtc(
+    State(state): State<AppState>,
+    Query(Go2rtcWebrtcQuery { src }): Query<Go2rtcWebrtcQuery>,
+    principal: Option<Extension<Principal>>,
+    offer: SDPOffer,
+) -> Result<SDPAnswer, Response> {
+    offer_stream(state, &src, principal, offer, false).await
+}
+
+/// MediaMTX `POST /{stream}/whep`.
+pub async fn mediamtx_whep(
+    State(state): State<AppState>,
+    Path(stream): Path<String>,
+    principal: Option<Extension<Principal>>,
+    offer: SDPOffer,
+) -> Result<SDPAnswer, Response> {
+    offer_stream(state, &stream, principal, offer, false).await
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MediamtxPathList {
+    page_count: usize,
+    item_count: usize,
+    items: Vec<MediamtxPath>,
+}
+
+#[derive(Serialize)]
+struct MediamtxPath {
+    name: String,
+    ready: bool,
+    tracks: Vec<String>,
+    readers: Vec<MediamtxReader>,
+}
+
+#[derive(Serialize)]
+struct MediamtxReader {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    id: String,
+}
+
+/// MediaMTX `GET /v3/paths/list`.
+pub async fn mediamtx_paths(State(state): State<AppState>) -> Json<MediamtxPathList> {
+    let sessions = state.sessions.list();
+    let items: Vec<MediamtxPath> = state
+        .all_streams()
+        .iter()
+        .map(|stream| MediamtxPath {
+            name: stream.info.name.clone(),
+            ready: true,
+            tracks: tracks(&stream.info),
+            readers: readers(&sessions, &stream.info.name)
+                .into_iter()
+                .map(|session| MediamtxReader {
+                    kind: "webRTCSession",
+                    id: session.id,
+                })
+                .collect(),
+        })
+        .collect();
+
+    Json(MediamtxPathList {
+        page_count: 1,
+        item_count: items.len(),
+        items,
+    })
+}
+
+fn readers(sessions: &[SessionInfo], source: &str) -> Vec<SessionInfo> {
+    sessions
+        .iter()
+        .filter(|session| session.source == source)
+        .cloned()
+        .collect()
+}
+
+// Codec names without the `video/` / `audio/` prefix, e.g. `H264`
+fn tracks(info: &SourceInfo) -> Vec<String> {
+    [info.video_codec.as_ref(), info.audio_codec.as_ref()]
+        .into_iter()
+        .flatten()
+        .map(|mime| {
+            mime.split_once('/')
+                .map_or(mime.as_str(), |(_, codec)| codec)
+                .to_owned()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(id: &str, source: &str) -> SessionInfo {
+        SessionInfo {
+            id: id.to_owned(),
+            source: source.to_owned(),
+            owner: None,
+            frame_keys: None,
+            local_candidates: None,
+        }
+    }
+
+    #[test]
+    fn names_codecs_without_their_kind() {
+        let info = SourceInfo {
+            name: "front".to_owned(),
+            url: "rtsp://192.0.2.1/stream".parse().unwrap(),
+            tags: BTreeMap::new(),
+            video_codec: Some("video/H264".to_owned()),
+            audio_codec: Some("audio/opus".to_owned()),
+            metadata_codec: Some("application/vnd.onvif.metadata".to_owned()),
+            relay_only: false,
+            substream: None,
+            display_name: None,
+            description: None,
+            interface: None,
+        };
+        assert_eq!(tracks(&info), ["H264", "opus"]);
+        assert!(
+            tracks(&SourceInfo {
+                video_codec: None,
+                audio_codec: None,
+                ..info
+            })
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn lists_readers_as_mediamtx_does() {
+        let sessions = [session("a1", "front"), session("b2", "back")];
+        let list = MediamtxPathList {
+            page_count: 1,
+            item_count: 1,
+            items: vec![MediamtxPath {
+                name: "front".to_owned(),
+                ready: true,
+                tracks: vec!["H264".to_owned()],
+                readers: readers(&sessions, "front")
+                    .into_iter()
+                    .map(|session| MediamtxReader {
+                        kind: "webRTCSession",
+                        id: session.id,
+                    })
+                    .collect(),
+            }],
+        };
+        assert_eq!(
+            serde_json::to_value(list).unwrap(),
+            serde_json::json!({
+                "pageCount": 1,
+                "itemCount": 1,
+                "items": [{
+                    "name": "front",
+                    "ready": true,
+                    "tracks": ["H264"],
+                    "readers": [{"type": "webRTCSession", "id": "a1"}],
+                }],
+            })
+        );
+    }
+}
@@ -0,0 +1,481 @@
+use std::{any::TypeId, collections::HashSet, ffi::OsString, ops::Range, path::Path};
+
+use serde_json::{Map, Value, json};
+
+use toml::{
+    Spanned,
+    de::{DeTable, DeValue},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read config file: {0}")]
+    Read(#[from] std::io::Error),
+    #[error("invalid config file: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("{path}:{line}: '{key}': {message}")]
+    Invalid {
+        path: String,
+        line: usize,
+        key: String,
+        message: String,
+    },
+}
+
+/// Expands `--config <path>` in `args` into the flags the TOML file sets, placed
+/// before the remaining arguments. Flags given on the command line win over the
+/// file's values for the same option.
+///
+/// Every key names a flag, written with `-` or `_`, e.g. `freeze_timeout = 10`
+/// or `tag = ["site=hq"]`. Tables stand for comma-separated option lists, so
+/// cameras can be given as `[[source]]` tables.
+///
+/// `${NAME}` in strings is replaced by the environment variable `NAME`.
+///
+/// Each value is checked against `command` on its own, so a mistake is reported
+/// with the key and line it is on.
+pub fn expand_args(
+    args: Vec<OsString>,
+    command: &clap::Command,
+) -> Result<Vec<OsString>, ConfigError> {
+    let Some(path) = config_path(&args) else {
+        return Ok(args);
+    };
+
+    let text = std::fs::read_to_string(&path)?;
+    let table = DeTable::parse(&text)?;
+
+    let overridden: HashSet<String> = args
+        .iter()
+        .filter_map(|arg| arg.to_str()?.strip_prefix("--"))
+        .map(|arg| arg.split_once('=').map_or(arg, |(name, _)| name).to_owned())
+        .collect();
+
+    let invalid = |key: &str, span: Range<usize>, message: String| ConfigError::Invalid {
+        path: path.display().to_string(),
+        line: text[..span.start].matches('\n').count() + 1,
+        key: key.to_owned(),
+        message,
+    };
+
+    let mut file_args = Vec::new();
+    for (key, value) in table.get_ref() {
+        let key = key.get_ref().as_ref();
+        let flag = key.replace('_', "-");
+        if overridden.contains(&flag) {
+            continue;
+        }
+        for (value, span) in
+            flag_values(value).map_err(|(span, message)| invalid(key, span, message))?
+        {
+            let arg = OsString::from(match value {
+                Some(value) => format!("--{}={}", flag, value),
+                None => format!("--{}", flag),
+            });
+            // Options that need others are checked with the whole command line
+            if let Err(e) = command
+                .clone()
+                .try_get_matches_from([OsString::from(command.get_name()), arg.clone()])
+                && e.kind() != clap::error::ErrorKind::MissingRequiredArgument
+            {
+                return Err(invalid(key, span, clap_message(&e)));
+            }
+            file_args.push(arg);
+        }
+    }
+
+    let mut args = args.into_iter();
+    Ok(args
+        .next()
+        .into_iter()
+        .chain(file_args)
+        .chain(args)
+        .collect())
+}
+
+/// JSON Schema (draft 2020-12) of the config file `expand_args` reads: one
+/// property per option of `command`, in both spellings.
+pub fn schema(command: &clap::Command) -> Value {
+    let mut properties = Map::new();
+    for arg in command.get_arguments() {
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        if matches!(long, "help" | "version" | "config" | "print-config-schema") {
+            continue;
+        }
+
+        let mut property = arg_schema(arg);
+        if let Some(help) = arg.get_help() {
+            property.insert("description".to_owned(), json!(help.to_string()));
+        }
+        let property = Value::Object(property);
+        properties.insert(long.to_owned(), property.clone());
+        if long.contains('-') {
+            properties.insert(long.replace('-', "_"), property);
+        }
+    }
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": format!("{} config", command.get_name()),
+        "type": "object",
+        "properties": properties,
+        "additionalProperties": false,
+    })
+}
+
+fn arg_schema(arg: &clap::Arg) -> Map<String, Value> {
+    if matches!(arg.get_action(), clap::ArgAction::SetTrue) {
+        let mut schema = Map::new();
+        schema.insert("type".to_owned(), json!("boolean"));
+        return schema;
+    }
+
+    let value_type = arg.get_value_parser().type_id();
+    let kind = if [
+        TypeId::of::<u8>(),
+        TypeId::of::<u16>(),
+        TypeId::of::<u32>(),
+        TypeId::of::<u64>(),
+        TypeId::of::<usize>(),
+        TypeId::of::<i32>(),
+        TypeId::of::<i64>(),
+    ]
+    .iter()
+    .any(|id| value_type == *id)
+    {
+        "integer"
+    } else if value_type == TypeId::of::<f32>() || value_type == TypeId::of::<f64>() {
+        "number"
+    } else {
+        "string"
+    };
+
+    let mut item = Map::new();
+    let possible: Vec<String> = arg
+        .get_possible_values()
+        .iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| value.get_name().to_owned())
+        .collect();
+    if !possible.is_empty() {
+        item.insert("enum".to_owned(), json!(possible));
+    } else if kind == "string" {
+        // Comma-separated option lists may be written as tables
+        item.insert("type".to_owned(), json!(["string", "object"]));
+    } else {
+        item.insert("type".to_owned(), json!(kind));
+    }
+
+    let defaults: Vec<Value> = arg
+        .get_default_values()
+        .iter()
+        .filter_map(|value| value.to_str())
+        .map(|value| match kind {
+            "integer" => value
+                .parse::<i64>()
+                .map_or_else(|_| json!(value), |v| json!(v)),
+            "number" => value
+                .parse::<f64>()
+                .map_or_else(|_| json!(value), |v| json!(v)),
+            _ => json!(value),
+        })
+        .collect();
+
+    if matches!(arg.get_action(), clap::ArgAction::Append) {
+        let mut schema = Map::new();
+        let item = Value::Object(item);
+        schema.insert(
+            "oneOf".to_owned(),
+            json!([item, { "type": "array", "items": item }]),
+        );
+        if !defaults.is_empty() {
+            schema.insert("default".to_owned(), json!(defaults));
+        }
+        schema
+    } else {
+        if let Some(default) = defaults.into_iter().next() {
+            item.insert("default".to_owned(), default);
+        }
+        item
+    }
+}
+
+fn config_path(args: &[OsString]) -> Option<std::path::PathBuf> {
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.to_str()?;
+        if arg == "--config" {
+            return args.next().map(|path| Path::new(path).to_owned());
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(Path::new(path).to_owned());
+        }
+    }
+    None
+}
+
+// A flag's value, if it takes one, and where it is in the file
+type FlagValue = (Option<String>, Range<usize>);
+// Why a value can't be used, and where it is
+type FlagError = (Range<usize>, String);
+
+/// Values to pass for one flag with where they are in the file: `None` is a
+/// bare switch, arrays repeat the flag. Strings are interpolated.
+fn flag_values(value: &Spanned<DeValue<'_>>) -> Result<Vec<FlagValue>, FlagError> {
+    let span = value.span();
+    Ok(match value.get_ref() {
+        DeValue::Boolean(true) => vec![(None, span)],
+        DeValue::Boolean(false) => Vec::new(),
+        DeValue::Array(values) => values
+            .iter()
+            .map(flag_values)
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
+            .collect(),
+        DeValue::Table(table) => {
+            let mut options = Vec::new();
+            for (name, value) in table {
+                let name = name.get_ref().replace('_', "-");
+                for (value, _) in flag_values(value)? {
+                    options.push(match value {
+                        Some(value) => format!("{}={}", name, value),
+                        None => name.clone(),
+                    });
+                }
+            }
+            vec![(Some(options.join(",")), span)]
+        }
+        DeValue::String(value) => {
+            let value = interpolate(value).map_err(|message| (span.clone(), message))?;
+            vec![(Some(value), span)]
+        }
+        DeValue::Integer(value) => {
+            let value = i64::from_str_radix(value.as_str(), value.radix())
+                .map_or_else(|_| value.to_string(), |value| value.to_string());
+            vec![(Some(value), span)]
+        }
+        DeValue::Float(value) => vec![(Some(value.as_str().replace('_', "")), span)],
+        DeValue::Datetime(_) => {
+            return Err((span, "dates and times are not supported".to_owned()));
+        }
+    })
+}
+
+/// Replaces `${NAME}` with the environment variable `NAME`, or with `fallback`
+/// for `${NAME:-fallback}` when it is unset or empty. `$${` is a literal `${`.
+fn interpolate(value: &str) -> Result<String, String> {
+    let mut interpolated = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        interpolated.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            interpolated.push_str("${");
+            rest = after;
+            continue;
+        }
+        let Some(after) = rest.strip_prefix("${") else {
+            interpolated.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let end = after
+            .find('}')
+            .ok_or_else(|| format!("unterminated '${{' in '{}'", value))?;
+        let (name, fallback) = match after[..end].split_once(":-") {
+            Some((name, fallback)) => (name, Some(fallback)),
+            None => (&after[..end], None),
+        };
+        match (std::env::var(name), fallback) {
+            (Ok(var), Some(fallback)) if var.is_empty() => interpolated.push_str(fallback),
+            (Ok(var), _) => interpolated.push_str(&var),
+            (Err(_), Some(fallback)) => interpolated.push_str(fallback),
+            (Err(std::env::VarError::NotPresent), None) => {
+                return Err(format!("environment variable '{}' is not set", name));
+            }
+            (Err(std::env::VarError::NotUnicode(_)), None) => {
+                return Err(format!(
+                    "environment variable '{}' is not valid UTF-8",
+                    name
+                ));
+            }
+        }
+        rest = &after[end + 1..];
+    }
+    interpolated.push_str(rest);
+    Ok(interpolated)
+}
+
+// The first line of a clap error, without its `error: ` prefix
+fn clap_message(error: &clap::Error) -> String {
+    let rendered = error.render().to_string();
+    let first = rendered.lines().next().unwrap_or_default();
+    first.strip_prefix("error: ").unwrap_or(first).to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Arg, ArgAction, Command};
+
+    use super::*;
+
+    fn command() -> Command {
+        Command::new("gateway")
+            .arg(Arg::new("config").long("config"))
+            .arg(
+                Arg::new("port")
+                    .long("port")
+                    .value_parser(clap::value_parser!(u16)),
+            )
+            .arg(
+                Arg::new("verbose")
+                    .long("verbose")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(Arg::new("tag").long("tag").action(ArgAction::Append))
+            .arg(Arg::new("source").long("source").action(ArgAction::Append))
+            .arg(Arg::new("stun-server").long("stun-server"))
+    }
+
+    // Expands `args` with `toml` as the config file
+    fn expand(name: &str, toml: &str, args: &[&str]) -> Result<Vec<String>, ConfigError> {
+        let path = std::env::temp_dir().join(format!("{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, toml).unwrap();
+        let path_arg = path.to_str().unwrap().to_owned();
+        let args = ["gateway", "--config", &path_arg]
+            .into_iter()
+            .chain(args.iter().copied())
+            .map(OsString::from)
+            .collect();
+        let expanded = expand_args(args, &command());
+        std::fs::remove_file(&path).unwrap();
+        expanded.map(|args| {
+            args.into_iter()
+                .map(|arg| arg.into_string().unwrap())
+                .filter(|arg| *arg != path_arg)
+                .collect()
+        })
+    }
+
+    #[test]
+    fn interpolates_environment() {
+        // SAFETY: no other test reads or writes these variables
+        unsafe {
+            std::env::set_var("RTSP_TO_WEBRTC_TEST_HOST", "cam.local");
+            std::env::set_var("RTSP_TO_WEBRTC_TEST_EMPTY", "");
+            std::env::remove_var("RTSP_TO_WEBRTC_TEST_UNSET");
+        }
+
+        assert_eq!(interpolate("plain").unwrap(), "plain");
+        assert_eq!(
+            interpolate("rtsp://${RTSP_TO_WEBRTC_TEST_HOST}/1").unwrap(),
+            "rtsp://cam.local/1"
+        );
+        assert_eq!(
+            interpolate("${RTSP_TO_WEBRTC_TEST_UNSET:-fallback}").unwrap(),
+            "fallback"
+        );
+        assert_eq!(
+            interpolate("${RTSP_TO_WEBRTC_TEST_EMPTY:-fallback}").unwrap(),
+            "fallback"
+        );
+        assert_eq!(
+            interpolate("${RTSP_TO_WEBRTC_TEST_HOST:-fallback}").unwrap(),
+            "cam.local"
+        );
+        assert_eq!(interpolate("${RTSP_TO_WEBRTC_TEST_EMPTY}").unwrap(), "");
+        assert_eq!(
+            interpolate("$${HOME} costs $5").unwrap(),
+            "${HOME} costs $5"
+        );
+        assert_eq!(
+            interpolate("${RTSP_TO_WEBRTC_TEST_UNSET}").unwrap_err(),
+            "environment variable 'RTSP_TO_WEBRTC_TEST_UNSET' is not set"
+        );
+        assert_eq!(
+            interpolate("a ${NAME").unwrap_err(),
+            "unterminated '${' in 'a ${NAME'"
+        );
+    }
+
+    #[test]
+    fn expands_file_into_flags() {
+        let toml = r#"
+            port = 8080
+            verbose = true
+            stun_server = "stun:stun.l.google.com:19302"
+            tag = ["site=hq", "floor=2"]
+
+            [[source]]
+            name = "door"
+            url = "rtsp://cam/1"
+            on_demand = true
+        "#;
+        let mut args = expand("expand", toml, &[]).unwrap();
+        assert_eq!(args.remove(0), "gateway");
+        assert_eq!(args.pop().unwrap(), "--config");
+        args.sort();
+        assert_eq!(
+            args,
+            [
+                "--port=8080",
+                "--source=name=door,on-demand,url=rtsp://cam/1",
+                "--stun-server=stun:stun.l.google.com:19302",
+                "--tag=floor=2",
+                "--tag=site=hq",
+                "--verbose",
+            ]
+        );
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let args = expand(
+            "override",
+            "port = 8080\nverbose = false\n",
+            &["--port=9000"],
+        )
+        .unwrap();
+        assert_eq!(args, ["gateway", "--config", "--port=9000"]);
+    }
+
+    #[test]
+    fn reports_key_and_line() {
+        let toml = "verbose = true\n\nport = \"http\"\n";
+        match expand("invalid", toml, &[]) {
+            Err(ConfigError::Invalid { line, key, .. }) => {
+                assert_eq!((line, key.as_str()), (3, "port"));
+            }
+            other => panic!("expected an invalid port, got {other:?}"),
+        }
+        match expand("date", "\n\nport = 1979-05-27\n", &[]) {
+            Err(ConfigError::Invalid { line, message, .. }) => {
+                assert_eq!(line, 3);
+                assert_eq!(message, "dates and times are not supported");
+            }
+            other => panic!("expected an invalid date, got {other:?}"),
+        }
+        assert!(matches!(
+            expand("unknown", "colour = \"red\"\n", &[]),
+            Err(ConfigError::Invalid { .. })
+        ));
+    }
+
+    #[test]
+    fn finds_config_path() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            config_path(&args(&["gateway", "--config", "a.toml"])),
+            Some("a.toml".into())
+        );
+        assert_eq!(
+            config_path(&args(&["gateway", "--port=1", "--config=b.toml"])),
+            Some("b.toml".into())
+        );
+        assert_eq!(config_path(&args(&["--config=c.toml"])), None);
+        assert_eq!(config_path(&args(&["gateway"])), None);
+    }
+}
@@ -0,0 +1,287 @@
+use axum::{
+    Json,
+    extract::Request,
+    http::{HeaderName, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use tracing::{error, warn};
+
+/// Header carrying the id of a request, taken from the client or made up.
+pub static REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Longer ids from clients are replaced rather than echoed
+const MAX_REQUEST_ID_LEN: usize = 128;
+// Error bodies are short texts; anything longer is summarized by the status
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+/// The body of every error the API returns.
+#[derive(Debug, Serialize)]
+pub struct ErrorEnvelope {
+    /// Machine-readable reason, e.g. `not_found`.
+    pub code: String,
+    /// What went wrong, for humans.
+    pub message: String,
+    /// Structured context a handler returned, if any.
+    pub details: Option<serde_json::Value>,
+    /// The request's `X-Request-Id`, to find it in the gateway's logs.
+    pub request_id: Option<String>,
+}
+
+/// Why a viewer's or publisher's offer could not be answered. The client gets
+/// a `400` or `500` with the reason, as an [`ErrorEnvelope`]; the cause is
+/// logged.
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error("offer rejected: {0}")]
+    BadOffer(webrtc::Error),
+    #[error("failed to set up the session: {0}")]
+    WebRtc(#[from] webrtc::Error),
+    #[error("failed to set up the session: no local description")]
+    NoLocalDescription,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Self::BadOffer(_) => StatusCode::BAD_REQUEST,
+            Self::WebRtc(_) | Self::NoLocalDescription => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        if status.is_server_error() {
+            error!("{}", self);
+        } else {
+            warn!("{}", self);
+        }
+        (status, self.to_string()).into_response()
+    }
+}
+
+impl From<AppError> for Response {
+    fn from(error: AppError) -> Self {
+        error.into_response()
+    }
+}
+
+/// Middleware giving every request an `X-Request-Id`: the client's own if it
+/// sent a sensible one, else a new UUID. The response carries it back.
+pub async fn request_id(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID)
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.as_bytes().iter().all(u8::is_ascii_graphic)
+        })
+        .cloned()
+        .unwrap_or_else(|| {
+            HeaderValue::from_str(&uuid::Uuid::new_v4().to_string())
+                .expect("a UUID is a valid header value")
+        });
+    request.headers_mut().insert(REQUEST_ID.clone(), id.clone());
+
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(REQUEST_ID.clone(), id);
+    response
+}
+
+/// Middleware rewriting error responses (4xx and 5xx) into an
+/// [`ErrorEnvelope`]. A text body becomes the message, a JSON body the
+/// details; headers such as `Retry-After` and `WWW-Authenticate` stay.
+pub async fn error_envelope(request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(&REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_owned);
+
+    let response = next.run(request).await;
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let json = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    let body = axum::body::to_bytes(body, MAX_ERROR_BODY)
+        .await
+        .unwrap_or_default();
+    let text = String::from_utf8_lossy(&body).trim().to_owned();
+
+    let (message, details) = match serde_json::from_slice(&body) {
+        Ok(details) if json => (reason(status).to_owned(), Some(details)),
+        _ if text.is_empty() => (reason(status).to_owned(), None),
+        _ => (text, None),
+    };
+    let envelope = ErrorEnvelope {
+        code: code(status),
+        message,
+        details,
+        request_id,
+    };
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.remove(header::CONTENT_TYPE);
+    let mut response = (status, Json(envelope)).into_response();
+    response.headers_mut().extend(parts.headers);
+    response
+}
+
+/// `404` for paths under `/api/` no route matches, so they get an envelope
+/// rather than the player's error page.
+pub async fn api_not_found() -> StatusCode {
+    StatusCode::NOT_FOUND
+}
+
+pub fn reason(status: StatusCode) -> &'static str {
+    status.canonical_reason().unwrap_or("Error")
+}
+
+/// `Service Unavailable` -> `service_unavailable`.
+pub fn code(status: StatusCode) -> String {
+    reason(status)
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == ' ' || *c == '-')
+        .map(|c| match c {
+            ' ' | '-' => '_',
+            c => c.to_ascii_lowercase(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, body::Body, middleware, routing::get};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn codes_from_reasons() {
+        assert_eq!(code(StatusCode::SERVICE_UNAVAILABLE), "service_unavailable");
+        assert_eq!(code(StatusCode::NOT_FOUND), "not_found");
+        assert_eq!(code(StatusCode::MULTI_STATUS), "multi_status");
+        assert_eq!(code(StatusCode::IM_A_TEAPOT), "im_a_teapot");
+        assert_eq!(
+            code(StatusCode::HTTP_VERSION_NOT_SUPPORTED),
+            "http_version_not_supported"
+        );
+        // Statuses without a reason
+        assert_eq!(code(StatusCode::from_u16(599).unwrap()), "error");
+    }
+
+    #[test]
+    fn app_error_statuses() {
+        let bad_offer = AppError::BadOffer(webrtc::Error::ErrNoRemoteDescription);
+        assert_eq!(bad_offer.into_response().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            AppError::NoLocalDescription.into_response().status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    async fn envelope(router: Router, request: Request) -> (Response, serde_json::Value) {
+        let response = router
+            .layer(middleware::from_fn(error_envelope))
+            .layer(middleware::from_fn(request_id))
+            .oneshot(request)
+            .await
+            .unwrap();
+        let (parts, body) = response.into_parts();
+        let body = axum::body::to_bytes(body, MAX_ERROR_BODY).await.unwrap();
+        let json = serde_json::from_slice(&body).unwrap_or_default();
+        (Response::from_parts(parts, Body::empty()), json)
+    }
+
+    #[tokio::test]
+    async fn wraps_text_errors() {
+        let router = Router::new().route(
+            "/",
+            get(|| async {
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    [(header::RETRY_AFTER, "2")],
+                    "No keyframe yet",
+                )
+            }),
+        );
+        let request = Request::builder()
+            .uri("/")
+            .header(&REQUEST_ID, "abc-123")
+            .body(Body::empty())
+            .unwrap();
+        let (response, json) = envelope(router, request).await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+        assert_eq!(response.headers()[&REQUEST_ID], "abc-123");
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "code": "service_unavailable",
+                "message": "No keyframe yet",
+                "details": null,
+                "request_id": "abc-123",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn wraps_json_and_empty_errors() {
+        let router = Router::new()
+            .route(
+                "/json",
+                get(|| async {
+                    (
+                        StatusCode::CONFLICT,
+                        Json(serde_json::json!({ "field": "name" })),
+                    )
+                }),
+            )
+            .route("/empty", get(|| async { StatusCode::FORBIDDEN }));
+
+        let request = Request::builder().uri("/json").body(Body::empty()).unwrap();
+        let (_, json) = envelope(router.clone(), request).await;
+        assert_eq!(json["code"], "conflict");
+        assert_eq!(json["message"], "Conflict");
+        assert_eq!(json["details"], serde_json::json!({ "field": "name" }));
+
+        let request = Request::builder()
+            .uri("/empty")
+            .body(Body::empty())
+            .unwrap();
+        let (_, json) = envelope(router, request).await;
+        assert_eq!(json["code"], "forbidden");
+        assert_eq!(json["message"], "Forbidden");
+        assert_eq!(json["details"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn passes_successes_and_replaces_odd_request_ids() {
+        let router = Router::new().route("/", get(|| async { "ok" }));
+        let request = Request::builder()
+            .uri("/")
+            .header(&REQUEST_ID, "has spaces")
+            .body(Body::empty())
+            .unwrap();
+        let response = router
+            .layer(middleware::from_fn(error_envelope))
+            .layer(middleware::from_fn(request_id))
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let id = response.headers()[&REQUEST_ID].to_str().unwrap();
+        assert!(uuid::Uuid::parse_str(id).is_ok(), "{id}");
+        let body = axum::body::to_bytes(response.into_body(), MAX_ERROR_BODY)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"ok");
+    }
+}
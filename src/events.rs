@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+/// Source-level events pushed to viewers and webhooks.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// No video packets arrived for the configured freeze timeout.
+    VideoFrozen { idle_ms: u64 },
+    /// Video packets are flowing again after a freeze.
+    VideoResumed { frozen_ms: u64 },
+    /// No audio arrived for `--audio-stall-timeout` while video kept flowing;
+    /// silence is inserted meanwhile.
+    AudioStalled { idle_ms: u64 },
+    /// Audio packets are flowing again after a stall.
+    AudioResumed { stalled_ms: u64 },
+    /// The source was disabled for maintenance; its viewers are disconnected.
+    SourceDisabled,
+    /// The source was enabled again and accepts viewers.
+    SourceEnabled,
+    /// The camera's ONVIF metadata reported motion starting or stopping.
+    Motion { active: bool },
+    /// The source's audio got louder than `--sound-threshold`, or quiet again;
+    /// `level_dbfs` is the loudest RMS level of the change.
+    Sound { active: bool, level_dbfs: f64 },
+}
+
+// Webhook requests that take longer than this are abandoned
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Webhook body: the event, labelled with the source it happened on.
+#[derive(Serialize)]
+struct Notification<'a> {
+    source: &'a str,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// POSTs every event of `source` as JSON to each of the given URLs.
+pub fn spawn_webhooks(source: String, urls: Vec<url::Url>, mut events: broadcast::Receiver<Event>) {
+    if urls.is_empty() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()
+            .expect("failed to build webhook HTTP client");
+
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Webhook notifier lagged, {} events skipped", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+
+            for url in &urls {
+                let notification = Notification {
+                    source: &source,
+                    event: &event,
+                };
+                match client.post(url.clone()).json(&notification).send().await {
+                    Ok(response) if response.status().is_success() => {
+                        debug!("Webhook {} accepted {:?}", url, event);
+                    }
+                    Ok(response) => {
+                        warn!("Webhook {} returned {}", url, response.status());
+                    }
+                    Err(e) => {
+                        warn!("Webhook {} failed: {}", url, e);
+                    }
+                }
+            }
+        }
+    });
+}
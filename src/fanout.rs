@@ -0,0 +1,680 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::{broadcast, watch},
+    task::JoinHandle,
+    time::Instant,
+};
+use tracing::{debug, trace, warn};
+use webrtc::{
+    Error as WebRTCError,
+    rtp::packet::Packet,
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    track::track_local::{TrackLocalWriter, track_local_static_rtp::TrackLocalStaticRTP},
+};
+
+use crate::{
+    gop::{Codec, GopCache},
+    restamp::Restamper,
+    sframe::{FrameEncryptor, FrameKeys},
+    startup::{StartupTimer, ViewerStartup},
+};
+
+// Packets a viewer's writer may fall behind by before it skips ahead
+const VIEWER_BACKLOG: usize = 512;
+// A receiver report losing at least this fraction of the packets (in 256ths,
+// about 10%) is lossy
+const SLOW_VIEWER_LOSS: u8 = 26;
+// A viewer whose reports are lossy this often within the window is too slow:
+// its video is cut down to keyframes, and if it still loses that much, it is
+// evicted
+const SLOW_VIEWER_REPORTS: u32 = 3;
+const SLOW_VIEWER_WINDOW: Duration = Duration::from_secs(10);
+// A viewer on keyframes only gets everything again once its reports have been
+// clear this long
+const SLOW_VIEWER_RECOVERY: Duration = Duration::from_secs(30);
+
+/// Called when a viewer is evicted for being too slow.
+pub type OnEvicted = Arc<dyn Fn() + Send + Sync>;
+
+/// What happens to every packet before it is handed to the viewers' writers.
+struct Forwarding {
+    restamper: Restamper,
+    gop: Option<GopCache>,
+}
+
+/// A source track that hands every viewer a track and writer task of its own,
+/// so a slow viewer never holds up the others, and new viewers can be sent the
+/// current GOP before joining the live packets.
+///
+/// Only H.264 and H.265 video is cached; other codecs are forwarded as is.
+pub struct FanoutTrack {
+    codec: RTCRtpCodecCapability,
+    id: String,
+    stream_id: String,
+    forwarding: Mutex<Forwarding>,
+    packets: broadcast::Sender<Packet>,
+    bytes: AtomicU64,
+    startup: Option<Arc<StartupTimer>>,
+}
+
+impl FanoutTrack {
+    pub fn new(codec: RTCRtpCodecCapability, id: String, stream_id: String) -> Self {
+        let forwarding = Forwarding {
+            restamper: Restamper::new(codec.clock_rate),
+            gop: Codec::from_mime_type(&codec.mime_type).map(GopCache::new),
+        };
+        Self {
+            codec,
+            id,
+            stream_id,
+            forwarding: Mutex::new(forwarding),
+            packets: broadcast::channel(VIEWER_BACKLOG).0,
+            bytes: AtomicU64::new(0),
+            startup: None,
+        }
+    }
+
+    /// Reports how long each viewer waited for its first keyframe to `startup`.
+    pub fn with_startup(mut self, startup: Arc<StartupTimer>) -> Self {
+        self.startup = Some(startup);
+        self
+    }
+
+    /// A track under the same ids carrying `codec`, for a transcode of this
+    /// one.
+    #[cfg(any(feature = "av1", feature = "transcode"))]
+    pub fn transcoded(&self, codec: RTCRtpCodecCapability) -> Self {
+        Self::new(codec, self.id.clone(), self.stream_id.clone())
+    }
+
+    pub fn codec(&self) -> RTCRtpCodecCapability {
+        self.codec.clone()
+    }
+
+    /// Whether a new viewer would start with a keyframe, without asking the
+    /// source for one.
+    pub fn has_keyframe(&self) -> bool {
+        let forwarding = self.forwarding.lock().unwrap();
+        forwarding.gop.as_ref().is_some_and(GopCache::has_keyframe)
+    }
+
+    /// The packets of the latest keyframe, parameter sets first, for stills;
+    /// empty until one was sent.
+    #[cfg(feature = "snapshot")]
+    pub fn keyframe(&self) -> Vec<Packet> {
+        let forwarding = self.forwarding.lock().unwrap();
+        forwarding
+            .gop
+            .as_ref()
+            .map(GopCache::keyframe)
+            .unwrap_or_default()
+    }
+
+    /// Payload bytes sent so far, for measuring the track's bitrate.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// A track for one viewer. It receives nothing until started.
+    pub fn subscribe(self: &Arc<Self>) -> Subscription {
+        Subscription {
+            fanout: self.clone(),
+            track: Arc::new(TrackLocalStaticRTP::new(
+                self.codec.clone(),
+                self.id.clone(),
+                self.stream_id.clone(),
+            )),
+            writer: Mutex::new(None),
+            created: Instant::now(),
+            keys: None,
+            alternate: None,
+            pace: Arc::new(Pace::new()),
+            on_evicted: None,
+        }
+    }
+
+    /// Every packet sent from now on, restamped, for consumers that are not
+    /// viewers (recordings).
+    pub fn listen(&self) -> broadcast::Receiver<Packet> {
+        self.packets.subscribe()
+    }
+
+    /// Hands a packet to the writers of all started viewers, on the track's
+    /// own timeline.
+    pub fn send(&self, mut pkt: Packet) {
+        // Held while sending, so a starting viewer's replay ends exactly where
+        // its live packets begin
+        let mut forwarding = self.forwarding.lock().unwrap();
+        let Forwarding { restamper, gop } = &mut *forwarding;
+        let discontinuity = restamper.restamp(&mut pkt);
+        if let Some(gop) = gop.as_mut() {
+            // Pictures of the previous upstream can't be continued
+            if discontinuity {
+                gop.reset();
+            }
+            gop.push(&pkt);
+        }
+        self.bytes
+            .fetch_add(pkt.payload.len() as u64, Ordering::Relaxed);
+        let _ = self.packets.send(pkt);
+    }
+}
+
+/// One viewer's track of a [`FanoutTrack`]; dropping it stops the viewer's
+/// writer.
+pub struct Subscription {
+    fanout: Arc<FanoutTrack>,
+    track: Arc<TrackLocalStaticRTP>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    created: Instant,
+    keys: Option<Arc<FrameKeys>>,
+    alternate: Option<(Arc<FanoutTrack>, watch::Receiver<bool>)>,
+    pace: Arc<Pace>,
+    on_evicted: Option<OnEvicted>,
+}
+
+impl Subscription {
+    /// Encrypts every frame with the session's key, if it has one (`--e2ee`).
+    pub fn encrypted(mut self, keys: Option<Arc<FrameKeys>>) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    /// Stops writing and calls `on_evicted` once the viewer keeps losing
+    /// packets even on keyframes only, so the session can be closed.
+    pub fn evictable(mut self, on_evicted: OnEvicted) -> Self {
+        self.on_evicted = Some(on_evicted);
+        self
+    }
+
+    /// Lets `selected` move the viewer to `alternate` (`true`) and back. Each
+    /// move waits for a keyframe of the track moved to, and the viewer's
+    /// sequence numbers and timestamps carry on across it.
+    pub fn switchable(
+        mut self,
+        alternate: Arc<FanoutTrack>,
+        selected: watch::Receiver<bool>,
+    ) -> Self {
+        self.alternate = Some((alternate, selected));
+        self
+    }
+
+    /// Sends the viewer's track as the simulcast encoding `rid`, under the
+    /// track and stream ids of `like`, so it can share a sender with the
+    /// encodings of `like`.
+    pub fn encoding(mut self, rid: &str, like: &FanoutTrack) -> Self {
+        self.track = Arc::new(TrackLocalStaticRTP::new_with_rid(
+            self.fanout.codec.clone(),
+            like.id.clone(),
+            rid.to_owned(),
+            like.stream_id.clone(),
+        ));
+        self
+    }
+
+    pub fn track(&self) -> Arc<TrackLocalStaticRTP> {
+        self.track.clone()
+    }
+
+    /// Where the viewer's receiver reports for the track go.
+    pub fn pace(&self) -> Arc<Pace> {
+        self.pace.clone()
+    }
+
+    /// Starts writing once the viewer is connected, beginning with the cached
+    /// GOP. Packets written earlier would be dropped by the connection.
+    pub fn start(&self) {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.is_some() {
+            return;
+        }
+
+        let (mut backlog, packets) = {
+            let forwarding = self.fanout.forwarding.lock().unwrap();
+            (
+                forwarding
+                    .gop
+                    .as_ref()
+                    .map(GopCache::replay)
+                    .unwrap_or_default(),
+                self.fanout.packets.subscribe(),
+            )
+        };
+        let mut feed = Feed {
+            packets,
+            switch: self.alternate.clone().map(|(alternate, selected)| Switch {
+                tracks: [self.fanout.clone(), alternate],
+                live: 0,
+                selected: Some(selected),
+                pending: None,
+                restamper: Restamper::new(self.fanout.codec.clock_rate),
+            }),
+        };
+        if let Some(switch) = feed.switch.as_mut() {
+            for pkt in &mut backlog {
+                switch.restamper.restamp(pkt);
+            }
+        }
+        let track = self.track.clone();
+        let id = self.fanout.id.clone();
+        let codec = Codec::from_mime_type(&self.fanout.codec.mime_type);
+        let created = self.created;
+        let pace = self.pace.clone();
+        let on_evicted = self.on_evicted.clone();
+        let mut keyframes_only = KeyframesOnly::default();
+        let mut encryptor = self
+            .keys
+            .clone()
+            .map(|keys| FrameEncryptor::new(&self.fanout.codec.mime_type, keys));
+        // Taken once the viewer's first keyframe is on its way
+        let mut startup = self.fanout.startup.clone();
+        let mut report_first_frame = move |pkt: &Packet, cached_gop: bool| {
+            if startup.is_some()
+                && codec.is_none_or(|codec| codec.starts_keyframe(&pkt.payload))
+                && let Some(startup) = startup.take()
+            {
+                startup.viewer(ViewerStartup {
+                    first_frame_ms: created.elapsed().as_millis() as u64,
+                    cached_gop,
+                });
+            }
+        };
+        *writer = Some(tokio::spawn(async move {
+            for pkt in &backlog {
+                report_first_frame(pkt, true);
+                match encryptor.as_mut() {
+                    Some(encryptor) => {
+                        for pkt in encryptor.push(pkt) {
+                            write(&track, &id, &pkt).await;
+                        }
+                    }
+                    None => {
+                        write(&track, &id, pkt).await;
+                    }
+                }
+            }
+            // Reused for every packet, rather than allocated per packet
+            let mut batch = Vec::new();
+            'live: while feed.next(&id, codec, &mut batch).await {
+                let slow = match pace.level() {
+                    PaceLevel::Normal => false,
+                    PaceLevel::KeyframesOnly => true,
+                    PaceLevel::Evicted => {
+                        warn!("🐢 Viewer of {} track can't keep up, evicting it", id);
+                        if let Some(on_evicted) = &on_evicted {
+                            on_evicted();
+                        }
+                        break 'live;
+                    }
+                };
+                for pkt in &mut batch {
+                    if let Some(codec) = codec
+                        && !keyframes_only.pass(pkt, codec, slow)
+                    {
+                        continue;
+                    }
+                    let pkt = &*pkt;
+                    report_first_frame(pkt, false);
+                    match encryptor.as_mut() {
+                        Some(encryptor) => {
+                            for pkt in encryptor.push(pkt) {
+                                if !write(&track, &id, &pkt).await {
+                                    break 'live;
+                                }
+                            }
+                        }
+                        None => {
+                            if !write(&track, &id, pkt).await {
+                                break 'live;
+                            }
+                        }
+                    }
+                }
+            }
+        }));
+    }
+}
+
+/// Where a viewer's writer takes its live packets from.
+struct Feed {
+    packets: broadcast::Receiver<Packet>,
+    switch: Option<Switch>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PaceLevel {
+    Normal,
+    KeyframesOnly,
+    Evicted,
+}
+
+/// How well a viewer keeps up with a track, from the packet loss its RTCP
+/// receiver reports show. A writer skipping ahead only means the gateway fell
+/// behind, not the viewer's link.
+#[derive(Debug)]
+pub struct Pace(Mutex<PaceState>);
+
+#[derive(Debug)]
+struct PaceState {
+    level: PaceLevel,
+    // Lossy reports since `window_start`
+    lossy: u32,
+    window_start: Instant,
+    last_lossy: Instant,
+}
+
+impl Pace {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self(Mutex::new(PaceState {
+            level: PaceLevel::Normal,
+            lossy: 0,
+            window_start: now,
+            last_lossy: now,
+        }))
+    }
+
+    /// Takes a receiver report's fraction lost, in 256ths; too many lossy
+    /// reports in one window make the viewer a step slower.
+    pub fn report(&self, fraction_lost: u8) {
+        if fraction_lost < SLOW_VIEWER_LOSS {
+            return;
+        }
+        let mut state = self.0.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(state.window_start) > SLOW_VIEWER_WINDOW {
+            state.lossy = 0;
+            state.window_start = now;
+        }
+        state.lossy += 1;
+        state.last_lossy = now;
+        if state.lossy >= SLOW_VIEWER_REPORTS {
+            state.level = match state.level {
+                PaceLevel::Normal => PaceLevel::KeyframesOnly,
+                PaceLevel::KeyframesOnly | PaceLevel::Evicted => PaceLevel::Evicted,
+            };
+            state.lossy = 0;
+            state.window_start = now;
+        }
+    }
+
+    fn level(&self) -> PaceLevel {
+        let mut state = self.0.lock().unwrap();
+        if state.level == PaceLevel::KeyframesOnly
+            && state.last_lossy.elapsed() >= SLOW_VIEWER_RECOVERY
+        {
+            state.level = PaceLevel::Normal;
+        }
+        state.level
+    }
+}
+
+/// Cuts a slow viewer's video down to keyframes, numbering what it lets
+/// through without gaps so the viewer doesn't ask for the rest. Going back to
+/// everything waits for a keyframe, as the frames in between are missing.
+#[derive(Default)]
+struct KeyframesOnly {
+    filtering: bool,
+    // Timestamp of the keyframe being let through
+    keyframe: Option<u32>,
+    skipped: u16,
+}
+
+impl KeyframesOnly {
+    /// Whether `pkt` goes out, renumbered in place.
+    fn pass(&mut self, pkt: &mut Packet, codec: Codec, slow: bool) -> bool {
+        let starts_keyframe = codec.starts_keyframe_or_parameter_set(&pkt.payload);
+        if slow && !self.filtering {
+            debug!("Slow viewer gets keyframes only");
+            self.filtering = true;
+        } else if !slow && self.filtering && starts_keyframe {
+            debug!("Slow viewer caught up, sending all frames again");
+            self.filtering = false;
+        }
+        if self.filtering {
+            if starts_keyframe {
+                self.keyframe = Some(pkt.header.timestamp);
+            }
+            if self.keyframe != Some(pkt.header.timestamp) {
+                self.skipped = self.skipped.wrapping_add(1);
+                return false;
+            }
+        }
+        pkt.header.sequence_number = pkt.header.sequence_number.wrapping_sub(self.skipped);
+        true
+    }
+}
+
+/// A switchable viewer's two tracks, and the one it waits to move to.
+struct Switch {
+    tracks: [Arc<FanoutTrack>; 2],
+    // Index of the track `Feed::packets` comes from
+    live: usize,
+    // `None` once nothing selects the track anymore
+    selected: Option<watch::Receiver<bool>>,
+    pending: Option<broadcast::Receiver<Packet>>,
+    // The two tracks' timelines differ, so the viewer's gets its own
+    restamper: Restamper,
+}
+
+impl Feed {
+    /// Replaces `batch` with the next packets to write; `false` once the
+    /// track is gone.
+    async fn next(&mut self, id: &str, codec: Option<Codec>, batch: &mut Vec<Packet>) -> bool {
+        let Feed { packets, switch } = self;
+        batch.clear();
+        loop {
+            let Some(switch) = switch.as_mut() else {
+                match packets.recv().await {
+                    Ok(pkt) => {
+                        batch.push(pkt);
+                        return true;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        debug!("{} track fell behind, skipped {} packets", id, skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return false,
+                }
+            };
+            tokio::select! {
+                received = packets.recv() => match received {
+                    Ok(mut pkt) => {
+                        switch.restamper.restamp(&mut pkt);
+                        batch.push(pkt);
+                        return true;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        debug!("{} track fell behind, skipped {} packets", id, skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return false,
+                },
+                changed = changed(&mut switch.selected) => {
+                    match changed {
+                        Some(alternate) => {
+                            let wanted = alternate as usize;
+                            switch.pending =
+                                (wanted != switch.live).then(|| switch.tracks[wanted].listen());
+                        }
+                        None => switch.selected = None,
+                    }
+                }
+                received = recv(&mut switch.pending) => match received {
+                    // Decoding can only pick up the other track at a keyframe
+                    Ok(pkt) if codec.is_none_or(|codec| {
+                        codec.starts_keyframe_or_parameter_set(&pkt.payload)
+                    }) => {
+                        switch.cut_over(packets, pkt, codec, batch);
+                        return true;
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => switch.pending = None,
+                },
+            }
+        }
+    }
+}
+
+impl Switch {
+    /// Makes the pending track the live one, filling the empty `batch` from
+    /// `pkt` on; keyframes are preceded by the track's parameter sets, in case
+    /// it only sends them now and then.
+    fn cut_over(
+        &mut self,
+        packets: &mut broadcast::Receiver<Packet>,
+        pkt: Packet,
+        codec: Option<Codec>,
+        batch: &mut Vec<Packet>,
+    ) {
+        if let Some(pending) = self.pending.take() {
+            *packets = pending;
+            self.live = 1 - self.live;
+        }
+        if codec.is_some_and(|codec| codec.starts_keyframe(&pkt.payload)) {
+            let forwarding = self.tracks[self.live].forwarding.lock().unwrap();
+            if let Some(gop) = &forwarding.gop {
+                batch.extend(gop.parameter_sets());
+            }
+        }
+        // Numbered to end right before the keyframe, on its timestamp
+        let first = pkt.header.sequence_number.wrapping_sub(batch.len() as u16);
+        for (offset, set) in batch.iter_mut().enumerate() {
+            set.header.sequence_number = first.wrapping_add(offset as u16);
+            set.header.timestamp = pkt.header.timestamp;
+            set.header.ssrc = pkt.header.ssrc;
+            set.header.marker = false;
+        }
+        batch.push(pkt);
+
+        self.restamper.rebase();
+        for pkt in batch {
+            self.restamper.restamp(pkt);
+        }
+    }
+}
+
+// The new selection, or `None` once the selecting side is gone; never
+// resolves without one
+async fn changed(selected: &mut Option<watch::Receiver<bool>>) -> Option<bool> {
+    match selected {
+        Some(selected) => match selected.changed().await {
+            Ok(()) => Some(*selected.borrow_and_update()),
+            Err(_) => None,
+        },
+        None => std::future::pending().await,
+    }
+}
+
+// Never resolves without a receiver
+async fn recv(
+    receiver: &mut Option<broadcast::Receiver<Packet>>,
+) -> Result<Packet, broadcast::error::RecvError> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// `false` once the viewer's connection is gone
+async fn write(track: &TrackLocalStaticRTP, id: &str, pkt: &Packet) -> bool {
+    match track.write_rtp(pkt).await {
+        Ok(_) => true,
+        Err(err) if WebRTCError::ErrClosedPipe != err => {
+            trace!("{} track write error: {}", id, err);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            writer.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn lossy_reports_step_a_viewer_down() {
+        let pace = Pace::new();
+        for _ in 0..10 {
+            pace.report(SLOW_VIEWER_LOSS - 1);
+        }
+        assert_eq!(pace.level(), PaceLevel::Normal);
+
+        for _ in 0..SLOW_VIEWER_REPORTS {
+            pace.report(SLOW_VIEWER_LOSS);
+        }
+        assert_eq!(pace.level(), PaceLevel::KeyframesOnly);
+
+        for _ in 0..SLOW_VIEWER_REPORTS {
+            pace.report(u8::MAX);
+        }
+        assert_eq!(pace.level(), PaceLevel::Evicted);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lossy_reports_far_apart_are_forgiven() {
+        let pace = Pace::new();
+        for _ in 0..SLOW_VIEWER_REPORTS * 2 {
+            pace.report(u8::MAX);
+            tokio::time::advance(SLOW_VIEWER_WINDOW).await;
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+        assert_eq!(pace.level(), PaceLevel::Normal);
+    }
+
+    #[test]
+    fn keyframes_only_renumbers_in_place() {
+        let packet = |sequence_number, timestamp, nal: u8| Packet {
+            header: webrtc::rtp::header::Header {
+                sequence_number,
+                timestamp,
+                ..Default::default()
+            },
+            payload: vec![nal, 0].into(),
+        };
+        let mut filter = KeyframesOnly::default();
+        let mut sent = Vec::new();
+        for (slow, mut pkt) in [
+            (true, packet(10, 0, 0x65)),
+            (true, packet(11, 3000, 0x41)),
+            (true, packet(12, 6000, 0x41)),
+            (true, packet(13, 9000, 0x65)),
+            (false, packet(14, 12000, 0x41)),
+            (false, packet(15, 15000, 0x65)),
+        ] {
+            if filter.pass(&mut pkt, Codec::H264, slow) {
+                sent.push(pkt.header.sequence_number);
+            }
+        }
+        // Numbered without gaps, and back to everything only from the next
+        // keyframe on
+        assert_eq!(sent, [10, 11, 12]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clear_reports_bring_all_frames_back() {
+        let pace = Pace::new();
+        for _ in 0..SLOW_VIEWER_REPORTS {
+            pace.report(u8::MAX);
+        }
+        assert_eq!(pace.level(), PaceLevel::KeyframesOnly);
+
+        tokio::time::advance(SLOW_VIEWER_RECOVERY).await;
+        pace.report(0);
+        assert_eq!(pace.level(), PaceLevel::Normal);
+    }
+}
@@ -0,0 +1,857 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use axum::http::{HeaderValue, header};
+use axum_server::{Handle, tls_rustls::RustlsConfig};
+use clap::Parser;
+use tower_http::{
+    cors::{Any, CorsLayer},
+    set_header::SetResponseHeaderLayer,
+    trace::TraceLayer,
+};
+use tracing::{error, info, warn};
+use webrtc::{
+    api::{
+        API, APIBuilder,
+        interceptor_registry::{configure_rtcp_reports, configure_twcc_receiver_only},
+        media_engine::MediaEngine,
+        setting_engine::SettingEngine,
+    },
+    ice::{udp_mux::UDPMuxDefault, udp_network::UDPNetwork},
+    ice_transport::ice_server::RTCIceServer,
+    interceptor::registry::Registry,
+    rtp_transceiver::rtp_codec::{RTCRtpHeaderExtensionCapability, RTPCodecType},
+    sdp::extmap::{SDES_MID_URI, SDES_RTP_STREAM_ID_URI},
+};
+
+use crate::{
+    abr::{self, Estimates},
+    acme::{self, AcmeError, AcmeOptions, Challenges},
+    alerts::{Notifier, WebhookNotifier, spawn_alerts},
+    api::{
+        add_source, catalog, delete_session, delete_source, disable_source, enable_source,
+        get_session, list_sessions, list_sources, metrics, source_metadata, source_pairing,
+        source_startup, stats_snapshot, viewer_count,
+    },
+    assets,
+    auth::{Auth, Role, require_role},
+    callback::AuthCallback,
+    candidates::CandidatePreference,
+    chaos::inject_fault,
+    cli::{Source, SourceSpec},
+    codec::{register_h265, register_rtx},
+    compat::{go2rtc_streams, go2rtc_webrtc, mediamtx_paths, mediamtx_whep},
+    errors::{REQUEST_ID, api_not_found, error_envelope, request_id},
+    health::{healthz, readyz},
+    hls::hls_file,
+    ingest,
+    jwt::JwtValidator,
+    mqtt::{Discovery, spawn_mqtt},
+    net::{bind_udp_mux, interface_addr},
+    onvif::{self, OnvifError},
+    persist::{EndedSessions, PersistentSessionStore},
+    record::{delete_recording, list_recordings, recording_file},
+    rtx,
+    security::{SecurityHeaders, SecurityHeadersError},
+    shutdown,
+    sse::{whep_sse, whep_sse_subscribe},
+    state::{AppState, InterfaceApis},
+    stats::spawn_snapshot_writer,
+    timeline, tls,
+    tokens::TokenFile,
+    tsdb::{StatsSink, spawn_stats_sink},
+    watchdog::{spawn_connect_reaper, spawn_session_reaper},
+    whep::{
+        whep_delete, whep_keepalive, whep_key, whep_offer, whep_patch, whep_resources,
+        whep_stream_offer,
+    },
+    whip::{whip_delete, whip_offer},
+    ws::ws_signaling,
+};
+
+// In-flight HTTPS requests get this long to finish on shutdown
+const GRACEFUL_SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum GatewayError {
+    #[error("failed to start source '{name}': {error:#}")]
+    Source { name: String, error: anyhow::Error },
+    #[error("failed to look up ONVIF camera '{host}': {error}")]
+    Onvif { host: String, error: OnvifError },
+    #[error("failed to set up WebRTC: {0}")]
+    WebRtc(#[from] webrtc::Error),
+    #[error("failed to bind the ICE UDP port: {0}")]
+    IceSocket(std::io::Error),
+    #[error(transparent)]
+    SecurityHeaders(#[from] SecurityHeadersError),
+    #[error("failed to create a self-signed certificate: {0:#}")]
+    SelfSigned(anyhow::Error),
+    #[error("failed to get a certificate: {0}")]
+    Acme(#[from] AcmeError),
+    #[error("failed to load TLS certificate {} / key {}: {error}", cert.display(), key.display())]
+    Tls {
+        cert: PathBuf,
+        key: PathBuf,
+        error: std::io::Error,
+    },
+    #[error("failed to listen on {addr}: {error}")]
+    Listen {
+        addr: SocketAddr,
+        error: std::io::Error,
+    },
+    #[error("HTTP server failed: {0}")]
+    Serve(std::io::Error),
+}
+
+/// Sets up a [`Gateway`]. Options start out as the command line's defaults.
+pub struct GatewayBuilder {
+    options: Source,
+}
+
+impl GatewayBuilder {
+    /// Replaces all options, e.g. with ones parsed from a command line.
+    pub fn options(mut self, options: Source) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Adds a camera, as `--source` would.
+    pub fn source(mut self, spec: SourceSpec) -> Self {
+        self.options.source.push(spec);
+        self
+    }
+
+    /// Address [`Gateway::serve`] listens on.
+    pub fn listen(mut self, addr: SocketAddr) -> Self {
+        self.options.listen = addr;
+        self.options.port = None;
+        self
+    }
+
+    /// Connects to the cameras, highest `priority=` first, and sets up the
+    /// routes and background tasks.
+    pub async fn build(self) -> Result<Gateway, GatewayError> {
+        Gateway::new(self.options).await
+    }
+}
+
+/// The RTSP to WebRTC pipeline and its HTTP API, for embedding in other
+/// applications. Either [`serve`](Self::serve) it, or merge its
+/// [`router`](Self::router) (or just the [`whep_router`](Self::whep_router))
+/// into an existing axum application.
+pub struct Gateway {
+    state: AppState,
+    options: Source,
+    whep: axum::Router,
+    app: axum::Router,
+}
+
+impl Gateway {
+    pub fn builder() -> GatewayBuilder {
+        GatewayBuilder {
+            options: Source::parse_from([env!("CARGO_PKG_NAME")]),
+        }
+    }
+
+    async fn new(source: Source) -> Result<Self, GatewayError> {
+        info!("Starting RTSP to WebRTC server");
+
+        let mut streams = Vec::new();
+        let mut specs = source.sources();
+        for camera in &source.onvif {
+            specs.push(
+                onvif::resolve(camera)
+                    .await
+                    .map_err(|error| GatewayError::Onvif {
+                        host: camera.host.clone(),
+                        error,
+                    })?,
+            );
+        }
+        // The first configured source is the default one, whenever it comes up
+        let default_stream = specs.first().map(|spec| spec.name.clone());
+        let background = if source.background_startup {
+            std::mem::take(&mut specs)
+        } else {
+            Vec::new()
+        };
+        let mut specs: Vec<_> = specs.into_iter().enumerate().collect();
+        // Stable, so sources of equal priority keep their configured order
+        specs.sort_by_key(|(_, spec)| std::cmp::Reverse(spec.priority));
+        let mut started = Vec::new();
+        for (index, spec) in specs {
+            match ingest::start_at_boot(&spec, &source).await {
+                Ok(stream) => {
+                    streams.push((index, stream));
+                    started.push(spec);
+                }
+                Err(error) => {
+                    return Err(GatewayError::Source {
+                        name: spec.name,
+                        error,
+                    });
+                }
+            }
+        }
+        // The first configured source stays the default one
+        streams.sort_by_key(|(index, _)| *index);
+        let streams: Vec<_> = streams.into_iter().map(|(_, stream)| stream).collect();
+
+        if streams.is_empty() && background.is_empty() {
+            info!("No sources configured, add them through POST /api/sources");
+        }
+
+        let bandwidth = Arc::new(Estimates::default());
+        // Multiplex ICE over one socket we own when a fixed port or DSCP
+        // marking is wanted, shared by the APIs of all interfaces
+        let mux = if source.ice_udp_port.is_some() || source.dscp.is_some() {
+            let port = source.ice_udp_port.unwrap_or(0);
+            Some(bind_udp_mux(source.ice_ip, port, source.dscp).map_err(GatewayError::IceSocket)?)
+        } else {
+            None
+        };
+        let mut app_state = AppState::new(
+            webrtc_api(&source, &bandwidth, mux.clone(), None)?,
+            streams,
+            source.clone(),
+        );
+        app_state.interface_apis = Arc::new(InterfaceApis::new({
+            let (source, bandwidth) = (source.clone(), bandwidth.clone());
+            Box::new(move |interface| {
+                info!(
+                    "🔌 Gathering ICE candidates on {} for the sources with that interface=",
+                    interface
+                );
+                // The shared socket takes packets on every address, so media
+                // would flow on any interface: theirs gets a socket of its own
+                // on the same port
+                let mux = match &mux {
+                    Some(_) => {
+                        let ip =
+                            interface_addr(interface).map_err(|e| webrtc::Error::Util(e.into()))?;
+                        let port = source.ice_udp_port.unwrap_or(0);
+                        Some(
+                            bind_udp_mux(Some(ip), port, source.dscp)
+                                .map_err(|e| webrtc::Error::Util(e.into()))?,
+                        )
+                    }
+                    None => None,
+                };
+                webrtc_api(&source, &bandwidth, mux, Some(interface))
+            })
+        }));
+        if let Some(name) = default_stream {
+            app_state.default_stream = name;
+        }
+        app_state.bandwidth = bandwidth;
+        app_state.log_sdp = source.log_sdp;
+        app_state.ice_servers = Arc::new(
+            source
+                .ice_server
+                .iter()
+                .map(|server| RTCIceServer {
+                    urls: server.urls.clone(),
+                    username: server.username.clone(),
+                    credential: server.credential.clone(),
+                })
+                .collect(),
+        );
+        app_state.candidate_preference = Arc::new(CandidatePreference {
+            types: source.candidate_type_preference.clone(),
+            family: source.ip_family_preference,
+        });
+        if let Some(dir) = source.session_log_dir.clone() {
+            match timeline::FileTimelineSink::new(dir) {
+                Ok(sink) => app_state.timelines = Some(Arc::new(sink)),
+                Err(e) => warn!("Cannot write session timelines: {}", e),
+            }
+        }
+        if let Some(path) = source.session_state_file.clone() {
+            // Read what the last run left before the new store overwrites it
+            app_state.ended_sessions = Arc::new(EndedSessions::load(&path));
+            app_state.sessions = Arc::new(PersistentSessionStore::new(
+                path,
+                app_state.shutting_down.clone(),
+            ));
+        }
+        // After the state is complete: these keep clones of it
+        for spec in started {
+            ingest::spawn_rebuilder(&app_state, spec);
+        }
+        spawn_background_startup(&app_state, background);
+        spawn_session_reaper(
+            &app_state.sessions,
+            std::time::Duration::from_secs(source.session_keepalive),
+        );
+        spawn_connect_reaper(
+            &[app_state.sessions.clone(), app_state.publishers.clone()],
+            std::time::Duration::from_secs(source.connect_timeout),
+        );
+        let mut notifiers: Vec<Arc<dyn Notifier>> = source
+            .alert_webhook
+            .iter()
+            .map(|url| Arc::new(WebhookNotifier::new(url.clone())) as Arc<dyn Notifier>)
+            .collect();
+        if let Some(url) = source.mqtt_url.clone() {
+            notifiers.push(Arc::new(spawn_mqtt(
+                app_state.clone(),
+                url,
+                source.mqtt_topic_prefix.clone(),
+                source
+                    .mqtt_discovery_prefix
+                    .clone()
+                    .zip(source.public_url.clone())
+                    .map(|(prefix, public_url)| Discovery { prefix, public_url }),
+            )));
+        }
+        spawn_alerts(
+            app_state.clone(),
+            source.alert.clone(),
+            std::time::Duration::from_secs(source.alert_interval),
+            notifiers,
+        );
+        if let Some(dir) = source.stats_snapshot_dir.clone() {
+            spawn_snapshot_writer(
+                app_state.clone(),
+                dir,
+                std::time::Duration::from_secs(source.stats_snapshot_interval),
+            );
+        }
+        if let Some(url) = source.stats_sink.clone() {
+            spawn_stats_sink(
+                app_state.clone(),
+                StatsSink {
+                    url,
+                    token: source.stats_sink_token.clone(),
+                    interval: std::time::Duration::from_secs(source.stats_sink_interval),
+                },
+            );
+        }
+
+        // Configure CORS to allow requests from any origin
+        let cors = CorsLayer::new()
+            .allow_origin(Any)
+            .allow_methods(Any)
+            .allow_headers(Any);
+
+        // Each route group requires a minimum token role
+        let jwt = source.jwt_issuer.clone().map(|issuer| {
+            JwtValidator::new(
+                issuer,
+                source.jwt_jwks_url.clone(),
+                source.jwt_audience.clone(),
+                source.jwt_source_claim.clone(),
+            )
+        });
+        let auth = Arc::new(Auth::new(
+            source.api_token.clone(),
+            jwt,
+            source.auth_callback.clone().map(AuthCallback::new),
+            Some(app_state.pairing.clone()),
+            source.token_file.clone().map(TokenFile::new),
+        ));
+        let default_admins = source
+            .api_token
+            .iter()
+            .filter(|token| token.default_role)
+            .count();
+        if default_admins > 0 {
+            warn!(
+                "⚠️ {} --api-token value(s) without a role are admins; prefix them with `admin:` to make that explicit",
+                default_admins
+            );
+        }
+        if !auth.is_enabled() {
+            warn!(
+                "⚠️ No --api-token, --jwt-issuer or --auth-callback set, anyone can watch and manage sources"
+            );
+        }
+        let whep_routes = axum::Router::new()
+            .route("/whep", axum::routing::post(whep_offer))
+            .route("/whep/{stream}", axum::routing::post(whep_stream_offer))
+            .route("/whep/resources", axum::routing::get(whep_resources))
+            .route("/api/catalog", axum::routing::get(catalog))
+            .route("/hls/{stream}/{file}", axum::routing::get(hls_file))
+            .route(
+                "/whep/resource/{id}",
+                axum::routing::delete(whep_delete)
+                    .patch(whep_patch)
+                    .head(whep_keepalive),
+            )
+            .route("/whep/resource/{id}/key", axum::routing::put(whep_key))
+            .route(
+                "/whep/resource/{id}/sse",
+                axum::routing::post(whep_sse_subscribe).get(whep_sse),
+            )
+            .route("/ws", axum::routing::get(ws_signaling));
+        #[cfg(feature = "snapshot")]
+        let whep_routes = whep_routes.route(
+            "/snapshot/{stream}",
+            axum::routing::get(crate::snapshot::snapshot),
+        );
+        let whep_routes = whep_routes
+            .route_layer(axum::middleware::from_fn_with_state(
+                (auth.clone(), Role::Viewer),
+                require_role,
+            ))
+            .layer(axum::middleware::from_fn(error_envelope));
+        let mut admin_routes = axum::Router::new()
+            .route("/whip/{stream}", axum::routing::post(whip_offer))
+            .route("/whip/resource/{id}", axum::routing::delete(whip_delete))
+            .route("/api/sources", axum::routing::post(add_source))
+            .route("/api/sources/{name}", axum::routing::delete(delete_source))
+            .route(
+                "/api/sources/{name}/disable",
+                axum::routing::post(disable_source),
+            )
+            .route(
+                "/api/sources/{name}/enable",
+                axum::routing::post(enable_source),
+            )
+            .route("/api/sessions/{id}", axum::routing::delete(delete_session));
+        if source.chaos {
+            warn!("💥 Fault injection enabled at POST /api/sources/{{name}}/faults");
+            admin_routes = admin_routes.route(
+                "/api/sources/{name}/faults",
+                axum::routing::post(inject_fault),
+            );
+        }
+        let admin_routes = admin_routes
+            .route_layer(axum::middleware::from_fn_with_state(
+                (auth.clone(), Role::Admin),
+                require_role,
+            ))
+            .layer(axum::middleware::from_fn(error_envelope));
+        let api_routes = axum::Router::new()
+            .route("/api/sources", axum::routing::get(list_sources))
+            .route(
+                "/api/sources/{name}/metadata",
+                axum::routing::get(source_metadata),
+            )
+            .route(
+                "/api/sources/{name}/startup",
+                axum::routing::get(source_startup),
+            )
+            .route(
+                "/api/sources/{name}/pairing",
+                axum::routing::get(source_pairing),
+            )
+            .route("/api/sessions", axum::routing::get(list_sessions))
+            .route("/api/sessions/{id}", axum::routing::get(get_session))
+            .route("/api/stats/snapshot", axum::routing::get(stats_snapshot))
+            .route("/api/viewers", axum::routing::get(viewer_count))
+            .route("/metrics", axum::routing::get(metrics))
+            .route_layer(axum::middleware::from_fn_with_state(
+                (auth.clone(), Role::Operator),
+                require_role,
+            ))
+            .route("/api/{*path}", axum::routing::any(api_not_found))
+            .layer(axum::middleware::from_fn(error_envelope));
+        let compat_routes = if source.compat_api {
+            axum::Router::new()
+                .route("/api/webrtc", axum::routing::post(go2rtc_webrtc))
+                .route("/{stream}/whep", axum::routing::post(mediamtx_whep))
+                .route_layer(axum::middleware::from_fn_with_state(
+                    (auth.clone(), Role::Viewer),
+                    require_role,
+                ))
+                .merge(
+                    axum::Router::new()
+                        .route("/api/streams", axum::routing::get(go2rtc_streams))
+                        .route("/v3/paths/list", axum::routing::get(mediamtx_paths))
+                        .route_layer(axum::middleware::from_fn_with_state(
+                            (auth.clone(), Role::Operator),
+                            require_role,
+                        )),
+                )
+                .layer(axum::middleware::from_fn(error_envelope))
+        } else {
+            axum::Router::new()
+        };
+
+        // Recordings are past footage: operators may watch and fetch them,
+        // only admins delete them
+        let recording_routes = if source.record_dir.is_some() {
+            axum::Router::new()
+                .route(
+                    "/api/recordings/{stream}",
+                    axum::routing::get(list_recordings),
+                )
+                .route(
+                    "/recordings/{stream}/{file}",
+                    axum::routing::get(recording_file),
+                )
+                .route_layer(axum::middleware::from_fn_with_state(
+                    (auth.clone(), Role::Operator),
+                    require_role,
+                ))
+                .merge(
+                    axum::Router::new()
+                        .route(
+                            "/recordings/{stream}/{file}",
+                            axum::routing::delete(delete_recording),
+                        )
+                        .route_layer(axum::middleware::from_fn_with_state(
+                            (auth.clone(), Role::Admin),
+                            require_role,
+                        )),
+                )
+                .layer(axum::middleware::from_fn(error_envelope))
+        } else {
+            axum::Router::new()
+        };
+
+        // Security headers only apply to the player and its assets
+        let security_headers = SecurityHeaders::new(
+            source.csp.as_deref(),
+            source.frame_ancestors.as_deref(),
+            &source.referrer_policy,
+        )?;
+        let static_max_age = source.static_max_age;
+        let static_files = (!source.no_static).then(|| {
+            tower::ServiceBuilder::new()
+                .layer(SetResponseHeaderLayer::overriding(
+                    header::CONTENT_SECURITY_POLICY,
+                    security_headers.content_security_policy,
+                ))
+                .layer(SetResponseHeaderLayer::overriding(
+                    header::X_FRAME_OPTIONS,
+                    security_headers.x_frame_options,
+                ))
+                .layer(SetResponseHeaderLayer::overriding(
+                    header::REFERRER_POLICY,
+                    security_headers.referrer_policy,
+                ))
+                .layer(SetResponseHeaderLayer::overriding(
+                    header::X_CONTENT_TYPE_OPTIONS,
+                    HeaderValue::from_static("nosniff"),
+                ))
+                .layer(SetResponseHeaderLayer::overriding(
+                    header::CACHE_CONTROL,
+                    move |response: &axum::http::Response<_>| {
+                        assets::cache_control(response, static_max_age)
+                    },
+                ))
+                // Caches must keep compressed and plain variants apart
+                .layer(SetResponseHeaderLayer::appending(
+                    header::VARY,
+                    HeaderValue::from_static("accept-encoding"),
+                ))
+                // `.br` / `.gz` files next to an asset are sent to clients that
+                // accept them; ranges are served either way. Unknown paths get
+                // the player's own `404.html`, if it has one
+                .service(
+                    tower_http::services::ServeDir::new(&source.static_dir)
+                        .precompressed_br()
+                        .precompressed_gzip()
+                        .not_found_service(tower_http::services::ServeFile::new(
+                            source.static_dir.join("404.html"),
+                        )),
+                )
+        });
+
+        // Probes of Docker, Kubernetes and load balancers carry no token
+        let health_routes = axum::Router::new()
+            .route("/healthz", axum::routing::get(healthz))
+            .route("/readyz", axum::routing::get(readyz));
+
+        let whep = whep_routes.clone().with_state(app_state.clone());
+        let mut app = axum::Router::new()
+            .merge(health_routes)
+            .merge(whep_routes)
+            .merge(admin_routes)
+            .merge(api_routes)
+            .merge(recording_routes)
+            .merge(compat_routes);
+        if let Some(static_files) = static_files {
+            app = app.fallback_service(static_files);
+        }
+        let app = app
+            .layer(
+                TraceLayer::new_for_http()
+                    .make_span_with(|request: &axum::http::Request<_>| {
+                        tracing::info_span!(
+                            "http_request",
+                            method = %request.method(),
+                            // The query may carry an `access_token`
+                            path = %request.uri().path(),
+                            version = ?request.version(),
+                            request_id = %request
+                                .headers()
+                                .get(&REQUEST_ID)
+                                .and_then(|id| id.to_str().ok())
+                                .unwrap_or_default(),
+                        )
+                    })
+                    .on_response(
+                        |response: &axum::http::Response<_>,
+                         latency: std::time::Duration,
+                         _span: &tracing::Span| {
+                            tracing::info!(
+                                status = %response.status(),
+                                latency_ms = %latency.as_millis(),
+                                "response"
+                            );
+                        },
+                    ),
+            )
+            .layer(axum::middleware::from_fn(request_id))
+            .layer(cors)
+            .with_state(app_state.clone());
+
+        Ok(Self {
+            state: app_state,
+            options: source,
+            whep,
+            app,
+        })
+    }
+
+    /// Every route the binary serves: WHEP/WHIP, the JSON API, `/metrics`,
+    /// health probes and (unless `--no-static`) the web player, with request
+    /// tracing and CORS.
+    pub fn router(&self) -> axum::Router {
+        self.app.clone()
+    }
+
+    /// Only the viewer endpoints (`/whep`, `/whep/{stream}`,
+    /// `/whep/resource/{id}`, `/api/catalog`, `/hls/...`, `/snapshot/...`),
+    /// behind the gateway's token checks, for applications that bring their
+    /// own API.
+    pub fn whep_router(&self) -> axum::Router {
+        self.whep.clone()
+    }
+
+    /// Hangs up on every viewer and publisher and stops the cameras; for
+    /// applications serving [`router`](Self::router) themselves.
+    pub async fn shutdown(&self) {
+        shutdown::close_all(&self.state).await;
+    }
+
+    /// Serves [`router`](Self::router) over HTTP or HTTPS, as the options
+    /// say, until Ctrl-C or SIGTERM, then shuts down gracefully.
+    pub async fn serve(self) -> Result<(), GatewayError> {
+        let addr = self.options.listen_addr();
+        // On Ctrl-C / SIGTERM, hang up on peers and tear the cameras down
+        // before the HTTP server stops
+        let shutdown = {
+            let state = self.state.clone();
+            async move {
+                shutdown::signal().await;
+                info!("🛑 Shutting down");
+                shutdown::close_all(&state).await;
+            }
+        };
+
+        let source = self.options;
+        if source.tls() {
+            let _ = rustls::crypto::ring::default_provider().install_default();
+        }
+        // ACME's HTTP-01 challenges are answered by the redirect server, which
+        // has to run before the certificate can be issued
+        let challenges = Arc::new(Challenges::default());
+        if let Some(redirect) = source.http_redirect.filter(|_| source.tls()) {
+            let challenges = challenges.clone();
+            tokio::spawn(async move {
+                if let Err(e) = tls::serve_redirect(redirect, addr.port(), challenges).await {
+                    error!("HTTP redirect server on {} failed: {}", redirect, e);
+                }
+            });
+        }
+        let acme = (!source.acme_domain.is_empty()).then(|| AcmeOptions {
+            directory: source.acme_directory.clone(),
+            domains: source.acme_domain.clone(),
+            email: source.acme_email.clone(),
+            dir: source.tls_dir.clone(),
+        });
+        let tls_files = match (source.tls_cert, source.tls_key) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            _ if source.tls_self_signed => Some(
+                tls::self_signed(&source.tls_dir, &source.tls_name)
+                    .map_err(GatewayError::SelfSigned)?,
+            ),
+            _ => match &acme {
+                Some(acme) => Some(acme::certificate(acme, &challenges).await?),
+                None => None,
+            },
+        };
+
+        if let Some((cert, key)) = tls_files {
+            let config = match RustlsConfig::from_pem_file(&cert, &key).await {
+                Ok(config) => config,
+                Err(error) => return Err(GatewayError::Tls { cert, key, error }),
+            };
+            if let Some(acme) = acme {
+                acme::spawn_renewal(acme, challenges, config.clone());
+            }
+
+            info!(
+                "🚀 WHEP server started on https://localhost:{}",
+                addr.port()
+            );
+            info!(
+                "📡 POST SDP offers to https://localhost:{}/whep",
+                addr.port()
+            );
+            info!(
+                "🗑️ DELETE sessions at https://localhost:{}/whep/resource/{{id}}",
+                addr.port()
+            );
+
+            let handle = Handle::new();
+            let handle_for_shutdown = handle.clone();
+            tokio::spawn(async move {
+                shutdown.await;
+                handle_for_shutdown.graceful_shutdown(Some(GRACEFUL_SHUTDOWN_TIMEOUT));
+            });
+            axum_server::bind_rustls(addr, config)
+                .handle(handle)
+                .serve(self.app.into_make_service())
+                .await
+                .map_err(GatewayError::Serve)?;
+            info!("👋 Shutdown complete");
+            return Ok(());
+        }
+
+        if source.http_redirect.is_some() {
+            warn!(
+                "--http-redirect needs --tls-cert, --tls-self-signed or --acme-domain, ignoring it"
+            );
+        }
+
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .map_err(|error| GatewayError::Listen { addr, error })?;
+
+        info!("🚀 WHEP server started on http://localhost:{}", addr.port());
+        info!(
+            "📡 POST SDP offers to http://localhost:{}/whep",
+            addr.port()
+        );
+        info!(
+            "🗑️ DELETE sessions at http://localhost:{}/whep/resource/{{id}}",
+            addr.port()
+        );
+
+        axum::serve(listener, self.app)
+            .with_graceful_shutdown(shutdown)
+            .await
+            .map_err(GatewayError::Serve)?;
+        info!("👋 Shutdown complete");
+        Ok(())
+    }
+}
+
+/// Connects to `specs` in the background, with `--background-startup`, each
+/// retried until it is up and then served like a source added at runtime.
+fn spawn_background_startup(state: &AppState, specs: Vec<SourceSpec>) {
+    state
+        .connecting
+        .write()
+        .unwrap()
+        .extend(specs.iter().map(|spec| spec.name.clone()));
+    for spec in specs {
+        let state = state.clone();
+        tokio::spawn(async move {
+            info!("⏳ [{}] Connecting in the background", spec.name);
+            // `0` keeps trying
+            let spec = SourceSpec {
+                attempts: Some(0),
+                ..spec
+            };
+            let stream = ingest::start_at_boot(&spec, &state.options).await;
+            state.connecting.write().unwrap().remove(&spec.name);
+            match stream {
+                Ok(stream) => {
+                    let control = stream.control.clone();
+                    if state.add_stream(stream) {
+                        info!("[{}] Source is up", spec.name);
+                        ingest::spawn_rebuilder(&state, spec);
+                    } else {
+                        warn!(
+                            "[{}] A source of the same name was added meanwhile",
+                            spec.name
+                        );
+                        control.stop.notify_one();
+                    }
+                }
+                Err(e) => error!("[{}] Giving up on the source: {:#}", spec.name, e),
+            }
+        });
+    }
+}
+
+/// The WebRTC API viewers connect through, gathering ICE candidates on
+/// `interface` (a name or an address) if given, else as `--ice-interface` /
+/// `--ice-ip` say.
+fn webrtc_api(
+    source: &Source,
+    bandwidth: &Arc<Estimates>,
+    mux: Option<Arc<UDPMuxDefault>>,
+    interface: Option<&str>,
+) -> Result<API, webrtc::Error> {
+    // Create a MediaEngine object to configure the supported codec
+    let mut m = MediaEngine::default();
+
+    m.register_default_codecs()?;
+    register_h265(&mut m)?;
+    register_rtx(&mut m)?;
+    // Simulcast encodings are told apart by their rid
+    if source.simulcast {
+        for uri in [SDES_MID_URI, SDES_RTP_STREAM_ID_URI] {
+            m.register_header_extension(
+                RTCRtpHeaderExtensionCapability {
+                    uri: uri.to_owned(),
+                },
+                RTPCodecType::Video,
+                None,
+            )?;
+        }
+    }
+
+    // Create a InterceptorRegistry. This is the user configurable RTP/RTCP Pipeline.
+    // This provides NACKs, RTCP Reports and other features. If you use `webrtc.NewPeerConnection`
+    // this is enabled by default. If you are manually managing You MUST create a InterceptorRegistry
+    // for each PeerConnection.
+    let mut registry = Registry::new();
+
+    // The default set of Interceptors, except that NACKs are answered from a
+    // history of our own size, over RTX where the viewer negotiated it
+    registry = rtx::configure_nack(registry, &mut m, source.nack_history);
+    registry = configure_rtcp_reports(registry);
+    registry = configure_twcc_receiver_only(registry, &mut m)?;
+    if source.abr {
+        registry = abr::configure_estimator(registry, bandwidth.clone());
+    }
+
+    let mut s = SettingEngine::default();
+    s.enable_sender_rtx(true);
+    if let Some(mux) = mux {
+        s.set_udp_network(UDPNetwork::Muxed(mux));
+    }
+
+    // Only gather candidates on the configured interface / address
+    let (interface, ip) = match interface {
+        Some(interface) => match interface.parse::<IpAddr>() {
+            Ok(ip) => (None, Some(ip)),
+            Err(_) => (Some(interface.to_owned()), None),
+        },
+        None => (source.ice_interface.clone(), source.ice_ip),
+    };
+    if let Some(interface) = interface {
+        s.set_interface_filter(Box::new(move |name| name == interface));
+    }
+    if let Some(ip) = ip {
+        s.set_ip_filter(Box::new(move |candidate| candidate == ip));
+    }
+
+    // Create the API object with the MediaEngine
+    Ok(APIBuilder::new()
+        .with_media_engine(m)
+        .with_interceptor_registry(registry)
+        .with_setting_engine(s)
+        .build())
+}
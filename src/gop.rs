@@ -0,0 +1,407 @@
+use std::collections::BTreeMap;
+
+use webrtc::rtp::packet::Packet;
+
+// GOPs longer than this are not cached; viewers wait for the next keyframe
+const MAX_GOP_PACKETS: usize = 4096;
+
+/// Video codecs whose keyframes can be found in their RTP payloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    H264,
+    H265,
+}
+
+impl Codec {
+    pub fn from_mime_type(mime_type: &str) -> Option<Self> {
+        let (_, name) = mime_type.split_once('/')?;
+        if name.eq_ignore_ascii_case("h264") {
+            Some(Self::H264)
+        } else if name.eq_ignore_ascii_case("h265") {
+            Some(Self::H265)
+        } else {
+            None
+        }
+    }
+
+    /// Whether a keyframe begins in this RTP payload.
+    pub fn starts_keyframe(self, payload: &[u8]) -> bool {
+        self.nal_starts(payload)
+            .into_iter()
+            .any(|nal_type| self.is_keyframe(nal_type))
+    }
+
+    /// Whether a keyframe, or the parameter sets sent ahead of one, begins in
+    /// this RTP payload.
+    pub fn starts_keyframe_or_parameter_set(self, payload: &[u8]) -> bool {
+        self.nal_starts(payload)
+            .into_iter()
+            .any(|nal_type| self.is_keyframe(nal_type) || self.is_parameter_set(nal_type))
+    }
+
+    /// Whether this RTP payload belongs to a picture later pictures may be
+    /// predicted from, so dropping it breaks decoding until the next keyframe.
+    pub fn is_reference(self, payload: &[u8]) -> bool {
+        match self {
+            // nal_ref_idc, which FU-A and STAP-A headers carry too
+            Self::H264 => payload.first().is_some_and(|header| header & 0x60 != 0),
+            Self::H265 => {
+                let Some(&header) = payload.first() else {
+                    return false;
+                };
+                let nal_types = match (header >> 1) & 0x3f {
+                    48 => aggregated(payload.get(2..).unwrap_or_default(), |nal| {
+                        nal.first().map(|b| (b >> 1) & 0x3f)
+                    }),
+                    // Every fragment names the type, not only the first
+                    49 => payload.get(2).map(|fu| vec![fu & 0x3f]).unwrap_or_default(),
+                    nal_type => vec![nal_type],
+                };
+                // Sub-layer non-reference pictures are the even VCL types up
+                // to RSV_VCL_N14; non-VCL units don't decide
+                nal_types
+                    .into_iter()
+                    .any(|nal_type| nal_type < 32 && (nal_type > 14 || nal_type % 2 == 1))
+            }
+        }
+    }
+
+    fn is_keyframe(self, nal_type: u8) -> bool {
+        match self {
+            // IDR slice
+            Self::H264 => nal_type == 5,
+            // IRAP pictures (BLA, IDR, CRA)
+            Self::H265 => (16..=21).contains(&nal_type),
+        }
+    }
+
+    fn is_parameter_set(self, nal_type: u8) -> bool {
+        match self {
+            // SPS, PPS
+            Self::H264 => matches!(nal_type, 7 | 8),
+            // VPS, SPS, PPS
+            Self::H265 => matches!(nal_type, 32..=34),
+        }
+    }
+
+    /// Types of the NAL units that begin in an RTP payload (RFC 6184 /
+    /// RFC 7798); continuation fragments begin none.
+    fn nal_starts(self, payload: &[u8]) -> Vec<u8> {
+        match self {
+            Self::H264 => {
+                let Some(&header) = payload.first() else {
+                    return Vec::new();
+                };
+                match header & 0x1f {
+                    // STAP-A
+                    24 => aggregated(&payload[1..], |nal| nal.first().map(|b| b & 0x1f)),
+                    // FU-A
+                    28 => match payload.get(1) {
+                        Some(fu) if fu & 0x80 != 0 => vec![fu & 0x1f],
+                        _ => Vec::new(),
+                    },
+                    nal_type => vec![nal_type],
+                }
+            }
+            Self::H265 => {
+                let Some(&header) = payload.first() else {
+                    return Vec::new();
+                };
+                match (header >> 1) & 0x3f {
+                    // Aggregation packet
+                    48 => aggregated(payload.get(2..).unwrap_or_default(), |nal| {
+                        nal.first().map(|b| (b >> 1) & 0x3f)
+                    }),
+                    // Fragmentation unit
+                    49 => match payload.get(2) {
+                        Some(fu) if fu & 0x80 != 0 => vec![fu & 0x3f],
+                        _ => Vec::new(),
+                    },
+                    nal_type => vec![nal_type],
+                }
+            }
+        }
+    }
+}
+
+// Walks the 16-bit length prefixed NAL units of an aggregation packet
+fn aggregated(mut units: &[u8], nal_type: impl Fn(&[u8]) -> Option<u8>) -> Vec<u8> {
+    let mut types = Vec::new();
+    while units.len() > 2 {
+        let len = u16::from_be_bytes([units[0], units[1]]) as usize;
+        let Some(nal) = units.get(2..2 + len) else {
+            break;
+        };
+        types.extend(nal_type(nal));
+        units = &units[2 + len..];
+    }
+    types
+}
+
+/// The packets a viewer needs to start decoding right away: the latest
+/// parameter sets and everything since the most recent keyframe.
+pub struct GopCache {
+    codec: Codec,
+    parameter_sets: BTreeMap<u8, Packet>,
+    gop: Vec<Packet>,
+    has_keyframe: bool,
+    next_sequence: Option<u16>,
+}
+
+impl GopCache {
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            parameter_sets: BTreeMap::new(),
+            gop: Vec::new(),
+            has_keyframe: false,
+            next_sequence: None,
+        }
+    }
+
+    /// Records a packet about to be forwarded to viewers.
+    ///
+    /// A gap in sequence numbers (loss, privacy, an RTSP restart) leaves the
+    /// cached pictures undecodable, so caching starts over at the next keyframe.
+    pub fn push(&mut self, pkt: &Packet) {
+        let sequence = pkt.header.sequence_number;
+        if self.next_sequence.is_some_and(|next| next != sequence) {
+            self.reset();
+        }
+        self.next_sequence = Some(sequence.wrapping_add(1));
+
+        let nal_types = self.codec.nal_starts(&pkt.payload);
+        if !nal_types.is_empty()
+            && nal_types
+                .iter()
+                .all(|&nal_type| self.codec.is_parameter_set(nal_type))
+        {
+            self.parameter_sets.insert(nal_types[0], pkt.clone());
+        }
+
+        let timestamp = pkt.header.timestamp;
+        if nal_types
+            .iter()
+            .any(|&nal_type| self.codec.is_keyframe(nal_type))
+        {
+            // Keep what came earlier in the keyframe's access unit (SPS, SEI...)
+            let access_unit = self
+                .gop
+                .iter()
+                .rposition(|p| p.header.timestamp != timestamp)
+                .map_or(0, |last| last + 1);
+            self.gop.drain(..access_unit);
+            self.has_keyframe = true;
+        } else if !self.has_keyframe {
+            self.gop.retain(|p| p.header.timestamp == timestamp);
+        }
+
+        if self.gop.len() >= MAX_GOP_PACKETS {
+            self.reset();
+            return;
+        }
+        self.gop.push(pkt.clone());
+    }
+
+    pub fn has_keyframe(&self) -> bool {
+        self.has_keyframe
+    }
+
+    /// The latest parameter sets, as sent.
+    pub fn parameter_sets(&self) -> Vec<Packet> {
+        self.parameter_sets.values().cloned().collect()
+    }
+
+    /// The cached packets, renumbered to end right before the next packet
+    /// forwarded, so the viewer's stream continues without a gap.
+    pub fn replay(&self) -> Vec<Packet> {
+        let Some(next_sequence) = self.next_sequence.filter(|_| self.has_keyframe) else {
+            return Vec::new();
+        };
+
+        let mut packets: Vec<Packet> = self
+            .parameter_sets
+            .values()
+            .chain(&self.gop)
+            .cloned()
+            .collect();
+        let first = next_sequence.wrapping_sub(packets.len() as u16);
+        for (offset, pkt) in packets.iter_mut().enumerate() {
+            pkt.header.sequence_number = first.wrapping_add(offset as u16);
+        }
+        packets
+    }
+
+    /// The latest parameter sets and the cached keyframe's access unit, for
+    /// decoding a still of it; empty without a keyframe.
+    #[cfg(feature = "snapshot")]
+    pub fn keyframe(&self) -> Vec<Packet> {
+        let Some(timestamp) = self
+            .gop
+            .first()
+            .filter(|_| self.has_keyframe)
+            .map(|pkt| pkt.header.timestamp)
+        else {
+            return Vec::new();
+        };
+        self.parameter_sets
+            .values()
+            .chain(
+                self.gop
+                    .iter()
+                    .take_while(|pkt| pkt.header.timestamp == timestamp),
+            )
+            .cloned()
+            .collect()
+    }
+
+    /// Drops the cached pictures; caching starts over at the next keyframe.
+    pub fn reset(&mut self) {
+        self.gop.clear();
+        self.has_keyframe = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::rtp::header::Header;
+
+    use super::*;
+
+    fn packet(sequence_number: u16, timestamp: u32, payload: &'static [u8]) -> Packet {
+        Packet {
+            header: Header {
+                sequence_number,
+                timestamp,
+                ..Default::default()
+            },
+            payload: payload.into(),
+        }
+    }
+
+    fn payloads(packets: &[Packet]) -> Vec<&[u8]> {
+        packets.iter().map(|pkt| &pkt.payload[..]).collect()
+    }
+
+    const SPS: &[u8] = &[0x67, 0x42, 0x00, 0x1f];
+    const PPS: &[u8] = &[0x68, 0xce, 0x3c, 0x80];
+    const SEI: &[u8] = &[0x06, 0x05, 0x01];
+    const IDR: &[u8] = &[0x65, 0x88, 0x84];
+    const P: &[u8] = &[0x41, 0x9a, 0x02];
+
+    #[test]
+    fn codec_from_mime_type() {
+        assert_eq!(Codec::from_mime_type("video/H264"), Some(Codec::H264));
+        assert_eq!(Codec::from_mime_type("video/h265"), Some(Codec::H265));
+        assert_eq!(Codec::from_mime_type("video/VP8"), None);
+        assert_eq!(Codec::from_mime_type("h264"), None);
+    }
+
+    #[test]
+    fn finds_h264_keyframes() {
+        assert!(Codec::H264.starts_keyframe(IDR));
+        assert!(!Codec::H264.starts_keyframe(P));
+        assert!(!Codec::H264.starts_keyframe(SPS));
+        assert!(Codec::H264.starts_keyframe_or_parameter_set(SPS));
+        // STAP-A of SPS, PPS and IDR
+        let stap_a = [0x78, 0, 2, 0x67, 0x42, 0, 2, 0x68, 0xce, 0, 2, 0x65, 0x88];
+        assert!(Codec::H264.starts_keyframe(&stap_a));
+        // FU-A start and middle of an IDR slice
+        assert!(Codec::H264.starts_keyframe(&[0x7c, 0x85, 0x00]));
+        assert!(!Codec::H264.starts_keyframe(&[0x7c, 0x05, 0x00]));
+        assert!(!Codec::H264.starts_keyframe(&[]));
+    }
+
+    #[test]
+    fn finds_h265_keyframes() {
+        // IDR_W_RADL, TRAIL_R, VPS
+        assert!(Codec::H265.starts_keyframe(&[0x26, 0x01, 0xaf]));
+        assert!(!Codec::H265.starts_keyframe(&[0x02, 0x01, 0xd0]));
+        assert!(Codec::H265.starts_keyframe_or_parameter_set(&[0x40, 0x01, 0x0c]));
+        // Aggregation packet of VPS and CRA
+        let ap = [0x60, 0x01, 0, 2, 0x40, 0x01, 0, 2, 0x2a, 0x01];
+        assert!(Codec::H265.starts_keyframe(&ap));
+        // Fragmentation unit start and middle of an IDR_W_RADL
+        assert!(Codec::H265.starts_keyframe(&[0x62, 0x01, 0x93, 0x00]));
+        assert!(!Codec::H265.starts_keyframe(&[0x62, 0x01, 0x13, 0x00]));
+    }
+
+    #[test]
+    fn tells_reference_pictures() {
+        assert!(Codec::H264.is_reference(P));
+        assert!(!Codec::H264.is_reference(&[0x01, 0x9e]));
+        // FU-A keeps nal_ref_idc in its indicator
+        assert!(!Codec::H264.is_reference(&[0x1c, 0x01, 0x00]));
+        // TRAIL_N, TRAIL_R, IDR_W_RADL
+        assert!(!Codec::H265.is_reference(&[0x00, 0x01]));
+        assert!(Codec::H265.is_reference(&[0x02, 0x01]));
+        assert!(Codec::H265.is_reference(&[0x26, 0x01]));
+        // FU of a TRAIL_N, middle fragment included
+        assert!(!Codec::H265.is_reference(&[0x62, 0x01, 0x00]));
+    }
+
+    #[test]
+    fn replays_parameter_sets_and_gop() {
+        let mut cache = GopCache::new(Codec::H264);
+        cache.push(&packet(10, 0, SPS));
+        cache.push(&packet(11, 0, PPS));
+        cache.push(&packet(12, 100, P));
+        assert!(!cache.has_keyframe());
+        assert!(cache.replay().is_empty());
+
+        cache.push(&packet(13, 3000, IDR));
+        cache.push(&packet(14, 6000, P));
+        assert!(cache.has_keyframe());
+        let replay = cache.replay();
+        assert_eq!(payloads(&replay), [SPS, PPS, IDR, P]);
+        let sequence: Vec<_> = replay
+            .iter()
+            .map(|pkt| pkt.header.sequence_number)
+            .collect();
+        // Ends right before the next packet, 15
+        assert_eq!(sequence, [11, 12, 13, 14]);
+        assert_eq!(payloads(&cache.parameter_sets()), [SPS, PPS]);
+    }
+
+    #[test]
+    fn keeps_keyframe_access_unit() {
+        let mut cache = GopCache::new(Codec::H264);
+        cache.push(&packet(1, 0, P));
+        cache.push(&packet(2, 3000, SEI));
+        cache.push(&packet(3, 3000, IDR));
+        assert_eq!(payloads(&cache.replay()), [SEI, IDR]);
+
+        // A new keyframe starts a new GOP
+        cache.push(&packet(4, 6000, P));
+        cache.push(&packet(5, 9000, IDR));
+        assert_eq!(payloads(&cache.replay()), [IDR]);
+    }
+
+    #[test]
+    fn starts_over_after_gap() {
+        let mut cache = GopCache::new(Codec::H264);
+        cache.push(&packet(1, 0, SPS));
+        cache.push(&packet(2, 0, IDR));
+        cache.push(&packet(4, 3000, P));
+        assert!(!cache.has_keyframe());
+        assert!(cache.replay().is_empty());
+        // Parameter sets outlive the gap
+        assert_eq!(payloads(&cache.parameter_sets()), [SPS]);
+
+        cache.push(&packet(5, 6000, IDR));
+        assert_eq!(payloads(&cache.replay()), [SPS, IDR]);
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        let mut cache = GopCache::new(Codec::H264);
+        cache.push(&packet(u16::MAX, 0, IDR));
+        cache.push(&packet(0, 3000, P));
+        let sequence: Vec<_> = cache
+            .replay()
+            .iter()
+            .map(|pkt| pkt.header.sequence_number)
+            .collect();
+        assert_eq!(sequence, [u16::MAX, 0]);
+    }
+}
@@ -0,0 +1,75 @@
+use std::{net::IpAddr, sync::atomic::Ordering, time::Duration};
+
+use axum::{Json, extract::State, http::StatusCode};
+use serde::Serialize;
+
+use crate::{cli::Source, state::AppState};
+
+// How long `--healthcheck` waits for `/readyz`
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Serialize)]
+pub struct Readiness {
+    ready: bool,
+    sources: usize,
+}
+
+/// `GET /healthz`: the process is up and serving HTTP.
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// `GET /readyz`: every source given at startup is running and the gateway is
+/// not shutting down. Sources that lose their camera later keep it ready, as
+/// they reconnect with backoff. With `--background-startup`, it is not ready
+/// until every source has connected once.
+pub async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let ready =
+        !state.shutting_down.load(Ordering::Relaxed) && state.connecting.read().unwrap().is_empty();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(Readiness {
+            ready,
+            sources: state.all_streams().len(),
+        }),
+    )
+}
+
+/// Asks the gateway running with the same `--listen` and TLS options for
+/// `/readyz`, for `--healthcheck`.
+pub async fn probe(source: &Source) -> Result<(), String> {
+    let tls = source.tls();
+    // A wildcard listen address is reached over loopback
+    let listen = source.listen_addr();
+    let ip = match listen.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(std::net::Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(std::net::Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    let url = format!(
+        "{}://{}/readyz",
+        if tls { "https" } else { "http" },
+        std::net::SocketAddr::new(ip, listen.port())
+    );
+
+    // The certificate is issued for a public name, not loopback
+    let client = reqwest::Client::builder()
+        .timeout(PROBE_TIMEOUT)
+        .danger_accept_invalid_certs(true)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| format!("{}: {}", url, e))?;
+    if !response.status().is_success() {
+        return Err(format!("{}: {}", url, response.status()));
+    }
+    Ok(())
+}
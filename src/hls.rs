@@ -0,0 +1,345 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    Extension,
+    extract::{Path, RawQuery, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+
+use bytes::Bytes;
+use tokio::sync::Notify;
+
+use crate::{
+    auth::Principal,
+    fanout::FanoutTrack,
+    segment::{Output, SegmentOptions, Segmenter},
+    state::AppState,
+};
+
+// Segments listed in the playlist, and kept in memory
+const PLAYLIST_SEGMENTS: usize = 6;
+
+/// The latest HLS segments of a source, cut from the packets its viewers get.
+pub struct HlsPlaylist {
+    inner: Mutex<Playlist>,
+}
+
+#[derive(Default)]
+struct Playlist {
+    segments: VecDeque<Segment>,
+    // Discontinuities that left the playlist
+    discontinuity_sequence: u64,
+    next_sequence: u64,
+    // Seconds no segment is longer than, which may only grow
+    target_duration: u64,
+    init: Option<Init>,
+    next_init_id: u64,
+    building: Option<Segment>,
+    // Fragments of the segment being cut
+    buffer: Vec<u8>,
+}
+
+#[derive(Clone)]
+struct Init {
+    id: u64,
+    data: Bytes,
+}
+
+struct Segment {
+    sequence: u64,
+    init: Init,
+    // Follows a segment with different tracks or parameter sets
+    discontinuity: bool,
+    data: Bytes,
+    duration: Duration,
+}
+
+impl HlsPlaylist {
+    /// Starts cutting the source's tracks into segments of about `segment`,
+    /// asking `keyframe_requests` for a keyframe where one is due; `None` if
+    /// it has no H.264 or Opus track.
+    pub fn spawn(
+        name: &str,
+        video: Option<&Arc<FanoutTrack>>,
+        audio: Option<&Arc<FanoutTrack>>,
+        segment: Duration,
+        keyframe_requests: Option<Arc<Notify>>,
+    ) -> Option<Arc<Self>> {
+        let segmenter = Segmenter::new(
+            name,
+            "HLS",
+            video,
+            audio,
+            SegmentOptions {
+                segment,
+                continuous: true,
+                keyframe_requests,
+            },
+        )?;
+        let playlist = Arc::new(Self {
+            inner: Mutex::new(Playlist {
+                target_duration: segment.as_secs().max(1),
+                ..Default::default()
+            }),
+        });
+        let sink = playlist.clone();
+        segmenter.spawn(move |output| sink.inner.lock().unwrap().push(output));
+        Some(playlist)
+    }
+}
+
+impl Playlist {
+    fn push(&mut self, output: Output) {
+        match output {
+            Output::Start(data) => {
+                let init = match &self.init {
+                    Some(init) if init.data == data => init.clone(),
+                    _ => {
+                        self.next_init_id += 1;
+                        Init {
+                            id: self.next_init_id,
+                            data: data.into(),
+                        }
+                    }
+                };
+                let discontinuity = self
+                    .segments
+                    .back()
+                    .is_some_and(|last| last.init.id != init.id);
+                self.init = Some(init.clone());
+                self.building = Some(Segment {
+                    sequence: self.next_sequence,
+                    init,
+                    discontinuity,
+                    data: Bytes::new(),
+                    duration: Duration::ZERO,
+                });
+            }
+            Output::Fragment { data, duration } => {
+                if let Some(segment) = self.building.as_mut() {
+                    self.buffer.extend_from_slice(&data);
+                    segment.duration += duration;
+                }
+            }
+            Output::End => {
+                let data = std::mem::take(&mut self.buffer);
+                let Some(mut segment) = self.building.take() else {
+                    return;
+                };
+                if data.is_empty() {
+                    return;
+                }
+                segment.data = data.into();
+                self.next_sequence += 1;
+                self.target_duration = self
+                    .target_duration
+                    .max(segment.duration.as_secs_f64().round() as u64);
+                self.segments.push_back(segment);
+                while self.segments.len() > PLAYLIST_SEGMENTS {
+                    if let Some(removed) = self.segments.pop_front() {
+                        self.discontinuity_sequence += removed.discontinuity as u64;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The media playlist, with `query` (e.g. an access token) carried over
+    /// to the URIs in it.
+    fn render(&self, query: &str) -> String {
+        let mut m3u8 = String::new();
+        let _ = writeln!(m3u8, "#EXTM3U");
+        let _ = writeln!(m3u8, "#EXT-X-VERSION:7");
+        let _ = writeln!(m3u8, "#EXT-X-TARGETDURATION:{}", self.target_duration);
+        let _ = writeln!(
+            m3u8,
+            "#EXT-X-MEDIA-SEQUENCE:{}",
+            self.segments
+                .front()
+                .map_or(self.next_sequence, |segment| segment.sequence)
+        );
+        if self.discontinuity_sequence > 0 {
+            let _ = writeln!(
+                m3u8,
+                "#EXT-X-DISCONTINUITY-SEQUENCE:{}",
+                self.discontinuity_sequence
+            );
+        }
+        let _ = writeln!(m3u8, "#EXT-X-INDEPENDENT-SEGMENTS");
+        let mut init = None;
+        for segment in &self.segments {
+            if segment.discontinuity {
+                let _ = writeln!(m3u8, "#EXT-X-DISCONTINUITY");
+            }
+            if init != Some(segment.init.id) {
+                init = Some(segment.init.id);
+                let _ = writeln!(
+                    m3u8,
+                    "#EXT-X-MAP:URI=\"init-{}.mp4{}\"",
+                    segment.init.id, query
+                );
+            }
+            let _ = writeln!(m3u8, "#EXTINF:{:.3},", segment.duration.as_secs_f64());
+            let _ = writeln!(m3u8, "{}.m4s{}", segment.sequence, query);
+        }
+        m3u8
+    }
+}
+
+/// `GET /hls/{stream}/{file}`: the source's playlist (`index.m3u8`), init
+/// segments (`init-<n>.mp4`) and media segments (`<n>.m4s`).
+pub async fn hls_file(
+    State(state): State<AppState>,
+    Path((name, file)): Path<(String, String)>,
+    RawQuery(query): RawQuery,
+    principal: Option<Extension<Principal>>,
+) -> Response {
+    if let Some(Extension(principal)) = &principal
+        && !principal.can_view(&name)
+    {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let Some(playlist) = state.stream(&name).and_then(|stream| stream.hls.clone()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let playlist = playlist.inner.lock().unwrap();
+
+    if file == "index.m3u8" {
+        let query = query.map(|query| format!("?{}", query)).unwrap_or_default();
+        return (
+            [
+                (header::CONTENT_TYPE, "application/vnd.apple.mpegurl"),
+                (header::CACHE_CONTROL, "no-cache"),
+            ],
+            playlist.render(&query),
+        )
+            .into_response();
+    }
+    if let Some(id) = file
+        .strip_prefix("init-")
+        .and_then(|file| file.strip_suffix(".mp4"))
+        .and_then(|id| id.parse::<u64>().ok())
+    {
+        let init = playlist
+            .segments
+            .iter()
+            .map(|segment| &segment.init)
+            .chain(&playlist.init)
+            .find(|init| init.id == id);
+        return match init {
+            Some(init) => {
+                ([(header::CONTENT_TYPE, "video/mp4")], init.data.clone()).into_response()
+            }
+            None => StatusCode::NOT_FOUND.into_response(),
+        };
+    }
+    if let Some(sequence) = file
+        .strip_suffix(".m4s")
+        .and_then(|sequence| sequence.parse::<u64>().ok())
+    {
+        let segment = playlist
+            .segments
+            .iter()
+            .find(|segment| segment.sequence == sequence);
+        return match segment {
+            Some(segment) => (
+                [(header::CONTENT_TYPE, "video/iso.segment")],
+                segment.data.clone(),
+            )
+                .into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        };
+    }
+    StatusCode::NOT_FOUND.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A segment cut from `init` that lasts `seconds`
+    fn cut(playlist: &mut Playlist, init: &[u8], seconds: f64) {
+        playlist.push(Output::Start(init.to_vec()));
+        for _ in 0..2 {
+            playlist.push(Output::Fragment {
+                data: vec![1; 8],
+                duration: Duration::from_secs_f64(seconds / 2.0),
+            });
+        }
+        playlist.push(Output::End);
+    }
+
+    fn playlist() -> Playlist {
+        Playlist {
+            target_duration: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn lists_segments() {
+        let mut playlist = playlist();
+        cut(&mut playlist, b"init", 2.0);
+        cut(&mut playlist, b"init", 2.5);
+        assert_eq!(
+            playlist.render("?access_token=x"),
+            "#EXTM3U\n\
+             #EXT-X-VERSION:7\n\
+             #EXT-X-TARGETDURATION:3\n\
+             #EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-MAP:URI=\"init-1.mp4?access_token=x\"\n\
+             #EXTINF:2.000,\n\
+             0.m4s?access_token=x\n\
+             #EXTINF:2.500,\n\
+             1.m4s?access_token=x\n"
+        );
+        assert_eq!(playlist.segments[0].data.len(), 16);
+    }
+
+    #[test]
+    fn marks_discontinuities() {
+        let mut playlist = playlist();
+        cut(&mut playlist, b"init", 2.0);
+        cut(&mut playlist, b"other", 2.0);
+        let m3u8 = playlist.render("");
+        assert!(m3u8.ends_with(
+            "0.m4s\n\
+             #EXT-X-DISCONTINUITY\n\
+             #EXT-X-MAP:URI=\"init-2.mp4\"\n\
+             #EXTINF:2.000,\n\
+             1.m4s\n"
+        ));
+
+        // Discontinuities that scroll out are counted
+        for _ in 0..PLAYLIST_SEGMENTS {
+            cut(&mut playlist, b"other", 2.0);
+        }
+        let m3u8 = playlist.render("");
+        assert!(m3u8.contains("#EXT-X-MEDIA-SEQUENCE:2\n"));
+        assert!(m3u8.contains("#EXT-X-DISCONTINUITY-SEQUENCE:1\n"));
+        assert!(!m3u8.contains("#EXT-X-DISCONTINUITY\n"));
+        assert_eq!(playlist.segments.len(), PLAYLIST_SEGMENTS);
+    }
+
+    #[test]
+    fn drops_empty_segments() {
+        let mut playlist = playlist();
+        playlist.push(Output::Start(b"init".to_vec()));
+        playlist.push(Output::End);
+        // Fragments without a segment are ignored
+        playlist.push(Output::Fragment {
+            data: vec![1],
+            duration: Duration::from_secs(1),
+        });
+        playlist.push(Output::End);
+        assert!(playlist.segments.is_empty());
+        assert!(playlist.render("").contains("#EXT-X-MEDIA-SEQUENCE:0\n"));
+    }
+}
@@ -0,0 +1,140 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Crockford's base32, as used by ULIDs
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+// Bitcoin's base58: no 0, O, I or l
+const BASE58: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+// Digits needed for any 128-bit value in base58
+const BASE58_LEN: usize = 22;
+
+/// How viewer and publisher session ids are spelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SessionIdFormat {
+    /// Random UUID (v4), 36 characters.
+    #[default]
+    Uuid,
+    /// ULID: 26 characters that sort by creation time, which they reveal.
+    Ulid,
+    /// Random base58, 22 characters.
+    Short,
+}
+
+/// Whether a session id tells anything about its session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SessionIdPolicy {
+    /// Nothing but the id itself.
+    #[default]
+    Opaque,
+    /// Prefixed with the source name, e.g. `front-door.3f2c…`.
+    Derivable,
+}
+
+/// A new id for a session of `source`.
+pub fn new_session_id(format: SessionIdFormat, policy: SessionIdPolicy, source: &str) -> String {
+    let random = uuid::Uuid::new_v4();
+    let id = match format {
+        SessionIdFormat::Uuid => random.to_string(),
+        SessionIdFormat::Ulid => ulid(random),
+        SessionIdFormat::Short => base58(random.as_u128()),
+    };
+
+    match policy {
+        SessionIdPolicy::Opaque => id,
+        SessionIdPolicy::Derivable => format!("{}.{}", url_token(source), id),
+    }
+}
+
+// 48-bit millisecond timestamp followed by 80 random bits
+fn ulid(random: uuid::Uuid) -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    // The version and variant bits of a v4 UUID are in bytes 6 and 8
+    let bytes = random.as_bytes();
+    let entropy = bytes[..6]
+        .iter()
+        .chain(&bytes[10..14])
+        .fold(0u128, |acc, &byte| acc << 8 | byte as u128);
+    let value = (millis & 0xffff_ffff_ffff) << 80 | entropy;
+
+    // 26 digits of 5 bits cover 130 bits; the first one only takes 3
+    (0..26)
+        .map(|digit| CROCKFORD[(value >> (125 - 5 * digit) & 0x1f) as usize] as char)
+        .collect()
+}
+
+// Fixed width, so ids of the same format always have the same length
+fn base58(mut value: u128) -> String {
+    let mut digits = [BASE58[0]; BASE58_LEN];
+    for digit in digits.iter_mut().rev() {
+        *digit = BASE58[(value % 58) as usize];
+        value /= 58;
+    }
+    digits.iter().map(|&digit| digit as char).collect()
+}
+
+/// `name` with everything but ASCII letters, digits, `-` and `_` replaced by
+/// `-`, for URL paths and file names.
+pub fn url_token(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis() -> u128 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+    }
+
+    #[test]
+    fn spells_base58_at_fixed_width() {
+        assert_eq!(base58(0), "1".repeat(BASE58_LEN));
+        assert_eq!(base58(57), format!("{}z", "1".repeat(BASE58_LEN - 1)));
+        assert_eq!(base58(58), format!("{}21", "1".repeat(BASE58_LEN - 2)));
+        assert_eq!(base58(u128::MAX).len(), BASE58_LEN);
+    }
+
+    #[test]
+    fn ulids_lead_with_the_time() {
+        let before = millis();
+        let id = ulid(uuid::Uuid::new_v4());
+        let after = millis();
+        assert_eq!(id.len(), 26);
+
+        let time = id[..10].bytes().fold(0u128, |acc, digit| {
+            acc << 5 | CROCKFORD.iter().position(|&c| c == digit).unwrap() as u128
+        });
+        assert!((before..=after).contains(&time));
+    }
+
+    #[test]
+    fn formats_and_prefixes_ids() {
+        let uuid = new_session_id(SessionIdFormat::Uuid, SessionIdPolicy::Opaque, "cam");
+        assert!(uuid::Uuid::parse_str(&uuid).is_ok());
+        let short = new_session_id(SessionIdFormat::Short, SessionIdPolicy::Opaque, "cam");
+        assert_eq!(short.len(), BASE58_LEN);
+        assert!(short.bytes().all(|c| BASE58.contains(&c)));
+
+        let derivable = new_session_id(
+            SessionIdFormat::Ulid,
+            SessionIdPolicy::Derivable,
+            "front door/1",
+        );
+        let (prefix, id) = derivable.split_once('.').unwrap();
+        assert_eq!(prefix, "front-door-1");
+        assert_eq!(id.len(), 26);
+    }
+}
@@ -0,0 +1,1250 @@
+use std::{
+    sync::{Arc, atomic::Ordering},
+    time::Duration,
+};
+
+use retina::{
+    client::{
+        Described, PacketItem, Playing, Session, SessionGroup, SetupOptions, TeardownPolicy,
+        Transport,
+    },
+    rtp::ReceivedPacket,
+};
+use tokio::{
+    sync::{Notify, broadcast, watch},
+    time::Instant,
+};
+use tokio_stream::StreamExt;
+use tracing::{debug, error, info, trace, warn};
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+
+use crate::{
+    audio::{Gain, SoundDetector},
+    avsync::{AvSync, Media},
+    backlog::frame_channel,
+    chaos::Faults,
+    cli::{Source, SourceSpec},
+    codec::{AUDIO_CODEC_PRIORITY, VIDEO_CODEC_PRIORITY, get_codec_priority, h265_fmtp},
+    events::{Event, spawn_webhooks},
+    fanout::FanoutTrack,
+    gop::Codec,
+    hls::HlsPlaylist,
+    metadata::{METADATA_ENCODINGS, Reassembler, motion},
+    packet::into_rtp_packet,
+    record::{self, RecordOptions},
+    sdp::msid_token,
+    shutdown,
+    silence::{SILENCE_PACKET_INTERVAL, SilenceFiller},
+    startup::{Phase, StartupTimer},
+    state::{AppState, AudioFormat, Capabilities, SourceControl, SourceInfo, Stream, VideoLayer},
+    stats::PipelineStats,
+    watchdog::{Activity, IdleGrace, spawn_freeze_watchdog},
+};
+
+#[cfg(feature = "transcode")]
+use crate::transcode::{self, MjpegTranscoder};
+
+// Bitrate in kbit/s of recordings transcoded to `--record-height` without a
+// `--record-bitrate`
+#[cfg(feature = "transcode")]
+const RECORD_TRANSCODE_BITRATE: u32 = 1000;
+// Longest wait for a camera to acknowledge TEARDOWN when stopping
+const TEARDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+// Reconnects to a camera that dropped its session start this far apart and
+// back off exponentially up to the cap
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+// A session that fails this many times in a row without a packet in between
+// counts as lost
+const MAX_CONSECUTIVE_ERRORS: u32 = 5;
+
+/// Connects to one camera, picks its best video and audio streams and starts
+/// forwarding their packets into fresh WebRTC tracks.
+///
+/// `source` supplies the options shared by all cameras (transport, timeouts,
+/// webhooks).
+pub async fn start(spec: &SourceSpec, source: &Source) -> anyhow::Result<Stream> {
+    let audio_stall_timeout = std::time::Duration::from_millis(source.audio_stall_timeout);
+    let sound_threshold = source.sound_threshold;
+
+    // Tracks the TEARDOWNs of this camera's sessions, so shutdown can await them
+    let session_group = Arc::new(SessionGroup::default().named(spec.name.clone()));
+    let startup = Arc::new(StartupTimer::new("Startup"));
+    let session = describe(spec, source.teardown, &session_group).await?;
+    startup.mark(Phase::Describe);
+
+    let play_metadata = !(spec.no_metadata || source.no_metadata);
+    let (video_track, mjpeg_index, audio_track, recorded_audio, metadata_stream, capabilities) = {
+        let mut available_video_streams = Vec::new();
+        let mut available_audio_streams = Vec::new();
+        let mut available_metadata_streams = Vec::new();
+        let mut mjpeg_streams = Vec::new();
+
+        for (index, stream) in session.streams().iter().enumerate() {
+            if stream.media() == "video"
+                && VIDEO_CODEC_PRIORITY
+                    .iter()
+                    .any(|(name, _)| *name == stream.encoding_name())
+            {
+                available_video_streams.push((index, stream));
+            } else if stream.media() == "audio"
+                && AUDIO_CODEC_PRIORITY
+                    .iter()
+                    .any(|(name, _)| *name == stream.encoding_name())
+            {
+                available_audio_streams.push((index, stream));
+            } else if stream.media() == "application"
+                && METADATA_ENCODINGS.contains(&stream.encoding_name())
+            {
+                if play_metadata {
+                    available_metadata_streams.push((index, stream));
+                } else {
+                    debug!(
+                        "[{}] Skipping metadata stream #{} as configured",
+                        spec.name, index
+                    );
+                }
+            } else if stream.media() == "video" && stream.encoding_name() == "jpeg" {
+                mjpeg_streams.push((index, stream));
+            }
+        }
+
+        // Browsers can't decode MJPEG over WebRTC. Builds with the `transcode`
+        // feature transcode it to H.264 for cameras with no other video
+        let mjpeg_stream = mjpeg_streams
+            .first()
+            .copied()
+            .filter(|_| cfg!(feature = "transcode") && available_video_streams.is_empty());
+        for (index, _) in &mjpeg_streams {
+            if mjpeg_stream.is_none_or(|(transcoded, _)| transcoded != *index) {
+                warn!(
+                    "[{}] Skipping MJPEG video stream #{}; switch the camera to H.264 or H.265",
+                    spec.name, index
+                );
+            }
+        }
+
+        if available_video_streams.is_empty()
+            && mjpeg_stream.is_none()
+            && available_audio_streams.is_empty()
+            && available_metadata_streams.is_empty()
+        {
+            anyhow::bail!("no supported video, audio or metadata streams found");
+        }
+
+        // Sort video streams: first by resolution (higher is better), then by codec priority
+        available_video_streams.sort_by(|(_, a), (_, b)| {
+            use retina::codec::ParametersRef;
+
+            let resolution_a = match a.parameters() {
+                Some(ParametersRef::Video(v)) => {
+                    let (w, h) = v.pixel_dimensions();
+                    w * h
+                }
+                _ => 0,
+            };
+
+            let resolution_b = match b.parameters() {
+                Some(ParametersRef::Video(v)) => {
+                    let (w, h) = v.pixel_dimensions();
+                    w * h
+                }
+                _ => 0,
+            };
+
+            // First compare by resolution (higher to lower)
+            match resolution_b.cmp(&resolution_a) {
+                std::cmp::Ordering::Equal => {
+                    // If resolution is the same, compare by codec priority
+                    get_codec_priority(a.encoding_name(), VIDEO_CODEC_PRIORITY)
+                        .cmp(&get_codec_priority(b.encoding_name(), VIDEO_CODEC_PRIORITY))
+                }
+                other => other,
+            }
+        });
+
+        // Sort audio streams by codec priority only
+        available_audio_streams.sort_by(|(_, a), (_, b)| {
+            get_codec_priority(a.encoding_name(), AUDIO_CODEC_PRIORITY)
+                .cmp(&get_codec_priority(b.encoding_name(), AUDIO_CODEC_PRIORITY))
+        });
+
+        let layers: Vec<VideoLayer> = available_video_streams
+            .iter()
+            .map(|(_, stream)| video_layer(stream))
+            .chain(mjpeg_stream.map(|(_, stream)| VideoLayer {
+                codec: "video/h264".to_owned(),
+                ..video_layer(stream)
+            }))
+            .collect();
+        let capabilities = Capabilities {
+            video: layers.first().cloned(),
+            audio: available_audio_streams
+                .first()
+                .map(|(_, stream)| AudioFormat {
+                    codec: format!("audio/{}", stream.encoding_name()),
+                    clock_rate: stream.clock_rate_hz(),
+                    channels: stream.channels().map(|channels| channels.get()),
+                }),
+            layers,
+        };
+
+        // Players may label the tracks from their msid, e.g. "Front door-video"
+        let msid = msid_token(spec.display_name.as_deref().unwrap_or(&spec.name));
+
+        // Audio-only sources (intercoms, radios) have no video track
+        let video_track = available_video_streams.first().map(|&video_stream| {
+            {
+                use retina::codec::ParametersRef;
+                let (width, height) = match video_stream.1.parameters() {
+                    Some(ParametersRef::Video(v)) => v.pixel_dimensions(),
+                    _ => (0, 0),
+                };
+                info!(
+                    "[{}] Selected video stream #{}: {} {}x{}",
+                    spec.name,
+                    video_stream.0,
+                    video_stream.1.encoding_name(),
+                    width,
+                    height
+                );
+            }
+            // H.265 viewers may take several profiles; bind to the camera's
+            let sdp_fmtp_line = match video_stream.1.parameters() {
+                Some(retina::codec::ParametersRef::Video(v))
+                    if video_stream.1.encoding_name() == "h265" =>
+                {
+                    h265_fmtp(v.rfc6381_codec()).unwrap_or_default()
+                }
+                _ => String::new(),
+            };
+            let track = FanoutTrack::new(
+                RTCRtpCodecCapability {
+                    mime_type: format!("video/{}", video_stream.1.encoding_name()),
+                    clock_rate: video_stream.1.clock_rate_hz(),
+                    sdp_fmtp_line,
+                    ..Default::default()
+                },
+                format!("{}-video", msid),
+                msid.clone(),
+            )
+            .with_startup(startup.clone());
+            (video_stream.0, Arc::new(track))
+        });
+        // The transcode of an MJPEG camera goes out like any H.264 video
+        let video_track = video_track.or_else(|| {
+            mjpeg_stream.map(|(index, stream)| {
+                info!(
+                    "[{}] Selected video stream #{}: jpeg, transcoded to H.264",
+                    spec.name, index
+                );
+                let track = FanoutTrack::new(
+                    RTCRtpCodecCapability {
+                        mime_type: "video/h264".to_owned(),
+                        clock_rate: stream.clock_rate_hz(),
+                        ..Default::default()
+                    },
+                    format!("{}-video", msid),
+                    msid.clone(),
+                )
+                .with_startup(startup.clone());
+                (index, Arc::new(track))
+            })
+        });
+
+        let audio_track = if !available_audio_streams.is_empty() {
+            let audio_stream = available_audio_streams[0];
+            info!(
+                "[{}] Selected audio stream #{}: {}",
+                spec.name,
+                audio_stream.0,
+                audio_stream.1.encoding_name()
+            );
+
+            let track = FanoutTrack::new(
+                RTCRtpCodecCapability {
+                    mime_type: format!("audio/{}", audio_stream.1.encoding_name()),
+                    clock_rate: audio_stream.1.clock_rate_hz(),
+                    ..Default::default()
+                },
+                format!("{}-audio", msid),
+                msid.clone(),
+            );
+            Some((audio_stream.0, Arc::new(track)))
+        } else {
+            None
+        };
+
+        // Browsers can't play AAC, but recordings can: unless viewers get
+        // Opus, a camera's AAC stream is set up for the recording alone
+        let live_opus = audio_track
+            .as_ref()
+            .is_some_and(|(_, track)| track.codec().mime_type == "audio/opus");
+        let recorded_audio = session
+            .streams()
+            .iter()
+            .enumerate()
+            .filter(|_| source.record_dir.is_some() && !live_opus)
+            .find_map(|(index, stream)| {
+                let Some(retina::codec::ParametersRef::Audio(parameters)) = stream.parameters()
+                else {
+                    return None;
+                };
+                if stream.encoding_name() != "mpeg4-generic" || parameters.extra_data().is_empty() {
+                    return None;
+                }
+                info!(
+                    "[{}] Selected audio stream #{} for recording: {}",
+                    spec.name,
+                    index,
+                    parameters.rfc6381_codec().unwrap_or("aac")
+                );
+                let config: String = parameters
+                    .extra_data()
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                let track = FanoutTrack::new(
+                    RTCRtpCodecCapability {
+                        mime_type: "audio/mpeg4-generic".to_owned(),
+                        clock_rate: stream.clock_rate_hz(),
+                        channels: stream.channels().map_or(1, |channels| channels.get()),
+                        sdp_fmtp_line: format!("mode=AAC-hbr;config={}", config),
+                        ..Default::default()
+                    },
+                    format!("{}-recorded-audio", msid),
+                    msid.clone(),
+                );
+                Some((index, Arc::new(track)))
+            });
+
+        // Metadata (e.g. ONVIF analytics) only reaches viewers over data
+        // channels and SSE, so devices without playable media still serve
+        let metadata_stream = available_metadata_streams.first().map(|&(index, stream)| {
+            info!(
+                "[{}] Selected metadata stream #{}: {}",
+                spec.name,
+                index,
+                stream.encoding_name()
+            );
+            (index, format!("application/{}", stream.encoding_name()))
+        });
+
+        (
+            video_track,
+            mjpeg_stream.map(|(index, _)| index),
+            audio_track,
+            recorded_audio,
+            metadata_stream,
+            capabilities,
+        )
+    };
+    let indices: Vec<usize> = video_track
+        .iter()
+        .map(|(index, _)| *index)
+        .chain(audio_track.iter().map(|(index, _)| *index))
+        .chain(recorded_audio.iter().map(|(index, _)| *index))
+        .chain(metadata_stream.iter().map(|(index, _)| *index))
+        .collect();
+    // Tracks and packet routing are fixed to these streams, so reconnects
+    // check the camera still describes them the same way
+    let layout = Layout::of(&session, &indices);
+
+    // On demand, the DESCRIBE above only served to pick the tracks
+    let mut session = if source.on_demand {
+        None
+    } else {
+        Some(play(session, &source.transport, &indices, &startup).await?)
+    };
+
+    let info = SourceInfo {
+        name: spec.name.clone(),
+        url: {
+            let mut url = url::Url::from(spec.url.clone());
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+            url
+        },
+        tags: spec
+            .tags
+            .iter()
+            .map(|tag| (tag.key.clone(), tag.value.clone()))
+            .collect(),
+        video_codec: video_track
+            .as_ref()
+            .map(|(_, track)| track.codec().mime_type),
+        audio_codec: audio_track
+            .as_ref()
+            .map(|(_, track)| track.codec().mime_type),
+        metadata_codec: metadata_stream.as_ref().map(|(_, codec)| codec.clone()),
+        relay_only: spec.relay_only,
+        substream: spec.substream.clone(),
+        display_name: spec.display_name.clone(),
+        description: spec.description.clone(),
+        interface: spec.interface.clone(),
+    };
+
+    let events = broadcast::channel(16).0;
+    let metadata = broadcast::channel(16).0;
+    let stats = Arc::new(PipelineStats::default());
+    let keyframe_requests = Arc::new(Notify::new());
+    let control = Arc::new(SourceControl::default());
+    let faults = source.chaos.then(|| Arc::new(Faults::default()));
+    let av_sync = (video_track.is_some() && audio_track.is_some() && source.av_sync_max_delay > 0)
+        .then(|| {
+            Arc::new(AvSync::new(
+                &spec.name,
+                Duration::from_millis(source.av_sync_max_delay),
+            ))
+        });
+    // MJPEG cameras only send keyframes; the transcode answers viewers' requests
+    let restart_interval = match mjpeg_index {
+        Some(_) => Duration::ZERO,
+        None => Duration::from_secs(source.keyframe_request_interval),
+    };
+    let viewers = watch::Sender::new(0);
+    let idle_grace = Duration::from_secs(source.on_demand_grace);
+
+    let video_activity = Arc::new(Activity::new());
+    video_activity.set_paused(session.is_none());
+    if video_track.is_some() {
+        spawn_freeze_watchdog(
+            video_activity.clone(),
+            std::time::Duration::from_secs(source.freeze_timeout),
+            events.clone(),
+            control.ended.subscribe(),
+        );
+    }
+    spawn_webhooks(
+        spec.name.clone(),
+        source.webhook.clone(),
+        events.subscribe(),
+    );
+    // Segments due in the middle of a GOP ask the camera for a keyframe
+    let segment_keyframes = source.segment_keyframes.then(|| keyframe_requests.clone());
+    if let Some(dir) = source.record_dir.clone() {
+        // Recordings at a quality of their own record a transcode, which
+        // puts keyframes where segments need them
+        #[cfg(feature = "transcode")]
+        let (recorded_video, recording_keyframes) =
+            match (source.record_height, source.record_bitrate) {
+                (None, None) => (
+                    video_track.as_ref().map(|(_, track)| track.clone()),
+                    segment_keyframes.clone(),
+                ),
+                (height, bitrate) => {
+                    let requests = Arc::new(Notify::new());
+                    let recorded_video = video_track.as_ref().map(|(_, track)| {
+                        transcode::reencode(
+                            &spec.name,
+                            track,
+                            height,
+                            bitrate.unwrap_or(RECORD_TRANSCODE_BITRATE),
+                            requests.clone(),
+                        )
+                    });
+                    (recorded_video, Some(requests))
+                }
+            };
+        #[cfg(not(feature = "transcode"))]
+        let (recorded_video, recording_keyframes) = (
+            video_track.as_ref().map(|(_, track)| track.clone()),
+            segment_keyframes.clone(),
+        );
+        record::spawn(
+            &spec.name,
+            recorded_video.as_ref(),
+            recorded_audio
+                .as_ref()
+                .or(audio_track.as_ref())
+                .map(|(_, track)| track),
+            RecordOptions {
+                dir,
+                segment: Duration::from_secs(source.record_segment),
+                retention: Duration::from_secs(source.record_retention * 3600),
+                keyframe_requests: recording_keyframes,
+            },
+        );
+    }
+
+    let hls = source
+        .hls
+        .then(|| {
+            HlsPlaylist::spawn(
+                &spec.name,
+                video_track.as_ref().map(|(_, track)| track),
+                audio_track.as_ref().map(|(_, track)| track),
+                Duration::from_secs(source.hls_segment),
+                segment_keyframes.clone(),
+            )
+        })
+        .flatten();
+
+    {
+        let video_track = video_track.clone();
+        let audio_track = audio_track.clone();
+        let recorded_audio = recorded_audio.clone();
+        let metadata_index = metadata_stream.as_ref().map(|(index, _)| *index);
+        let metadata = metadata.clone();
+        let events = events.clone();
+        let stats = stats.clone();
+        let keyframe_requests = keyframe_requests.clone();
+        #[cfg(feature = "transcode")]
+        let mjpeg = mjpeg_index
+            .zip(video_track.as_ref())
+            .map(|(index, (_, track))| {
+                let transcoder = MjpegTranscoder::spawn(
+                    &spec.name,
+                    track.clone(),
+                    keyframe_requests.clone(),
+                    source.mjpeg_bitrate,
+                );
+                (index, transcoder)
+            });
+        let control = control.clone();
+        let faults = faults.clone();
+        let startup = startup.clone();
+        let mut viewer_count = viewers.subscribe();
+        let mut suspended = control.suspended.subscribe();
+        let on_demand = source.on_demand;
+        let spec = spec.clone();
+        let (teardown, transport) = (source.teardown, source.transport.clone());
+        tokio::spawn(async move {
+            // Create buffers for packets with channels
+            let (video_tx, mut video_rx) = frame_channel(
+                video_track
+                    .as_ref()
+                    .and_then(|(_, track)| Codec::from_mime_type(&track.codec().mime_type)),
+                100,
+                stats.clone(),
+            );
+            let (audio_tx, mut audio_rx) = tokio::sync::mpsc::channel::<ReceivedPacket>(100);
+            if let Some(av_sync) = &av_sync {
+                video_rx = av_sync.spawn_delay(Media::Video, video_rx);
+                audio_rx = av_sync.spawn_delay(Media::Audio, audio_rx);
+            }
+            let mut reassembler = Reassembler::default();
+            let mut moving = None;
+
+            // Task for writing video packets (if available)
+            if let Some((_, video_track)) = &video_track {
+                let video_track_clone = video_track.clone();
+                let video_stats = stats.clone();
+                let video_control = control.clone();
+                let video_faults = faults.clone();
+                let video_codec = Codec::from_mime_type(&video_track.codec().mime_type);
+                let video_startup = startup.clone();
+                let name = spec.name.clone();
+                tokio::spawn(async move {
+                    while let Some(rtp) = video_rx.recv().await {
+                        let pkt = match into_rtp_packet(rtp) {
+                            Ok(pkt) => pkt,
+                            Err(err) => {
+                                trace!("video packet parse error: {}", err);
+                                video_stats.video.dropped.fetch_add(1, Ordering::Relaxed);
+                                continue;
+                            }
+                        };
+                        let pkt = match &video_faults {
+                            Some(faults) => faults.corrupt(pkt),
+                            None => pkt,
+                        };
+                        if video_codec.is_some_and(|codec| codec.starts_keyframe(&pkt.payload))
+                            && video_startup.mark(Phase::FirstKeyframe)
+                        {
+                            info!("⏱️  [{}] {}", name, video_startup.report());
+                        }
+                        if video_control.is_private() {
+                            continue;
+                        }
+                        video_track_clone.send(pkt);
+                        video_stats.video.forwarded.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            }
+
+            // Task for writing audio packets (if available)
+            if let Some((_, audio_track)) = &audio_track {
+                let audio_track_clone = audio_track.clone();
+                let encoding_name = audio_track
+                    .codec()
+                    .mime_type
+                    .trim_start_matches("audio/")
+                    .to_owned();
+                let gain = Gain::new(&encoding_name, spec.gain);
+                if gain.is_none() && spec.gain != 0 {
+                    warn!(
+                        "[{}] Can't apply gain to {} audio, relaying it as is",
+                        spec.name, encoding_name
+                    );
+                }
+                let mut sound = sound_threshold
+                    .and_then(|threshold| SoundDetector::new(&encoding_name, threshold));
+                if sound.is_none() && sound_threshold.is_some() {
+                    warn!(
+                        "[{}] Can't measure {} audio, no sound events",
+                        spec.name, encoding_name
+                    );
+                }
+                let audio_events = events.clone();
+                let mut silence = if audio_stall_timeout.is_zero() {
+                    None
+                } else {
+                    SilenceFiller::new(&encoding_name)
+                };
+                let video_activity = video_activity.clone();
+                let has_video = video_track.is_some();
+                let audio_stats = stats.clone();
+                let audio_control = control.clone();
+                let audio_faults = faults.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let wait = match &silence {
+                            Some(filler) => filler.wait(audio_stall_timeout),
+                            None => std::time::Duration::MAX,
+                        };
+
+                        let pkt = match tokio::time::timeout(wait, audio_rx.recv()).await {
+                            Ok(Some(rtp)) => {
+                                let pkt = match into_rtp_packet(rtp) {
+                                    Ok(pkt) => pkt,
+                                    Err(err) => {
+                                        trace!("audio packet parse error: {}", err);
+                                        audio_stats.audio.dropped.fetch_add(1, Ordering::Relaxed);
+                                        continue;
+                                    }
+                                };
+                                let pkt = match &audio_faults {
+                                    Some(faults) => faults.corrupt(pkt),
+                                    None => pkt,
+                                };
+                                let pkt = match &gain {
+                                    Some(gain) => gain.apply(pkt),
+                                    None => pkt,
+                                };
+                                if let Some(detector) = sound.as_mut()
+                                    && !audio_control.is_private()
+                                    && let Some((active, level)) = detector.push(&pkt.payload)
+                                {
+                                    let _ = audio_events.send(Event::Sound {
+                                        active,
+                                        level_dbfs: (level * 10.0).round() / 10.0,
+                                    });
+                                }
+                                match silence.as_mut() {
+                                    Some(filler) => {
+                                        let (pkt, inserted) = filler.pass(pkt);
+                                        if let Some(inserted) = inserted {
+                                            info!(
+                                                "🔈 Audio stream resumed after {} silence packets",
+                                                inserted
+                                            );
+                                            let stalled = audio_stall_timeout
+                                                + SILENCE_PACKET_INTERVAL * inserted as u32;
+                                            let _ = audio_events.send(Event::AudioResumed {
+                                                stalled_ms: stalled.as_millis() as u64,
+                                            });
+                                        }
+                                        pkt
+                                    }
+                                    None => pkt,
+                                }
+                            }
+                            Ok(None) => break,
+                            Err(_) => {
+                                // Only fill gaps while video keeps flowing
+                                if has_video && video_activity.idle() > audio_stall_timeout {
+                                    continue;
+                                }
+                                let Some(filler) = silence.as_mut() else {
+                                    continue;
+                                };
+                                if !filler.is_filling() {
+                                    warn!("🔇 Audio stream stalled, inserting silence");
+                                    let _ = audio_events.send(Event::AudioStalled {
+                                        idle_ms: audio_stall_timeout.as_millis() as u64,
+                                    });
+                                }
+                                match filler.next_silence() {
+                                    Some(pkt) => pkt,
+                                    None => continue,
+                                }
+                            }
+                        };
+
+                        if audio_control.is_private() {
+                            continue;
+                        }
+                        audio_track_clone.send(pkt);
+                        audio_stats.audio.forwarded.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            }
+
+            let connect = async || match describe(&spec, teardown, &session_group).await {
+                Ok(described) => match layout.check(&described) {
+                    Ok(()) => {
+                        startup.mark(Phase::Describe);
+                        play(described, &transport, &indices, &startup).await
+                    }
+                    Err(e) => {
+                        // The tracks can't follow, so the source starts afresh
+                        control.rebuild.notify_one();
+                        Err(e)
+                    }
+                },
+                Err(e) => Err(e),
+            };
+            // Cameras start every new session with a keyframe
+            let restart = async |reason: &str, old: Option<Session<Playing>>| {
+                info!("🔑 [{}] {}, starting new RTSP session", spec.name, reason);
+                startup.begin(reason);
+                if let Some(av_sync) = &av_sync {
+                    av_sync.reset();
+                }
+                // The old session is only torn down once the new one plays,
+                // so the camera's TEARDOWN doesn't add to the hiccup
+                let mut restarted = connect().await;
+                if let Some(old) = old
+                    && let Err(e) = &restarted
+                {
+                    // Cameras allowing few sessions refuse a new one while the
+                    // old one is still up
+                    debug!(
+                        "[{}] New RTSP session failed next to the old one, retrying without it: {:#}",
+                        spec.name, e
+                    );
+                    drop(old);
+                    await_teardown(&spec.name, &session_group).await;
+                    startup.begin(reason);
+                    restarted = connect().await;
+                }
+                restarted
+                    .inspect_err(|e| warn!("[{}] RTSP restart failed: {:#}", spec.name, e))
+                    .ok()
+            };
+
+            // Main loop for reading packets from RTSP
+            // The session just started, so it opened with a keyframe
+            let mut restarted_at = Instant::now();
+            let mut idle = IdleGrace::new(idle_grace);
+            // When to try again to get a session the source should have
+            let mut reconnect_at: Option<Instant> = None;
+            let mut backoff = Backoff::default();
+            let mut errors = 0;
+            // When the first keyframe request since the last restart came in
+            let mut keyframe_requested: Option<Instant> = None;
+            loop {
+                let wanted = !*suspended.borrow() && (!on_demand || *viewer_count.borrow() > 0);
+                if session.is_some() || !wanted {
+                    if reconnect_at.take().is_some() {
+                        control.reconnect_at.send_replace(None);
+                    }
+                } else if reconnect_at.is_none() {
+                    let delay = backoff.next();
+                    warn!(
+                        "[{}] No RTSP session, reconnecting in {:?}",
+                        spec.name, delay
+                    );
+                    reconnect_at = Some(Instant::now() + delay);
+                    control.reconnect_at.send_replace(reconnect_at);
+                }
+                let reconnect_timer =
+                    tokio::time::sleep_until(reconnect_at.unwrap_or_else(Instant::now));
+                let keyframe_timer = tokio::time::sleep_until(restarted_at + restart_interval);
+                let item = tokio::select! {
+                    item = async { session.as_mut().unwrap().next().await }, if session.is_some() => {
+                        match item {
+                            Some(Err(e)) if errors + 1 >= MAX_CONSECUTIVE_ERRORS => {
+                                error!("[{}] RTSP session lost: {}", spec.name, e);
+                                session = None;
+                                continue;
+                            }
+                            Some(item) => item,
+                            None => {
+                                error!("[{}] Camera closed the RTSP connection", spec.name);
+                                session = None;
+                                continue;
+                            }
+                        }
+                    }
+                    _ = reconnect_timer, if reconnect_at.is_some() => {
+                        restarted_at = Instant::now();
+                        errors = 0;
+                        stats.reconnects.fetch_add(1, Ordering::Relaxed);
+                        session = restart("Reconnecting", None).await;
+                        if session.is_none() {
+                            // Back off further
+                            reconnect_at = None;
+                        }
+                        continue;
+                    }
+                    _ = keyframe_requests.notified(), if !restart_interval.is_zero() && session.is_some() => {
+                        // Requests coming in until the restart are served by it
+                        keyframe_requested.get_or_insert_with(Instant::now);
+                        continue;
+                    }
+                    _ = keyframe_timer, if keyframe_requested.is_some() => {
+                        // Any restart since the request started with a keyframe
+                        let requested = keyframe_requested.take();
+                        if session.is_none() || requested.is_some_and(|at| restarted_at >= at) {
+                            continue;
+                        }
+                        restarted_at = Instant::now();
+                        session = restart("Keyframe requested", session.take()).await;
+                        continue;
+                    }
+                    _ = control.restart.notified(), if session.is_some() => {
+                        restarted_at = Instant::now();
+                        session = restart("Restart requested", session.take()).await;
+                        continue;
+                    }
+                    Ok(()) = suspended.changed() => {
+                        if *suspended.borrow_and_update() {
+                            if session.take().is_some() {
+                                info!("⏸️  [{}] Source disabled, closing RTSP session", spec.name);
+                            }
+                            idle.cancel();
+                            video_activity.set_paused(true);
+                        } else if session.is_none() && (!on_demand || *viewer_count.borrow() > 0) {
+                            restarted_at = Instant::now();
+                            session = restart("Source enabled", None).await;
+                            video_activity.set_paused(session.is_none());
+                        }
+                        continue;
+                    }
+                    Ok(()) = viewer_count.changed(), if on_demand => {
+                        let count = *viewer_count.borrow_and_update();
+                        idle.viewers_changed(count, session.is_some());
+                        if count > 0 && session.is_none() && !*suspended.borrow() {
+                            restarted_at = Instant::now();
+                            session = restart("Viewer joined", None).await;
+                            video_activity.set_paused(session.is_none());
+                        }
+                        continue;
+                    }
+                    _ = control.stop.notified() => {
+                        info!("🛑 [{}] Stopping source", spec.name);
+                        break;
+                    }
+                    _ = async { faults.as_ref().unwrap().disconnect.notified().await }, if faults.is_some() && session.is_some() => {
+                        // What follows is up to the same handling as a real
+                        // loss of the session: reconnecting with backoff
+                        error!("💥 [{}] RTSP connection dropped by injected fault", spec.name);
+                        session = None;
+                        continue;
+                    }
+                    _ = idle.expired(), if idle.is_running() => {
+                        info!("💤 [{}] No viewers for {:?}, closing RTSP session", spec.name, idle.grace());
+                        session = None;
+                        idle.cancel();
+                        video_activity.set_paused(true);
+                        continue;
+                    }
+                };
+
+                match item {
+                    Ok(PacketItem::Rtp(_))
+                        if faults.as_ref().is_some_and(|faults| faults.is_stalled()) => {}
+                    Ok(PacketItem::Rtp(rtp)) => {
+                        startup.mark(Phase::FirstRtp);
+                        // Only a session that delivers counts as reconnected
+                        backoff.reset();
+                        errors = 0;
+                        let stream_id = rtp.stream_id();
+
+                        // MJPEG goes to its transcoder, which sends the
+                        // H.264 on the video track
+                        #[cfg(feature = "transcode")]
+                        if let Some((_, transcoder)) =
+                            mjpeg.as_ref().filter(|(index, _)| *index == stream_id)
+                        {
+                            video_activity.touch();
+                            stats.video.received.fetch_add(1, Ordering::Relaxed);
+                            if !control.is_private() && !transcoder.push(rtp) {
+                                stats.video.dropped.fetch_add(1, Ordering::Relaxed);
+                            }
+                            continue;
+                        }
+
+                        // Send packet to the corresponding channel without blocking
+                        if video_track
+                            .as_ref()
+                            .is_some_and(|(index, _)| *index == stream_id)
+                        {
+                            video_activity.touch();
+                            stats.video.received.fetch_add(1, Ordering::Relaxed);
+                            if video_tx.push(rtp) {
+                                warn!(
+                                    "[{}] Video writer fell behind, dropping frames until the next keyframe",
+                                    spec.name
+                                );
+                                keyframe_requests.notify_one();
+                            }
+                        } else if audio_track
+                            .as_ref()
+                            .is_some_and(|(index, _)| *index == stream_id)
+                        {
+                            stats.audio.received.fetch_add(1, Ordering::Relaxed);
+                            if audio_tx.try_send(rtp).is_err() {
+                                warn!("Audio buffer full, dropping packet");
+                                stats.audio.dropped.fetch_add(1, Ordering::Relaxed);
+                            }
+                        } else if let Some((_, track)) = recorded_audio
+                            .as_ref()
+                            .filter(|(index, _)| *index == stream_id)
+                        {
+                            // Privacy mode pauses recordings too
+                            if !control.is_private()
+                                && let Ok(pkt) = into_rtp_packet(rtp)
+                            {
+                                track.send(pkt);
+                            }
+                        } else if metadata_index == Some(stream_id) {
+                            if let Some(document) =
+                                reassembler.push(rtp.sequence_number(), rtp.payload(), rtp.mark())
+                                && !control.is_private()
+                            {
+                                // Only changes of motion are events
+                                if let Some(active) = motion(&document)
+                                    && moving.replace(active) != Some(active)
+                                {
+                                    let _ = events.send(Event::Motion { active });
+                                }
+                                // Nobody listening is not an error
+                                let _ = metadata.send(document);
+                            }
+                        } else {
+                            warn!("Received RTP for unknown stream ID: {}", stream_id);
+                        }
+                    }
+                    Ok(PacketItem::Rtcp(rtcp)) => {
+                        debug!(
+                            "Received RTCP compound packet from stream {}",
+                            rtcp.stream_id()
+                        );
+                        for pkt in rtcp.pkts() {
+                            match pkt.as_typed() {
+                                Ok(Some(retina::rtcp::TypedPacketRef::SenderReport(sr))) => {
+                                    debug!(
+                                        "  RTCP SR: ssrc={:#x}, ntp={}, rtp={}",
+                                        sr.ssrc(),
+                                        sr.ntp_timestamp().0,
+                                        sr.rtp_timestamp()
+                                    );
+                                    let media = if video_track
+                                        .as_ref()
+                                        .is_some_and(|(index, _)| *index == rtcp.stream_id())
+                                    {
+                                        Some(Media::Video)
+                                    } else if audio_track
+                                        .as_ref()
+                                        .is_some_and(|(index, _)| *index == rtcp.stream_id())
+                                    {
+                                        Some(Media::Audio)
+                                    } else {
+                                        None
+                                    };
+                                    if let (Some(av_sync), Some(media)) = (&av_sync, media) {
+                                        av_sync.sender_report(
+                                            media,
+                                            sr.ntp_timestamp().0,
+                                            sr.rtp_timestamp(),
+                                        );
+                                    }
+                                }
+                                Ok(Some(retina::rtcp::TypedPacketRef::ReceiverReport(rr))) => {
+                                    debug!("  RTCP RR: ssrc={:#x}", rr.ssrc());
+                                }
+                                Ok(Some(_)) => {
+                                    debug!("  RTCP: other typed packet");
+                                }
+                                Ok(None) => {
+                                    debug!("  RTCP: payload_type={}", pkt.payload_type());
+                                }
+                                Err(e) => {
+                                    warn!("  RTCP parse error: {}", e);
+                                }
+                            }
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("Error receiving packet: {:?}", e);
+                        errors += 1;
+                    }
+                }
+            }
+
+            drop(session);
+            await_teardown(&spec.name, &session_group).await;
+            control.ended.send_replace(true);
+            control.stopped.notify_one();
+        });
+    }
+
+    Ok(Stream {
+        info,
+        capabilities,
+        video_track,
+        audio_track,
+        events,
+        metadata,
+        stats,
+        keyframe_requests,
+        control,
+        viewers,
+        startup,
+        hls,
+        faults,
+    })
+}
+
+/// Starts `spec` like [`start`] at boot: once its host name resolves (with
+/// `wait-for-dns`), and retrying failed connections up to its attempts.
+pub async fn start_at_boot(spec: &SourceSpec, source: &Source) -> anyhow::Result<Stream> {
+    let interval = Duration::from_secs(source.startup_retry_interval);
+    if spec.wait_for_dns || source.wait_for_dns {
+        wait_for_dns(spec, interval).await;
+    }
+
+    let attempts = spec.attempts.unwrap_or(source.startup_attempts);
+    let mut attempt = 1;
+    loop {
+        match start(spec, source).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) if attempts == 0 || attempt < attempts => {
+                warn!(
+                    "[{}] Connection attempt {} failed, retrying in {:?}: {:#}",
+                    spec.name, attempt, interval, e
+                );
+                attempt += 1;
+                tokio::time::sleep(interval).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Starts the source of `spec` afresh whenever its camera stops describing the
+/// streams its tracks were built for, e.g. after a codec or profile change.
+/// Its viewers are hung up on, and new ones are asked to retry while it
+/// reconnects. Ends with the source.
+pub fn spawn_rebuilder(state: &AppState, spec: SourceSpec) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        while let Some(stream) = state.stream(&spec.name) {
+            let mut ended = stream.control.ended.subscribe();
+            tokio::select! {
+                biased;
+                _ = stream.control.rebuild.notified() => {}
+                _ = ended.wait_for(|ended| *ended) => return,
+            }
+            {
+                let mut streams = state.streams.write().unwrap();
+                // Removed, or replaced by a source of the same name, meanwhile
+                if !streams
+                    .get(&spec.name)
+                    .is_some_and(|current| Arc::ptr_eq(current, &stream))
+                {
+                    return;
+                }
+                streams.remove(&spec.name);
+                state.connecting.write().unwrap().insert(spec.name.clone());
+            }
+            warn!(
+                "🔁 [{}] The camera's streams changed, starting the source afresh",
+                spec.name
+            );
+            let viewers = shutdown::close_source(&state, &stream).await;
+            drop(stream);
+
+            // `0` keeps trying
+            let restarted = start_at_boot(
+                &SourceSpec {
+                    attempts: Some(0),
+                    ..spec.clone()
+                },
+                &state.options,
+            )
+            .await;
+            state.connecting.write().unwrap().remove(&spec.name);
+            match restarted {
+                Ok(stream) => {
+                    let control = stream.control.clone();
+                    if !state.add_stream(stream) {
+                        warn!(
+                            "[{}] A source of the same name was added meanwhile",
+                            spec.name
+                        );
+                        control.stop.notify_one();
+                        return;
+                    }
+                    info!(
+                        "[{}] Source rebuilt, {} viewer(s) were disconnected",
+                        spec.name, viewers
+                    );
+                }
+                Err(e) => {
+                    error!("[{}] Giving up on the source: {:#}", spec.name, e);
+                    return;
+                }
+            }
+        }
+    });
+}
+
+// Polls until the camera's host name resolves, e.g. while the site's DNS and
+// DHCP come back after a power cycle
+async fn wait_for_dns(spec: &SourceSpec, interval: Duration) {
+    let Some(host) = spec.url.host_str() else {
+        return;
+    };
+    let port = spec.url.port().unwrap_or(554);
+    let mut waiting = false;
+    while tokio::net::lookup_host((host, port)).await.is_err() {
+        if !waiting {
+            info!("⏳ [{}] Waiting for {} to resolve", spec.name, host);
+            waiting = true;
+        }
+        tokio::time::sleep(interval).await;
+    }
+    if waiting {
+        info!("[{}] {} resolves now", spec.name, host);
+    }
+}
+
+/// Waits for the TEARDOWNs of the source's dropped sessions; dropping a
+/// session starts its TEARDOWN (per `--teardown`).
+async fn await_teardown(name: &str, session_group: &SessionGroup) {
+    match tokio::time::timeout(TEARDOWN_TIMEOUT, session_group.await_teardown()).await {
+        Ok(Ok(())) => debug!("[{}] RTSP session torn down", name),
+        Ok(Err(e)) => warn!("[{}] RTSP TEARDOWN failed: {}", name, e),
+        Err(_) => warn!("[{}] RTSP TEARDOWN timed out", name),
+    }
+}
+
+/// Capped exponential backoff between reconnects to a camera.
+struct Backoff {
+    next: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            next: RECONNECT_BACKOFF_MIN,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before the next attempt; each call doubles the one after.
+    fn next(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(RECONNECT_BACKOFF_MAX);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.next = RECONNECT_BACKOFF_MIN;
+    }
+}
+
+async fn describe(
+    spec: &SourceSpec,
+    teardown: TeardownPolicy,
+    session_group: &Arc<SessionGroup>,
+) -> anyhow::Result<Session<Described>> {
+    let creds = match (spec.username.clone(), spec.password.clone()) {
+        (Some(user), pass) => Some(retina::client::Credentials {
+            username: user,
+            password: pass.unwrap_or_default(),
+        }),
+
+        _ => None,
+    };
+
+    Ok(Session::describe(
+        spec.url.clone().into(),
+        retina::client::SessionOptions::default()
+            .creds(creds)
+            .teardown(teardown)
+            .session_group(session_group.clone())
+            .user_agent("RTSP to WebRTC example".to_owned()),
+    )
+    .await?)
+}
+
+/// Media type and encoding of the forwarded streams, by index, as first
+/// described by the camera.
+struct Layout(Vec<(usize, String)>);
+
+impl Layout {
+    fn of(session: &Session<Described>, indices: &[usize]) -> Self {
+        Self(
+            indices
+                .iter()
+                .map(|&index| (index, Self::key(&session.streams()[index])))
+                .collect(),
+        )
+    }
+
+    fn key(stream: &retina::client::Stream) -> String {
+        format!("{}/{}", stream.media(), stream.encoding_name())
+    }
+
+    /// Fails if the camera now describes different streams at those indices
+    /// (e.g. after a configuration change), which only new tracks can follow.
+    fn check(&self, session: &Session<Described>) -> anyhow::Result<()> {
+        for (index, expected) in &self.0 {
+            let found = session.streams().get(*index).map(Self::key);
+            if found.as_ref() != Some(expected) {
+                anyhow::bail!(
+                    "stream layout changed: #{} is {} instead of {}",
+                    index,
+                    found.as_deref().unwrap_or("missing"),
+                    expected
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Describes a camera's video stream for the catalog.
+fn video_layer(stream: &retina::client::Stream) -> VideoLayer {
+    use retina::codec::ParametersRef;
+
+    let (width, height) = match stream.parameters() {
+        Some(ParametersRef::Video(v)) => {
+            let (width, height) = v.pixel_dimensions();
+            (Some(width), Some(height))
+        }
+        _ => (None, None),
+    };
+    VideoLayer {
+        codec: format!("video/{}", stream.encoding_name()),
+        width,
+        height,
+        framerate: stream.framerate(),
+    }
+}
+
+/// Sets up the chosen video, audio and metadata streams and starts playing.
+async fn play(
+    mut session: Session<Described>,
+    transport: &Transport,
+    indices: &[usize],
+    startup: &StartupTimer,
+) -> anyhow::Result<Session<Playing>> {
+    for &index in indices {
+        session
+            .setup(index, SetupOptions::default().transport(transport.clone()))
+            .await?;
+    }
+    startup.mark(Phase::Setup);
+
+    let session = session.play(retina::client::PlayOptions::default()).await?;
+    startup.mark(Phase::Play);
+    Ok(session)
+}
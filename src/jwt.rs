@@ -0,0 +1,312 @@
+use std::{collections::HashMap, time::Duration};
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, jwk::JwkSet};
+use serde::Deserialize;
+use tokio::{
+    sync::{Mutex, RwLock},
+    time::Instant,
+};
+use tracing::{debug, info};
+
+use crate::auth::{Principal, Role};
+
+// Unknown key ids trigger a JWKS refetch at most this often
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+pub enum JwtError {
+    #[error(transparent)]
+    Jwt(#[from] jsonwebtoken::errors::Error),
+    #[error("failed to fetch signing keys: {0}")]
+    Fetch(#[from] reqwest::Error),
+    #[error("no signing key with id {0:?}")]
+    UnknownKey(Option<String>),
+    #[error("unsupported signing algorithm {0:?}")]
+    UnsupportedAlgorithm(Algorithm),
+}
+
+#[derive(Deserialize)]
+struct OpenIdConfiguration {
+    jwks_uri: url::Url,
+}
+
+/// Validates viewer JWTs against the signing keys published by an OpenID
+/// Connect issuer.
+pub struct JwtValidator {
+    issuer: String,
+    audiences: Vec<String>,
+    source_claim: Option<String>,
+    jwks_url: Mutex<Option<url::Url>>,
+    keys: RwLock<HashMap<String, DecodingKey>>,
+    last_refresh: Mutex<Option<Instant>>,
+    client: reqwest::Client,
+}
+
+impl JwtValidator {
+    /// `jwks_url` overrides the key set location found via OIDC discovery.
+    pub fn new(
+        issuer: url::Url,
+        jwks_url: Option<url::Url>,
+        audiences: Vec<String>,
+        source_claim: Option<String>,
+    ) -> Self {
+        Self {
+            issuer: issuer.as_str().trim_end_matches('/').to_owned(),
+            audiences,
+            source_claim,
+            jwks_url: Mutex::new(jwks_url),
+            keys: RwLock::new(HashMap::new()),
+            last_refresh: Mutex::new(None),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Checks signature, issuer, audience and expiry, granting viewer access.
+    ///
+    /// When a source claim is configured, it limits the sources the token may
+    /// watch; it holds either an array of names or a space-separated string.
+    pub async fn validate(&self, token: &str) -> Result<Principal, JwtError> {
+        let header = jsonwebtoken::decode_header(token)?;
+        if !matches!(
+            header.alg,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512
+                | Algorithm::ES256
+                | Algorithm::ES384
+                | Algorithm::EdDSA
+        ) {
+            return Err(JwtError::UnsupportedAlgorithm(header.alg));
+        }
+
+        let kid = header.kid.clone().unwrap_or_default();
+        let key = match self.keys.read().await.get(&kid).cloned() {
+            Some(key) => key,
+            None => {
+                self.refresh().await?;
+                self.keys
+                    .read()
+                    .await
+                    .get(&kid)
+                    .cloned()
+                    .ok_or(JwtError::UnknownKey(header.kid))?
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer, &format!("{}/", self.issuer)]);
+        if self.audiences.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.audiences);
+        }
+
+        let claims =
+            jsonwebtoken::decode::<HashMap<String, serde_json::Value>>(token, &key, &validation)?
+                .claims;
+
+        let sources = self
+            .source_claim
+            .as_ref()
+            .map(|name| match claims.get(name) {
+                Some(serde_json::Value::Array(values)) => values
+                    .iter()
+                    .filter_map(|v| v.as_str().map(str::to_owned))
+                    .collect(),
+                Some(serde_json::Value::String(value)) => {
+                    value.split_whitespace().map(str::to_owned).collect()
+                }
+                _ => Vec::new(),
+            });
+
+        let subject = claims
+            .get("sub")
+            .and_then(|sub| sub.as_str())
+            .unwrap_or_default();
+
+        Ok(Principal {
+            id: format!("jwt:{}", subject),
+            role: Role::Viewer,
+            sources,
+            relay_only: false,
+            substream_only: false,
+            av1: false,
+        })
+    }
+
+    async fn refresh(&self) -> Result<(), JwtError> {
+        let mut last_refresh = self.last_refresh.lock().await;
+        if last_refresh.is_some_and(|at| at.elapsed() < JWKS_REFRESH_INTERVAL) {
+            return Ok(());
+        }
+        *last_refresh = Some(Instant::now());
+
+        let jwks_url = {
+            let mut jwks_url = self.jwks_url.lock().await;
+            match jwks_url.as_ref() {
+                Some(url) => url.clone(),
+                None => {
+                    let discovery = format!("{}/.well-known/openid-configuration", self.issuer);
+                    let config: OpenIdConfiguration = self
+                        .client
+                        .get(discovery)
+                        .send()
+                        .await?
+                        .error_for_status()?
+                        .json()
+                        .await?;
+                    debug!("Discovered JWKS at {}", config.jwks_uri);
+                    jwks_url.insert(config.jwks_uri).clone()
+                }
+            }
+        };
+
+        let jwks: JwkSet = self
+            .client
+            .get(jwks_url.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let keys: HashMap<String, DecodingKey> = jwks
+            .keys
+            .iter()
+            .filter_map(|jwk| {
+                let key = DecodingKey::from_jwk(jwk).ok()?;
+                Some((jwk.common.key_id.clone().unwrap_or_default(), key))
+            })
+            .collect();
+
+        info!("Loaded {} signing keys from {}", keys.len(), jwks_url);
+        *self.keys.write().await = keys;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{EncodingKey, Header};
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    const ISSUER: &str = "https://id.example.com";
+
+    // A validator trusting one fresh Ed25519 key under `kid`, and that key to
+    // sign with; the key set counts as just fetched, so nothing goes online
+    async fn validator(source_claim: Option<&str>) -> (JwtValidator, EncodingKey) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let validator = JwtValidator::new(
+            ISSUER.parse().unwrap(),
+            None,
+            vec!["gateway".to_owned()],
+            source_claim.map(str::to_owned),
+        );
+        validator.keys.write().await.insert(
+            "kid".to_owned(),
+            DecodingKey::from_ed_der(pair.public_key().as_ref()),
+        );
+        *validator.last_refresh.lock().await = Some(Instant::now());
+        (validator, EncodingKey::from_ed_der(pkcs8.as_ref()))
+    }
+
+    fn sign(key: &EncodingKey, kid: &str, claims: serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(kid.to_owned());
+        jsonwebtoken::encode(&header, &claims, key).unwrap()
+    }
+
+    fn claims() -> serde_json::Value {
+        json!({
+            "iss": ISSUER,
+            "aud": "gateway",
+            "sub": "alice",
+            "exp": jsonwebtoken::get_current_timestamp() + 60,
+        })
+    }
+
+    #[tokio::test]
+    async fn grants_viewer_access() {
+        let (validator, key) = validator(None).await;
+        let principal = validator
+            .validate(&sign(&key, "kid", claims()))
+            .await
+            .unwrap();
+        assert_eq!(principal.id, "jwt:alice");
+        assert_eq!(principal.role, Role::Viewer);
+        assert!(principal.can_view("front"));
+
+        // The issuer may or may not end in a slash
+        let mut slashed = claims();
+        slashed["iss"] = json!(format!("{}/", ISSUER));
+        assert!(
+            validator
+                .validate(&sign(&key, "kid", slashed))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_sources_by_claim() {
+        let (validator, key) = validator(Some("cams")).await;
+        for cams in [json!(["front", "back"]), json!("front back")] {
+            let mut claims = claims();
+            claims["cams"] = cams;
+            let principal = validator
+                .validate(&sign(&key, "kid", claims))
+                .await
+                .unwrap();
+            assert!(principal.can_view("back"));
+            assert!(!principal.can_view("garage"));
+        }
+        let principal = validator
+            .validate(&sign(&key, "kid", claims()))
+            .await
+            .unwrap();
+        assert!(!principal.can_view("front"));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_tokens() {
+        let (validator, key) = validator(None).await;
+        for (field, value) in [
+            ("iss", json!("https://evil.example.com")),
+            ("aud", json!("elsewhere")),
+            ("exp", json!(jsonwebtoken::get_current_timestamp() - 3600)),
+        ] {
+            let mut claims = claims();
+            claims[field] = value;
+            assert!(matches!(
+                validator.validate(&sign(&key, "kid", claims)).await,
+                Err(JwtError::Jwt(_))
+            ));
+        }
+
+        assert!(matches!(
+            validator.validate(&sign(&key, "other", claims())).await,
+            Err(JwtError::UnknownKey(Some(kid))) if kid == "other"
+        ));
+
+        let shared = jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &claims(),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        assert!(matches!(
+            validator.validate(&shared).await,
+            Err(JwtError::UnsupportedAlgorithm(Algorithm::HS256))
+        ));
+    }
+}
@@ -0,0 +1,87 @@
+//! RTSP to WebRTC gateway as a library: [`Gateway`] pulls cameras over RTSP
+//! and serves them to browsers over WHEP, either on its own listener or as an
+//! [`axum::Router`] merged into another application.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), rtsp_to_webrtc::GatewayError> {
+//! let gateway = rtsp_to_webrtc::Gateway::builder()
+//!     .source("name=door,url=rtsp://10.0.0.5/stream1".parse().unwrap())
+//!     .listen(([0, 0, 0, 0], 8080).into())
+//!     .build()
+//!     .await?;
+//! gateway.serve().await
+//! # }
+//! ```
+
+mod abr;
+mod acme;
+mod alerts;
+mod api;
+mod assets;
+mod audio;
+mod auth;
+#[cfg(feature = "av1")]
+mod av1;
+mod avsync;
+mod backlog;
+mod callback;
+mod candidates;
+mod chaos;
+pub mod cli;
+mod codec;
+mod compat;
+pub mod config;
+mod errors;
+mod events;
+mod fanout;
+mod gateway;
+mod gop;
+pub mod health;
+mod hls;
+mod ids;
+mod ingest;
+mod jwt;
+pub mod logging;
+mod metadata;
+mod mp4;
+mod mqtt;
+mod net;
+mod onvif;
+mod packet;
+mod pairing;
+mod persist;
+mod pool;
+mod record;
+mod redact;
+mod restamp;
+mod rtx;
+pub mod runtime;
+mod sdp;
+mod security;
+mod segment;
+mod sframe;
+mod shutdown;
+mod silence;
+#[cfg(feature = "snapshot")]
+mod snapshot;
+mod speedtest;
+mod sse;
+mod startup;
+mod state;
+mod stats;
+mod store;
+mod telemetry;
+mod timeline;
+mod tls;
+pub mod tokens;
+#[cfg(feature = "transcode")]
+mod transcode;
+mod trickle;
+mod tsdb;
+mod watchdog;
+mod whep;
+mod whip;
+mod ws;
+
+pub use cli::{Source, SourceSpec};
+pub use gateway::{Gateway, GatewayBuilder, GatewayError};
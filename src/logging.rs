@@ -0,0 +1,150 @@
+use std::fmt;
+
+use serde_json::{Map, Value};
+use tracing::{
+    Event, Subscriber,
+    field::{Field, Visit},
+};
+use tracing_subscriber::{
+    EnvFilter,
+    fmt::{
+        FmtContext, FormatEvent, FormatFields, FormattedFields,
+        format::Writer,
+        time::{FormatTime, SystemTime},
+    },
+    registry::LookupSpan,
+};
+
+/// How log lines are written to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Colored, human-readable lines.
+    #[default]
+    Pretty,
+    /// One JSON object per line, for Loki, ELK and the like.
+    Json,
+}
+
+/// Checks a `--log-level` / `RUST_LOG` filter such as `info,webrtc=warn`.
+pub fn parse_filter(filter: &str) -> Result<String, String> {
+    EnvFilter::try_new(filter)
+        .map(|_| filter.to_owned())
+        .map_err(|e| e.to_string())
+}
+
+/// Installs the global subscriber; `filter` has been checked by `parse_filter`.
+pub fn init(format: LogFormat, filter: &str) {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(filter))
+        .with_level(true);
+    match format {
+        LogFormat::Pretty => builder.with_ansi(true).init(),
+        LogFormat::Json => builder.with_ansi(false).event_format(JsonFormat).init(),
+    }
+}
+
+/// Writes events as `{"timestamp", "level", "target", "message", "fields",
+/// "spans"}`, leaving out what is empty.
+struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+
+        let mut fields = JsonFields::default();
+        event.record(&mut fields);
+
+        let metadata = event.metadata();
+        let mut line = Map::new();
+        line.insert("timestamp".to_owned(), Value::String(timestamp));
+        line.insert(
+            "level".to_owned(),
+            Value::String(metadata.level().as_str().to_owned()),
+        );
+        line.insert(
+            "target".to_owned(),
+            Value::String(metadata.target().to_owned()),
+        );
+        if let Some(message) = fields.message {
+            line.insert("message".to_owned(), Value::String(message));
+        }
+        if !fields.fields.is_empty() {
+            line.insert("fields".to_owned(), Value::Object(fields.fields));
+        }
+
+        // Spans with the fields they were opened with, outermost first
+        let spans: Vec<Value> = ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+            .map(|span| {
+                let mut entry = Map::new();
+                entry.insert("name".to_owned(), Value::String(span.name().to_owned()));
+                if let Some(fields) = span.extensions().get::<FormattedFields<N>>()
+                    && !fields.is_empty()
+                {
+                    entry.insert("fields".to_owned(), Value::String(fields.to_string()));
+                }
+                Value::Object(entry)
+            })
+            .collect();
+        if !spans.is_empty() {
+            line.insert("spans".to_owned(), Value::Array(spans));
+        }
+
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+#[derive(Default)]
+struct JsonFields {
+    message: Option<String>,
+    fields: Map<String, Value>,
+}
+
+impl JsonFields {
+    fn insert(&mut self, field: &Field, value: Value) {
+        match (field.name(), value) {
+            ("message", Value::String(message)) => self.message = Some(message),
+            (name, value) => {
+                self.fields.insert(name.to_owned(), value);
+            }
+        }
+    }
+}
+
+impl Visit for JsonFields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, Value::String(format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, Value::String(value.to_owned()));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, Value::Bool(value));
+    }
+}
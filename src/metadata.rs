@@ -0,0 +1,72 @@
+use crate::onvif::{element, elements};
+
+/// Data channel label on which viewers receive the source's metadata documents
+/// instead of its events.
+pub const METADATA_LABEL: &str = "metadata";
+
+// RTP metadata encodings forwarded to viewers, in order of preference
+pub const METADATA_ENCODINGS: &[&str] = &["vnd.onvif.metadata"];
+
+// Documents growing beyond this are assumed corrupt and thrown away
+const MAX_DOCUMENT_SIZE: usize = 256 * 1024;
+
+/// Reassembles metadata documents (e.g. ONVIF analytics XML) that arrive split
+/// over several RTP packets, the last one carrying the marker bit.
+#[derive(Default)]
+pub struct Reassembler {
+    buf: Vec<u8>,
+    discarding: bool,
+    next_sequence: Option<u16>,
+}
+
+impl Reassembler {
+    /// Appends one packet's payload, returning the document it completes.
+    ///
+    /// A gap in sequence numbers drops the partial document it would corrupt.
+    pub fn push(&mut self, sequence: u16, payload: &[u8], mark: bool) -> Option<String> {
+        if self.next_sequence.is_some_and(|next| next != sequence) {
+            self.buf.clear();
+            self.discarding = true;
+        }
+        self.next_sequence = Some(sequence.wrapping_add(1));
+
+        if self.buf.len() + payload.len() > MAX_DOCUMENT_SIZE {
+            self.buf.clear();
+            self.discarding = true;
+        } else if !self.discarding {
+            self.buf.extend_from_slice(payload);
+        }
+
+        if !mark {
+            return None;
+        }
+
+        let document = std::mem::take(&mut self.buf);
+        if std::mem::take(&mut self.discarding) || document.is_empty() {
+            return None;
+        }
+        String::from_utf8(document).ok()
+    }
+}
+
+/// Whether a metadata document reports motion starting (`true`) or stopping,
+/// going by the `IsMotion` or `State` item of its ONVIF events on a motion
+/// topic, such as `tns1:RuleEngine/CellMotionDetector/Motion` or
+/// `tns1:VideoSource/MotionAlarm`. The document's last such event counts.
+pub fn motion(document: &str) -> Option<bool> {
+    let mut active = None;
+    for notification in elements(document, "NotificationMessage") {
+        if !element(notification.inner, "Topic").is_some_and(|topic| topic.inner.contains("Motion"))
+        {
+            continue;
+        }
+        for item in elements(notification.inner, "SimpleItem") {
+            if matches!(item.attr("Name"), Some("IsMotion" | "State"))
+                && let Some(value) = item.attr("Value")
+            {
+                active = Some(value.eq_ignore_ascii_case("true"));
+            }
+        }
+    }
+    active
+}
@@ -0,0 +1,517 @@
+// Just enough of ISO BMFF (ISO/IEC 14496-12) for fragmented MP4 recordings and
+// HLS: an init segment describing the tracks, then moof/mdat fragments.
+
+// Sample flags of trun entries (ISO/IEC 14496-12, 8.8.3.1)
+const SYNC_SAMPLE: u32 = 0x0200_0000;
+const NON_SYNC_SAMPLE: u32 = 0x0101_0000;
+
+// 16.16 fixed point identity matrix of mvhd and tkhd
+const UNITY_MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// What a track holds, as far as the init segment is concerned.
+#[derive(Clone)]
+pub enum TrackKind {
+    /// H.264 with its parameter sets (NAL units without start codes).
+    H264 {
+        sps: Vec<u8>,
+        pps: Vec<u8>,
+        width: u16,
+        height: u16,
+    },
+    Opus {
+        channels: u8,
+    },
+    /// AAC with its AudioSpecificConfig (ISO/IEC 14496-3, 1.6.2.1).
+    Aac {
+        config: Vec<u8>,
+        channels: u8,
+        sample_rate: u32,
+    },
+}
+
+pub struct Track {
+    pub id: u32,
+    pub timescale: u32,
+    pub kind: TrackKind,
+}
+
+pub struct Sample {
+    pub data: Vec<u8>,
+    pub duration: u32,
+    pub keyframe: bool,
+}
+
+/// The samples of one track in a fragment, starting at `decode_time` (in the
+/// track's timescale, since the start of the file).
+pub struct Run<'a> {
+    pub track_id: u32,
+    pub decode_time: u64,
+    pub samples: &'a [Sample],
+}
+
+/// `ftyp` and `moov` for `tracks`, to be followed by fragments.
+pub fn init_segment(tracks: &[Track]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_box(&mut buf, b"ftyp", |b| {
+        b.extend_from_slice(b"isom");
+        put_u32(b, 0x200);
+        for brand in [b"isom", b"iso6", b"mp41", b"avc1"] {
+            b.extend_from_slice(brand);
+        }
+    });
+    write_box(&mut buf, b"moov", |b| {
+        write_full_box(b, b"mvhd", 0, 0, |b| {
+            put_u32(b, 0); // creation time
+            put_u32(b, 0); // modification time
+            put_u32(b, 1000); // timescale
+            put_u32(b, 0); // duration: all in fragments
+            put_u32(b, 0x0001_0000); // rate
+            put_u16(b, 0x0100); // volume
+            b.extend_from_slice(&[0; 10]);
+            UNITY_MATRIX.iter().for_each(|&v| put_u32(b, v));
+            b.extend_from_slice(&[0; 24]);
+            put_u32(b, tracks.iter().map(|t| t.id).max().unwrap_or(0) + 1);
+        });
+        for track in tracks {
+            write_trak(b, track);
+        }
+        write_box(b, b"mvex", |b| {
+            for track in tracks {
+                write_full_box(b, b"trex", 0, 0, |b| {
+                    put_u32(b, track.id);
+                    put_u32(b, 1); // sample description index
+                    put_u32(b, 0); // duration
+                    put_u32(b, 0); // size
+                    put_u32(b, 0); // flags
+                });
+            }
+        });
+    });
+    buf
+}
+
+fn write_trak(b: &mut Vec<u8>, track: &Track) {
+    let (handler, name, width, height, volume): (&[u8; 4], &str, u16, u16, u16) = match &track.kind
+    {
+        TrackKind::H264 { width, height, .. } => (b"vide", "VideoHandler", *width, *height, 0),
+        TrackKind::Opus { .. } | TrackKind::Aac { .. } => (b"soun", "SoundHandler", 0, 0, 0x0100),
+    };
+    write_box(b, b"trak", |b| {
+        // Enabled, in movie
+        write_full_box(b, b"tkhd", 0, 0x3, |b| {
+            put_u32(b, 0); // creation time
+            put_u32(b, 0); // modification time
+            put_u32(b, track.id);
+            put_u32(b, 0);
+            put_u32(b, 0); // duration
+            b.extend_from_slice(&[0; 8]);
+            put_u16(b, 0); // layer
+            put_u16(b, 0); // alternate group
+            put_u16(b, volume);
+            put_u16(b, 0);
+            UNITY_MATRIX.iter().for_each(|&v| put_u32(b, v));
+            put_u32(b, (width as u32) << 16);
+            put_u32(b, (height as u32) << 16);
+        });
+        write_box(b, b"mdia", |b| {
+            write_full_box(b, b"mdhd", 0, 0, |b| {
+                put_u32(b, 0);
+                put_u32(b, 0);
+                put_u32(b, track.timescale);
+                put_u32(b, 0);
+                put_u16(b, 0x55c4); // "und"
+                put_u16(b, 0);
+            });
+            write_full_box(b, b"hdlr", 0, 0, |b| {
+                put_u32(b, 0);
+                b.extend_from_slice(handler);
+                b.extend_from_slice(&[0; 12]);
+                b.extend_from_slice(name.as_bytes());
+                b.push(0);
+            });
+            write_box(b, b"minf", |b| {
+                match &track.kind {
+                    TrackKind::H264 { .. } => write_full_box(b, b"vmhd", 0, 1, |b| {
+                        b.extend_from_slice(&[0; 8]);
+                    }),
+                    TrackKind::Opus { .. } | TrackKind::Aac { .. } => {
+                        write_full_box(b, b"smhd", 0, 0, |b| {
+                            put_u32(b, 0);
+                        })
+                    }
+                }
+                write_box(b, b"dinf", |b| {
+                    write_full_box(b, b"dref", 0, 0, |b| {
+                        put_u32(b, 1);
+                        // Media in the same file
+                        write_full_box(b, b"url ", 0, 1, |_| {});
+                    });
+                });
+                write_box(b, b"stbl", |b| {
+                    write_full_box(b, b"stsd", 0, 0, |b| {
+                        put_u32(b, 1);
+                        write_sample_entry(b, &track.kind);
+                    });
+                    // Samples are all in the fragments
+                    for kind in [b"stts", b"stsc", b"stco"] {
+                        write_full_box(b, kind, 0, 0, |b| put_u32(b, 0));
+                    }
+                    write_full_box(b, b"stsz", 0, 0, |b| {
+                        put_u32(b, 0);
+                        put_u32(b, 0);
+                    });
+                });
+            });
+        });
+    });
+}
+
+fn write_sample_entry(b: &mut Vec<u8>, kind: &TrackKind) {
+    match kind {
+        TrackKind::H264 {
+            sps,
+            pps,
+            width,
+            height,
+        } => write_box(b, b"avc1", |b| {
+            b.extend_from_slice(&[0; 6]);
+            put_u16(b, 1); // data reference index
+            b.extend_from_slice(&[0; 16]);
+            put_u16(b, *width);
+            put_u16(b, *height);
+            put_u32(b, 0x0048_0000); // 72 dpi
+            put_u32(b, 0x0048_0000);
+            put_u32(b, 0);
+            put_u16(b, 1); // frame count
+            b.extend_from_slice(&[0; 32]); // compressor name
+            put_u16(b, 0x0018); // depth
+            put_u16(b, 0xffff);
+            write_box(b, b"avcC", |b| {
+                b.push(1);
+                // Profile, constraints and level, as in the SPS
+                b.extend_from_slice(sps.get(1..4).unwrap_or(&[0; 3]));
+                b.push(0xff); // 4 byte NAL unit lengths
+                b.push(0xe1); // one SPS
+                put_u16(b, sps.len() as u16);
+                b.extend_from_slice(sps);
+                b.push(1); // one PPS
+                put_u16(b, pps.len() as u16);
+                b.extend_from_slice(pps);
+            });
+        }),
+        // Opus in ISO BMFF (https://opus-codec.org/docs/opus_in_isobmff.html)
+        TrackKind::Opus { channels } => write_box(b, b"Opus", |b| {
+            b.extend_from_slice(&[0; 6]);
+            put_u16(b, 1); // data reference index
+            b.extend_from_slice(&[0; 8]);
+            put_u16(b, *channels as u16);
+            put_u16(b, 16); // sample size
+            put_u32(b, 0);
+            put_u32(b, 48000 << 16);
+            write_box(b, b"dOps", |b| {
+                b.push(0); // version
+                b.push(*channels);
+                put_u16(b, 0); // pre-skip
+                put_u32(b, 48000); // input sample rate
+                put_u16(b, 0); // output gain
+                b.push(0); // channel mapping family
+            });
+        }),
+        // MPEG-4 audio in ISO BMFF (ISO/IEC 14496-14, 5.6)
+        TrackKind::Aac {
+            config,
+            channels,
+            sample_rate,
+        } => write_box(b, b"mp4a", |b| {
+            b.extend_from_slice(&[0; 6]);
+            put_u16(b, 1); // data reference index
+            b.extend_from_slice(&[0; 8]);
+            put_u16(b, *channels as u16);
+            put_u16(b, 16); // sample size
+            put_u32(b, 0);
+            put_u32(b, (*sample_rate).min(u16::MAX as u32) << 16);
+            write_full_box(b, b"esds", 0, 0, |b| {
+                // ES_Descriptor (ISO/IEC 14496-1, 7.2.6.5), sizes in one byte
+                // as the config is a few bytes
+                b.extend_from_slice(&[0x03, 23 + config.len() as u8]);
+                put_u16(b, 0); // ES id
+                b.push(0); // flags
+                // DecoderConfigDescriptor: MPEG-4 audio, audio stream
+                b.extend_from_slice(&[0x04, 15 + config.len() as u8, 0x40, 0x15]);
+                b.extend_from_slice(&[0; 3]); // buffer size
+                put_u32(b, 0); // max bitrate
+                put_u32(b, 0); // average bitrate
+                // DecoderSpecificInfo
+                b.extend_from_slice(&[0x05, config.len() as u8]);
+                b.extend_from_slice(config);
+                // SLConfigDescriptor: predefined for MP4
+                b.extend_from_slice(&[0x06, 1, 0x02]);
+            });
+        }),
+    }
+}
+
+/// One `moof` and `mdat` holding `runs`, numbered `sequence`.
+pub fn fragment(sequence: u32, runs: &[Run<'_>]) -> Vec<u8> {
+    // Data offsets point from the moof into the mdat, so the moof is sized
+    // first; offsets don't change its size
+    let moof_len = moof(sequence, runs, 0).len();
+    let mut buf = moof(sequence, runs, moof_len + 8);
+    let data_len: usize = runs
+        .iter()
+        .flat_map(|run| run.samples)
+        .map(|sample| sample.data.len())
+        .sum();
+    put_u32(&mut buf, (data_len + 8) as u32);
+    buf.extend_from_slice(b"mdat");
+    for sample in runs.iter().flat_map(|run| run.samples) {
+        buf.extend_from_slice(&sample.data);
+    }
+    buf
+}
+
+fn moof(sequence: u32, runs: &[Run<'_>], mut data_offset: usize) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_box(&mut buf, b"moof", |b| {
+        write_full_box(b, b"mfhd", 0, 0, |b| put_u32(b, sequence));
+        for run in runs {
+            write_box(b, b"traf", |b| {
+                // Default base is moof
+                write_full_box(b, b"tfhd", 0, 0x02_0000, |b| put_u32(b, run.track_id));
+                write_full_box(b, b"tfdt", 1, 0, |b| put_u64(b, run.decode_time));
+                // Data offset, sample duration, size and flags present
+                write_full_box(b, b"trun", 0, 0x0701, |b| {
+                    put_u32(b, run.samples.len() as u32);
+                    put_u32(b, data_offset as u32);
+                    for sample in run.samples {
+                        put_u32(b, sample.duration);
+                        put_u32(b, sample.data.len() as u32);
+                        put_u32(
+                            b,
+                            if sample.keyframe {
+                                SYNC_SAMPLE
+                            } else {
+                                NON_SYNC_SAMPLE
+                            },
+                        );
+                    }
+                });
+            });
+            data_offset += run
+                .samples
+                .iter()
+                .map(|sample| sample.data.len())
+                .sum::<usize>();
+        }
+    });
+    buf
+}
+
+fn write_box(buf: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = buf.len();
+    put_u32(buf, 0);
+    buf.extend_from_slice(kind);
+    body(buf);
+    let len = (buf.len() - start) as u32;
+    buf[start..start + 4].copy_from_slice(&len.to_be_bytes());
+}
+
+fn write_full_box(
+    buf: &mut Vec<u8>,
+    kind: &[u8; 4],
+    version: u8,
+    flags: u32,
+    body: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(buf, kind, |b| {
+        put_u32(b, (version as u32) << 24 | flags);
+        body(b);
+    });
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The boxes directly in `data`, which they must fill exactly
+    fn boxes(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut boxes = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            assert!(len >= 8 && len <= rest.len(), "bad box length {}", len);
+            boxes.push((rest[4..8].try_into().unwrap(), &rest[8..len]));
+            rest = &rest[len..];
+        }
+        boxes
+    }
+
+    // The body of the box at `path`, through containers, for every match
+    fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Vec<&'a [u8]> {
+        let Some((kind, rest)) = path.split_first() else {
+            return vec![data];
+        };
+        boxes(data)
+            .into_iter()
+            .filter(|(found, _)| found == *kind)
+            .flat_map(|(_, body)| find(body, rest))
+            .collect()
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    fn tracks() -> Vec<Track> {
+        vec![
+            Track {
+                id: 1,
+                timescale: 90000,
+                kind: TrackKind::H264 {
+                    sps: vec![0x67, 0x42, 0xc0, 0x1f, 0xda],
+                    pps: vec![0x68, 0xce, 0x3c, 0x80],
+                    width: 1280,
+                    height: 720,
+                },
+            },
+            Track {
+                id: 2,
+                timescale: 48000,
+                kind: TrackKind::Aac {
+                    config: vec![0x11, 0x90],
+                    channels: 2,
+                    sample_rate: 48000,
+                },
+            },
+        ]
+    }
+
+    #[test]
+    fn describes_tracks() {
+        let init = init_segment(&tracks());
+        let kinds: Vec<[u8; 4]> = boxes(&init).iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, [*b"ftyp", *b"moov"]);
+
+        let mvhd = find(&init, &[b"moov", b"mvhd"])[0];
+        assert_eq!(u32_at(mvhd, mvhd.len() - 4), 3); // next track id
+        let ids: Vec<u32> = find(&init, &[b"moov", b"trak", b"tkhd"])
+            .iter()
+            .map(|tkhd| u32_at(tkhd, 12))
+            .collect();
+        assert_eq!(ids, [1, 2]);
+        let trex: Vec<u32> = find(&init, &[b"moov", b"mvex", b"trex"])
+            .iter()
+            .map(|trex| u32_at(trex, 4))
+            .collect();
+        assert_eq!(trex, [1, 2]);
+
+        let timescales: Vec<u32> = find(&init, &[b"moov", b"trak", b"mdia", b"mdhd"])
+            .iter()
+            .map(|mdhd| u32_at(mdhd, 12))
+            .collect();
+        assert_eq!(timescales, [90000, 48000]);
+        let handlers: Vec<&[u8]> = find(&init, &[b"moov", b"trak", b"mdia", b"hdlr"])
+            .iter()
+            .map(|hdlr| &hdlr[8..12])
+            .collect();
+        assert_eq!(handlers, [b"vide", b"soun"]);
+    }
+
+    #[test]
+    fn writes_sample_entries() {
+        let init = init_segment(&tracks());
+        let stsd = &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsd"];
+        let entries = find(&init, stsd);
+
+        // avc1 keeps its 78 bytes of fields before avcC
+        let avc1 = find(&entries[0][8..], &[b"avc1"])[0];
+        assert_eq!(u32_at(avc1, 24), 1280 << 16 | 720);
+        let avcc = find(&avc1[78..], &[b"avcC"])[0];
+        assert_eq!(
+            avcc,
+            [
+                1, 0x42, 0xc0, 0x1f, 0xff, 0xe1, 0, 5, 0x67, 0x42, 0xc0, 0x1f, 0xda, 1, 0, 4, 0x68,
+                0xce, 0x3c, 0x80
+            ]
+        );
+
+        // mp4a keeps 28 bytes before esds, whose descriptors nest by length
+        let mp4a = find(&entries[1][8..], &[b"mp4a"])[0];
+        let esds = find(&mp4a[28..], &[b"esds"])[0];
+        let descriptor = &esds[4..];
+        assert_eq!(descriptor[0], 0x03);
+        assert_eq!(descriptor[1] as usize, descriptor.len() - 2);
+        assert_eq!(&descriptor[5..7], [0x04, 17]);
+        assert_eq!(&descriptor[20..24], [0x05, 2, 0x11, 0x90]);
+    }
+
+    #[test]
+    fn fragments_point_into_mdat() {
+        let video = [
+            Sample {
+                data: vec![1; 10],
+                duration: 3000,
+                keyframe: true,
+            },
+            Sample {
+                data: vec![2; 4],
+                duration: 3000,
+                keyframe: false,
+            },
+        ];
+        let audio = [Sample {
+            data: vec![3; 6],
+            duration: 1024,
+            keyframe: true,
+        }];
+        let fragment = fragment(
+            7,
+            &[
+                Run {
+                    track_id: 1,
+                    decode_time: 90000,
+                    samples: &video,
+                },
+                Run {
+                    track_id: 2,
+                    decode_time: 1 << 33,
+                    samples: &audio,
+                },
+            ],
+        );
+
+        let kinds: Vec<[u8; 4]> = boxes(&fragment).iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, [*b"moof", *b"mdat"]);
+        assert_eq!(u32_at(find(&fragment, &[b"moof", b"mfhd"])[0], 4), 7);
+        let decode_times: Vec<u64> = find(&fragment, &[b"moof", b"traf", b"tfdt"])
+            .iter()
+            .map(|tfdt| u64::from_be_bytes(tfdt[4..12].try_into().unwrap()))
+            .collect();
+        assert_eq!(decode_times, [90000, 1 << 33]);
+
+        // Offsets count from the start of the moof
+        let truns = find(&fragment, &[b"moof", b"traf", b"trun"]);
+        let video_offset = u32_at(truns[0], 8) as usize;
+        let audio_offset = u32_at(truns[1], 8) as usize;
+        assert_eq!(&fragment[video_offset..][..10], [1; 10]);
+        assert_eq!(&fragment[video_offset + 10..][..4], [2; 4]);
+        assert_eq!(&fragment[audio_offset..], [3; 6]);
+
+        // Count, offset, then duration, size and flags per sample
+        assert_eq!(u32_at(truns[0], 4), 2);
+        assert_eq!(u32_at(truns[0], 20), SYNC_SAMPLE);
+        assert_eq!(u32_at(truns[0], 32), NON_SYNC_SAMPLE);
+    }
+}
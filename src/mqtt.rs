@@ -0,0 +1,265 @@
+use std::{future::Future, pin::Pin, sync::atomic::Ordering, time::Duration};
+
+use rumqttc::{AsyncClient, Event as MqttEvent, LastWill, MqttOptions, Packet, QoS};
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+use crate::{
+    alerts::{Alert, Notifier},
+    events::Event,
+    state::AppState,
+};
+
+// Wait this long before polling again after losing the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum MqttUrlParseError {
+    #[error("invalid scheme, expected 'mqtt'")]
+    InvalidScheme,
+    #[error("MQTT URL has no host")]
+    MissingHost,
+    #[error(transparent)]
+    UrlParseError(#[from] url::ParseError),
+}
+
+/// Broker address, written as `mqtt://[user:password@]host[:port]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttUrl(pub url::Url);
+
+impl std::str::FromStr for MqttUrl {
+    type Err = MqttUrlParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = url::Url::parse(s)?;
+        if url.scheme() != "mqtt" {
+            return Err(MqttUrlParseError::InvalidScheme);
+        }
+        if url.host_str().is_none() {
+            return Err(MqttUrlParseError::MissingHost);
+        }
+        Ok(MqttUrl(url))
+    }
+}
+
+/// Home Assistant MQTT discovery settings.
+pub struct Discovery {
+    /// Discovery topic prefix, `homeassistant` by default in Home Assistant.
+    pub prefix: String,
+    /// Base URL the gateway is reachable at from Home Assistant's clients.
+    pub public_url: url::Url,
+}
+
+/// Connects to the broker, publishes every source's events to
+/// `{prefix}/{source}/events` and obeys commands sent to
+/// `{prefix}/{source}/control`:
+///
+/// - `restart` reconnects to the camera,
+/// - `privacy on` / `privacy off` stops and resumes forwarding to viewers.
+///
+/// `{prefix}/status` holds `online` while connected and `offline` otherwise.
+/// With `discovery`, every source is also announced to Home Assistant as a
+/// camera entity.
+pub fn spawn_mqtt(
+    state: AppState,
+    url: MqttUrl,
+    prefix: String,
+    discovery: Option<Discovery>,
+) -> MqttNotifier {
+    let url = url.0;
+    let mut options = MqttOptions::new(
+        format!("rtsp-to-webrtc-{}", &uuid::Uuid::new_v4().to_string()[..8]),
+        url.host_str().unwrap_or_default(),
+        url.port().unwrap_or(1883),
+    );
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        format!("{}/status", prefix),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if !url.username().is_empty() {
+        options.set_credentials(url.username(), url.password().unwrap_or_default());
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+
+    for stream in state.all_streams() {
+        spawn_event_publisher(
+            client.clone(),
+            format!("{}/{}/events", prefix, stream.info.name),
+            stream.events.subscribe(),
+        );
+    }
+
+    {
+        let client = client.clone();
+        let prefix = prefix.clone();
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                        info!(
+                            "📨 Connected to MQTT broker {}",
+                            url.host_str().unwrap_or_default()
+                        );
+                        // Subscriptions do not survive a reconnect
+                        let _ = client
+                            .subscribe(format!("{}/+/control", prefix), QoS::AtLeastOnce)
+                            .await;
+                        let _ = client
+                            .publish(
+                                format!("{}/status", prefix),
+                                QoS::AtLeastOnce,
+                                true,
+                                "online",
+                            )
+                            .await;
+                        if let Some(discovery) = &discovery {
+                            announce(&client, &state, &prefix, discovery).await;
+                        }
+                    }
+                    Ok(MqttEvent::Incoming(Packet::Publish(publish))) => {
+                        let command = String::from_utf8_lossy(&publish.payload);
+                        control(&state, &prefix, &publish.topic, command.trim());
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("MQTT connection error: {}", e);
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+    }
+
+    MqttNotifier { client, prefix }
+}
+
+/// Publishes retained Home Assistant discovery configs, one camera per source,
+/// with the WHEP URL as an entity attribute.
+async fn announce(client: &AsyncClient, state: &AppState, prefix: &str, discovery: &Discovery) {
+    for stream in state.all_streams() {
+        let name = &stream.info.name;
+        let object_id: String = format!("rtsp_to_webrtc_{}", name)
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+
+        let config = serde_json::json!({
+            "name": name,
+            "unique_id": object_id,
+            "topic": format!("{}/{}/image", prefix, name),
+            "availability_topic": format!("{}/status", prefix),
+            "json_attributes_topic": format!("{}/{}/attributes", prefix, name),
+            "device": {
+                "identifiers": [object_id],
+                "name": name,
+                "manufacturer": "rtsp-to-webrtc",
+            },
+        });
+        let attributes = serde_json::json!({
+            "whep_url": discovery
+                .public_url
+                .join(&format!("whep/{}", name))
+                .map(String::from)
+                .unwrap_or_default(),
+            "video_codec": stream.info.video_codec,
+            "audio_codec": stream.info.audio_codec,
+        });
+
+        for (topic, payload) in [
+            (
+                format!("{}/camera/{}/config", discovery.prefix, object_id),
+                config,
+            ),
+            (format!("{}/{}/attributes", prefix, name), attributes),
+        ] {
+            if let Err(e) = client
+                .publish(topic, QoS::AtLeastOnce, true, payload.to_string())
+                .await
+            {
+                warn!("Home Assistant discovery for '{}' failed: {}", name, e);
+            }
+        }
+    }
+}
+
+fn control(state: &AppState, prefix: &str, topic: &str, command: &str) {
+    let Some(name) = topic
+        .strip_prefix(prefix)
+        .and_then(|topic| topic.strip_prefix('/'))
+        .and_then(|topic| topic.strip_suffix("/control"))
+    else {
+        return;
+    };
+    let Some(stream) = state.stream(name) else {
+        warn!("MQTT command '{}' for unknown source '{}'", command, name);
+        return;
+    };
+
+    match command {
+        "restart" => stream.control.restart.notify_one(),
+        "privacy on" | "privacy off" => {
+            let private = command == "privacy on";
+            stream.control.privacy.store(private, Ordering::Relaxed);
+            info!(
+                "🙈 [{}] Privacy mode {}",
+                name,
+                if private { "on" } else { "off" }
+            );
+        }
+        _ => warn!("Unsupported MQTT command '{}' for '{}'", command, name),
+    }
+}
+
+fn spawn_event_publisher(
+    client: AsyncClient,
+    topic: String,
+    mut events: broadcast::Receiver<Event>,
+) {
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("MQTT publisher lagged, {} events skipped", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            let Ok(json) = serde_json::to_vec(&event) else {
+                continue;
+            };
+            if let Err(e) = client.publish(&topic, QoS::AtLeastOnce, false, json).await {
+                debug!("MQTT publish to {} failed: {}", topic, e);
+            }
+        }
+    });
+}
+
+/// Publishes alerts to `{prefix}/{source}/alerts`.
+pub struct MqttNotifier {
+    client: AsyncClient,
+    prefix: String,
+}
+
+impl Notifier for MqttNotifier {
+    fn notify<'a>(
+        &'a self,
+        alert: &'a Alert,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            self.client
+                .publish(
+                    format!("{}/{}/alerts", self.prefix, alert.source),
+                    QoS::AtLeastOnce,
+                    false,
+                    serde_json::to_vec(alert)?,
+                )
+                .await?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use webrtc::ice::udp_mux::{UDPMuxDefault, UDPMuxParams};
+
+use crate::cli::Dscp;
+
+/// Binds the single UDP socket all ICE traffic is multiplexed over.
+///
+/// Binds to all IPv4 addresses unless `ip` is given; port `0` picks an ephemeral
+/// port. When `dscp` is set, outgoing packets are marked with it.
+///
+/// A fixed port can be bound again on a more specific address, which then
+/// gets the packets sent to that address.
+pub fn bind_udp_mux(
+    ip: Option<IpAddr>,
+    port: u16,
+    dscp: Option<Dscp>,
+) -> std::io::Result<Arc<UDPMuxDefault>> {
+    let addr = SocketAddr::new(ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), port);
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if let Some(dscp) = dscp {
+        // DSCP occupies the upper six bits of the TOS / traffic class byte
+        let tos = u32::from(dscp.0) << 2;
+        match addr {
+            SocketAddr::V4(_) => socket.set_tos_v4(tos)?,
+            SocketAddr::V6(_) => socket.set_tclass_v6(tos)?,
+        }
+    }
+    socket.set_nonblocking(true)?;
+    // Sources with `interface=` listen on the same port on their interface
+    if port != 0 {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&addr.into())?;
+
+    let socket = tokio::net::UdpSocket::from_std(socket.into())?;
+    Ok(UDPMuxDefault::new(UDPMuxParams::new(socket)))
+}
+
+/// The address of `interface`: itself if it is one, else the interface of that
+/// name's first IPv4 address, or its first IPv6 one.
+pub fn interface_addr(interface: &str) -> std::io::Result<IpAddr> {
+    if let Ok(ip) = interface.parse() {
+        return Ok(ip);
+    }
+    let addrs: Vec<IpAddr> = webrtc::util::ifaces::ifaces()?
+        .into_iter()
+        .filter(|iface| iface.name == interface)
+        .filter_map(|iface| iface.addr.map(|addr| addr.ip()))
+        .collect();
+    addrs
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .or(addrs.first())
+        .copied()
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no address on interface {}", interface),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_interfaces_and_addresses() {
+        assert_eq!(
+            interface_addr("192.0.2.1").unwrap(),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))
+        );
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            interface_addr("lo").unwrap(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        );
+        assert!(interface_addr("no-such-interface0").is_err());
+    }
+
+    #[tokio::test]
+    async fn interfaces_share_the_fixed_port() {
+        let port = std::net::UdpSocket::bind("0.0.0.0:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let _shared = bind_udp_mux(None, port, None).unwrap();
+        let _interface = bind_udp_mux(Some(IpAddr::V4(Ipv4Addr::LOCALHOST)), port, None).unwrap();
+    }
+}
@@ -0,0 +1,728 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use ring::digest;
+use tracing::{debug, info, warn};
+
+use crate::cli::{RTSPUrl, SourceSpec};
+
+// WS-Discovery's port, which cameras also answer unicast probes on
+const DISCOVERY_PORT: u16 = 3702;
+// Cameras that answer probes do so within a few hundred milliseconds
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum OnvifCameraParseError {
+    #[error("unknown ONVIF option '{0}'")]
+    UnknownOption(String),
+    #[error("ONVIF camera is missing 'host'")]
+    MissingHost,
+}
+
+/// A camera whose stream URL is looked up over ONVIF, written as
+/// comma-separated options: `host=cam.local[:port],username=..,password=..
+/// [,name=..,profile=..]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OnvifCamera {
+    /// Host name or address, with the port of its web service if it isn't 80.
+    pub host: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Name the source is listed under; the host by default.
+    pub name: Option<String>,
+    /// Token or name of the media profile to stream; by default the first
+    /// H.264 or H.265 one.
+    pub profile: Option<String>,
+}
+
+impl std::str::FromStr for OnvifCamera {
+    type Err = OnvifCameraParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut camera = OnvifCamera {
+            host: String::new(),
+            username: None,
+            password: None,
+            name: None,
+            profile: None,
+        };
+        for option in s.split(',').filter(|o| !o.is_empty()) {
+            match option.split_once('=') {
+                Some(("host", value)) => camera.host = value.to_owned(),
+                Some(("username", value)) => camera.username = Some(value.to_owned()),
+                Some(("password", value)) => camera.password = Some(value.to_owned()),
+                Some(("name", value)) => camera.name = Some(value.to_owned()),
+                Some(("profile", value)) => camera.profile = Some(value.to_owned()),
+                _ => return Err(OnvifCameraParseError::UnknownOption(option.to_owned())),
+            }
+        }
+        if camera.host.is_empty() {
+            return Err(OnvifCameraParseError::MissingHost);
+        }
+        Ok(camera)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum OnvifError {
+    #[error("request to {url} failed: {error}")]
+    Http { url: String, error: reqwest::Error },
+    #[error("camera refused the credentials ({0})")]
+    Unauthorized(String),
+    #[error("camera answered {action} with a fault: {reason}")]
+    Fault {
+        action: &'static str,
+        reason: String,
+    },
+    #[error("camera has no media profiles")]
+    NoProfiles,
+    #[error("camera has no media profile '{wanted}' (available: {available})")]
+    UnknownProfile { wanted: String, available: String },
+    #[error("camera returned no stream URI")]
+    MissingUri,
+    #[error("camera returned an unusable stream URI '{uri}': {error}")]
+    InvalidUri {
+        uri: String,
+        error: crate::cli::RTSPUrlParseError,
+    },
+}
+
+/// A media profile as listed by `GetProfiles`.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub token: String,
+    pub name: String,
+    /// Video encoding (`H264`, `H265`, `JPEG`...), if the profile has video.
+    pub encoding: Option<String>,
+    pub resolution: Option<(u32, u32)>,
+}
+
+impl Profile {
+    fn is_supported(&self) -> bool {
+        self.encoding
+            .as_deref()
+            .is_some_and(|encoding| matches!(encoding, "H264" | "H265"))
+    }
+}
+
+/// Looks up the RTSP URL of an ONVIF camera and turns it into a source.
+pub async fn resolve(camera: &OnvifCamera) -> Result<SourceSpec, OnvifError> {
+    let device_url = device_service(&camera.host).await;
+    debug!("[{}] ONVIF device service at {}", camera.host, device_url);
+    let client = Client {
+        http: reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("failed to build HTTP client"),
+        username: camera.username.clone(),
+        password: camera.password.clone(),
+        clock_offset: 0,
+    };
+    let client = client.with_camera_clock(&device_url).await;
+
+    let capabilities = client
+        .call(
+            &device_url,
+            "GetCapabilities",
+            r#"<tds:GetCapabilities xmlns:tds="http://www.onvif.org/ver10/device/wsdl"><tds:Category>Media</tds:Category></tds:GetCapabilities>"#,
+        )
+        .await?;
+    let media_url = element(&capabilities, "Media")
+        .and_then(|media| text(media.inner, "XAddr"))
+        .unwrap_or_else(|| device_url.clone());
+
+    let profiles = client
+        .call(
+            &media_url,
+            "GetProfiles",
+            r#"<trt:GetProfiles xmlns:trt="http://www.onvif.org/ver10/media/wsdl"/>"#,
+        )
+        .await?;
+    let profiles = parse_profiles(&profiles);
+    for profile in &profiles {
+        info!(
+            "📷 [{}] ONVIF profile '{}' ({}): {}{}",
+            camera.host,
+            profile.token,
+            profile.name,
+            profile.encoding.as_deref().unwrap_or("no video"),
+            profile
+                .resolution
+                .map(|(width, height)| format!(" {}x{}", width, height))
+                .unwrap_or_default(),
+        );
+    }
+    let profile = select_profile(&profiles, camera.profile.as_deref())?;
+
+    let stream_uri = client
+        .call(
+            &media_url,
+            "GetStreamUri",
+            &format!(
+                r#"<trt:GetStreamUri xmlns:trt="http://www.onvif.org/ver10/media/wsdl" xmlns:tt="http://www.onvif.org/ver10/schema"><trt:StreamSetup><tt:Stream>RTP-Unicast</tt:Stream><tt:Transport><tt:Protocol>RTSP</tt:Protocol></tt:Transport></trt:StreamSetup><trt:ProfileToken>{}</trt:ProfileToken></trt:GetStreamUri>"#,
+                escape(&profile.token)
+            ),
+        )
+        .await?;
+    let uri = element(&stream_uri, "MediaUri")
+        .and_then(|media_uri| text(media_uri.inner, "Uri"))
+        .ok_or(OnvifError::MissingUri)?;
+    let url: RTSPUrl = uri.parse().map_err(|error| OnvifError::InvalidUri {
+        uri: uri.clone(),
+        error,
+    })?;
+    info!(
+        "📷 [{}] Streaming ONVIF profile '{}' from {}",
+        camera.host, profile.token, url.0
+    );
+
+    Ok(SourceSpec {
+        name: camera.name.clone().unwrap_or_else(|| camera.host.clone()),
+        url,
+        username: camera.username.clone(),
+        password: camera.password.clone(),
+        tags: Vec::new(),
+        relay_only: false,
+        substream: None,
+        display_name: None,
+        description: None,
+        priority: 0,
+        attempts: None,
+        wait_for_dns: false,
+        no_metadata: false,
+        gain: 0,
+        interface: None,
+    })
+}
+
+/// The device service URL of `host`: what the camera announces in reply to a
+/// WS-Discovery probe, or the usual `/onvif/device_service` if it doesn't
+/// answer. A host given with a port is taken as is.
+async fn device_service(host: &str) -> String {
+    let fallback = match host.parse::<std::net::Ipv6Addr>() {
+        Ok(_) => format!("http://[{}]/onvif/device_service", host),
+        Err(_) => format!("http://{}/onvif/device_service", host),
+    };
+    if host.parse::<std::net::SocketAddr>().is_ok()
+        || (host.contains(':') && host.parse::<std::net::IpAddr>().is_err())
+    {
+        return fallback;
+    }
+    match tokio::time::timeout(DISCOVERY_TIMEOUT, probe(host)).await {
+        Ok(Ok(Some(url))) => url,
+        Ok(Ok(None)) => fallback,
+        Ok(Err(e)) => {
+            warn!("[{}] WS-Discovery probe failed: {}", host, e);
+            fallback
+        }
+        Err(_) => {
+            debug!("[{}] No answer to the WS-Discovery probe", host);
+            fallback
+        }
+    }
+}
+
+/// Sends a unicast WS-Discovery probe to `host` and returns the first HTTP
+/// address its `ProbeMatch` lists, preferring one on the probed host.
+async fn probe(host: &str) -> std::io::Result<Option<String>> {
+    let Some(addr) = tokio::net::lookup_host((host, DISCOVERY_PORT))
+        .await?
+        .next()
+    else {
+        return Ok(None);
+    };
+    let local: std::net::SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = tokio::net::UdpSocket::bind(local).await?;
+    let message = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><e:Envelope xmlns:e="http://www.w3.org/2003/05/soap-envelope" xmlns:w="http://schemas.xmlsoap.org/ws/2004/08/addressing" xmlns:d="http://schemas.xmlsoap.org/ws/2005/04/discovery" xmlns:dn="http://www.onvif.org/ver10/network/wsdl"><e:Header><w:MessageID>uuid:{}</w:MessageID><w:To e:mustUnderstand="true">urn:schemas-xmlsoap-org:ws:2005:04:discovery</w:To><w:Action e:mustUnderstand="true">http://schemas.xmlsoap.org/ws/2005/04/discovery/Probe</w:Action></e:Header><e:Body><d:Probe><d:Types>dn:NetworkVideoTransmitter</d:Types></d:Probe></e:Body></e:Envelope>"#,
+        uuid::Uuid::new_v4()
+    );
+    socket.send_to(message.as_bytes(), addr).await?;
+
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        if from.ip() != addr.ip() {
+            continue;
+        }
+        let reply = String::from_utf8_lossy(&buf[..len]);
+        let Some(xaddrs) = text(&reply, "XAddrs") else {
+            continue;
+        };
+        let urls: Vec<&str> = xaddrs
+            .split_whitespace()
+            .filter(|url| url.starts_with("http://") || url.starts_with("https://"))
+            .collect();
+        let on_host = urls.iter().find(|url| {
+            url::Url::parse(url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_owned))
+                .is_some_and(|url_host| url_host == host)
+        });
+        return Ok(on_host.or(urls.first()).map(|url| url.to_string()));
+    }
+}
+
+fn select_profile<'a>(
+    profiles: &'a [Profile],
+    wanted: Option<&str>,
+) -> Result<&'a Profile, OnvifError> {
+    if profiles.is_empty() {
+        return Err(OnvifError::NoProfiles);
+    }
+    let Some(wanted) = wanted else {
+        return Ok(profiles
+            .iter()
+            .find(|profile| profile.is_supported())
+            .unwrap_or(&profiles[0]));
+    };
+    profiles
+        .iter()
+        .find(|profile| profile.token == wanted || profile.name.eq_ignore_ascii_case(wanted))
+        .ok_or_else(|| OnvifError::UnknownProfile {
+            wanted: wanted.to_owned(),
+            available: profiles
+                .iter()
+                .map(|profile| profile.token.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        })
+}
+
+fn parse_profiles(response: &str) -> Vec<Profile> {
+    elements(response, "Profiles")
+        .into_iter()
+        .filter_map(|profile| {
+            let encoder = element(profile.inner, "VideoEncoderConfiguration");
+            Some(Profile {
+                token: profile.attr("token")?.to_owned(),
+                // The profile's own name comes before those of its configurations
+                name: text(profile.inner, "Name").unwrap_or_default(),
+                encoding: encoder.and_then(|encoder| text(encoder.inner, "Encoding")),
+                resolution: encoder
+                    .and_then(|encoder| element(encoder.inner, "Resolution"))
+                    .and_then(|resolution| {
+                        Some((
+                            text(resolution.inner, "Width")?.parse().ok()?,
+                            text(resolution.inner, "Height")?.parse().ok()?,
+                        ))
+                    }),
+            })
+        })
+        .collect()
+}
+
+struct Client {
+    http: reqwest::Client,
+    username: Option<String>,
+    password: Option<String>,
+    /// Seconds the camera's clock is ahead of ours; digests carry its time.
+    clock_offset: i64,
+}
+
+impl Client {
+    /// Measures how far the camera's clock is off, since cameras reject
+    /// digests created too far from their own time. Best effort.
+    async fn with_camera_clock(mut self, device_url: &str) -> Self {
+        let body =
+            r#"<tds:GetSystemDateAndTime xmlns:tds="http://www.onvif.org/ver10/device/wsdl"/>"#;
+        let response = match self.post(device_url, body, false).await {
+            Ok(Ok(response)) => response,
+            Ok(Err((status, _))) => {
+                debug!("GetSystemDateAndTime failed: {}", status);
+                return self;
+            }
+            Err(e) => {
+                debug!("GetSystemDateAndTime failed: {}", e);
+                return self;
+            }
+        };
+        let camera_time = element(&response, "UTCDateTime").and_then(|utc| {
+            let date = element(utc.inner, "Date")?;
+            let time = element(utc.inner, "Time")?;
+            let field = |xml: &str, name| text(xml, name)?.parse::<i64>().ok();
+            Some(
+                days_from_civil(
+                    field(date.inner, "Year")?,
+                    field(date.inner, "Month")?,
+                    field(date.inner, "Day")?,
+                ) * 86400
+                    + field(time.inner, "Hour")? * 3600
+                    + field(time.inner, "Minute")? * 60
+                    + field(time.inner, "Second")?,
+            )
+        });
+        if let Some(camera_time) = camera_time {
+            self.clock_offset = camera_time - unix_time();
+            if self.clock_offset.abs() > 5 {
+                info!(
+                    "🕒 Camera clock is {} s off, signing requests with its time",
+                    self.clock_offset
+                );
+            }
+        }
+        self
+    }
+
+    /// Calls an ONVIF operation, returning the response envelope.
+    async fn call(
+        &self,
+        url: &str,
+        action: &'static str,
+        body: &str,
+    ) -> Result<String, OnvifError> {
+        match self.post(url, body, true).await? {
+            Err((status, response)) => {
+                let reason = text(&response, "Text")
+                    .or_else(|| text(&response, "faultstring"))
+                    .unwrap_or_else(|| status.to_string());
+                if status == reqwest::StatusCode::UNAUTHORIZED
+                    || reason.contains("NotAuthorized")
+                    || text(&response, "Subcode").is_some_and(|code| code.contains("NotAuthorized"))
+                {
+                    return Err(OnvifError::Unauthorized(reason));
+                }
+                Err(OnvifError::Fault { action, reason })
+            }
+            Ok(response) => Ok(response),
+        }
+    }
+
+    async fn post(
+        &self,
+        url: &str,
+        body: &str,
+        authenticate: bool,
+    ) -> Result<Result<String, (reqwest::StatusCode, String)>, OnvifError> {
+        let header = match (&self.username, authenticate) {
+            (Some(username), true) => self.security_header(username),
+            _ => String::new(),
+        };
+        let envelope = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope"><s:Header>{}</s:Header><s:Body>{}</s:Body></s:Envelope>"#,
+            header, body
+        );
+        let http_error = |error| OnvifError::Http {
+            url: url.to_owned(),
+            error,
+        };
+        let response = self
+            .http
+            .post(url)
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                "application/soap+xml; charset=utf-8",
+            )
+            .body(envelope)
+            .send()
+            .await
+            .map_err(http_error)?;
+        let status = response.status();
+        let text = response.text().await.map_err(http_error)?;
+        Ok(if status.is_success() {
+            Ok(text)
+        } else {
+            Err((status, text))
+        })
+    }
+
+    /// A WS-Security `UsernameToken` with a password digest: base64 of
+    /// SHA-1(nonce + created + password).
+    fn security_header(&self, username: &str) -> String {
+        let nonce = uuid::Uuid::new_v4().into_bytes();
+        let created = iso8601(unix_time() + self.clock_offset);
+        let password = self.password.as_deref().unwrap_or_default();
+        let mut context = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        context.update(&nonce);
+        context.update(created.as_bytes());
+        context.update(password.as_bytes());
+        let password_digest = BASE64_STANDARD.encode(context.finish());
+
+        format!(
+            r#"<Security s:mustUnderstand="1" xmlns="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd"><UsernameToken><Username>{}</Username><Password Type="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-username-token-profile-1.0#PasswordDigest">{}</Password><Nonce EncodingType="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-soap-message-security-1.0#Base64Binary">{}</Nonce><Created xmlns="http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-utility-1.0.xsd">{}</Created></UsernameToken></Security>"#,
+            escape(username),
+            password_digest,
+            BASE64_STANDARD.encode(nonce),
+            created
+        )
+    }
+}
+
+/// An XML element found by local name, ignoring its namespace prefix.
+#[derive(Clone, Copy)]
+pub struct Element<'a> {
+    attrs: &'a str,
+    pub inner: &'a str,
+}
+
+impl<'a> Element<'a> {
+    pub fn attr(&self, name: &str) -> Option<&'a str> {
+        let mut rest = self.attrs;
+        while let Some(eq) = rest.find('=') {
+            let key = rest[..eq].trim();
+            let key = key.rsplit(':').next().unwrap_or(key);
+            let value = rest[eq + 1..].trim_start();
+            let quote = value.chars().next()?;
+            let value = &value[1..];
+            let end = value.find(quote)?;
+            if key == name {
+                return Some(&value[..end]);
+            }
+            rest = &value[end + 1..];
+        }
+        None
+    }
+}
+
+/// Every element named `name` in `xml`, outermost first; elements of the
+/// same name are not expected to nest.
+pub fn elements<'a>(xml: &'a str, name: &str) -> Vec<Element<'a>> {
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let tag = &rest[..end];
+        if tag.starts_with(['/', '?', '!']) {
+            continue;
+        }
+        let (tag_name, attrs) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        let (tag_name, self_closing) = match tag_name.strip_suffix('/') {
+            Some(tag_name) => (tag_name, true),
+            None => (tag_name, tag.ends_with('/')),
+        };
+        if tag_name.rsplit(':').next() != Some(name) {
+            continue;
+        }
+        let attrs = attrs.strip_suffix('/').unwrap_or(attrs);
+        rest = &rest[end + 1..];
+        if self_closing {
+            found.push(Element { attrs, inner: "" });
+            continue;
+        }
+        let close = format!("</{}>", tag_name);
+        let Some(inner_end) = rest.find(&close) else {
+            break;
+        };
+        found.push(Element {
+            attrs,
+            inner: &rest[..inner_end],
+        });
+        rest = &rest[inner_end + close.len()..];
+    }
+    found
+}
+
+pub fn element<'a>(xml: &'a str, name: &str) -> Option<Element<'a>> {
+    elements(xml, name).into_iter().next()
+}
+
+/// The text of the first element named `name`.
+fn text(xml: &str, name: &str) -> Option<String> {
+    let text = element(xml, name)?.inner.trim();
+    Some(
+        text.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&"),
+    )
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// `2024-05-01T12:00:00Z` for seconds since the epoch.
+fn iso8601(secs: i64) -> String {
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+// Howard Hinnant's conversions between days since the epoch and dates
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILES: &str = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope"><s:Body>
+<trt:GetProfilesResponse>
+<trt:Profiles token="snap" fixed="true"><tt:Name>Snapshot</tt:Name>
+<tt:VideoEncoderConfiguration token="enc0"><tt:Name>enc</tt:Name><tt:Encoding>JPEG</tt:Encoding></tt:VideoEncoderConfiguration>
+</trt:Profiles>
+<trt:Profiles fixed='true' token='main'><tt:Name>Main &amp; wide</tt:Name>
+<tt:VideoEncoderConfiguration token="enc1"><tt:Name>enc</tt:Name><tt:Encoding>H264</tt:Encoding>
+<tt:Resolution><tt:Width>1920</tt:Width><tt:Height>1080</tt:Height></tt:Resolution></tt:VideoEncoderConfiguration>
+</trt:Profiles>
+<trt:Profiles token="audio"><tt:Name>Audio</tt:Name><tt:AudioSourceConfiguration token="a"/></trt:Profiles>
+</trt:GetProfilesResponse></s:Body></s:Envelope>"#;
+
+    #[test]
+    fn parses_cameras() {
+        assert_eq!(
+            "host=cam.local:8080,username=admin,password=a=b,profile=main"
+                .parse::<OnvifCamera>()
+                .unwrap(),
+            OnvifCamera {
+                host: "cam.local:8080".to_owned(),
+                username: Some("admin".to_owned()),
+                password: Some("a=b".to_owned()),
+                name: None,
+                profile: Some("main".to_owned()),
+            }
+        );
+        assert_eq!(
+            "username=admin".parse::<OnvifCamera>(),
+            Err(OnvifCameraParseError::MissingHost)
+        );
+        assert_eq!(
+            "host=cam,port=80".parse::<OnvifCamera>(),
+            Err(OnvifCameraParseError::UnknownOption("port=80".to_owned()))
+        );
+    }
+
+    #[test]
+    fn reads_profiles() {
+        let profiles = parse_profiles(PROFILES);
+        let tokens: Vec<&str> = profiles.iter().map(|p| p.token.as_str()).collect();
+        assert_eq!(tokens, ["snap", "main", "audio"]);
+        assert_eq!(profiles[1].name, "Main & wide");
+        assert_eq!(profiles[1].encoding.as_deref(), Some("H264"));
+        assert_eq!(profiles[1].resolution, Some((1920, 1080)));
+        assert_eq!(profiles[2].encoding, None);
+    }
+
+    #[test]
+    fn selects_profiles() {
+        let profiles = parse_profiles(PROFILES);
+        assert_eq!(select_profile(&profiles, None).unwrap().token, "main");
+        assert_eq!(
+            select_profile(&profiles, Some("snap")).unwrap().token,
+            "snap"
+        );
+        assert_eq!(
+            select_profile(&profiles, Some("AUDIO")).unwrap().token,
+            "audio"
+        );
+        match select_profile(&profiles, Some("sub")) {
+            Err(OnvifError::UnknownProfile { available, .. }) => {
+                assert_eq!(available, "snap, main, audio")
+            }
+            other => panic!("unexpected {:?}", other.map(|p| &p.token)),
+        }
+        assert!(matches!(
+            select_profile(&[], None),
+            Err(OnvifError::NoProfiles)
+        ));
+
+        // Without H.264 or H.265, the first profile will have to do
+        assert_eq!(select_profile(&profiles[..1], None).unwrap().token, "snap");
+    }
+
+    #[test]
+    fn finds_elements() {
+        let xml = r#"<a:Root><b:Item id="1" name = 'one'/><Item id="2">two</Item><c:Other>x</c:Other></a:Root>"#;
+        let items = elements(xml, "Item");
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].attr("name"), Some("one"));
+        assert_eq!(items[0].inner, "");
+        assert_eq!(items[1].attr("id"), Some("2"));
+        assert_eq!(items[1].attr("missing"), None);
+        assert_eq!(text(xml, "Item"), Some(String::new()));
+        assert_eq!(text(xml, "Other").as_deref(), Some("x"));
+        assert!(element(xml, "Nothing").is_none());
+        assert_eq!(escape(r#"<a & "b">"#), "&lt;a &amp; &quot;b&quot;&gt;");
+    }
+
+    #[test]
+    fn converts_dates() {
+        assert_eq!(iso8601(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso8601(951_782_400 + 3723), "2000-02-29T01:02:03Z");
+        assert_eq!(iso8601(-1), "1969-12-31T23:59:59Z");
+        for days in [-800_000, -1, 0, 11_016, 20_000, 2_932_896] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn digests_passwords() {
+        let client = Client {
+            http: reqwest::Client::new(),
+            username: Some("admin".to_owned()),
+            password: Some("secret".to_owned()),
+            clock_offset: -3600,
+        };
+        let header = client.security_header("a<b");
+        assert!(header.contains("<Username>a&lt;b</Username>"));
+
+        let field = |name| {
+            let value = element(&header, name).unwrap().inner;
+            value.to_owned()
+        };
+        let created = field("Created");
+        let nonce = BASE64_STANDARD.decode(field("Nonce")).unwrap();
+        let mut context = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        context.update(&nonce);
+        context.update(created.as_bytes());
+        context.update(b"secret");
+        assert_eq!(field("Password"), BASE64_STANDARD.encode(context.finish()));
+
+        // Created is on the camera's clock
+        assert!(created < iso8601(unix_time() - 3500));
+    }
+}
@@ -0,0 +1,16 @@
+use retina::rtp::ReceivedPacket;
+use webrtc::{
+    rtp::{header::Header, packet::Packet},
+    util::Unmarshal,
+};
+
+/// Converts a received packet without copying its payload.
+///
+/// Only the header is parsed; the payload keeps sharing retina's buffer.
+pub fn into_rtp_packet(rtp: ReceivedPacket) -> Result<Packet, webrtc::util::Error> {
+    let header = Header::unmarshal(&mut rtp.raw())?;
+    Ok(Packet {
+        header,
+        payload: rtp.into_payload_bytes(),
+    })
+}
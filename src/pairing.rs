@@ -0,0 +1,158 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use ring::{hmac, rand::SystemRandom};
+
+use crate::auth::{Principal, Role};
+
+// Pairing tokens start with this, so they're told apart from other tokens
+const TOKEN_PREFIX: &str = "pair.";
+// Blank modules around the code, as the QR spec asks, and pixels per module
+const QUIET_ZONE: usize = 4;
+const MODULE_PIXELS: usize = 8;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PairingError {
+    #[error("viewing URL does not fit in a QR code: {0}")]
+    Qr(#[from] qrcode::types::QrError),
+    #[error("failed to encode QR code: {0}")]
+    Png(#[from] png::EncodingError),
+}
+
+/// Issues and checks pairing tokens: short-lived viewer tokens for one source,
+/// handed to a phone as a link to the player.
+///
+/// Tokens are signed with a key drawn at startup, so a restart voids them.
+pub struct Pairing {
+    key: hmac::Key,
+    ttl: Duration,
+}
+
+impl Pairing {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            key: hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+                .expect("failed to generate the pairing key"),
+            ttl,
+        }
+    }
+
+    /// A token letting its bearer watch `source` until the returned Unix time.
+    pub fn issue(&self, source: &str) -> (String, u64) {
+        let expires = unix_time() + self.ttl.as_secs();
+        let claims = format!("{}.{}", expires, BASE64_URL_SAFE_NO_PAD.encode(source));
+        let signature = hmac::sign(&self.key, claims.as_bytes());
+        let token = format!(
+            "{}{}.{}",
+            TOKEN_PREFIX,
+            claims,
+            BASE64_URL_SAFE_NO_PAD.encode(signature.as_ref())
+        );
+        (token, expires)
+    }
+
+    /// The viewer a pairing token stands for, if it is one, genuine and not
+    /// yet expired.
+    pub fn verify(&self, token: &str) -> Option<Principal> {
+        let (claims, signature) = token.strip_prefix(TOKEN_PREFIX)?.rsplit_once('.')?;
+        let signature = BASE64_URL_SAFE_NO_PAD.decode(signature).ok()?;
+        hmac::verify(&self.key, claims.as_bytes(), &signature).ok()?;
+
+        let (expires, source) = claims.split_once('.')?;
+        if expires.parse::<u64>().ok()? <= unix_time() {
+            return None;
+        }
+        let source = String::from_utf8(BASE64_URL_SAFE_NO_PAD.decode(source).ok()?).ok()?;
+        Some(Principal {
+            id: format!("pairing:{}", source),
+            role: Role::Viewer,
+            sources: Some(vec![source]),
+            relay_only: false,
+            substream_only: false,
+            av1: false,
+        })
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// `text` as a black-on-white QR code PNG.
+pub fn qr_png(text: &str) -> Result<Vec<u8>, PairingError> {
+    let code = qrcode::QrCode::new(text)?;
+    let modules = code.width();
+    let colors = code.to_colors();
+    let size = (modules + 2 * QUIET_ZONE) * MODULE_PIXELS;
+
+    let mut pixels = vec![u8::MAX; size * size];
+    for (index, color) in colors.iter().enumerate() {
+        if *color != qrcode::Color::Dark {
+            continue;
+        }
+        let (x, y) = (index % modules + QUIET_ZONE, index / modules + QUIET_ZONE);
+        for row in y * MODULE_PIXELS..(y + 1) * MODULE_PIXELS {
+            pixels[row * size + x * MODULE_PIXELS..][..MODULE_PIXELS].fill(0);
+        }
+    }
+
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_its_own_tokens() {
+        let pairing = Pairing::new(Duration::from_secs(60));
+        let (token, expires) = pairing.issue("front door");
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert!(expires > unix_time());
+
+        let principal = pairing.verify(&token).unwrap();
+        assert_eq!(principal.id, "pairing:front door");
+        assert_eq!(principal.role, Role::Viewer);
+        assert!(principal.can_view("front door"));
+        assert!(!principal.can_view("back"));
+    }
+
+    #[test]
+    fn rejects_forged_and_expired_tokens() {
+        let pairing = Pairing::new(Duration::from_secs(60));
+        let (token, _) = pairing.issue("front");
+
+        let other = BASE64_URL_SAFE_NO_PAD.encode("back");
+        let front = BASE64_URL_SAFE_NO_PAD.encode("front");
+        assert!(pairing.verify(&token.replace(&front, &other)).is_none());
+        assert!(
+            Pairing::new(Duration::from_secs(60))
+                .verify(&token)
+                .is_none()
+        );
+        assert!(pairing.verify(&token[TOKEN_PREFIX.len()..]).is_none());
+        assert!(pairing.verify("pair.garbage").is_none());
+
+        let expired = Pairing::new(Duration::ZERO);
+        let (token, _) = expired.issue("front");
+        assert!(expired.verify(&token).is_none());
+    }
+
+    #[test]
+    fn draws_qr_codes() {
+        let png = qr_png("https://gateway.example.com/?access_token=pair.x").unwrap();
+        let decoder = png::Decoder::new(std::io::Cursor::new(png));
+        let info = decoder.read_info().unwrap().info().clone();
+        assert_eq!(info.width, info.height);
+        assert_eq!(info.width as usize % MODULE_PIXELS, 0);
+        assert_eq!(info.color_type, png::ColorType::Grayscale);
+    }
+}
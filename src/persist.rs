@@ -0,0 +1,265 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use dashmap::DashSet;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use webrtc::peer_connection::RTCPeerConnection;
+
+use crate::store::{InMemorySessionStore, SessionInfo, SessionStore, StoredSession};
+
+/// What is kept on disk of a viewer session: enough to recognise its
+/// resource URL after a restart. Tokens and keys stay in memory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistedSession {
+    pub id: String,
+    pub source: String,
+}
+
+/// A session store that also keeps the ids of its sessions in a file, with
+/// `--session-state-file`, so they can be told apart from made-up ones once
+/// the gateway restarts.
+///
+/// The file is left alone once shutdown begins, so sessions closed by a
+/// graceful shutdown count as ended by the restart too.
+pub struct PersistentSessionStore {
+    inner: InMemorySessionStore,
+    path: PathBuf,
+    shutting_down: Arc<AtomicBool>,
+    // Serializes rewrites of the file
+    writing: Mutex<()>,
+}
+
+impl PersistentSessionStore {
+    pub fn new(path: PathBuf, shutting_down: Arc<AtomicBool>) -> Self {
+        let store = Self {
+            inner: InMemorySessionStore::default(),
+            path,
+            shutting_down,
+            writing: Mutex::new(()),
+        };
+        store.save();
+        store
+    }
+
+    /// Rewrites the file with the current sessions. Best effort: a failed
+    /// write is logged and the sessions keep working.
+    fn save(&self) {
+        let _writing = self.writing.lock().unwrap();
+        if self.shutting_down.load(Ordering::Relaxed) {
+            return;
+        }
+        let sessions: Vec<_> = self
+            .inner
+            .list()
+            .into_iter()
+            .map(|info| PersistedSession {
+                id: info.id,
+                source: info.source,
+            })
+            .collect();
+        // Write a temporary file and rename it, so a crash mid-write leaves
+        // the previous list rather than a truncated one
+        let temp = self.path.with_extension("tmp");
+        let result = serde_json::to_vec(&sessions)
+            .map_err(std::io::Error::from)
+            .and_then(|json| std::fs::write(&temp, json))
+            .and_then(|()| std::fs::rename(&temp, &self.path));
+        if let Err(e) = result {
+            warn!("Cannot save sessions to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl SessionStore for PersistentSessionStore {
+    fn insert(&self, info: SessionInfo, pc: Arc<RTCPeerConnection>) {
+        self.inner.insert(info, pc);
+        self.save();
+    }
+
+    fn remove(&self, id: &str) -> Option<Arc<RTCPeerConnection>> {
+        let pc = self.inner.remove(id);
+        if pc.is_some() {
+            self.save();
+        }
+        pc
+    }
+
+    fn get(&self, id: &str) -> Option<StoredSession> {
+        self.inner.get(id)
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn touch(&self, id: &str) -> bool {
+        self.inner.touch(id)
+    }
+
+    fn idle(&self, timeout: Duration) -> Vec<String> {
+        self.inner.idle(timeout)
+    }
+
+    fn list(&self) -> Vec<SessionInfo> {
+        self.inner.list()
+    }
+
+    fn owned_by(&self, owner: Option<&str>) -> Vec<SessionInfo> {
+        self.inner.owned_by(owner)
+    }
+}
+
+/// Viewer sessions that were still open when the gateway last stopped, as
+/// found in `--session-state-file` at boot. Requests for their resources get
+/// `410 Gone` instead of `404 Not Found`.
+#[derive(Default)]
+pub struct EndedSessions {
+    ids: DashSet<String>,
+    /// Sessions ended by the restart, by source.
+    by_source: BTreeMap<String, u64>,
+}
+
+impl EndedSessions {
+    /// Reads the sessions left in `path`; a missing or unreadable file means
+    /// there are none.
+    pub fn load(path: &Path) -> Self {
+        let sessions: Vec<PersistedSession> = match std::fs::read(path) {
+            Ok(json) => serde_json::from_slice(&json).unwrap_or_else(|e| {
+                warn!("Ignoring session state in {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                warn!("Cannot read session state {}: {}", path.display(), e);
+                Vec::new()
+            }
+        };
+        if !sessions.is_empty() {
+            info!(
+                "♻️  {} viewer session(s) ended by the restart",
+                sessions.len()
+            );
+        }
+
+        let mut by_source = BTreeMap::new();
+        for session in &sessions {
+            *by_source.entry(session.source.clone()).or_default() += 1;
+        }
+        Self {
+            ids: sessions.into_iter().map(|session| session.id).collect(),
+            by_source,
+        }
+    }
+
+    /// Whether `id` was a session before the restart.
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Stops recognising `id`, once its client has deleted it.
+    pub fn forget(&self, id: &str) -> bool {
+        self.ids.remove(id).is_some()
+    }
+
+    /// Sessions the restart ended, by source.
+    pub fn by_source(&self) -> &BTreeMap<String, u64> {
+        &self.by_source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::{api::APIBuilder, peer_connection::configuration::RTCConfiguration};
+
+    use super::*;
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("sessions-{}-{}.json", name, std::process::id()))
+    }
+
+    fn info(id: &str, source: &str) -> SessionInfo {
+        SessionInfo {
+            id: id.to_owned(),
+            source: source.to_owned(),
+            owner: Some("token:secret-owner".to_owned()),
+            frame_keys: None,
+            local_candidates: None,
+        }
+    }
+
+    fn saved(path: &Path) -> Vec<(String, String)> {
+        let sessions: Vec<PersistedSession> =
+            serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+        sessions
+            .into_iter()
+            .map(|session| (session.id, session.source))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn keeps_session_ids_on_disk() {
+        let pc = Arc::new(
+            APIBuilder::new()
+                .build()
+                .new_peer_connection(RTCConfiguration::default())
+                .await
+                .unwrap(),
+        );
+        let path = path("store");
+        let shutting_down = Arc::new(AtomicBool::new(false));
+        let store = PersistentSessionStore::new(path.clone(), shutting_down.clone());
+        assert!(saved(&path).is_empty());
+
+        store.insert(info("a1", "front"), pc.clone());
+        store.insert(info("b2", "back"), pc.clone());
+        let mut sessions = saved(&path);
+        sessions.sort();
+        assert_eq!(
+            sessions,
+            [
+                ("a1".to_owned(), "front".to_owned()),
+                ("b2".to_owned(), "back".to_owned())
+            ]
+        );
+        // Owners stay in memory
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("owner"));
+
+        store.remove("a1");
+        assert_eq!(saved(&path), [("b2".to_owned(), "back".to_owned())]);
+
+        // Sessions closed by the shutdown stay listed
+        shutting_down.store(true, Ordering::Relaxed);
+        store.remove("b2");
+        assert_eq!(saved(&path).len(), 1);
+        assert!(!path.with_extension("tmp").exists());
+
+        let ended = EndedSessions::load(&path);
+        assert!(ended.contains("b2"));
+        assert_eq!(ended.by_source().get("back"), Some(&1));
+        assert!(ended.forget("b2"));
+        assert!(!ended.contains("b2"));
+        assert!(!ended.forget("b2"));
+
+        pc.close().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn loads_nothing_from_missing_or_broken_files() {
+        let path = path("broken");
+        assert!(EndedSessions::load(&path).by_source().is_empty());
+        std::fs::write(&path, "{not json").unwrap();
+        let ended = EndedSessions::load(&path);
+        assert!(ended.by_source().is_empty());
+        assert!(!ended.contains("not json"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
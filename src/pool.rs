@@ -0,0 +1,66 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+/// A free list of fixed-size byte buffers shared between tasks.
+pub struct BufferPool {
+    size: usize,
+    max_idle: usize,
+    free: Mutex<Vec<Box<[u8]>>>,
+}
+
+impl BufferPool {
+    pub fn new(size: usize, max_idle: usize) -> Arc<Self> {
+        Arc::new(Self {
+            size,
+            max_idle,
+            free: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Takes an idle buffer or allocates a new one; it returns to the pool on drop.
+    pub fn get(self: &Arc<Self>) -> PooledBuffer {
+        let buf = self
+            .free
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| vec![0u8; self.size].into_boxed_slice());
+
+        PooledBuffer {
+            buf: Some(buf),
+            pool: self.clone(),
+        }
+    }
+}
+
+pub struct PooledBuffer {
+    buf: Option<Box<[u8]>>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.buf.as_deref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.buf.as_deref_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut free = self.pool.free.lock().unwrap();
+        if free.len() < self.pool.max_idle
+            && let Some(buf) = self.buf.take()
+        {
+            free.push(buf);
+        }
+    }
+}
@@ -0,0 +1,533 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, mpsc},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    Extension, Json,
+    extract::{Path as UrlPath, Request, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use tokio::sync::Notify;
+use tower_http::services::ServeFile;
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::Principal,
+    fanout::FanoutTrack,
+    ids::url_token,
+    segment::{Output, SegmentOptions, Segmenter},
+    state::AppState,
+};
+
+// Outputs waiting for the disk; past this, segments lose fragments
+const WRITE_QUEUE: usize = 32;
+// Extension of segments still being written
+const PART_EXTENSION: &str = "part";
+
+/// Where and how the sources are recorded.
+#[derive(Clone)]
+pub struct RecordOptions {
+    pub dir: PathBuf,
+    /// Segments roll over at the first keyframe after this much video.
+    pub segment: Duration,
+    /// Segments older than this are deleted; zero keeps them.
+    pub retention: Duration,
+    /// Asked for a keyframe when a segment is due and the GOP goes on.
+    pub keyframe_requests: Option<Arc<Notify>>,
+}
+
+/// Records a source's tracks as fragmented MP4 segments in `<dir>/<name>/`,
+/// from the same packets its viewers get. Segments are written as
+/// `<millis>.mp4.part` and renamed to `<millis>.mp4` once complete and
+/// synced, on a thread of their own so a slow disk doesn't hold up the
+/// runtime. Parts left by a crash are repaired first.
+///
+/// Only H.264 video and Opus or AAC audio can be recorded; other tracks are
+/// skipped. Recording ends with the source.
+pub fn spawn(
+    name: &str,
+    video: Option<&Arc<FanoutTrack>>,
+    audio: Option<&Arc<FanoutTrack>>,
+    options: RecordOptions,
+) {
+    let Some(segmenter) = Segmenter::new(
+        name,
+        "Recording",
+        video,
+        audio,
+        SegmentOptions {
+            segment: options.segment,
+            continuous: false,
+            keyframe_requests: options.keyframe_requests.clone(),
+        },
+    ) else {
+        return;
+    };
+
+    let dir = options.dir.join(url_token(name));
+    let mut recorder = Recorder {
+        name: name.to_owned(),
+        dir,
+        options,
+        file: None,
+    };
+    let (tx, rx) = mpsc::sync_channel(WRITE_QUEUE);
+    let writer = std::thread::Builder::new()
+        .name(format!("record-{}", url_token(name)))
+        .spawn(move || {
+            if let Err(e) = std::fs::create_dir_all(&recorder.dir) {
+                warn!(
+                    "[{}] Cannot create recording directory {}: {}",
+                    recorder.name,
+                    recorder.dir.display(),
+                    e
+                );
+                return;
+            }
+            info!(
+                "⏺️ [{}] Recording to {}",
+                recorder.name,
+                recorder.dir.display()
+            );
+            recorder.repair();
+            for output in rx {
+                recorder.write(output);
+            }
+            recorder.finish();
+        });
+    if let Err(e) = writer {
+        warn!("[{}] Cannot start recording: {}", name, e);
+        return;
+    }
+
+    // Whatever the disk can't take in time is dropped up to the next
+    // segment, which leaves a gap rather than stalling the source
+    let name = name.to_owned();
+    let mut skipping = false;
+    segmenter.spawn(move |output| {
+        if skipping && !matches!(output, Output::Start(_)) {
+            return;
+        }
+        match tx.try_send(output) {
+            Ok(()) => skipping = false,
+            Err(mpsc::TrySendError::Full(_)) => {
+                if !skipping {
+                    warn!(
+                        "[{}] Recording can't keep up with the disk, skipping to the next segment",
+                        name
+                    );
+                }
+                skipping = true;
+            }
+            // The writer gave up, e.g. without a directory
+            Err(mpsc::TrySendError::Disconnected(_)) => {}
+        }
+    });
+}
+
+struct Recorder {
+    name: String,
+    dir: PathBuf,
+    options: RecordOptions,
+    // The segment being written, under its `.part` name
+    file: Option<(PathBuf, File)>,
+}
+
+impl Recorder {
+    fn write(&mut self, output: Output) {
+        match output {
+            Output::Start(init) => {
+                // The previous segment's end may have been skipped
+                self.finish();
+                self.prune();
+                let started = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                let path = self.dir.join(format!("{}.mp4.{}", started, PART_EXTENSION));
+                let file = File::create(&path).and_then(|mut file| {
+                    std::io::Write::write_all(&mut file, &init)?;
+                    Ok(file)
+                });
+                match file {
+                    Ok(file) => {
+                        debug!("[{}] Recording segment {}", self.name, path.display());
+                        self.file = Some((path, file));
+                    }
+                    Err(e) => warn!(
+                        "[{}] Failed to create recording {}: {}",
+                        self.name,
+                        path.display(),
+                        e
+                    ),
+                }
+            }
+            Output::Fragment { data, .. } => {
+                let Some((path, file)) = self.file.as_mut() else {
+                    return;
+                };
+                if let Err(e) = std::io::Write::write_all(file, &data) {
+                    warn!(
+                        "[{}] Failed to write recording {}: {}",
+                        self.name,
+                        path.display(),
+                        e
+                    );
+                    self.file = None;
+                }
+            }
+            Output::End => self.finish(),
+        }
+    }
+
+    // Gives the segment being written its final name once it's on disk, so
+    // a `.mp4` is never cut short by a power loss
+    fn finish(&mut self) {
+        let Some((part, file)) = self.file.take() else {
+            return;
+        };
+        if let Err(e) = file.sync_all() {
+            warn!(
+                "[{}] Failed to sync recording {}: {}",
+                self.name,
+                part.display(),
+                e
+            );
+            return;
+        }
+        drop(file);
+        self.rename(&part);
+    }
+
+    fn rename(&self, part: &Path) -> bool {
+        let path = part.with_extension("");
+        if let Err(e) = std::fs::rename(part, &path) {
+            warn!(
+                "[{}] Failed to finish recording {}: {}",
+                self.name,
+                part.display(),
+                e
+            );
+            return false;
+        }
+        debug!("[{}] Recorded segment {}", self.name, path.display());
+        // The new name is only durable once the directory is
+        if let Err(e) = File::open(&self.dir).and_then(|dir| dir.sync_all()) {
+            warn!(
+                "[{}] Failed to sync recording directory {}: {}",
+                self.name,
+                self.dir.display(),
+                e
+            );
+        }
+        true
+    }
+
+    // Segments that were being written when the gateway crashed or lost
+    // power are cut back to their last complete fragment and finished; those
+    // without a complete init segment are deleted
+    fn repair(&self) {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let part = entry.path();
+            if part.extension().is_none_or(|ext| ext != PART_EXTENSION) {
+                continue;
+            }
+            let repaired =
+                File::options()
+                    .read(true)
+                    .write(true)
+                    .open(&part)
+                    .and_then(|mut file| {
+                        let len = file.metadata()?.len();
+                        let complete = complete_len(&mut file, len)?;
+                        if complete > 0 && complete < len {
+                            file.set_len(complete)?;
+                            file.sync_all()?;
+                        }
+                        Ok((complete, len))
+                    });
+            match repaired {
+                Ok((0, _)) => match std::fs::remove_file(&part) {
+                    Ok(()) => info!(
+                        "🩹 [{}] Deleted recording {}, cut short before anything playable",
+                        self.name,
+                        part.display()
+                    ),
+                    Err(e) => warn!(
+                        "[{}] Failed to delete recording {}: {}",
+                        self.name,
+                        part.display(),
+                        e
+                    ),
+                },
+                Ok((complete, len)) => {
+                    if self.rename(&part) {
+                        info!(
+                            "🩹 [{}] Repaired recording {}, kept {} of {} bytes",
+                            self.name,
+                            part.with_extension("").display(),
+                            complete,
+                            len
+                        );
+                    }
+                }
+                Err(e) => warn!(
+                    "[{}] Failed to repair recording {}: {}",
+                    self.name,
+                    part.display(),
+                    e
+                ),
+            }
+        }
+    }
+
+    // Deletes segments past `--record-retention`
+    fn prune(&self) {
+        if self.options.retention.is_zero() {
+            return;
+        }
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            // Includes parts left behind by failed writes
+            let expired = path
+                .extension()
+                .is_some_and(|ext| ext == "mp4" || ext == PART_EXTENSION)
+                && entry
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .is_ok_and(|modified| {
+                        modified.elapsed().unwrap_or_default() > self.options.retention
+                    });
+            if expired {
+                remove(&self.name, &path);
+            }
+        }
+    }
+}
+
+/// How much of a segment of `len` bytes plays: its `ftyp` and `moov`, then
+/// every fragment whose `moof` and `mdat` were both written in full. `0` if
+/// not even the init segment was.
+fn complete_len(file: &mut File, len: u64) -> std::io::Result<u64> {
+    let (mut offset, mut complete, mut init) = (0, 0, false);
+    let mut header = [0; 8];
+    while offset + 8 <= len {
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header)?;
+        // Boxes are never written with 64-bit or open-ended sizes
+        let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        if size < 8 || offset + size > len {
+            break;
+        }
+        offset += size;
+        match &header[4..] {
+            b"moov" => {
+                init = true;
+                complete = offset;
+            }
+            b"mdat" if init => complete = offset,
+            _ => {}
+        }
+    }
+    Ok(complete)
+}
+
+fn remove(name: &str, path: &Path) {
+    match std::fs::remove_file(path) {
+        Ok(()) => debug!("[{}] Deleted expired recording {}", name, path.display()),
+        Err(e) => warn!(
+            "[{}] Failed to delete recording {}: {}",
+            name,
+            path.display(),
+            e
+        ),
+    }
+}
+
+/// A finished recording as listed by `GET /api/recordings/{stream}`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Recording {
+    /// File name, to fetch from `/recordings/{stream}/{file}`.
+    pub file: String,
+    /// Unix time in milliseconds the segment started at.
+    pub started_ms: u64,
+    pub size: u64,
+}
+
+/// The start time of a finished segment named `file`, `None` for anything
+/// else: parts still being written, and names that could leave the directory.
+fn segment_start(file: &str) -> Option<u64> {
+    file.strip_suffix(".mp4")
+        .filter(|millis| !millis.is_empty() && millis.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|millis| millis.parse().ok())
+}
+
+/// Where `name`'s finished segment `file` is, if recording is on, the caller
+/// may see the source and `file` names a finished segment.
+fn segment_path(
+    state: &AppState,
+    principal: Option<&Principal>,
+    name: &str,
+    file: &str,
+) -> Result<PathBuf, StatusCode> {
+    if principal.is_some_and(|principal| !principal.can_view(name)) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let dir = state
+        .options
+        .record_dir
+        .as_ref()
+        .ok_or(StatusCode::NOT_FOUND)?;
+    segment_start(file).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(dir.join(url_token(name)).join(file))
+}
+
+/// `GET /api/recordings/{stream}`: the source's finished segments, oldest
+/// first. Sources removed since keep their recordings until retention.
+pub async fn list_recordings(
+    State(state): State<AppState>,
+    UrlPath(name): UrlPath<String>,
+    principal: Option<Extension<Principal>>,
+) -> Result<Json<Vec<Recording>>, StatusCode> {
+    if let Some(Extension(principal)) = &principal
+        && !principal.can_view(&name)
+    {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let dir = state
+        .options
+        .record_dir
+        .as_ref()
+        .ok_or(StatusCode::NOT_FOUND)?
+        .join(url_token(&name));
+    recordings(&dir)
+        .map(Json)
+        .map_err(|_| StatusCode::NOT_FOUND)
+}
+
+/// The finished segments in `dir`, oldest first.
+fn recordings(dir: &Path) -> std::io::Result<Vec<Recording>> {
+    let mut recordings: Vec<Recording> = std::fs::read_dir(dir)?
+        .flatten()
+        .filter_map(|entry| {
+            let file = entry.file_name().into_string().ok()?;
+            let started_ms = segment_start(&file)?;
+            let size = entry.metadata().ok()?.len();
+            Some(Recording {
+                file,
+                started_ms,
+                size,
+            })
+        })
+        .collect();
+    recordings.sort_by_key(|recording| recording.started_ms);
+    Ok(recordings)
+}
+
+/// `GET /recordings/{stream}/{file}`: a finished segment, with range requests
+/// so players can seek in it.
+pub async fn recording_file(
+    State(state): State<AppState>,
+    UrlPath((name, file)): UrlPath<(String, String)>,
+    principal: Option<Extension<Principal>>,
+    request: Request,
+) -> Response {
+    let path = match segment_path(&state, principal.as_deref(), &name, &file) {
+        Ok(path) => path,
+        Err(status) => return status.into_response(),
+    };
+    match ServeFile::new(path).try_call(request).await {
+        Ok(response) => response.into_response(),
+        Err(e) => {
+            warn!("[{}] Failed to read recording {}: {}", name, file, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// `DELETE /recordings/{stream}/{file}`: deletes a finished segment ahead of
+/// `--record-retention`.
+pub async fn delete_recording(
+    State(state): State<AppState>,
+    UrlPath((name, file)): UrlPath<(String, String)>,
+    principal: Option<Extension<Principal>>,
+) -> StatusCode {
+    let path = match segment_path(&state, principal.as_deref(), &name, &file) {
+        Ok(path) => path,
+        Err(status) => return status,
+    };
+    match std::fs::remove_file(&path) {
+        Ok(()) => {
+            info!("🗑️ [{}] Deleted recording {}", name, path.display());
+            StatusCode::NO_CONTENT
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        Err(e) => {
+            warn!(
+                "[{}] Failed to delete recording {}: {}",
+                name,
+                path.display(),
+                e
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_finished_segments_are_served() {
+        assert_eq!(segment_start("1767000000000.mp4"), Some(1767000000000));
+        for file in [
+            "1767000000000.mp4.part",
+            "../1767000000000.mp4",
+            "+1767000000000.mp4",
+            ".mp4",
+            "index.html",
+        ] {
+            assert_eq!(segment_start(file), None, "{}", file);
+        }
+    }
+
+    #[test]
+    fn lists_segments_oldest_first() {
+        let dir = std::env::temp_dir().join(format!("recordings-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("1767000300000.mp4"), b"later").unwrap();
+        std::fs::write(dir.join("1767000000000.mp4"), b"first").unwrap();
+        std::fs::write(dir.join("1767000600000.mp4.part"), b"").unwrap();
+
+        let listed = recordings(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            listed.unwrap(),
+            [
+                Recording {
+                    file: "1767000000000.mp4".to_owned(),
+                    started_ms: 1767000000000,
+                    size: 5,
+                },
+                Recording {
+                    file: "1767000300000.mp4".to_owned(),
+                    started_ms: 1767000300000,
+                    size: 5,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use tokio::time::Instant;
+
+use webrtc::rtp::packet::Packet;
+
+// Sequence number jumps treated as loss or reordering rather than a new
+// upstream timeline (RFC 3550, appendix A.1)
+const MAX_DROPOUT: u16 = 3000;
+const MAX_MISORDER: u16 = 100;
+
+struct Timeline {
+    ssrc: u32,
+    sequence_offset: u16,
+    timestamp_offset: u32,
+    last_sequence: u16,
+    last_out_sequence: u16,
+    last_out_timestamp: u32,
+    last_at: Instant,
+}
+
+/// Maps the sequence numbers and timestamps of whatever feeds a track (one RTSP
+/// session after another, publishers) onto one continuous timeline, so viewers
+/// never see the jumps of a reconnect.
+///
+/// The SSRC needs no rewriting: each viewer's connection stamps its own.
+pub struct Restamper {
+    clock_rate: u32,
+    timeline: Option<Timeline>,
+    rebase: bool,
+}
+
+impl Restamper {
+    /// `clock_rate` (Hz) bridges the time spent reconnecting; with `0` the
+    /// new timeline continues one tick after the last timestamp.
+    pub fn new(clock_rate: u32) -> Self {
+        Self {
+            clock_rate,
+            timeline: None,
+            rebase: false,
+        }
+    }
+
+    /// Treats the next packet as the start of a new upstream timeline, for
+    /// callers that know they switched upstreams.
+    pub fn rebase(&mut self) {
+        self.rebase = true;
+    }
+
+    /// Rewrites `pkt` onto the local timeline, returning whether it starts a
+    /// new upstream timeline (a new SSRC, or a jump in sequence numbers).
+    pub fn restamp(&mut self, pkt: &mut Packet) -> bool {
+        let rebase = std::mem::take(&mut self.rebase);
+        let ssrc = pkt.header.ssrc;
+        let sequence = pkt.header.sequence_number;
+        let timestamp = pkt.header.timestamp;
+        let now = Instant::now();
+
+        let Some(timeline) = self.timeline.as_mut() else {
+            // The first timeline is kept as is
+            self.timeline = Some(Timeline {
+                ssrc,
+                sequence_offset: 0,
+                timestamp_offset: 0,
+                last_sequence: sequence,
+                last_out_sequence: sequence,
+                last_out_timestamp: timestamp,
+                last_at: now,
+            });
+            return false;
+        };
+
+        let delta = sequence.wrapping_sub(timeline.last_sequence);
+        let discontinuity = rebase
+            || timeline.ssrc != ssrc
+            || (delta > MAX_DROPOUT && delta < u16::MAX - MAX_MISORDER);
+        if discontinuity {
+            // Continue right after the last packet sent, as much later as
+            // the upstream was away
+            let away = now.duration_since(timeline.last_at).as_secs_f64();
+            let ticks = (away * self.clock_rate as f64) as u32;
+            timeline.ssrc = ssrc;
+            timeline.last_sequence = sequence.wrapping_sub(1);
+            timeline.sequence_offset = timeline
+                .last_out_sequence
+                .wrapping_add(1)
+                .wrapping_sub(sequence);
+            timeline.timestamp_offset = timeline
+                .last_out_timestamp
+                .wrapping_add(ticks.max(1))
+                .wrapping_sub(timestamp);
+        }
+
+        pkt.header.sequence_number = sequence.wrapping_add(timeline.sequence_offset);
+        pkt.header.timestamp = timestamp.wrapping_add(timeline.timestamp_offset);
+
+        // Late packets must not move the timeline back
+        if sequence.wrapping_sub(timeline.last_sequence) < u16::MAX / 2 {
+            timeline.last_sequence = sequence;
+            timeline.last_out_sequence = pkt.header.sequence_number;
+            timeline.last_out_timestamp = pkt.header.timestamp;
+            timeline.last_at = now;
+        }
+        discontinuity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use webrtc::rtp::header::Header;
+
+    use super::*;
+
+    fn packet(ssrc: u32, sequence_number: u16, timestamp: u32) -> Packet {
+        Packet {
+            header: Header {
+                ssrc,
+                sequence_number,
+                timestamp,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    // Restamps a packet, returning its new sequence number and timestamp and
+    // whether it started a new timeline
+    fn restamp(restamper: &mut Restamper, mut pkt: Packet) -> (u16, u32, bool) {
+        let rebased = restamper.restamp(&mut pkt);
+        (pkt.header.sequence_number, pkt.header.timestamp, rebased)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_first_timeline() {
+        let mut restamper = Restamper::new(90_000);
+        assert_eq!(
+            restamp(&mut restamper, packet(1, 100, 1000)),
+            (100, 1000, false)
+        );
+        assert_eq!(
+            restamp(&mut restamper, packet(1, 101, 4000)),
+            (101, 4000, false)
+        );
+        // Loss within the dropout window is passed on as loss
+        assert_eq!(
+            restamp(&mut restamper, packet(1, 111, 34000)),
+            (111, 34000, false)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn continues_new_ssrc_after_time_away() {
+        let mut restamper = Restamper::new(90_000);
+        restamp(&mut restamper, packet(1, 100, 1000));
+        restamp(&mut restamper, packet(1, 101, 4000));
+        tokio::time::advance(Duration::from_secs(2)).await;
+
+        assert_eq!(
+            restamp(&mut restamper, packet(2, 5000, 777)),
+            (102, 184_000, true)
+        );
+        assert_eq!(
+            restamp(&mut restamper, packet(2, 5001, 3777)),
+            (103, 187_000, false)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sequence_jump_starts_new_timeline() {
+        // Without a clock rate the new timeline starts one tick later
+        let mut restamper = Restamper::new(0);
+        restamp(&mut restamper, packet(1, 100, 1000));
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(
+            restamp(&mut restamper, packet(1, 40_000, 50)),
+            (101, 1001, true)
+        );
+        assert_eq!(
+            restamp(&mut restamper, packet(1, 40_001, 60)),
+            (102, 1011, false)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn late_packets_keep_timeline() {
+        let mut restamper = Restamper::new(90_000);
+        restamp(&mut restamper, packet(1, 65_530, 1000));
+        tokio::time::advance(Duration::from_secs(2)).await;
+        restamp(&mut restamper, packet(2, 10, 0));
+        restamp(&mut restamper, packet(2, 12, 6000));
+
+        // Reordered, so it lands before the last packet sent
+        assert_eq!(
+            restamp(&mut restamper, packet(2, 11, 3000)),
+            (65_532, 184_000, false)
+        );
+        assert_eq!(
+            restamp(&mut restamper, packet(2, 13, 9000)),
+            (65_534, 190_000, false)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rebase_starts_new_timeline() {
+        let mut restamper = Restamper::new(90_000);
+        restamp(&mut restamper, packet(1, 100, 1000));
+        restamper.rebase();
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert_eq!(
+            restamp(&mut restamper, packet(1, 101, 1000)),
+            (101, 10_000, true)
+        );
+    }
+}
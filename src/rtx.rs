@@ -0,0 +1,487 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::{BufMut, BytesMut};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+use webrtc::{
+    api::media_engine::MediaEngine,
+    interceptor::{
+        Attributes, Error, Interceptor, InterceptorBuilder, RTCPReader, RTCPWriter, RTPReader,
+        RTPWriter, registry::Registry, stream_info::StreamInfo,
+    },
+    rtcp::transport_feedbacks::transport_layer_nack::TransportLayerNack,
+    rtp::packet::Packet,
+    rtp_transceiver::{RTCPFeedback, rtp_codec::RTPCodecType},
+};
+
+/// Packets kept per viewer track unless `--nack-history` says otherwise.
+pub const DEFAULT_NACK_HISTORY: usize = 1024;
+// Sequence numbers wrap at 2^16, so the history must divide it
+const MAX_NACK_HISTORY: usize = 1 << 15;
+
+/// Checks that a `--nack-history` size is a power of two up to 32768.
+pub fn parse_nack_history(value: &str) -> Result<usize, String> {
+    let size: usize = value.parse().map_err(|e| format!("{}", e))?;
+    if size.is_power_of_two() && size <= MAX_NACK_HISTORY {
+        Ok(size)
+    } else {
+        Err(format!(
+            "must be a power of two between 1 and {}",
+            MAX_NACK_HISTORY
+        ))
+    }
+}
+
+/// Sets up NACK generation for received streams and the [`Retransmitter`]
+/// for sent ones, in place of the default NACK interceptors.
+pub fn configure_nack(mut registry: Registry, m: &mut MediaEngine, history: usize) -> Registry {
+    for parameter in ["", "pli"] {
+        m.register_feedback(
+            RTCPFeedback {
+                typ: "nack".to_owned(),
+                parameter: parameter.to_owned(),
+            },
+            RTPCodecType::Video,
+        );
+    }
+    registry.add(Box::new(Retransmitter::builder(history)));
+    registry.add(Box::new(
+        webrtc::interceptor::nack::generator::Generator::builder(),
+    ));
+    registry
+}
+
+/// Answers viewers' NACKs from a history of the packets sent on each track:
+/// with RTX (RFC 4588) on the track's repair stream where the viewer
+/// negotiated one, otherwise by resending the packet as it was.
+pub struct Retransmitter {
+    history: usize,
+    streams: Arc<Streams>,
+}
+
+#[derive(Default)]
+struct Streams {
+    sent: Mutex<HashMap<u32, Arc<SentStream>>>,
+    // Repair streams by the SSRC of the stream they repair
+    repairs: Mutex<HashMap<u32, Arc<RepairStream>>>,
+}
+
+pub struct RetransmitterBuilder {
+    history: usize,
+}
+
+impl Retransmitter {
+    pub fn builder(history: usize) -> RetransmitterBuilder {
+        RetransmitterBuilder { history }
+    }
+}
+
+impl Streams {
+    async fn answer(&self, nack: &TransportLayerNack) {
+        let Some(stream) = self.sent.lock().await.get(&nack.media_ssrc).cloned() else {
+            return;
+        };
+        let repair = self.repairs.lock().await.get(&nack.media_ssrc).cloned();
+
+        let mut resent = 0;
+        let mut missing = 0;
+        for pair in &nack.nacks {
+            for sequence_number in pair.into_iter() {
+                let Some(packet) = stream.get(sequence_number).await else {
+                    missing += 1;
+                    continue;
+                };
+                let result = match &repair {
+                    Some(repair) => repair.send(&packet).await,
+                    None => stream.writer.write(&packet, &Attributes::new()).await,
+                };
+                match result {
+                    Ok(_) => resent += 1,
+                    Err(e) => {
+                        warn!("Failed to retransmit packet {}: {}", sequence_number, e);
+                        return;
+                    }
+                }
+            }
+        }
+        debug!(
+            "NACK for SSRC {}: {} resent{}, {} no longer kept",
+            nack.media_ssrc,
+            resent,
+            if repair.is_some() { " over RTX" } else { "" },
+            missing
+        );
+    }
+}
+
+impl InterceptorBuilder for RetransmitterBuilder {
+    fn build(&self, _id: &str) -> Result<Arc<dyn Interceptor + Send + Sync>, Error> {
+        Ok(Arc::new(Retransmitter {
+            history: self.history,
+            streams: Arc::default(),
+        }))
+    }
+}
+
+#[async_trait]
+impl Interceptor for Retransmitter {
+    async fn bind_rtcp_reader(
+        &self,
+        reader: Arc<dyn RTCPReader + Send + Sync>,
+    ) -> Arc<dyn RTCPReader + Send + Sync> {
+        Arc::new(NackReader {
+            streams: self.streams.clone(),
+            reader,
+        })
+    }
+
+    async fn bind_rtcp_writer(
+        &self,
+        writer: Arc<dyn RTCPWriter + Send + Sync>,
+    ) -> Arc<dyn RTCPWriter + Send + Sync> {
+        writer
+    }
+
+    async fn bind_local_stream(
+        &self,
+        info: &StreamInfo,
+        writer: Arc<dyn RTPWriter + Send + Sync>,
+    ) -> Arc<dyn RTPWriter + Send + Sync> {
+        // Nothing is written to a repair stream but retransmissions
+        if let Some(repaired) = &info.associated_stream {
+            self.streams.repairs.lock().await.insert(
+                repaired.ssrc,
+                Arc::new(RepairStream {
+                    ssrc: info.ssrc,
+                    payload_type: info.payload_type,
+                    sequence_number: Mutex::new(rand_sequence_number()),
+                    writer: writer.clone(),
+                }),
+            );
+            return writer;
+        }
+        let nack = info
+            .rtcp_feedback
+            .iter()
+            .any(|feedback| feedback.typ == "nack" && feedback.parameter.is_empty());
+        if !nack {
+            return writer;
+        }
+
+        let stream = Arc::new(SentStream {
+            packets: Mutex::new(History::new(self.history)),
+            writer,
+        });
+        self.streams
+            .sent
+            .lock()
+            .await
+            .insert(info.ssrc, stream.clone());
+        stream
+    }
+
+    async fn unbind_local_stream(&self, info: &StreamInfo) {
+        self.streams.sent.lock().await.remove(&info.ssrc);
+        if let Some(repaired) = &info.associated_stream {
+            self.streams.repairs.lock().await.remove(&repaired.ssrc);
+        }
+    }
+
+    async fn bind_remote_stream(
+        &self,
+        _info: &StreamInfo,
+        reader: Arc<dyn RTPReader + Send + Sync>,
+    ) -> Arc<dyn RTPReader + Send + Sync> {
+        reader
+    }
+
+    async fn unbind_remote_stream(&self, _info: &StreamInfo) {}
+
+    async fn close(&self) -> Result<(), Error> {
+        self.streams.sent.lock().await.clear();
+        self.streams.repairs.lock().await.clear();
+        Ok(())
+    }
+}
+
+/// Passes RTCP on, answering the NACKs in it.
+struct NackReader {
+    streams: Arc<Streams>,
+    reader: Arc<dyn RTCPReader + Send + Sync>,
+}
+
+#[async_trait]
+impl RTCPReader for NackReader {
+    async fn read(
+        &self,
+        buf: &mut [u8],
+        attributes: &Attributes,
+    ) -> Result<
+        (
+            Vec<Box<dyn webrtc::rtcp::packet::Packet + Send + Sync>>,
+            Attributes,
+        ),
+        Error,
+    > {
+        let (packets, attributes) = self.reader.read(buf, attributes).await?;
+        for packet in &packets {
+            if let Some(nack) = packet.as_any().downcast_ref::<TransportLayerNack>() {
+                let nack = nack.clone();
+                let streams = self.streams.clone();
+                tokio::spawn(async move { streams.answer(&nack).await });
+            }
+        }
+        Ok((packets, attributes))
+    }
+}
+
+/// A track's outgoing stream, remembering what it sent.
+struct SentStream {
+    packets: Mutex<History>,
+    writer: Arc<dyn RTPWriter + Send + Sync>,
+}
+
+impl SentStream {
+    async fn get(&self, sequence_number: u16) -> Option<Packet> {
+        self.packets.lock().await.get(sequence_number).cloned()
+    }
+}
+
+#[async_trait]
+impl RTPWriter for SentStream {
+    async fn write(&self, packet: &Packet, attributes: &Attributes) -> Result<usize, Error> {
+        self.packets.lock().await.add(packet);
+        self.writer.write(packet, attributes).await
+    }
+}
+
+/// The RTX stream repairing one outgoing stream.
+struct RepairStream {
+    ssrc: u32,
+    payload_type: u8,
+    sequence_number: Mutex<u16>,
+    writer: Arc<dyn RTPWriter + Send + Sync>,
+}
+
+impl RepairStream {
+    /// Sends `packet` again in an RTX packet: the original sequence number
+    /// followed by the original payload, on the repair stream's own SSRC,
+    /// payload type and sequence numbers.
+    async fn send(&self, packet: &Packet) -> Result<usize, Error> {
+        let mut payload = BytesMut::with_capacity(2 + packet.payload.len());
+        payload.put_u16(packet.header.sequence_number);
+        payload.put_slice(&packet.payload);
+
+        let mut header = packet.header.clone();
+        header.ssrc = self.ssrc;
+        header.payload_type = self.payload_type;
+        header.padding = false;
+        header.sequence_number = {
+            let mut sequence_number = self.sequence_number.lock().await;
+            *sequence_number = sequence_number.wrapping_add(1);
+            *sequence_number
+        };
+        let rtx = Packet {
+            header,
+            payload: payload.freeze(),
+        };
+        self.writer.write(&rtx, &Attributes::new()).await
+    }
+}
+
+/// The latest packets of a stream, by sequence number.
+struct History {
+    packets: Vec<Option<Packet>>,
+    last: Option<u16>,
+}
+
+impl History {
+    fn new(size: usize) -> Self {
+        Self {
+            packets: vec![None; size],
+            last: None,
+        }
+    }
+
+    fn slot(&self, sequence_number: u16) -> usize {
+        sequence_number as usize % self.packets.len()
+    }
+
+    fn add(&mut self, packet: &Packet) {
+        let sequence_number = packet.header.sequence_number;
+        if let Some(last) = self.last {
+            let ahead = sequence_number.wrapping_sub(last);
+            if ahead == 0 || ahead >= 1 << 15 {
+                // A duplicate or a late packet; keep the newer history
+                return;
+            }
+            // Packets skipped in between were never sent
+            for skipped in 1..ahead.min(self.packets.len() as u16) {
+                let slot = self.slot(last.wrapping_add(skipped));
+                self.packets[slot] = None;
+            }
+        }
+        let slot = self.slot(sequence_number);
+        self.packets[slot] = Some(packet.clone());
+        self.last = Some(sequence_number);
+    }
+
+    fn get(&self, sequence_number: u16) -> Option<&Packet> {
+        let behind = self.last?.wrapping_sub(sequence_number) as usize;
+        if behind >= self.packets.len() {
+            return None;
+        }
+        // The slot may still hold a packet from before a jump in numbering
+        self.packets[self.slot(sequence_number)]
+            .as_ref()
+            .filter(|packet| packet.header.sequence_number == sequence_number)
+    }
+}
+
+// RTP streams start at a random sequence number (RFC 3550 section 5.1)
+fn rand_sequence_number() -> u16 {
+    let bytes = uuid::Uuid::new_v4().into_bytes();
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::{rtcp::transport_feedbacks::transport_layer_nack::NackPair, rtp::header::Header};
+
+    use super::*;
+
+    // Keeps what is written to it
+    #[derive(Default)]
+    struct Recorder(std::sync::Mutex<Vec<Packet>>);
+
+    #[async_trait]
+    impl RTPWriter for Recorder {
+        async fn write(&self, packet: &Packet, _: &Attributes) -> Result<usize, Error> {
+            self.0.lock().unwrap().push(packet.clone());
+            Ok(packet.payload.len())
+        }
+    }
+
+    impl Recorder {
+        fn sequence_numbers(&self) -> Vec<u16> {
+            let packets = self.0.lock().unwrap();
+            packets.iter().map(|p| p.header.sequence_number).collect()
+        }
+    }
+
+    fn packet(sequence_number: u16) -> Packet {
+        Packet {
+            header: Header {
+                ssrc: 1,
+                payload_type: 96,
+                sequence_number,
+                ..Default::default()
+            },
+            payload: vec![sequence_number as u8; 4].into(),
+        }
+    }
+
+    fn nack(packet_id: u16, lost_packets: u16) -> TransportLayerNack {
+        TransportLayerNack {
+            media_ssrc: 1,
+            nacks: vec![NackPair {
+                packet_id,
+                lost_packets,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn takes_power_of_two_histories() {
+        assert_eq!(parse_nack_history("512"), Ok(512));
+        assert_eq!(parse_nack_history("32768"), Ok(MAX_NACK_HISTORY));
+        assert!(parse_nack_history("1000").is_err());
+        assert!(parse_nack_history("65536").is_err());
+        assert!(parse_nack_history("0").is_err());
+        assert!(parse_nack_history("many").is_err());
+    }
+
+    #[test]
+    fn keeps_the_latest_packets() {
+        let mut history = History::new(4);
+        for sequence_number in [65534, 65535, 0, 1] {
+            history.add(&packet(sequence_number));
+        }
+        assert!(history.get(65534).is_some());
+        assert!(history.get(1).is_some());
+
+        // Five ahead evicts the oldest, and the two skipped were never sent
+        history.add(&packet(4));
+        assert!(history.get(65534).is_none());
+        assert!(history.get(1).is_some());
+        assert!(history.get(2).is_none());
+        assert!(history.get(3).is_none());
+        assert!(history.get(4).is_some());
+
+        // Late and duplicate packets leave it as it is
+        history.add(&packet(0));
+        assert!(history.get(4).is_some());
+        assert!(history.get(5).is_none());
+    }
+
+    #[tokio::test]
+    async fn resends_as_sent_without_rtx() {
+        let writer = Arc::new(Recorder::default());
+        let streams = Streams::default();
+        let stream = Arc::new(SentStream {
+            packets: Mutex::new(History::new(16)),
+            writer: writer.clone(),
+        });
+        streams.sent.lock().await.insert(1, stream.clone());
+        for sequence_number in 10..15 {
+            stream
+                .write(&packet(sequence_number), &Attributes::new())
+                .await
+                .unwrap();
+        }
+
+        // 11, then 12 and 14 from the bitmask, and 40 which was never kept
+        streams.answer(&nack(11, 0b101)).await;
+        streams.answer(&nack(40, 0)).await;
+        assert_eq!(writer.sequence_numbers(), [10, 11, 12, 13, 14, 11, 12, 14]);
+    }
+
+    #[tokio::test]
+    async fn resends_over_rtx() {
+        let media = Arc::new(Recorder::default());
+        let repair = Arc::new(Recorder::default());
+        let streams = Streams::default();
+        let stream = Arc::new(SentStream {
+            packets: Mutex::new(History::new(16)),
+            writer: media.clone(),
+        });
+        streams.sent.lock().await.insert(1, stream.clone());
+        streams.repairs.lock().await.insert(
+            1,
+            Arc::new(RepairStream {
+                ssrc: 2,
+                payload_type: 97,
+                sequence_number: Mutex::new(u16::MAX),
+                writer: repair.clone(),
+            }),
+        );
+        stream
+            .write(&packet(300), &Attributes::new())
+            .await
+            .unwrap();
+
+        streams.answer(&nack(300, 0)).await;
+        let sent = repair.0.lock().unwrap();
+        let [rtx] = sent.as_slice() else {
+            panic!("expected one retransmission, got {}", sent.len());
+        };
+        assert_eq!(rtx.header.ssrc, 2);
+        assert_eq!(rtx.header.payload_type, 97);
+        assert_eq!(rtx.header.sequence_number, 0);
+        // The original sequence number, then the original payload
+        assert_eq!(&rtx.payload[..], [1, 44, 44, 44, 44, 44]);
+        assert_eq!(media.sequence_numbers(), [300]);
+    }
+}
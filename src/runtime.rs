@@ -0,0 +1,75 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use tracing::{info, warn};
+
+/// Builds the multi-threaded tokio runtime the gateway runs on.
+///
+/// When `cpus` is non-empty, every runtime thread is pinned to one of the listed
+/// cores, assigned round-robin as threads start.
+pub fn build(
+    worker_threads: Option<usize>,
+    cpus: &[usize],
+) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+
+    if let Some(worker_threads) = worker_threads.or_else(cgroup_cpu_limit) {
+        builder.worker_threads(worker_threads);
+    }
+
+    if !cpus.is_empty() {
+        let cores: Arc<[core_affinity::CoreId]> = cpus
+            .iter()
+            .map(|&id| core_affinity::CoreId { id })
+            .collect();
+        let next = AtomicUsize::new(0);
+
+        builder.on_thread_start(move || {
+            let core = cores[next.fetch_add(1, Ordering::Relaxed) % cores.len()];
+            if !core_affinity::set_for_current(core) {
+                warn!("Failed to pin runtime thread to CPU {}", core.id);
+            }
+        });
+    }
+
+    builder.build()
+}
+
+/// Worker threads for the CPU quota of the container we run in (cgroup v2
+/// `cpu.max`, or v1 `cpu.cfs_quota_us`), rounded up and at most the CPUs we
+/// may run on.
+fn cgroup_cpu_limit() -> Option<usize> {
+    let read = |path: &str| std::fs::read_to_string(path).ok();
+    let (quota, period) = match read("/sys/fs/cgroup/cpu.max") {
+        Some(max) => {
+            let (quota, period) = max.trim().split_once(' ')?;
+            (quota.parse::<u64>().ok()?, period.parse::<u64>().ok()?)
+        }
+        // An unlimited v1 quota is -1, which doesn't parse
+        None => (
+            read("/sys/fs/cgroup/cpu/cpu.cfs_quota_us")?
+                .trim()
+                .parse::<u64>()
+                .ok()?,
+            read("/sys/fs/cgroup/cpu/cpu.cfs_period_us")?
+                .trim()
+                .parse::<u64>()
+                .ok()?,
+        ),
+    };
+    if quota == 0 || period == 0 {
+        return None;
+    }
+
+    let cores = std::thread::available_parallelism().map_or(usize::MAX, |n| n.get());
+    let limit = (quota.div_ceil(period) as usize).min(cores);
+    info!(
+        "Container CPU limit is {:.1} CPUs, using {} worker threads",
+        quota as f64 / period as f64,
+        limit
+    );
+    Some(limit)
+}
@@ -0,0 +1,358 @@
+// msid identifiers are at most 64 token characters (RFC 8830); leave room for
+// the `-video` / `-audio` suffix of track ids
+const MSID_TOKEN_LEN: usize = 58;
+
+/// Names the session in an SDP after its source: `s=` carries the title and
+/// `i=` the description, if any. Media sections are left untouched.
+pub fn label_session(sdp: &str, title: &str, description: Option<&str>) -> String {
+    let mut out: Vec<String> = Vec::new();
+    let mut in_media = false;
+
+    for line in sdp.lines() {
+        in_media |= line.starts_with("m=");
+        if in_media {
+            out.push(line.to_owned());
+        } else if line.starts_with("s=") {
+            out.push(format!("s={}", single_line(title)));
+            if let Some(description) = description {
+                out.push(format!("i={}", single_line(description)));
+            }
+        } else if !line.starts_with("i=") {
+            out.push(line.to_owned());
+        }
+    }
+
+    let mut sdp = out.join("\r\n");
+    sdp.push_str("\r\n");
+    sdp
+}
+
+/// Drops the RTX (RFC 4588) payload types from an SDP, so a publisher
+/// retransmits on its media streams, which the gateway's receivers take.
+pub fn strip_rtx(sdp: &str) -> String {
+    let rtx: Vec<&str> = sdp
+        .lines()
+        .filter_map(|line| line.strip_prefix("a=rtpmap:"))
+        .filter_map(|map| map.split_once(' '))
+        .filter(|(_, encoding)| encoding.to_ascii_lowercase().starts_with("rtx/"))
+        .map(|(payload_type, _)| payload_type)
+        .collect();
+    if rtx.is_empty() {
+        return sdp.to_owned();
+    }
+
+    let mut out: Vec<String> = Vec::new();
+    for line in sdp.lines() {
+        if let Some(media) = line.strip_prefix("m=") {
+            // m=<media> <port> <proto> <fmt> ...
+            let kept: Vec<&str> = media
+                .split(' ')
+                .enumerate()
+                .filter(|(i, fmt)| *i < 3 || !rtx.contains(fmt))
+                .map(|(_, field)| field)
+                .collect();
+            out.push(format!("m={}", kept.join(" ")));
+            continue;
+        }
+        let attribute = ["a=rtpmap:", "a=fmtp:", "a=rtcp-fb:"]
+            .iter()
+            .find_map(|prefix| line.strip_prefix(prefix));
+        if let Some(value) = attribute
+            && rtx.contains(&value.split(' ').next().unwrap_or_default())
+        {
+            continue;
+        }
+        out.push(line.to_owned());
+    }
+
+    let mut sdp = out.join("\r\n");
+    sdp.push_str("\r\n");
+    sdp
+}
+
+/// The rids a receiver offers to take as simulcast (RFC 8853), from the
+/// `a=simulcast:recv` of the offer's first video section, in its order.
+/// Paused rids are left out, and of alternatives only the first is kept.
+pub fn simulcast_recv_rids(sdp: &str) -> Vec<String> {
+    let mut in_video = false;
+    for line in sdp.lines() {
+        if let Some(media) = line.strip_prefix("m=") {
+            if in_video {
+                break;
+            }
+            in_video = media.starts_with("video ");
+        } else if in_video && let Some(simulcast) = line.strip_prefix("a=simulcast:") {
+            // `recv <rids>` may follow `send <rids>`
+            let mut fields = simulcast.split_whitespace();
+            while let Some(direction) = fields.next() {
+                let rids = fields.next().unwrap_or_default();
+                if direction == "recv" {
+                    return rids
+                        .split(';')
+                        .filter_map(|alternatives| alternatives.split(',').next())
+                        .filter(|rid| !rid.is_empty() && !rid.starts_with('~'))
+                        .map(str::to_owned)
+                        .collect();
+                }
+            }
+        }
+    }
+    Vec::new()
+}
+
+/// Announces exactly the encodings `rids` as sent simulcast in the media
+/// sections that carry rids, in place of the `a=rid` and `a=simulcast` lines
+/// there: the answer otherwise echoes every rid the offer lists, besides the
+/// ones the sender announces.
+pub fn answer_simulcast(sdp: &str, rids: &[&str]) -> String {
+    let mut out: Vec<String> = Vec::new();
+    let mut announced = false;
+    for line in sdp.lines() {
+        if line.starts_with("m=") {
+            announced = false;
+        } else if line.starts_with("a=rid:") || line.starts_with("a=simulcast:") {
+            if !announced {
+                for rid in rids {
+                    out.push(format!("a=rid:{} send", rid));
+                }
+                out.push(format!("a=simulcast:send {}", rids.join(";")));
+                announced = true;
+            }
+            continue;
+        }
+        out.push(line.to_owned());
+    }
+
+    let mut sdp = out.join("\r\n");
+    sdp.push_str("\r\n");
+    sdp
+}
+
+/// Turns a display name into an msid identifier, so players can label tracks
+/// from the SDP alone. Characters that are not allowed in a token become `-`.
+pub fn msid_token(name: &str) -> String {
+    let token: String = name
+        .chars()
+        .take(MSID_TOKEN_LEN)
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`{|}~".contains(c) {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+
+    if token.is_empty() {
+        "-".to_owned()
+    } else {
+        token
+    }
+}
+
+/// ICE credentials and candidates of an SDP, or of a trickle ICE fragment
+/// (`application/trickle-ice-sdpfrag`, RFC 8840).
+#[derive(Debug, Default)]
+pub struct IceFragment {
+    /// The first `a=ice-ufrag`; all sections share it when bundled.
+    pub ufrag: Option<String>,
+    /// `candidate:...` values with the mid of the media section they belong to.
+    pub candidates: Vec<(Option<String>, String)>,
+}
+
+pub fn parse_ice_fragment(sdp: &str) -> IceFragment {
+    let mut fragment = IceFragment::default();
+    let mut mid = None;
+
+    for line in sdp.lines() {
+        if line.starts_with("m=") {
+            mid = None;
+        } else if let Some(value) = line.strip_prefix("a=mid:") {
+            mid = Some(value.to_owned());
+        } else if let Some(value) = line.strip_prefix("a=ice-ufrag:") {
+            fragment.ufrag.get_or_insert_with(|| value.to_owned());
+        } else if let Some(candidate) = line.strip_prefix("a=")
+            && candidate.starts_with("candidate:")
+        {
+            fragment
+                .candidates
+                .push((mid.clone(), candidate.to_owned()));
+        }
+    }
+
+    fragment
+}
+
+/// Whether the client that made `offer` trickles its ICE candidates: it
+/// supports trickle ICE (RFC 8838) and sent none with the offer, so it will
+/// `PATCH` them and can take the gateway's in return.
+pub fn trickles_candidates(offer: &str) -> bool {
+    let trickle = offer.lines().any(|line| {
+        line.strip_prefix("a=ice-options:")
+            .is_some_and(|options| options.split_whitespace().any(|o| o == "trickle"))
+    });
+    trickle && parse_ice_fragment(offer).candidates.is_empty()
+}
+
+/// A trickle ICE fragment (RFC 8840) carrying `candidates` for the first
+/// media section of `local`, the description they were gathered for; with
+/// `complete`, it also ends the candidates. Bundled sections share them.
+pub fn ice_fragment(local: &str, candidates: &[String], complete: bool) -> String {
+    let attribute = |name: &str| {
+        local
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .unwrap_or_default()
+    };
+    let mut lines = vec![
+        format!("a=ice-ufrag:{}", attribute("a=ice-ufrag:")),
+        format!("a=ice-pwd:{}", attribute("a=ice-pwd:")),
+    ];
+    if let Some(media) = local.lines().find(|line| line.starts_with("m=")) {
+        lines.push(media.to_owned());
+        lines.push(format!("a=mid:{}", attribute("a=mid:")));
+    }
+    lines.extend(
+        candidates
+            .iter()
+            .map(|candidate| format!("a={}", candidate)),
+    );
+    if complete {
+        lines.push("a=end-of-candidates".to_owned());
+    }
+    let mut fragment = lines.join("\r\n");
+    fragment.push_str("\r\n");
+    fragment
+}
+
+// SDP text fields end at the line break and must not be empty
+fn single_line(text: &str) -> String {
+    let text = text.replace(['\r', '\n'], " ");
+    if text.trim().is_empty() {
+        "-".to_owned()
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "candidate:1 1 udp 2130706431 192.0.2.1 5000 typ host";
+
+    #[test]
+    fn labels_the_session() {
+        let sdp = "v=0\r\no=- 1 2 IN IP4 0.0.0.0\r\ns=-\r\ni=old\r\nt=0 0\r\n\
+            m=video 9 UDP/TLS/RTP/SAVPF 96\r\ni=camera\r\ns=kept\r\n";
+        assert_eq!(
+            label_session(sdp, "Front door", Some("North side\nby the gate")),
+            "v=0\r\no=- 1 2 IN IP4 0.0.0.0\r\ns=Front door\r\ni=North side by the gate\r\n\
+             t=0 0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\ni=camera\r\ns=kept\r\n"
+        );
+        assert!(label_session(sdp, " ", None).contains("\r\ns=-\r\nt=0 0"));
+    }
+
+    #[test]
+    fn makes_msid_tokens() {
+        assert_eq!(msid_token("Front door (north)"), "Front-door--north-");
+        assert_eq!(msid_token("cam_1.main"), "cam_1.main");
+        assert_eq!(msid_token(""), "-");
+        assert_eq!(msid_token(&"x".repeat(100)).len(), MSID_TOKEN_LEN);
+    }
+
+    #[test]
+    fn strips_rtx() {
+        let sdp = "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96 97 98\r\n\
+            a=rtpmap:96 H264/90000\r\na=rtcp-fb:96 nack\r\na=rtpmap:97 rtx/90000\r\n\
+            a=fmtp:97 apt=96\r\na=rtpmap:98 VP8/90000\r\n";
+        assert_eq!(
+            strip_rtx(sdp),
+            "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96 98\r\n\
+             a=rtpmap:96 H264/90000\r\na=rtcp-fb:96 nack\r\na=rtpmap:98 VP8/90000\r\n"
+        );
+        let plain = "v=0\nm=audio 9 RTP/AVP 0\n";
+        assert_eq!(strip_rtx(plain), plain);
+    }
+
+    #[test]
+    fn reads_simulcast_rids() {
+        let offer = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=simulcast:recv a\r\n\
+            m=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rid:hi recv\r\n\
+            a=simulcast:send x recv hi,mid;~lo;sub\r\n\
+            m=video 9 UDP/TLS/RTP/SAVPF 97\r\na=simulcast:recv other\r\n";
+        assert_eq!(simulcast_recv_rids(offer), ["hi", "sub"]);
+        assert!(simulcast_recv_rids("v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n").is_empty());
+    }
+
+    #[test]
+    fn answers_simulcast_with_given_rids() {
+        let answer = "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=mid:0\r\n\
+            a=rid:hi send\r\na=rid:mid send\r\na=rid:lo send\r\na=simulcast:send hi;mid;lo\r\n\
+            m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:1\r\n";
+        assert_eq!(
+            answer_simulcast(answer, &["hi", "lo"]),
+            "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=mid:0\r\n\
+             a=rid:hi send\r\na=rid:lo send\r\na=simulcast:send hi;lo\r\n\
+             m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:1\r\n"
+        );
+    }
+
+    #[test]
+    fn parses_ice_fragments() {
+        let fragment = parse_ice_fragment(&format!(
+            "a=ice-ufrag:EsAw\r\na=ice-pwd:pw\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+             a=mid:0\r\na={}\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=ice-ufrag:other\r\n\
+             a=candidate:2 1 udp 1 192.0.2.2 5000 typ host\r\na=end-of-candidates\r\n",
+            HOST
+        ));
+        assert_eq!(fragment.ufrag.as_deref(), Some("EsAw"));
+        assert_eq!(
+            fragment.candidates,
+            vec![
+                (Some("0".to_owned()), HOST.to_owned()),
+                (
+                    None,
+                    "candidate:2 1 udp 1 192.0.2.2 5000 typ host".to_owned()
+                ),
+            ]
+        );
+        assert!(parse_ice_fragment("").candidates.is_empty());
+    }
+
+    #[test]
+    fn tells_trickling_offers() {
+        let trickling = "v=0\r\na=ice-options:trickle\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n";
+        assert!(trickles_candidates(trickling));
+        assert!(!trickles_candidates(&format!(
+            "{}a={}\r\n",
+            trickling, HOST
+        )));
+        assert!(!trickles_candidates(
+            "v=0\r\na=ice-options:ice2\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n"
+        ));
+    }
+
+    #[test]
+    fn builds_ice_fragments() {
+        let local = "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=mid:0\r\n\
+            a=ice-ufrag:EsAw\r\na=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1\r\n\
+            m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:1\r\n";
+        assert_eq!(
+            ice_fragment(local, &[HOST.to_owned()], true),
+            format!(
+                "a=ice-ufrag:EsAw\r\na=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1\r\n\
+                 m=video 9 UDP/TLS/RTP/SAVPF 96\r\na=mid:0\r\na={}\r\n\
+                 a=end-of-candidates\r\n",
+                HOST
+            )
+        );
+
+        let fragment = parse_ice_fragment(&ice_fragment(local, &[HOST.to_owned()], false));
+        assert_eq!(fragment.ufrag.as_deref(), Some("EsAw"));
+        assert_eq!(
+            fragment.candidates,
+            vec![(Some("0".to_owned()), HOST.to_owned())]
+        );
+    }
+}
@@ -0,0 +1,53 @@
+use axum::http::HeaderValue;
+
+/// Header values applied to the static player responses.
+pub struct SecurityHeaders {
+    pub content_security_policy: Option<HeaderValue>,
+    pub x_frame_options: Option<HeaderValue>,
+    pub referrer_policy: Option<HeaderValue>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid security header value: {0}")]
+pub struct SecurityHeadersError(#[from] axum::http::header::InvalidHeaderValue);
+
+impl SecurityHeaders {
+    /// Combines the configured CSP with a `frame-ancestors` directive.
+    ///
+    /// `X-Frame-Options` is derived for older browsers when the ancestors are just
+    /// `'none'` or `'self'`; allowlists can only be expressed through CSP.
+    pub fn new(
+        csp: Option<&str>,
+        frame_ancestors: Option<&str>,
+        referrer_policy: &str,
+    ) -> Result<Self, SecurityHeadersError> {
+        let csp = match (csp, frame_ancestors) {
+            (Some(csp), Some(ancestors)) => Some(format!(
+                "{}; frame-ancestors {}",
+                csp.trim_end_matches([';', ' ']),
+                ancestors
+            )),
+            (Some(csp), None) => Some(csp.to_owned()),
+            (None, Some(ancestors)) => Some(format!("frame-ancestors {}", ancestors)),
+            (None, None) => None,
+        };
+
+        let x_frame_options = match frame_ancestors.map(str::trim) {
+            Some("'none'") => Some(HeaderValue::from_static("DENY")),
+            Some("'self'") => Some(HeaderValue::from_static("SAMEORIGIN")),
+            _ => None,
+        };
+
+        let referrer_policy = if referrer_policy.is_empty() {
+            None
+        } else {
+            Some(HeaderValue::from_str(referrer_policy)?)
+        };
+
+        Ok(Self {
+            content_security_policy: csp.map(HeaderValue::try_from).transpose()?,
+            x_frame_options,
+            referrer_policy,
+        })
+    }
+}
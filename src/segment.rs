@@ -0,0 +1,724 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::{Notify, broadcast};
+use tracing::{debug, warn};
+use webrtc::{
+    rtp::{codecs::h264::H264Packet, packet::Packet, packetizer::Depacketizer},
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+};
+
+use crate::{
+    fanout::FanoutTrack,
+    mp4::{self, Run, Sample, Track, TrackKind},
+};
+
+const VIDEO_TRACK_ID: u32 = 1;
+const AUDIO_TRACK_ID: u32 = 2;
+// Opus always runs at 48 kHz in RTP and MP4
+const OPUS_CLOCK_RATE: u32 = 48000;
+// Samples per AAC frame
+const AAC_FRAME_LENGTH: u64 = 1024;
+// Fragments of audio-only segments, in seconds
+const AUDIO_FRAGMENT: u64 = 1;
+
+/// How a source is cut into segments.
+#[derive(Clone)]
+pub struct SegmentOptions {
+    /// Segments end at the first keyframe after this much video.
+    pub segment: Duration,
+    /// Decode times and fragment numbers run on from one segment to the
+    /// next, as in a playlist, instead of starting over in each file.
+    pub continuous: bool,
+    /// Asked for a keyframe once a segment reaches `segment` without one, so
+    /// GOPs longer than that don't stretch the segment.
+    pub keyframe_requests: Option<Arc<Notify>>,
+}
+
+/// What a segmenter produces: each segment is a `Start` with its init
+/// segment (`ftyp` and `moov`), then its fragments, then `End`.
+pub enum Output {
+    Start(Vec<u8>),
+    Fragment { data: Vec<u8>, duration: Duration },
+    End,
+}
+
+/// Cuts a source's tracks into fragmented MP4 segments starting at a
+/// keyframe, from the same packets its viewers get. Video fragments hold one
+/// GOP.
+///
+/// Only H.264 video and Opus or AAC audio are supported; other tracks are
+/// skipped.
+pub struct Segmenter {
+    name: String,
+    purpose: &'static str,
+    options: SegmentOptions,
+    video: Option<VideoInput>,
+    audio: Option<AudioInput>,
+    video_packets: Option<broadcast::Receiver<Packet>>,
+    audio_packets: Option<broadcast::Receiver<Packet>>,
+    segment: Option<Segment>,
+    sequence: u32,
+    // Clock times decode times count from, kept across continuous segments
+    bases: Option<(u64, Option<u64>)>,
+    outputs: Vec<Output>,
+}
+
+impl Segmenter {
+    /// `purpose` names what the segments are for, in logs.
+    pub fn new(
+        name: &str,
+        purpose: &'static str,
+        video: Option<&Arc<FanoutTrack>>,
+        audio: Option<&Arc<FanoutTrack>>,
+        options: SegmentOptions,
+    ) -> Option<Self> {
+        let video = video.filter(|track| {
+            let mime_type = track.codec().mime_type;
+            let supported = mime_type.eq_ignore_ascii_case("video/h264");
+            if !supported {
+                warn!(
+                    "[{}] {} skips {} video, only H.264",
+                    name, purpose, mime_type
+                );
+            }
+            supported
+        });
+        let audio = audio.and_then(|track| {
+            let codec = track.codec();
+            let input = AudioInput::new(&codec);
+            if input.is_none() {
+                warn!(
+                    "[{}] {} skips {} audio, only Opus and AAC",
+                    name, purpose, codec.mime_type
+                );
+            }
+            input.map(|input| (track, input))
+        });
+        if video.is_none() && audio.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            name: name.to_owned(),
+            purpose,
+            options,
+            video: video.map(|track| VideoInput::new(track.codec().clock_rate)),
+            audio_packets: audio.as_ref().map(|(track, _)| track.listen()),
+            audio: audio.map(|(_, input)| input),
+            video_packets: video.map(|track| track.listen()),
+            segment: None,
+            sequence: 0,
+            bases: None,
+            outputs: Vec::new(),
+        })
+    }
+
+    /// Hands the segments to `sink` until the source ends.
+    pub fn spawn(mut self, mut sink: impl FnMut(Output) + Send + 'static) {
+        let mut video_packets = self.video_packets.take();
+        let mut audio_packets = self.audio_packets.take();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    pkt = recv(&mut video_packets) => match pkt {
+                        Ok(pkt) => self.video_packet(&pkt),
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            debug!("[{}] {} fell behind, skipped {} video packets", self.name, self.purpose, skipped);
+                            self.restart();
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    pkt = recv(&mut audio_packets) => match pkt {
+                        Ok(pkt) => self.audio_packet(&pkt),
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            debug!("[{}] {} fell behind, skipped {} audio packets", self.name, self.purpose, skipped);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                }
+                self.outputs.drain(..).for_each(&mut sink);
+            }
+            self.close_segment();
+            self.outputs.drain(..).for_each(&mut sink);
+            debug!("[{}] {} stopped", self.name, self.purpose);
+        });
+    }
+
+    fn video_packet(&mut self, pkt: &Packet) {
+        let Some(video) = self.video.as_mut() else {
+            return;
+        };
+        let mut frames = Vec::new();
+        video.push(pkt, &mut frames);
+        for frame in frames {
+            self.video_frame(frame);
+        }
+    }
+
+    fn video_frame(&mut self, frame: Frame) {
+        let Some(video) = self.video.as_ref() else {
+            return;
+        };
+        let parameter_sets = video.sps.clone().zip(video.pps.clone());
+        let clock_rate = video.clock_rate as u64;
+
+        if frame.keyframe
+            && let Some(segment) = self.segment.as_mut()
+        {
+            // Fragments hold whole GOPs, and segments start at a keyframe
+            if let Some(last) = segment.video.last.take() {
+                segment.video.last_duration = frame
+                    .time
+                    .saturating_sub(last.time)
+                    .clamp(1, u32::MAX as u64) as u32;
+                segment.video.add(last, segment.video_base);
+            }
+            self.write_fragment();
+            // The clock doesn't run backwards, but a frame from before the
+            // segment must not wrap around into a due one either
+            let due = self.segment.as_ref().is_some_and(|segment| {
+                frame.time.saturating_sub(segment.start)
+                    >= self.options.segment.as_secs() * clock_rate
+                    || segment.parameter_sets != parameter_sets
+            });
+            if due {
+                self.close_segment();
+            }
+        }
+
+        if self.segment.is_none() {
+            // Decoding starts at a keyframe with its parameter sets
+            if !frame.keyframe || parameter_sets.is_none() {
+                return;
+            }
+            self.open_segment(frame.time);
+        }
+        let Some(segment) = self.segment.as_mut() else {
+            return;
+        };
+        if !frame.keyframe
+            && !segment.keyframe_requested
+            && let Some(requests) = &self.options.keyframe_requests
+            && frame.time.saturating_sub(segment.start)
+                >= self.options.segment.as_secs() * clock_rate
+        {
+            debug!(
+                "[{}] {} segment is due, asking for a keyframe",
+                self.name, self.purpose
+            );
+            requests.notify_one();
+            segment.keyframe_requested = true;
+        }
+        segment.video.push(frame, segment.video_base);
+    }
+
+    fn audio_packet(&mut self, pkt: &Packet) {
+        let Some(audio) = self.audio.as_mut() else {
+            return;
+        };
+        let time = audio.clock.extend(pkt.header.timestamp);
+        let frames: Vec<Frame> = match audio.kind {
+            TrackKind::Aac { .. } => aac_access_units(&pkt.payload)
+                .into_iter()
+                .zip(0..)
+                .map(|(data, index)| Frame {
+                    data: data.to_vec(),
+                    time: time + index * AAC_FRAME_LENGTH,
+                    keyframe: true,
+                })
+                .collect(),
+            _ => vec![Frame {
+                data: pkt.payload.to_vec(),
+                time,
+                keyframe: true,
+            }],
+        };
+        let clock_rate = audio.clock_rate as u64;
+        for frame in frames {
+            self.audio_frame(frame, clock_rate);
+        }
+    }
+
+    fn audio_frame(&mut self, frame: Frame, clock_rate: u64) {
+        let time = frame.time;
+        if self.video.is_none() {
+            let due = self.segment.as_ref().is_some_and(|segment| {
+                time.saturating_sub(segment.start) >= self.options.segment.as_secs() * clock_rate
+            });
+            if due {
+                self.close_segment();
+            }
+            if self.segment.is_none() {
+                self.open_segment(time);
+            }
+        }
+
+        let Some(segment) = self.segment.as_mut() else {
+            return;
+        };
+        let base = *segment.audio_base.get_or_insert(time);
+        // Audio from before the segment's first picture
+        if time < base {
+            return;
+        }
+        segment.audio.push(frame, base);
+
+        let flush = self.video.is_none()
+            && segment.audio.samples.len() as u64 * segment.audio.last_duration as u64
+                >= AUDIO_FRAGMENT * clock_rate;
+        if flush {
+            self.write_fragment();
+        }
+    }
+
+    /// Starts a segment at `start`, on the clock of the video track or, if
+    /// there is none, the audio track.
+    fn open_segment(&mut self, start: u64) {
+        let tracks: Vec<Track> = self
+            .video
+            .as_ref()
+            .and_then(VideoInput::track)
+            .into_iter()
+            .chain(self.audio.as_ref().map(|audio| Track {
+                id: AUDIO_TRACK_ID,
+                timescale: audio.clock_rate,
+                kind: audio.kind.clone(),
+            }))
+            .collect();
+        self.outputs.push(Output::Start(mp4::init_segment(&tracks)));
+
+        let (video_base, audio_base) = match self.bases {
+            Some(bases) => bases,
+            None => {
+                // Audio joins at the sound heard with the first picture
+                let audio_base = self
+                    .audio
+                    .as_ref()
+                    .filter(|_| self.video.is_some())
+                    .and_then(|audio| audio.clock.last.map(|_| audio.clock.now));
+                (start, audio_base)
+            }
+        };
+        if !self.options.continuous {
+            self.sequence = 0;
+        }
+        self.segment = Some(Segment {
+            parameter_sets: self
+                .video
+                .as_ref()
+                .and_then(|video| video.sps.clone().zip(video.pps.clone())),
+            start,
+            video_base,
+            audio_base,
+            video: Pending::default(),
+            audio: Pending::default(),
+            keyframe_requested: false,
+        });
+    }
+
+    fn write_fragment(&mut self) {
+        let Some(segment) = self.segment.as_mut() else {
+            return;
+        };
+        let runs: Vec<Run<'_>> = segment
+            .video
+            .run(VIDEO_TRACK_ID)
+            .into_iter()
+            .chain(segment.audio.run(AUDIO_TRACK_ID))
+            .collect();
+        if runs.is_empty() {
+            return;
+        }
+        // The video track, if any, tells how long the fragment lasts
+        let (samples, clock_rate) = match &self.video {
+            Some(video) => (&segment.video.samples, video.clock_rate),
+            None => (
+                &segment.audio.samples,
+                self.audio.as_ref().map_or(1, |audio| audio.clock_rate),
+            ),
+        };
+        let ticks: u64 = samples.iter().map(|sample| sample.duration as u64).sum();
+        let duration = Duration::from_secs_f64(ticks as f64 / clock_rate.max(1) as f64);
+
+        self.sequence += 1;
+        let data = mp4::fragment(self.sequence, &runs);
+        segment.video.samples.clear();
+        segment.audio.samples.clear();
+        self.outputs.push(Output::Fragment { data, duration });
+    }
+
+    fn close_segment(&mut self) {
+        let Some(segment) = self.segment.as_mut() else {
+            return;
+        };
+        segment.video.flush_last(segment.video_base);
+        if let Some(base) = segment.audio_base {
+            segment.audio.flush_last(base);
+        }
+        if self.options.continuous {
+            self.bases = Some((segment.video_base, segment.audio_base));
+        }
+        self.write_fragment();
+        self.segment = None;
+        self.outputs.push(Output::End);
+    }
+
+    /// Starts over at the next keyframe after packets were lost.
+    fn restart(&mut self) {
+        if let Some(video) = self.video.as_mut() {
+            video.reset();
+        }
+        self.close_segment();
+    }
+}
+
+// Waits forever on tracks the source doesn't have
+async fn recv(
+    packets: &mut Option<broadcast::Receiver<Packet>>,
+) -> Result<Packet, broadcast::error::RecvError> {
+    match packets {
+        Some(packets) => packets.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Depacketizes into 4-byte length prefixed NAL units, as MP4 stores them.
+pub fn avc_depacketizer() -> H264Packet {
+    let mut depacketizer = H264Packet::default();
+    depacketizer.is_avc = true;
+    depacketizer
+}
+
+/// Extends 32-bit RTP timestamps into a 64-bit timeline starting at 0.
+#[derive(Default)]
+struct Clock {
+    last: Option<u32>,
+    now: u64,
+}
+
+impl Clock {
+    fn extend(&mut self, timestamp: u32) -> u64 {
+        if let Some(last) = self.last {
+            // Late packets don't move the clock back
+            let delta = timestamp.wrapping_sub(last) as i32;
+            if delta > 0 {
+                self.now += delta as u64;
+                self.last = Some(timestamp);
+            }
+        } else {
+            self.last = Some(timestamp);
+        }
+        self.now
+    }
+}
+
+/// A picture or an audio frame, at its place on the track's clock.
+struct Frame {
+    data: Vec<u8>,
+    time: u64,
+    keyframe: bool,
+}
+
+/// Turns RTP packets into H.264 access units, keeping the latest parameter
+/// sets out of band.
+struct VideoInput {
+    clock_rate: u32,
+    clock: Clock,
+    depacketizer: H264Packet,
+    timestamp: Option<u32>,
+    access_unit: Vec<u8>,
+    keyframe: bool,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+}
+
+impl VideoInput {
+    fn new(clock_rate: u32) -> Self {
+        Self {
+            clock_rate,
+            clock: Clock::default(),
+            depacketizer: avc_depacketizer(),
+            timestamp: None,
+            access_unit: Vec::new(),
+            keyframe: false,
+            sps: None,
+            pps: None,
+        }
+    }
+
+    /// The access unit completed by `pkt`, or by a packet of the next one.
+    fn push(&mut self, pkt: &Packet, frames: &mut Vec<Frame>) {
+        let timestamp = pkt.header.timestamp;
+        if self.timestamp.is_some_and(|current| current != timestamp) {
+            frames.extend(self.finish());
+        }
+        self.timestamp = Some(timestamp);
+
+        // Middle fragments of a NAL unit give nothing yet
+        let Ok(nals) = self.depacketizer.depacketize(&pkt.payload) else {
+            return;
+        };
+        let mut nals = &nals[..];
+        while nals.len() >= 4 {
+            let len = u32::from_be_bytes([nals[0], nals[1], nals[2], nals[3]]) as usize;
+            let Some(nal) = nals.get(4..4 + len) else {
+                break;
+            };
+            match nal.first().map(|header| header & 0x1f) {
+                Some(7) => self.sps = Some(nal.to_vec()),
+                Some(8) => self.pps = Some(nal.to_vec()),
+                // Access unit delimiters and filler data
+                Some(9 | 12) | None => {}
+                Some(nal_type) => {
+                    self.keyframe |= nal_type == 5;
+                    self.access_unit.extend_from_slice(&nals[..4 + len]);
+                }
+            }
+            nals = &nals[4 + len..];
+        }
+
+        if pkt.header.marker {
+            frames.extend(self.finish());
+        }
+    }
+
+    fn finish(&mut self) -> Option<Frame> {
+        let timestamp = self.timestamp.take()?;
+        let keyframe = std::mem::take(&mut self.keyframe);
+        if self.access_unit.is_empty() {
+            return None;
+        }
+        Some(Frame {
+            data: std::mem::take(&mut self.access_unit),
+            time: self.clock.extend(timestamp),
+            keyframe,
+        })
+    }
+
+    /// Drops the access unit being assembled, after lost packets.
+    fn reset(&mut self) {
+        self.depacketizer = avc_depacketizer();
+        self.timestamp = None;
+        self.access_unit.clear();
+        self.keyframe = false;
+    }
+
+    fn track(&self) -> Option<Track> {
+        let (sps, pps) = (self.sps.clone()?, self.pps.clone()?);
+        let (width, height) = h264_reader::rbsp::decode_nal(&sps)
+            .ok()
+            .and_then(|rbsp| {
+                h264_reader::nal::sps::SeqParameterSet::from_bits(
+                    h264_reader::rbsp::BitReader::new(&*rbsp),
+                )
+                .ok()
+            })
+            .and_then(|sps| sps.pixel_dimensions().ok())
+            .unwrap_or_default();
+        Some(Track {
+            id: VIDEO_TRACK_ID,
+            timescale: self.clock_rate,
+            kind: TrackKind::H264 {
+                sps,
+                pps,
+                width: width as u16,
+                height: height as u16,
+            },
+        })
+    }
+}
+
+struct AudioInput {
+    kind: TrackKind,
+    clock_rate: u32,
+    clock: Clock,
+}
+
+impl AudioInput {
+    /// `None` for codecs MP4 segments can't hold. AAC comes as in an RTSP
+    /// camera's SDP: `mpeg4-generic` with the AudioSpecificConfig in the
+    /// `config` parameter of its fmtp line.
+    fn new(codec: &RTCRtpCodecCapability) -> Option<Self> {
+        let channels = codec.channels.clamp(1, 2) as u8;
+        let (kind, clock_rate) = match codec.mime_type.to_ascii_lowercase().as_str() {
+            "audio/opus" => (TrackKind::Opus { channels }, OPUS_CLOCK_RATE),
+            "audio/mpeg4-generic" => {
+                let config = codec
+                    .sdp_fmtp_line
+                    .split(';')
+                    .find_map(|param| param.trim().strip_prefix("config="))
+                    .and_then(decode_hex)?;
+                let kind = TrackKind::Aac {
+                    config,
+                    channels,
+                    sample_rate: codec.clock_rate,
+                };
+                (kind, codec.clock_rate)
+            }
+            _ => return None,
+        };
+        Some(Self {
+            kind,
+            clock_rate,
+            clock: Clock::default(),
+        })
+    }
+}
+
+/// The access units of an RTP payload of AAC in the `AAC-hbr` mode of RFC
+/// 3640 (13-bit sizes, 3-bit indices), which is how cameras send it. Access
+/// units split over several packets are skipped.
+fn aac_access_units(payload: &[u8]) -> Vec<&[u8]> {
+    let Some((bits, rest)) = payload.split_first_chunk::<2>() else {
+        return Vec::new();
+    };
+    let Some((headers, mut data)) = rest.split_at_checked(u16::from_be_bytes(*bits) as usize / 8)
+    else {
+        return Vec::new();
+    };
+    let mut units = Vec::new();
+    for header in headers.chunks_exact(2) {
+        let size = (u16::from_be_bytes([header[0], header[1]]) >> 3) as usize;
+        let Some((unit, rest)) = data.split_at_checked(size) else {
+            break;
+        };
+        units.push(unit);
+        data = rest;
+    }
+    units
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.len().is_multiple_of(2).then_some(())?;
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Samples of one track waiting to go into the next fragment. The newest
+/// frame waits for the one after it, which tells its duration.
+#[derive(Default)]
+struct Pending {
+    samples: Vec<Sample>,
+    // Decode time of the first sample
+    start: u64,
+    last: Option<Frame>,
+    last_duration: u32,
+}
+
+impl Pending {
+    fn push(&mut self, frame: Frame, base: u64) {
+        if let Some(last) = self.last.take() {
+            self.last_duration = frame
+                .time
+                .saturating_sub(last.time)
+                .clamp(1, u32::MAX as u64) as u32;
+            self.add(last, base);
+        }
+        self.last = Some(frame);
+    }
+
+    // Ends the segment: the newest frame lasts as long as the one before
+    fn flush_last(&mut self, base: u64) {
+        if let Some(last) = self.last.take() {
+            self.add(last, base);
+        }
+    }
+
+    fn add(&mut self, frame: Frame, base: u64) {
+        if self.samples.is_empty() {
+            self.start = frame.time.saturating_sub(base);
+        }
+        self.samples.push(Sample {
+            data: frame.data,
+            duration: self.last_duration.max(1),
+            keyframe: frame.keyframe,
+        });
+    }
+
+    fn run(&self, track_id: u32) -> Option<Run<'_>> {
+        (!self.samples.is_empty()).then(|| Run {
+            track_id,
+            decode_time: self.start,
+            samples: &self.samples,
+        })
+    }
+}
+
+/// A segment being cut.
+struct Segment {
+    // The parameter sets of the init segment
+    parameter_sets: Option<(Vec<u8>, Vec<u8>)>,
+    // Clock time the segment started at
+    start: u64,
+    // Clock times decode times count from
+    video_base: u64,
+    audio_base: Option<u64>,
+    video: Pending,
+    audio: Pending,
+    // A keyframe was asked for to end the segment
+    keyframe_requested: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(time: u64, keyframe: bool) -> Frame {
+        Frame {
+            data: vec![0, 0, 0, 2, if keyframe { 0x65 } else { 0x41 }, 0],
+            time,
+            keyframe,
+        }
+    }
+
+    #[tokio::test]
+    async fn asks_for_a_keyframe_when_the_gop_outlasts_the_segment() {
+        let track = Arc::new(FanoutTrack::new(
+            RTCRtpCodecCapability {
+                mime_type: "video/H264".to_owned(),
+                clock_rate: 90000,
+                ..Default::default()
+            },
+            "video".to_owned(),
+            "test".to_owned(),
+        ));
+        let requests = Arc::new(Notify::new());
+        let mut segmenter = Segmenter::new(
+            "test",
+            "Test",
+            Some(&track),
+            None,
+            SegmentOptions {
+                segment: Duration::from_secs(2),
+                continuous: false,
+                keyframe_requests: Some(requests.clone()),
+            },
+        )
+        .unwrap();
+        let video = segmenter.video.as_mut().unwrap();
+        video.sps = Some(vec![0x67, 0x42, 0xc0, 0x1f]);
+        video.pps = Some(vec![0x68, 0xce, 0x3c, 0x80]);
+        let requested = async || {
+            tokio::time::timeout(Duration::ZERO, requests.notified())
+                .await
+                .is_ok()
+        };
+
+        segmenter.video_frame(frame(0, true));
+        segmenter.video_frame(frame(90000, false));
+        assert!(!requested().await);
+
+        // Once per segment
+        segmenter.video_frame(frame(180000, false));
+        segmenter.video_frame(frame(183000, false));
+        assert!(requested().await);
+        assert!(!requested().await);
+
+        // The keyframe it brings starts the next segment
+        segmenter.video_frame(frame(186000, true));
+        segmenter.video_frame(frame(366000, false));
+        assert!(requested().await);
+    }
+}
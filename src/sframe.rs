@@ -0,0 +1,518 @@
+// SFrame (RFC 9605) encryption of the frames sent to a viewer, so media stays
+// opaque to TURN relays and anything else between the gateway and the player.
+// Players decrypt in an encoded transform (insertable streams).
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use ring::{aead, hkdf};
+use tracing::warn;
+use webrtc::rtp::{
+    codecs::{
+        h264::H264Packet,
+        vp8::{Vp8Packet, Vp8Payloader},
+    },
+    packet::Packet,
+    packetizer::{Depacketizer, Payloader},
+};
+
+use crate::segment::avc_depacketizer;
+
+// AES_128_GCM_SHA256_128
+const CIPHER_SUITE: u16 = 0x0004;
+const NONCE_LEN: usize = 12;
+// Largest payload of the rebuilt packets, as webrtc-rs packetizes
+const MTU: usize = 1200;
+// H.264 FU-A payload type (RFC 6184)
+const FU_A: u8 = 28;
+
+/// A session's SFrame key. Frames are dropped until one is set, never sent in
+/// the clear.
+#[derive(Default)]
+pub struct FrameKeys {
+    inner: Mutex<KeyState>,
+}
+
+#[derive(Default)]
+struct KeyState {
+    key: Option<FrameKey>,
+    // Runs on across keys, so re-sending a key can't reuse a nonce
+    counter: u64,
+}
+
+struct FrameKey {
+    kid: u64,
+    aead: aead::LessSafeKey,
+    salt: [u8; NONCE_LEN],
+}
+
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+impl FrameKeys {
+    /// Encrypts from now on with `base_key` as key `kid`; `false` if this is
+    /// the session's first key.
+    pub fn set(&self, kid: u64, base_key: &[u8]) -> bool {
+        // Key and salt derivation of RFC 9605, section 4.4.2
+        let secret = hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(base_key);
+        let expand = |label: &str, out: &mut [u8]| {
+            let info = [
+                label.as_bytes(),
+                &kid.to_be_bytes(),
+                &CIPHER_SUITE.to_be_bytes(),
+            ];
+            secret
+                .expand(&info, Len(out.len()))
+                .and_then(|okm| okm.fill(out))
+                .expect("SFrame key sizes are valid for HKDF-SHA256");
+        };
+        let mut key = [0; 16];
+        let mut salt = [0; NONCE_LEN];
+        expand("SFrame 1.0 Secret key ", &mut key);
+        expand("SFrame 1.0 Secret salt ", &mut salt);
+        let aead = aead::LessSafeKey::new(
+            aead::UnboundKey::new(&aead::AES_128_GCM, &key).expect("AES-128 keys are 16 bytes"),
+        );
+
+        let mut state = self.inner.lock().unwrap();
+        state.key.replace(FrameKey { kid, aead, salt }).is_some()
+    }
+
+    /// `frame` as an SFrame ciphertext (header, then the encrypted frame and
+    /// tag), authenticating the codec bytes in `clear` that stay in front of
+    /// it; `None` without a key.
+    fn seal(&self, clear: &[u8], frame: &[u8]) -> Option<Vec<u8>> {
+        let mut state = self.inner.lock().unwrap();
+        let counter = state.counter;
+        let key = state.key.as_ref()?;
+
+        let mut nonce = key.salt;
+        for (byte, ctr) in nonce[NONCE_LEN - 8..].iter_mut().zip(counter.to_be_bytes()) {
+            *byte ^= ctr;
+        }
+        let mut sealed = header(key.kid, counter);
+        let mut body = frame.to_vec();
+        key.aead
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from([&sealed[..], clear].concat()),
+                &mut body,
+            )
+            .ok()?;
+        sealed.extend_from_slice(&body);
+        state.counter += 1;
+        Some(sealed)
+    }
+}
+
+impl fmt::Debug for FrameKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kid = self.inner.lock().unwrap().key.as_ref().map(|key| key.kid);
+        f.debug_struct("FrameKeys").field("kid", &kid).finish()
+    }
+}
+
+// The SFrame header (RFC 9605, section 4.3): KID and counter, inline when they
+// fit in 3 bits, otherwise as their minimal big-endian bytes
+fn header(kid: u64, counter: u64) -> Vec<u8> {
+    fn field(value: u64) -> (u8, Vec<u8>) {
+        if value < 8 {
+            return (value as u8, Vec::new());
+        }
+        let bytes = value.to_be_bytes();
+        let start = bytes.iter().position(|&b| b != 0).unwrap_or(7);
+        (0x8 | (7 - start) as u8, bytes[start..].to_vec())
+    }
+    let (k, kid) = field(kid);
+    let (c, counter) = field(counter);
+    [&[k << 4 | c][..], &kid, &counter].concat()
+}
+
+/// How frames of a codec are found in packets and split back into packets.
+enum Format {
+    /// One frame per packet.
+    Audio,
+    H264(H264Packet),
+    Vp8(Vp8Packet, Vp8Payloader),
+    /// Codecs that can't be encrypted; nothing is sent.
+    Unsupported,
+}
+
+/// Rebuilds one viewer's packets with every frame encrypted under the
+/// session's key.
+///
+/// Codec headers stay in the clear for the browser's depacketizer, as other
+/// insertable streams implementations do: the first 2 bytes of the first
+/// H.264 NAL unit that isn't a parameter set or delimiter (everything after
+/// it is encrypted), the first 10 bytes of VP8 keyframes and 3 of other VP8
+/// frames, and nothing of audio.
+pub struct FrameEncryptor {
+    keys: Arc<FrameKeys>,
+    format: Format,
+    // Packets of the frame being assembled
+    header: Option<webrtc::rtp::header::Header>,
+    frame: Vec<u8>,
+    sequence_number: Option<u16>,
+}
+
+impl FrameEncryptor {
+    pub fn new(mime_type: &str, keys: Arc<FrameKeys>) -> Self {
+        let format = if mime_type.to_ascii_lowercase().starts_with("audio/") {
+            Format::Audio
+        } else if mime_type.eq_ignore_ascii_case("video/h264") {
+            // Length prefixed, so NAL units are never split at start codes
+            // that turn up in the ciphertext
+            Format::H264(avc_depacketizer())
+        } else if mime_type.eq_ignore_ascii_case("video/vp8") {
+            Format::Vp8(Vp8Packet::default(), Vp8Payloader::default())
+        } else {
+            warn!("Can't encrypt {} frames, not sending them", mime_type);
+            Format::Unsupported
+        };
+        Self {
+            keys,
+            format,
+            header: None,
+            frame: Vec::new(),
+            sequence_number: None,
+        }
+    }
+
+    /// The packets to send for `pkt`: those of the frame it completes, if any.
+    pub fn push(&mut self, pkt: &Packet) -> Vec<Packet> {
+        let mut packets = Vec::new();
+        if matches!(self.format, Format::Unsupported) {
+            return packets;
+        }
+        if matches!(self.format, Format::Audio) {
+            self.frame = pkt.payload.to_vec();
+            self.header = Some(pkt.header.clone());
+            self.finish(&mut packets);
+            return packets;
+        }
+
+        if self
+            .header
+            .as_ref()
+            .is_some_and(|header| header.timestamp != pkt.header.timestamp)
+        {
+            self.finish(&mut packets);
+        }
+        let payload = match &mut self.format {
+            Format::H264(depacketizer) => depacketizer.depacketize(&pkt.payload),
+            Format::Vp8(depacketizer, _) => depacketizer.depacketize(&pkt.payload),
+            Format::Audio | Format::Unsupported => return packets,
+        };
+        // Middle fragments of an H.264 NAL unit give nothing yet
+        if let Ok(payload) = payload {
+            self.frame.extend_from_slice(&payload);
+        }
+        self.header = Some(pkt.header.clone());
+        if pkt.header.marker {
+            self.finish(&mut packets);
+        }
+        packets
+    }
+
+    fn finish(&mut self, packets: &mut Vec<Packet>) {
+        let frame = std::mem::take(&mut self.frame);
+        let Some(header) = self.header.take() else {
+            return;
+        };
+        if frame.is_empty() {
+            return;
+        }
+
+        let payloads = match &mut self.format {
+            Format::Audio => self
+                .keys
+                .seal(&[], &frame)
+                .map(|sealed| vec![sealed.into()]),
+            Format::H264(_) => seal_h264(&self.keys, &frame),
+            Format::Vp8(_, payloader) => {
+                // Keyframes have the P bit clear
+                let clear_len = if frame[0] & 0x01 == 0 { 10 } else { 3 }.min(frame.len());
+                self.keys
+                    .seal(&frame[..clear_len], &frame[clear_len..])
+                    .and_then(|sealed| {
+                        let frame = [&frame[..clear_len], &sealed[..]].concat();
+                        payloader.payload(MTU, &Bytes::from(frame)).ok()
+                    })
+            }
+            Format::Unsupported => None,
+        };
+        let Some(payloads) = payloads else {
+            return;
+        };
+
+        // Frames come out in different sizes, so packets are renumbered
+        let mut sequence_number = *self.sequence_number.get_or_insert(header.sequence_number);
+        let last = payloads.len().saturating_sub(1);
+        for (i, payload) in payloads.into_iter().enumerate() {
+            let mut header = header.clone();
+            header.sequence_number = sequence_number;
+            header.marker = i == last;
+            packets.push(Packet { header, payload });
+            sequence_number = sequence_number.wrapping_add(1);
+        }
+        self.sequence_number = Some(sequence_number);
+    }
+}
+
+// The NAL units of an access unit, with everything from the first NAL unit
+// that isn't a parameter set or delimiter on encrypted as one frame, as
+// packet payloads
+fn seal_h264(keys: &FrameKeys, access_unit: &[u8]) -> Option<Vec<Bytes>> {
+    let mut nals = Vec::new();
+    let mut rest = access_unit;
+    while rest.len() >= 4 {
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let Some(nal) = rest.get(4..4 + len) else {
+            break;
+        };
+        nals.push(nal);
+        rest = &rest[4 + len..];
+    }
+
+    let first = nals
+        .iter()
+        .position(|nal| !matches!(nal.first().map(|header| header & 0x1f), Some(7..=9) | None));
+    let mut payloads = Vec::new();
+    let clear_nals = &nals[..first.unwrap_or(nals.len())];
+    for nal in clear_nals {
+        packetize_nal(nal, &mut payloads);
+    }
+    if let Some(first) = first {
+        let clear_len = nals[first].len().min(2);
+        // The receiver sees an Annex B access unit, so that's what the
+        // encrypted part decrypts to
+        let mut plaintext = nals[first][clear_len..].to_vec();
+        for nal in &nals[first + 1..] {
+            plaintext.extend_from_slice(&[0, 0, 0, 1]);
+            plaintext.extend_from_slice(nal);
+        }
+        let sealed = keys.seal(&nals[first][..clear_len], &plaintext)?;
+        packetize_nal(
+            &[&nals[first][..clear_len], &sealed[..]].concat(),
+            &mut payloads,
+        );
+    }
+    Some(payloads)
+}
+
+// Single NAL unit packets, or FU-A fragments of larger NAL units (RFC 6184)
+fn packetize_nal(nal: &[u8], payloads: &mut Vec<Bytes>) {
+    let Some((&header, body)) = nal.split_first() else {
+        return;
+    };
+    if nal.len() <= MTU {
+        payloads.push(Bytes::copy_from_slice(nal));
+        return;
+    }
+    let chunks: Vec<&[u8]> = body.chunks(MTU - 2).collect();
+    let last = chunks.len() - 1;
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let mut payload = Vec::with_capacity(chunk.len() + 2);
+        payload.push(header & 0xe0 | FU_A);
+        let start = if i == 0 { 0x80 } else { 0 };
+        let end = if i == last { 0x40 } else { 0 };
+        payload.push(start | end | header & 0x1f);
+        payload.extend_from_slice(chunk);
+        payloads.push(payload.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::rtp::header::Header;
+
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn packet(sequence_number: u16, marker: bool, payload: &[u8]) -> Packet {
+        Packet {
+            header: Header {
+                sequence_number,
+                timestamp: 3000,
+                marker,
+                ..Default::default()
+            },
+            payload: Bytes::copy_from_slice(payload),
+        }
+    }
+
+    /// Decrypts what `seal` made of a frame, as a receiver would.
+    fn open(keys: &FrameKeys, clear: &[u8], sealed: &[u8]) -> Vec<u8> {
+        let state = keys.inner.lock().unwrap();
+        let key = state.key.as_ref().unwrap();
+        let config = sealed[0];
+        let field_len = |bits: u8| {
+            if bits & 0x8 != 0 {
+                (bits & 0x7) as usize + 1
+            } else {
+                0
+            }
+        };
+        let kid_len = field_len(config >> 4);
+        let counter_len = field_len(config & 0xf);
+        let header_len = 1 + kid_len + counter_len;
+        let counter = if counter_len == 0 {
+            (config & 0x7) as u64
+        } else {
+            sealed[1 + kid_len..header_len]
+                .iter()
+                .fold(0, |counter, &b| counter << 8 | b as u64)
+        };
+
+        let mut nonce = key.salt;
+        for (byte, ctr) in nonce[NONCE_LEN - 8..].iter_mut().zip(counter.to_be_bytes()) {
+            *byte ^= ctr;
+        }
+        let mut body = sealed[header_len..].to_vec();
+        key.aead
+            .open_in_place(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from([&sealed[..header_len], clear].concat()),
+                &mut body,
+            )
+            .unwrap()
+            .to_vec()
+    }
+
+    #[test]
+    fn encodes_headers() {
+        assert_eq!(header(0, 0), [0x00]);
+        assert_eq!(header(7, 7), [0x77]);
+        assert_eq!(header(8, 0), [0x80, 0x08]);
+        assert_eq!(header(0, 0x100), [0x09, 0x01, 0x00]);
+        assert_eq!(header(0x123, 0x4567), [0x99, 0x01, 0x23, 0x45, 0x67]);
+        let mut longest = vec![0xff];
+        longest.extend([0xff; 16]);
+        assert_eq!(header(u64::MAX, u64::MAX), longest);
+    }
+
+    // Not an RFC 9605 test vector: the inputs are those of its
+    // AES_128_GCM_SHA256_128 vector (appendix C), but the expected salt and
+    // ciphertext were computed with a separate HKDF-SHA256 and AES-GCM
+    // implementation, so this cross-checks key derivation and nonces only
+    #[test]
+    fn seals_as_independent_implementation() {
+        let keys = FrameKeys::default();
+        assert!(!keys.set(0x123, &hex("000102030405060708090a0b0c0d0e0f")));
+        {
+            let state = keys.inner.lock().unwrap();
+            let key = state.key.as_ref().unwrap();
+            assert_eq!(key.salt[..], hex("75234edefe07819026751816"));
+        }
+        keys.inner.lock().unwrap().counter = 0x4567;
+
+        let sealed = keys
+            .seal(b"IETF SFrame WG", b"draft-ietf-sframe-enc")
+            .unwrap();
+        assert_eq!(
+            sealed,
+            hex(concat!(
+                "9901234567",
+                "b7412c2513a1b66dbb48841bbaf17f598751176ad8",
+                "47681a69c6d0b091c07018ce4adb34eb",
+            ))
+        );
+        assert_eq!(keys.inner.lock().unwrap().counter, 0x4568);
+    }
+
+    #[test]
+    fn drops_frames_without_key() {
+        let keys = Arc::new(FrameKeys::default());
+        let mut encryptor = FrameEncryptor::new("audio/opus", keys);
+        assert!(encryptor.push(&packet(1, true, b"opus")).is_empty());
+    }
+
+    #[test]
+    fn encrypts_audio_frames_whole() {
+        let keys = Arc::new(FrameKeys::default());
+        keys.set(1, &[0x2a; 16]);
+        let mut encryptor = FrameEncryptor::new("audio/opus", keys.clone());
+
+        for (counter, sequence_number) in [(0, 500), (1, 501)] {
+            let packets = encryptor.push(&packet(sequence_number, false, b"opus"));
+            assert_eq!(packets.len(), 1);
+            let payload = &packets[0].payload;
+            // KID 1 and the counter fit in the header byte; 16 bytes of tag
+            assert_eq!(payload[0], 0x10 | counter);
+            assert_eq!(payload.len(), 1 + 4 + 16);
+            assert_eq!(open(&keys, &[], payload), b"opus");
+            assert_eq!(packets[0].header.sequence_number, sequence_number);
+        }
+    }
+
+    #[test]
+    fn keeps_h264_headers_clear() {
+        let keys = Arc::new(FrameKeys::default());
+        keys.set(1, &[0x2a; 16]);
+        let mut encryptor = FrameEncryptor::new("video/H264", keys.clone());
+        let sps = [0x67, 0x42, 0x00, 0x1f];
+        let idr = [0x65, 0x88, 0x84, 0x00, 0x21];
+
+        assert!(encryptor.push(&packet(7, false, &sps)).is_empty());
+        let packets = encryptor.push(&packet(8, true, &idr));
+        assert_eq!(packets.len(), 2);
+        assert_eq!(&packets[0].payload[..], sps);
+        assert!(!packets[0].header.marker);
+
+        let payload = &packets[1].payload;
+        assert_eq!(payload[..2], idr[..2]);
+        assert_eq!(open(&keys, &idr[..2], &payload[2..]), idr[2..]);
+        assert!(packets[1].header.marker);
+        // Renumbered, as frames come out in a different number of packets
+        let sequence: Vec<_> = packets
+            .iter()
+            .map(|pkt| pkt.header.sequence_number)
+            .collect();
+        assert_eq!(sequence, [8, 9]);
+    }
+
+    #[test]
+    fn fragments_large_h264_frames() {
+        let keys = Arc::new(FrameKeys::default());
+        keys.set(1, &[0x2a; 16]);
+        let mut encryptor = FrameEncryptor::new("video/H264", keys.clone());
+        let mut idr = vec![0x65];
+        idr.extend((0..3000).map(|i| i as u8));
+
+        let packets = encryptor.push(&packet(1, true, &idr));
+        assert_eq!(packets.len(), 3);
+        let mut nal = vec![packets[0].payload[0] & 0xe0 | packets[0].payload[1] & 0x1f];
+        for (i, pkt) in packets.iter().enumerate() {
+            assert!(pkt.payload.len() <= MTU);
+            // FU-A with start and end bits
+            assert_eq!(pkt.payload[0] & 0x1f, FU_A);
+            assert_eq!(pkt.payload[1] & 0x80 != 0, i == 0);
+            assert_eq!(pkt.payload[1] & 0x40 != 0, i == 2);
+            nal.extend_from_slice(&pkt.payload[2..]);
+        }
+        assert_eq!(nal[..2], idr[..2]);
+        assert_eq!(open(&keys, &idr[..2], &nal[2..]), idr[2..]);
+    }
+
+    #[test]
+    fn sends_nothing_of_unsupported_codecs() {
+        let keys = Arc::new(FrameKeys::default());
+        keys.set(1, &[0x2a; 16]);
+        let mut encryptor = FrameEncryptor::new("video/H265", keys);
+        assert!(encryptor.push(&packet(1, true, &[0x26, 0x01])).is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use tracing::{info, warn};
+
+use crate::{
+    state::{AppState, Stream},
+    store::SessionStore,
+};
+
+// Longest wait for a source to stop before exiting anyway
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Closes every viewer and publisher connection, then stops all sources, which
+/// sends the cameras a TEARDOWN as `--teardown` allows.
+///
+/// Sources are removed from the state afterwards, ending SSE streams so the
+/// HTTP server can finish its graceful shutdown.
+pub async fn close_all(state: &AppState) {
+    state.shutting_down.store(true, Ordering::Relaxed);
+    let viewers = close_sessions(state.sessions.as_ref()).await;
+    let publishers = close_sessions(state.publishers.as_ref()).await;
+    info!(
+        "🔌 Closed {} viewer and {} publisher connections",
+        viewers, publishers
+    );
+
+    let streams = state.all_streams();
+    for stream in &streams {
+        stream.control.stop.notify_one();
+    }
+    for stream in &streams {
+        let name = &stream.info.name;
+        if tokio::time::timeout(STOP_TIMEOUT, stream.control.stopped.notified())
+            .await
+            .is_err()
+        {
+            warn!("[{}] Source did not stop in time", name);
+        }
+        state.remove_stream(name);
+    }
+}
+
+/// Closes the viewer connections of a source removed from the state, then
+/// stops it and waits for its TEARDOWN, so the name can be reused right away.
+pub async fn close_source(state: &AppState, stream: &Stream) -> usize {
+    let name = &stream.info.name;
+    let closed = close_viewers(state, name).await;
+
+    stream.control.stop.notify_one();
+    if tokio::time::timeout(STOP_TIMEOUT, stream.control.stopped.notified())
+        .await
+        .is_err()
+    {
+        warn!("[{}] Source did not stop in time", name);
+    }
+    closed
+}
+
+/// Closes the viewer connections of source `name`.
+pub async fn close_viewers(state: &AppState, name: &str) -> usize {
+    let mut closed = 0;
+    for info in state.sessions.list() {
+        if info.source != name {
+            continue;
+        }
+        if let Some(pc) = state.sessions.remove(&info.id) {
+            let _ = pc.close().await;
+            closed += 1;
+        }
+    }
+    closed
+}
+
+async fn close_sessions(sessions: &dyn SessionStore) -> usize {
+    let mut closed = 0;
+    for info in sessions.list() {
+        if let Some(pc) = sessions.remove(&info.id) {
+            let _ = pc.close().await;
+            closed += 1;
+        }
+    }
+    closed
+}
@@ -0,0 +1,228 @@
+use bytes::Bytes;
+use webrtc::rtp::{header::Header, packet::Packet};
+
+// Silence payloads for one 20 ms packet, with the RTP timestamp increment it covers
+const OPUS_SILENCE: &[u8] = &[0xf8, 0xff, 0xfe];
+const PCMU_SILENCE: &[u8] = &[0xff; 160];
+const PCMA_SILENCE: &[u8] = &[0xd5; 160];
+
+pub const SILENCE_PACKET_INTERVAL: std::time::Duration = std::time::Duration::from_millis(20);
+
+/// Keeps an audio track alive while the upstream audio stream stalls.
+///
+/// Real packets are passed through with sequence numbers and timestamps shifted so
+/// that the injected silence packets fit into one continuous RTP timeline.
+pub struct SilenceFiller {
+    payload: &'static [u8],
+    samples_per_packet: u32,
+    last_out: Option<Header>,
+    seq_offset: u16,
+    ts_offset: u32,
+    inserted: u64,
+}
+
+impl SilenceFiller {
+    /// Returns `None` for codecs without a known silence payload.
+    pub fn new(encoding_name: &str) -> Option<Self> {
+        let (payload, samples_per_packet) = match encoding_name {
+            "opus" => (OPUS_SILENCE, 960),
+            "pcmu" => (PCMU_SILENCE, 160),
+            "pcma" => (PCMA_SILENCE, 160),
+            _ => return None,
+        };
+
+        Some(Self {
+            payload,
+            samples_per_packet,
+            last_out: None,
+            seq_offset: 0,
+            ts_offset: 0,
+            inserted: 0,
+        })
+    }
+
+    /// Whether silence has been inserted since the last real packet.
+    pub fn is_filling(&self) -> bool {
+        self.inserted > 0
+    }
+
+    /// How long to wait for a real packet before inserting silence: the stall
+    /// timeout at first, then one packet interval per silence packet.
+    pub fn wait(&self, stall_timeout: std::time::Duration) -> std::time::Duration {
+        if self.is_filling() {
+            SILENCE_PACKET_INTERVAL
+        } else {
+            stall_timeout
+        }
+    }
+
+    /// Rewrites a real packet onto the output timeline.
+    ///
+    /// Returns the rewritten packet and, if this packet ends a gap, the number of
+    /// silence packets that were inserted.
+    pub fn pass(&mut self, mut pkt: Packet) -> (Packet, Option<u64>) {
+        let ended_gap = if self.inserted > 0 {
+            if let Some(last) = &self.last_out {
+                self.seq_offset = last
+                    .sequence_number
+                    .wrapping_add(1)
+                    .wrapping_sub(pkt.header.sequence_number);
+
+                // Keep the upstream clock if it kept running during the stall,
+                // otherwise continue right after the last silence packet.
+                let natural = pkt.header.timestamp.wrapping_add(self.ts_offset);
+                if (natural.wrapping_sub(last.timestamp) as i32) <= 0 {
+                    self.ts_offset = last
+                        .timestamp
+                        .wrapping_add(self.samples_per_packet)
+                        .wrapping_sub(pkt.header.timestamp);
+                }
+            }
+            Some(std::mem::take(&mut self.inserted))
+        } else {
+            None
+        };
+
+        pkt.header.sequence_number = pkt.header.sequence_number.wrapping_add(self.seq_offset);
+        pkt.header.timestamp = pkt.header.timestamp.wrapping_add(self.ts_offset);
+        self.last_out = Some(pkt.header.clone());
+
+        (pkt, ended_gap)
+    }
+
+    /// Produces the next silence packet, or `None` if no real packet was seen yet.
+    pub fn next_silence(&mut self) -> Option<Packet> {
+        let last = self.last_out.as_mut()?;
+
+        last.sequence_number = last.sequence_number.wrapping_add(1);
+        last.timestamp = last.timestamp.wrapping_add(self.samples_per_packet);
+        last.marker = false;
+        self.inserted += 1;
+
+        Some(Packet {
+            header: last.clone(),
+            payload: Bytes::from_static(self.payload),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        sync::mpsc,
+        time::{Instant, timeout},
+    };
+
+    use super::*;
+
+    const STALL: Duration = Duration::from_millis(500);
+
+    fn packet(sequence_number: u16, timestamp: u32) -> Packet {
+        Packet {
+            header: Header {
+                sequence_number,
+                timestamp,
+                ..Default::default()
+            },
+            payload: Bytes::from_static(&[0x42; 160]),
+        }
+    }
+
+    /// Drives the filler the way ingest does, recording when each packet
+    /// went out and whether it ended a gap.
+    async fn run(
+        mut filler: SilenceFiller,
+        mut rx: mpsc::UnboundedReceiver<Packet>,
+    ) -> Vec<(Duration, Packet, Option<u64>)> {
+        let start = Instant::now();
+        let mut out = Vec::new();
+        loop {
+            match timeout(filler.wait(STALL), rx.recv()).await {
+                Ok(Some(pkt)) => {
+                    let (pkt, gap) = filler.pass(pkt);
+                    out.push((start.elapsed(), pkt, gap));
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    if let Some(pkt) = filler.next_silence() {
+                        out.push((start.elapsed(), pkt, None));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fills_stall_every_packet_interval() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            tx.send(packet(100, 1000)).unwrap();
+            tokio::time::sleep(Duration::from_millis(590)).await;
+            // The camera's clock stood still during the stall
+            tx.send(packet(101, 1160)).unwrap();
+        });
+        let out = run(SilenceFiller::new("pcmu").unwrap(), rx).await;
+
+        let at: Vec<_> = out.iter().map(|(at, _, _)| at.as_millis()).collect();
+        assert_eq!(at, [0, 500, 520, 540, 560, 580, 590]);
+        let seq: Vec<_> = out
+            .iter()
+            .map(|(_, pkt, _)| pkt.header.sequence_number)
+            .collect();
+        assert_eq!(seq, [100, 101, 102, 103, 104, 105, 106]);
+        let ts: Vec<_> = out.iter().map(|(_, pkt, _)| pkt.header.timestamp).collect();
+        assert_eq!(ts, [1000, 1160, 1320, 1480, 1640, 1800, 1960]);
+        for (_, pkt, _) in &out[1..6] {
+            assert_eq!(&pkt.payload[..], PCMU_SILENCE);
+        }
+        let (_, last, gap) = &out[6];
+        assert_eq!(gap, &Some(5));
+        assert_eq!(last.payload[0], 0x42);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_upstream_clock_that_ran_on() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            tx.send(packet(100, 1000)).unwrap();
+            tokio::time::sleep(Duration::from_millis(590)).await;
+            // 590 ms at 8 kHz later
+            tx.send(packet(101, 1000 + 4720)).unwrap();
+            tx.send(packet(102, 1000 + 4880)).unwrap();
+        });
+        let out = run(SilenceFiller::new("pcma").unwrap(), rx).await;
+
+        assert_eq!(out.len(), 8);
+        let (_, resumed, gap) = &out[6];
+        assert_eq!(gap, &Some(5));
+        assert_eq!(resumed.header.sequence_number, 106);
+        assert_eq!(resumed.header.timestamp, 5720);
+        let (_, next, gap) = &out[7];
+        assert_eq!(gap, &None);
+        assert_eq!(next.header.sequence_number, 107);
+        assert_eq!(next.header.timestamp, 5880);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_first_packet_before_filling() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            tx.send(packet(7, 0)).unwrap();
+        });
+        let out = run(SilenceFiller::new("opus").unwrap(), rx).await;
+
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].0, Duration::from_secs(2));
+        assert_eq!(out[0].1.header.sequence_number, 7);
+        assert_eq!(out[0].2, None);
+    }
+
+    #[test]
+    fn has_no_silence_for_unknown_codecs() {
+        assert!(SilenceFiller::new("mpeg4-generic").is_none());
+    }
+}
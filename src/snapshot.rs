@@ -0,0 +1,174 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use axum::{
+    Extension,
+    extract::{Path, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use bytes::{Bytes, BytesMut};
+use ffmpeg_next as ffmpeg;
+use tracing::warn;
+use webrtc::{
+    api::media_engine::MIME_TYPE_H264,
+    rtp::{codecs::h264::H264Packet, packet::Packet, packetizer::Depacketizer as _},
+};
+
+use crate::{auth::Principal, state::AppState};
+
+// Seconds a client waits for the keyframe asked of the source
+const KEYFRAME_RETRY_AFTER: &str = "2";
+// Quantizers of the JPEG encoder, from 2 (best) to 31
+const JPEG_QUALITY: (i32, i32) = (2, 5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("ffmpeg failed: {0}")]
+    Ffmpeg(#[from] ffmpeg::Error),
+    #[error("failed to depacketize H.264: {0}")]
+    Rtp(#[from] webrtc::rtp::Error),
+    #[error("the keyframe decoded to no picture")]
+    NoPicture,
+}
+
+/// The latest still of each stream, so dashboards polling it only decode each
+/// keyframe once.
+#[derive(Default)]
+pub struct Snapshots(Mutex<HashMap<String, (u32, Bytes)>>);
+
+/// `GET /snapshot/{stream}`: the source's latest keyframe as a JPEG. `503`
+/// with `Retry-After` while none has passed yet, after asking the source for
+/// one; `404` for sources without H.264 video.
+pub async fn snapshot(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    principal: Option<Extension<Principal>>,
+) -> Response {
+    if let Some(Extension(principal)) = &principal
+        && !principal.can_view(&name)
+    {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let Some(stream) = state.stream(&name) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let Some((_, video_track)) = &stream.video_track else {
+        return (StatusCode::NOT_FOUND, "Source has no video").into_response();
+    };
+    if !video_track
+        .codec()
+        .mime_type
+        .eq_ignore_ascii_case(MIME_TYPE_H264)
+    {
+        return (StatusCode::NOT_FOUND, "Only H.264 video has snapshots").into_response();
+    }
+
+    let packets = video_track.keyframe();
+    let Some(timestamp) = packets.last().map(|pkt| pkt.header.timestamp) else {
+        stream.keyframe_requests.notify_one();
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, KEYFRAME_RETRY_AFTER)],
+            "No keyframe yet",
+        )
+            .into_response();
+    };
+    let cached = state
+        .snapshots
+        .0
+        .lock()
+        .unwrap()
+        .get(&name)
+        .filter(|(cached, _)| *cached == timestamp)
+        .map(|(_, jpeg)| jpeg.clone());
+    let jpeg = match cached {
+        Some(jpeg) => jpeg,
+        None => match tokio::task::spawn_blocking(move || encode_jpeg(&packets)).await {
+            Ok(Ok(jpeg)) => {
+                state
+                    .snapshots
+                    .0
+                    .lock()
+                    .unwrap()
+                    .insert(name, (timestamp, jpeg.clone()));
+                jpeg
+            }
+            Ok(Err(e)) => {
+                warn!("[{}] Snapshot failed: {}", name, e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+            Err(e) => {
+                warn!("[{}] Snapshot failed: {}", name, e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        },
+    };
+    (
+        [
+            (header::CONTENT_TYPE, "image/jpeg"),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        jpeg,
+    )
+        .into_response()
+}
+
+/// Decodes the H.264 keyframe in `packets`, parameter sets first, and encodes
+/// it as a JPEG at the picture's own size.
+fn encode_jpeg(packets: &[Packet]) -> Result<Bytes, SnapshotError> {
+    ffmpeg::init()?;
+    let mut depacketizer = H264Packet::default();
+    let mut access_unit = BytesMut::new();
+    for pkt in packets {
+        access_unit.extend_from_slice(&depacketizer.depacketize(&pkt.payload)?);
+    }
+
+    let codec =
+        ffmpeg::decoder::find(ffmpeg::codec::Id::H264).ok_or(ffmpeg::Error::DecoderNotFound)?;
+    let mut decoder = ffmpeg::codec::Context::new_with_codec(codec)
+        .decoder()
+        .video()?;
+    decoder.send_packet(&ffmpeg::Packet::copy(&access_unit))?;
+    // Frame threads hold the picture back until told there's no more
+    decoder.send_eof()?;
+    let mut decoded = ffmpeg::frame::Video::empty();
+    decoder
+        .receive_frame(&mut decoded)
+        .map_err(|_| SnapshotError::NoPicture)?;
+
+    // The JPEG encoder takes full-range 4:2:0
+    let format = ffmpeg::format::Pixel::YUVJ420P;
+    let mut scaler = ffmpeg::software::scaling::Context::get(
+        decoded.format(),
+        decoded.width(),
+        decoded.height(),
+        format,
+        decoded.width(),
+        decoded.height(),
+        ffmpeg::software::scaling::Flags::BILINEAR,
+    )?;
+    let mut scaled = ffmpeg::frame::Video::empty();
+    scaler.run(&decoded, &mut scaled)?;
+    scaled.set_pts(Some(0));
+
+    let codec =
+        ffmpeg::encoder::find(ffmpeg::codec::Id::MJPEG).ok_or(ffmpeg::Error::EncoderNotFound)?;
+    let mut encoder = ffmpeg::codec::Context::new_with_codec(codec)
+        .encoder()
+        .video()?;
+    encoder.set_width(decoded.width());
+    encoder.set_height(decoded.height());
+    encoder.set_format(format);
+    encoder.set_time_base((1, 1));
+    encoder.set_qmin(JPEG_QUALITY.0);
+    encoder.set_qmax(JPEG_QUALITY.1);
+    let mut encoder = encoder.open()?;
+    encoder.send_frame(&scaled)?;
+    encoder.send_eof()?;
+    let mut encoded = ffmpeg::Packet::empty();
+    encoder.receive_packet(&mut encoded)?;
+    encoded
+        .data()
+        .map(Bytes::copy_from_slice)
+        .ok_or(SnapshotError::NoPicture)
+}
@@ -0,0 +1,148 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use serde::Deserialize;
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+use webrtc::data_channel::{
+    RTCDataChannel, data_channel_message::DataChannelMessage,
+    data_channel_state::RTCDataChannelState,
+};
+
+/// Label of the data channel viewers open to run a downlink test.
+pub const SPEEDTEST_LABEL: &str = "speedtest";
+
+// Burst sizes requested by viewers are clamped to this range
+const DEFAULT_BURST_BYTES: usize = 1024 * 1024;
+const MAX_BURST_BYTES: usize = 8 * 1024 * 1024;
+const CHUNK_SIZE: usize = 16 * 1024;
+// Sending pauses while more than this is buffered for the viewer, and resumes
+// once it has drained below the low mark
+const BUFFERED_HIGH: usize = 1024 * 1024;
+const BUFFERED_LOW: usize = 256 * 1024;
+const CLOSE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+static PADDING: [u8; CHUNK_SIZE] = [0; CHUNK_SIZE];
+
+#[derive(Deserialize, Default)]
+struct SpeedtestRequest {
+    bytes: Option<usize>,
+}
+
+/// Answers every message on a speedtest channel with a burst of padding.
+///
+/// A request is a JSON object like `{"bytes": 2097152}` (an empty message uses the
+/// default size). The burst is framed by `speedtest_start` and `speedtest_end`
+/// text messages so the viewer can time the binary chunks in between. Only one
+/// burst runs at a time; requests meanwhile get `speedtest_busy`.
+pub fn serve(dc: Arc<RTCDataChannel>) {
+    let dc_for_message = dc.clone();
+    let running = Arc::new(AtomicBool::new(false));
+    dc.on_message(Box::new(move |msg: DataChannelMessage| {
+        let dc = dc_for_message.clone();
+        let running = running.clone();
+        Box::pin(async move {
+            let request: SpeedtestRequest = if msg.data.is_empty() {
+                SpeedtestRequest::default()
+            } else {
+                match serde_json::from_slice(&msg.data) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        warn!("Invalid speedtest request: {}", e);
+                        return;
+                    }
+                }
+            };
+            let total = request
+                .bytes
+                .unwrap_or(DEFAULT_BURST_BYTES)
+                .clamp(CHUNK_SIZE, MAX_BURST_BYTES);
+
+            if running.swap(true, Ordering::AcqRel) {
+                debug!("Speedtest requested while one is running");
+                let busy = serde_json::json!({ "event": "speedtest_busy" }).to_string();
+                let _ = dc.send_text(busy).await;
+                return;
+            }
+            if let Err(e) = burst(&dc, total).await {
+                debug!("Speedtest aborted: {}", e);
+            }
+            running.store(false, Ordering::Release);
+        })
+    }));
+}
+
+/// Sends `total` bytes of padding without buffering more than
+/// [`BUFFERED_HIGH`] at once. `send_ms` runs until the viewer has received all
+/// of it, i.e. the send buffer has drained.
+async fn burst(dc: &RTCDataChannel, total: usize) -> Result<(), webrtc::Error> {
+    let drained = Arc::new(Notify::new());
+    let drained_for_handler = drained.clone();
+    dc.set_buffered_amount_low_threshold(BUFFERED_LOW).await;
+    dc.on_buffered_amount_low(Box::new(move || {
+        drained_for_handler.notify_waiters();
+        Box::pin(async {})
+    }))
+    .await;
+
+    let start = Instant::now();
+    dc.send_text(
+        serde_json::json!({ "event": "speedtest_start", "bytes": total, "chunk": CHUNK_SIZE })
+            .to_string(),
+    )
+    .await?;
+
+    let chunk = Bytes::from_static(&PADDING);
+    let mut sent = 0;
+    while sent < total {
+        if dc.buffered_amount().await > BUFFERED_HIGH {
+            wait_buffered_at_most(dc, &drained, BUFFERED_LOW).await?;
+        }
+        let len = CHUNK_SIZE.min(total - sent);
+        sent += dc.send(&chunk.slice(..len)).await?;
+    }
+    dc.set_buffered_amount_low_threshold(0).await;
+    wait_buffered_at_most(dc, &drained, 0).await?;
+
+    dc.send_text(
+        serde_json::json!({
+            "event": "speedtest_end",
+            "bytes": sent,
+            "send_ms": start.elapsed().as_millis() as u64,
+        })
+        .to_string(),
+    )
+    .await?;
+
+    debug!("Speedtest burst of {} bytes sent", sent);
+    Ok(())
+}
+
+/// Waits until no more than `amount` bytes are buffered, which `drained` is
+/// notified of whenever the buffer falls to the channel's low threshold.
+/// Fails if the channel closes first.
+async fn wait_buffered_at_most(
+    dc: &RTCDataChannel,
+    drained: &Notify,
+    amount: usize,
+) -> Result<(), webrtc::Error> {
+    loop {
+        let notified = drained.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if dc.buffered_amount().await <= amount {
+            return Ok(());
+        }
+        if dc.ready_state() != RTCDataChannelState::Open {
+            return Err(webrtc::Error::ErrDataChannelNotOpen);
+        }
+        // A closing channel never drains, so look again now and then
+        let _ = tokio::time::timeout(CLOSE_CHECK_INTERVAL, notified).await;
+    }
+}
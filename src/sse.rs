@@ -0,0 +1,210 @@
+use std::{convert::Infallible, sync::atomic::Ordering, time::Duration};
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::{HeaderValue, StatusCode, header},
+    response::{
+        IntoResponse, Response,
+        sse::{self, KeepAlive, Sse},
+    },
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::{Stream, wrappers::ReceiverStream};
+use tracing::{debug, info};
+
+use crate::{
+    events::Event,
+    state::{AppState, AudioFormat, VideoLayer},
+    whep::{SDPAnswer, missing_session},
+};
+
+/// Link relation advertising the extension on WHEP answers.
+const SSE_REL: &str = "urn:ietf:params:whep:ext:core:server-sent-events";
+/// Events a viewer can subscribe to, as named by the WHEP draft.
+const SUPPORTED_EVENTS: [&str; 5] = ["active", "inactive", "layers", "reconnect", "viewercount"];
+// How often the stream checks whether its session is still there
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// Events waiting for a slow client before the stream is dropped
+const EVENT_BUFFER: usize = 16;
+
+/// Body of a `layers` event: what the source forwards and what it could.
+#[derive(Serialize)]
+struct Layers<'a> {
+    video: Option<MediaLayers<'a, VideoLayer>>,
+    audio: Option<MediaLayers<'a, AudioFormat>>,
+}
+
+#[derive(Serialize)]
+struct MediaLayers<'a, T> {
+    active: Vec<&'a T>,
+    inactive: Vec<&'a T>,
+}
+
+/// Query of `GET /whep/resource/{id}/sse`.
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// Comma-separated event names; all supported events if absent.
+    events: Option<String>,
+}
+
+/// Adds the `Link` header announcing the session's event stream to a WHEP
+/// answer.
+pub fn advertise(answer: SDPAnswer) -> Response {
+    let id = answer.1.rsplit('/').next().unwrap_or_default();
+    let link = format!(
+        "</whep/resource/{}/sse>; rel=\"{}\"; events=\"{}\"",
+        id,
+        SSE_REL,
+        SUPPORTED_EVENTS.join(",")
+    );
+    let mut response = answer.into_response();
+    if let Ok(link) = HeaderValue::from_str(&link) {
+        response.headers_mut().append(header::LINK, link);
+    }
+    response
+}
+
+/// `POST /whep/resource/{id}/sse`: subscribes to a JSON array of event names.
+/// Unsupported names are ignored; the `Location` of the reply is the event
+/// stream to `GET`.
+pub async fn whep_sse_subscribe(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(requested): Json<Vec<String>>,
+) -> Result<Response, (StatusCode, &'static str)> {
+    if state.sessions.get(&id).is_none() {
+        return Err((
+            missing_session(&state.ended_sessions, &id),
+            "no such session",
+        ));
+    }
+    let events: Vec<&str> = SUPPORTED_EVENTS
+        .into_iter()
+        .filter(|event| requested.iter().any(|requested| requested == event))
+        .collect();
+    if events.is_empty() {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "none of the requested events are supported",
+        ));
+    }
+
+    let location = format!("/whep/resource/{}/sse?events={}", id, events.join(","));
+    Ok((StatusCode::CREATED, [(header::LOCATION, location)]).into_response())
+}
+
+/// `GET /whep/resource/{id}/sse`: the session's events as Server-Sent Events,
+/// until the session ends.
+pub async fn whep_sse(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<EventsQuery>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, StatusCode> {
+    let Some(session) = state.sessions.get(&id) else {
+        return Err(missing_session(&state.ended_sessions, &id));
+    };
+    let stream = state
+        .stream(&session.info.source)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let wanted: Vec<&'static str> = match &query.events {
+        Some(events) => SUPPORTED_EVENTS
+            .into_iter()
+            .filter(|event| events.split(',').any(|requested| requested == *event))
+            .collect(),
+        None => SUPPORTED_EVENTS.to_vec(),
+    };
+    let wants = move |event: &str| wanted.contains(&event);
+
+    let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+
+    // Where the session stands when the client subscribes
+    if wants("active") {
+        let _ = tx.try_send(event("active", json!({})));
+    }
+    if wants("layers") {
+        let capabilities = &stream.capabilities;
+        let layers = Layers {
+            video: capabilities.video.as_ref().map(|video| MediaLayers {
+                active: vec![video],
+                inactive: capabilities
+                    .layers
+                    .iter()
+                    .filter(|layer| {
+                        (&layer.codec, layer.width, layer.height)
+                            != (&video.codec, video.width, video.height)
+                    })
+                    .collect(),
+            }),
+            audio: capabilities.audio.as_ref().map(|audio| MediaLayers {
+                active: vec![audio],
+                inactive: Vec::new(),
+            }),
+        };
+        let _ = tx.try_send(event("layers", json!(layers)));
+    }
+    let mut viewers = stream.viewers.subscribe();
+    if wants("viewercount") {
+        let count = *viewers.borrow_and_update();
+        let _ = tx.try_send(event("viewercount", json!({ "viewercount": count })));
+    }
+
+    info!("📨 Session {} subscribed to server events", &id[..8]);
+    let mut events = stream.events.subscribe();
+    let source = stream.info.name.clone();
+    tokio::spawn(async move {
+        let mut session_check = tokio::time::interval(SESSION_CHECK_INTERVAL);
+        loop {
+            let next = tokio::select! {
+                received = events.recv() => match received {
+                    Ok(
+                        Event::VideoFrozen { .. }
+                        | Event::AudioStalled { .. }
+                        | Event::SourceDisabled,
+                    ) => Some(("inactive", json!({}))),
+                    Ok(
+                        Event::VideoResumed { .. }
+                        | Event::AudioResumed { .. }
+                        | Event::SourceEnabled,
+                    ) => Some(("active", json!({}))),
+                    Ok(Event::Motion { .. } | Event::Sound { .. }) => None,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                    Err(broadcast::error::RecvError::Lagged(_)) => None,
+                },
+                changed = viewers.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let count = *viewers.borrow_and_update();
+                    Some(("viewercount", json!({ "viewercount": count })))
+                }
+                _ = session_check.tick() => {
+                    if state.sessions.get(&id).is_some() {
+                        continue;
+                    }
+                    // The gateway is going away; another instance can take the viewer
+                    if state.shutting_down.load(Ordering::Relaxed) && wants("reconnect") {
+                        let url = format!("/whep/{}", source);
+                        let _ = tx.send(event("reconnect", json!({ "url": url }))).await;
+                    }
+                    break;
+                }
+            };
+            if let Some((name, data)) = next
+                && wants(name)
+                && tx.send(event(name, data)).await.is_err()
+            {
+                break;
+            }
+        }
+        debug!("Server events of session {} ended", &id[..8]);
+    });
+
+    Ok(Sse::new(ReceiverStream::new(rx)).keep_alive(KeepAlive::default()))
+}
+
+fn event(name: &str, data: serde_json::Value) -> Result<sse::Event, Infallible> {
+    Ok(sse::Event::default().event(name).data(data.to_string()))
+}
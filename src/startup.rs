@@ -0,0 +1,115 @@
+use std::{fmt, sync::Mutex, time::Instant};
+
+use serde::Serialize;
+
+/// Steps of bringing up an RTSP session, in the order they happen.
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+    Describe,
+    Setup,
+    Play,
+    FirstRtp,
+    FirstKeyframe,
+}
+
+/// How quickly the most recent viewer saw its first picture.
+#[derive(Debug, Clone, Serialize)]
+pub struct ViewerStartup {
+    /// From the WHEP request to the first keyframe written to the viewer.
+    pub first_frame_ms: u64,
+    /// Whether that keyframe came from the GOP cache rather than the source.
+    pub cached_gop: bool,
+}
+
+/// Where the time to first frame of a source's latest (re)connect went, as
+/// milliseconds since it began.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StartupReport {
+    /// What started the session, e.g. "Startup" or "Viewer joined".
+    pub reason: String,
+    pub describe_ms: Option<u64>,
+    pub setup_ms: Option<u64>,
+    pub play_ms: Option<u64>,
+    pub first_rtp_ms: Option<u64>,
+    pub first_keyframe_ms: Option<u64>,
+    pub last_viewer: Option<ViewerStartup>,
+}
+
+impl fmt::Display for StartupReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.reason)?;
+        for (name, ms) in [
+            ("DESCRIBE", self.describe_ms),
+            ("SETUP", self.setup_ms),
+            ("PLAY", self.play_ms),
+            ("first RTP", self.first_rtp_ms),
+            ("first keyframe", self.first_keyframe_ms),
+        ] {
+            if let Some(ms) = ms {
+                write!(f, " {} at {} ms", name, ms)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Timeline {
+    started: Instant,
+    report: StartupReport,
+}
+
+/// Records the startup timeline of a source, for `GET
+/// /api/sources/{name}/startup`.
+pub struct StartupTimer(Mutex<Timeline>);
+
+impl StartupTimer {
+    pub fn new(reason: &str) -> Self {
+        Self(Mutex::new(Timeline {
+            started: Instant::now(),
+            report: StartupReport {
+                reason: reason.to_owned(),
+                ..Default::default()
+            },
+        }))
+    }
+
+    /// Starts a new timeline for a (re)connect. The last viewer's numbers are
+    /// kept until another viewer joins.
+    pub fn begin(&self, reason: &str) {
+        let mut timeline = self.0.lock().unwrap();
+        timeline.started = Instant::now();
+        timeline.report = StartupReport {
+            reason: reason.to_owned(),
+            last_viewer: timeline.report.last_viewer.take(),
+            ..Default::default()
+        };
+    }
+
+    /// Records the first time `phase` is reached since the timeline began,
+    /// returning whether this was it.
+    pub fn mark(&self, phase: Phase) -> bool {
+        let mut timeline = self.0.lock().unwrap();
+        let elapsed = timeline.started.elapsed().as_millis() as u64;
+        let report = &mut timeline.report;
+        let slot = match phase {
+            Phase::Describe => &mut report.describe_ms,
+            Phase::Setup => &mut report.setup_ms,
+            Phase::Play => &mut report.play_ms,
+            Phase::FirstRtp => &mut report.first_rtp_ms,
+            Phase::FirstKeyframe => &mut report.first_keyframe_ms,
+        };
+        if slot.is_some() {
+            return false;
+        }
+        *slot = Some(elapsed);
+        true
+    }
+
+    pub fn viewer(&self, startup: ViewerStartup) {
+        self.0.lock().unwrap().report.last_viewer = Some(startup);
+    }
+
+    pub fn report(&self) -> StartupReport {
+        self.0.lock().unwrap().report.clone()
+    }
+}
@@ -1,25 +1,368 @@
-use std::sync::Arc;
-use webrtc::{api::API, track::track_local::track_local_static_rtp::TrackLocalStaticRTP};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::sync::{Notify, broadcast, watch};
+use webrtc::{api::API, ice_transport::ice_server::RTCIceServer};
+
+use crate::{
+    abr::Estimates,
+    candidates::CandidatePreference,
+    chaos::Faults,
+    cli::Source,
+    events::Event,
+    fanout::FanoutTrack,
+    hls::HlsPlaylist,
+    pairing::Pairing,
+    persist::EndedSessions,
+    pool::BufferPool,
+    startup::StartupTimer,
+    stats::PipelineStats,
+    store::{InMemorySessionStore, SessionStore},
+    timeline::TimelineSink,
+};
+
+#[cfg(feature = "av1")]
+use crate::av1::Transcoders;
+#[cfg(feature = "snapshot")]
+use crate::snapshot::Snapshots;
+
+// RTCP read buffers are sized for one MTU; a few idle ones are kept around
+const RTCP_BUFFER_SIZE: usize = 1500;
+const RTCP_BUFFERS_IDLE: usize = 64;
+
+/// Descriptive information about a source, as exposed by the API.
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceInfo {
+    pub name: String,
+    /// Upstream URL with credentials removed.
+    pub url: url::Url,
+    pub tags: BTreeMap<String, String>,
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+    /// Metadata carried besides the media, e.g. `application/vnd.onvif.metadata`.
+    pub metadata_codec: Option<String>,
+    /// Viewers of this source may only connect through TURN relays.
+    pub relay_only: bool,
+    /// Source served instead to substream-only viewers.
+    pub substream: Option<String>,
+    /// Shown by players instead of `name`, when set.
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+    /// Local interface or address its viewers' ICE runs on, if not the
+    /// gateway-wide one.
+    pub interface: Option<String>,
+}
+
+impl SourceInfo {
+    /// What players should call this source.
+    pub fn title(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// A video stream a source offers.
+#[derive(Debug, Clone, Serialize)]
+pub struct VideoLayer {
+    pub codec: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub framerate: Option<f32>,
+}
+
+/// The audio format a source forwards.
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioFormat {
+    pub codec: String,
+    pub clock_rate: u32,
+    pub channels: Option<u16>,
+}
+
+/// What players can expect from a source, for camera pickers and codec checks.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Capabilities {
+    /// The video layer forwarded to viewers.
+    pub video: Option<VideoLayer>,
+    pub audio: Option<AudioFormat>,
+    /// Every supported video stream the camera offers, best first.
+    pub layers: Vec<VideoLayer>,
+}
+
+/// A running source: its description, the tracks viewers subscribe to and the
+/// events it reports.
+pub struct Stream {
+    pub info: SourceInfo,
+    pub capabilities: Capabilities,
+    pub video_track: Option<(usize, Arc<FanoutTrack>)>,
+    pub audio_track: Option<(usize, Arc<FanoutTrack>)>,
+    pub events: broadcast::Sender<Event>,
+    /// Complete metadata documents, as they arrive from the source.
+    pub metadata: broadcast::Sender<String>,
+    pub stats: Arc<PipelineStats>,
+    /// Signalled when a viewer needs a keyframe (joined, or reported loss).
+    pub keyframe_requests: Arc<Notify>,
+    pub control: Arc<SourceControl>,
+    /// Number of connected viewers.
+    pub viewers: watch::Sender<usize>,
+    /// Where the latest (re)connect's time to first frame went.
+    pub startup: Arc<StartupTimer>,
+    /// The source served as HLS, with `--hls`.
+    pub hls: Option<Arc<HlsPlaylist>>,
+    /// Failures injected into an RTSP source's pipeline, with `--chaos`.
+    pub faults: Option<Arc<Faults>>,
+}
+
+/// Counts as one viewer of a stream until dropped.
+pub struct Viewer(watch::Sender<usize>);
+
+impl Viewer {
+    pub fn new(viewers: &watch::Sender<usize>) -> Self {
+        viewers.send_modify(|count| *count += 1);
+        Self(viewers.clone())
+    }
+}
+
+impl Drop for Viewer {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count = count.saturating_sub(1));
+    }
+}
+
+/// Admits viewer sessions up to `--max-viewers`.
+pub struct ViewerLimit {
+    max: Option<usize>,
+    count: AtomicUsize,
+}
+
+impl ViewerLimit {
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    /// A slot for one more viewer, or `None` if the limit is reached.
+    pub fn admit(self: &Arc<Self>) -> Option<ViewerSlot> {
+        self.count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (self.max.is_none_or(|max| count < max)).then_some(count + 1)
+            })
+            .ok()?;
+        Some(ViewerSlot(self.clone()))
+    }
+
+    /// Viewer sessions admitted and not yet gone, negotiating ones included.
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    pub fn max(&self) -> Option<usize> {
+        self.max
+    }
+}
+
+/// Holds one of the `ViewerLimit`'s places until dropped.
+pub struct ViewerSlot(Arc<ViewerLimit>);
+
+impl Drop for ViewerSlot {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Remote control of a running source.
+#[derive(Default)]
+pub struct SourceControl {
+    /// While set, nothing is forwarded to viewers.
+    pub privacy: AtomicBool,
+    /// Signalled to reconnect to the camera.
+    pub restart: Notify,
+    /// Signalled when the camera no longer describes the streams the source's
+    /// tracks were built for, to start the source afresh.
+    pub rebuild: Notify,
+    /// Signalled to stop the source for good, e.g. on shutdown.
+    pub stop: Notify,
+    /// Signalled once the source has stopped and torn its upstream down.
+    pub stopped: Notify,
+    /// Set once the source has stopped for good, so tasks watching it (like
+    /// its freeze watchdog) exit too.
+    pub ended: watch::Sender<bool>,
+    /// While set, the source holds no RTSP session and turns viewers away,
+    /// but keeps its configuration.
+    pub suspended: watch::Sender<bool>,
+    /// While the source has lost its camera, when it next tries to reconnect;
+    /// viewers are turned away meanwhile.
+    pub reconnect_at: watch::Sender<Option<tokio::time::Instant>>,
+}
+
+impl SourceControl {
+    pub fn is_private(&self) -> bool {
+        self.privacy.load(Ordering::Relaxed)
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        *self.suspended.borrow()
+    }
+
+    /// How long until the next reconnect attempt, while the source has lost
+    /// its camera.
+    pub fn reconnecting_in(&self) -> Option<std::time::Duration> {
+        self.reconnect_at
+            .borrow()
+            .map(|at| at.saturating_duration_since(tokio::time::Instant::now()))
+    }
+}
+
+/// Builds the WebRTC API of viewers of sources with `interface=`.
+pub type InterfaceApiBuilder = Box<dyn Fn(&str) -> Result<API, webrtc::Error> + Send + Sync>;
+
+/// WebRTC APIs that gather ICE candidates on one local interface or address,
+/// for sources with `interface=`; one per interface, built on first use.
+#[derive(Default)]
+pub struct InterfaceApis {
+    build: Option<InterfaceApiBuilder>,
+    apis: Mutex<HashMap<String, Arc<API>>>,
+}
+
+impl InterfaceApis {
+    pub fn new(build: InterfaceApiBuilder) -> Self {
+        Self {
+            build: Some(build),
+            apis: Mutex::default(),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct AppState {
     pub api: Arc<API>,
-    pub peer_connections: dashmap::DashMap<String, Arc<webrtc::peer_connection::RTCPeerConnection>>,
-    pub video_track: (usize, Arc<TrackLocalStaticRTP>),
-    pub audio_track: Option<(usize, Arc<TrackLocalStaticRTP>)>,
+    /// APIs of sources with their own `interface=`.
+    pub interface_apis: Arc<InterfaceApis>,
+    /// Running sources by name; WHIP publishers come and go at runtime.
+    pub streams: Arc<RwLock<BTreeMap<String, Arc<Stream>>>>,
+    /// Source served at the bare `/whep` endpoint.
+    pub default_stream: String,
+    /// Sources given at startup that are still being connected to, with
+    /// `--background-startup`.
+    pub connecting: Arc<RwLock<BTreeSet<String>>>,
+    pub sessions: Arc<dyn SessionStore>,
+    /// WHIP publishers, keyed by resource id like viewer sessions.
+    pub publishers: Arc<dyn SessionStore>,
+    pub rtcp_buffers: Arc<BufferPool>,
+    /// Log offers and answers (with secrets redacted).
+    pub log_sdp: bool,
+    pub candidate_preference: Arc<CandidatePreference>,
+    /// STUN/TURN servers handed to every peer connection.
+    pub ice_servers: Arc<Vec<RTCIceServer>>,
+    /// Options shared by all cameras, for sources added at runtime.
+    pub options: Arc<Source>,
+    /// Set once shutdown begins, so `/readyz` stops reporting ready.
+    pub shutting_down: Arc<AtomicBool>,
+    /// Where viewer sessions' quality timelines go, with `--session-log-dir`.
+    pub timelines: Option<Arc<dyn TimelineSink>>,
+    pub viewer_limit: Arc<ViewerLimit>,
+    /// Viewer sessions the last restart ended, with `--session-state-file`.
+    pub ended_sessions: Arc<EndedSessions>,
+    /// Viewers' bandwidth estimates, with `--abr`.
+    pub bandwidth: Arc<Estimates>,
+    /// Signs the viewing links handed out for pairing phones.
+    pub pairing: Arc<Pairing>,
+    /// AV1 transcodes for `+av1` viewers.
+    #[cfg(feature = "av1")]
+    pub av1: Arc<Transcoders>,
+    /// The latest JPEG still of each source.
+    #[cfg(feature = "snapshot")]
+    pub snapshots: Arc<Snapshots>,
 }
 
 impl AppState {
-    pub fn new(
-        api: API,
-        video_track: (usize, Arc<TrackLocalStaticRTP>),
-        audio_track: Option<(usize, Arc<TrackLocalStaticRTP>)>,
-    ) -> Self {
+    /// The first stream becomes the default one; without streams, the one
+    /// later added under `--name`.
+    pub fn new(api: API, streams: Vec<Stream>, options: Source) -> Self {
         Self {
             api: Arc::new(api),
-            peer_connections: dashmap::DashMap::new(),
-            video_track,
-            audio_track,
+            interface_apis: Arc::default(),
+            default_stream: streams
+                .first()
+                .map(|stream| stream.info.name.clone())
+                .unwrap_or_else(|| options.name.clone()),
+            streams: Arc::new(RwLock::new(
+                streams
+                    .into_iter()
+                    .map(|stream| (stream.info.name.clone(), Arc::new(stream)))
+                    .collect(),
+            )),
+            connecting: Arc::default(),
+            sessions: Arc::new(InMemorySessionStore::default()),
+            viewer_limit: Arc::new(ViewerLimit::new(options.max_viewers)),
+            pairing: Arc::new(Pairing::new(Duration::from_secs(options.pairing_ttl))),
+            #[cfg(feature = "av1")]
+            av1: Arc::new(Transcoders::new(options.av1_bitrate, options.av1_ladder)),
+            #[cfg(feature = "snapshot")]
+            snapshots: Arc::default(),
+            publishers: Arc::new(InMemorySessionStore::default()),
+            rtcp_buffers: BufferPool::new(RTCP_BUFFER_SIZE, RTCP_BUFFERS_IDLE),
+            log_sdp: false,
+            candidate_preference: Arc::new(CandidatePreference {
+                types: Vec::new(),
+                family: None,
+            }),
+            ice_servers: Arc::new(Vec::new()),
+            options: Arc::new(options),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            timelines: None,
+            ended_sessions: Arc::default(),
+            bandwidth: Arc::default(),
+        }
+    }
+
+    /// The WebRTC API for viewers of `source`: one gathering on its own
+    /// `interface=`, if it has one, else the gateway's.
+    pub fn api_for(&self, source: &SourceInfo) -> Result<Arc<API>, webrtc::Error> {
+        let (Some(interface), Some(build)) = (&source.interface, &self.interface_apis.build) else {
+            return Ok(self.api.clone());
+        };
+        let mut apis = self.interface_apis.apis.lock().unwrap();
+        if let Some(api) = apis.get(interface) {
+            return Ok(api.clone());
+        }
+        let api = Arc::new(build(interface)?);
+        apis.insert(interface.clone(), api.clone());
+        Ok(api)
+    }
+
+    pub fn stream(&self, name: &str) -> Option<Arc<Stream>> {
+        self.streams.read().unwrap().get(name).cloned()
+    }
+
+    /// Running sources, ordered by name.
+    pub fn all_streams(&self) -> Vec<Arc<Stream>> {
+        self.streams.read().unwrap().values().cloned().collect()
+    }
+
+    /// Registers a source started at runtime; `false` if the name is taken.
+    pub fn add_stream(&self, stream: Stream) -> bool {
+        let mut streams = self.streams.write().unwrap();
+        if streams.contains_key(&stream.info.name) {
+            return false;
         }
+        streams.insert(stream.info.name.clone(), Arc::new(stream));
+        true
+    }
+
+    pub fn remove_stream(&self, name: &str) -> Option<Arc<Stream>> {
+        self.streams.write().unwrap().remove(name)
+    }
+
+    /// Whether `name` is a source given at startup that isn't connected yet.
+    pub fn is_connecting(&self, name: &str) -> bool {
+        self.connecting.read().unwrap().contains(name)
     }
 }
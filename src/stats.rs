@@ -0,0 +1,419 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    startup::StartupReport,
+    state::{AppState, SourceInfo},
+    store::SessionInfo,
+};
+
+/// Packet counters for one track of a source's forwarding pipeline.
+#[derive(Default)]
+pub struct TrackStats {
+    /// Packets read from the RTSP session.
+    pub received: AtomicU64,
+    /// Packets lost to a full queue or a parse error.
+    pub dropped: AtomicU64,
+    /// Packets written to the WebRTC track.
+    pub forwarded: AtomicU64,
+}
+
+impl TrackStats {
+    pub fn snapshot(&self) -> TrackCounts {
+        TrackCounts {
+            received: self.received.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            forwarded: self.forwarded.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Whole video frames dropped because the video writer fell behind, by
+/// what was dropped.
+#[derive(Default)]
+pub struct FrameDrops {
+    /// Frames no other frame is predicted from, dropped first.
+    pub non_reference: AtomicU64,
+    /// Reference frames; the video waits for the next keyframe after one.
+    pub reference: AtomicU64,
+    /// Frames a newer keyframe made unnecessary.
+    pub superseded: AtomicU64,
+}
+
+impl FrameDrops {
+    pub fn snapshot(&self) -> FrameDropCounts {
+        FrameDropCounts {
+            non_reference: self.non_reference.load(Ordering::Relaxed),
+            reference: self.reference.load(Ordering::Relaxed),
+            superseded: self.superseded.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Packet counters of a source, by track.
+#[derive(Default)]
+pub struct PipelineStats {
+    pub video: TrackStats,
+    pub audio: TrackStats,
+    pub video_frames_dropped: FrameDrops,
+    /// Viewer connections that ended in ICE/DTLS failure.
+    pub viewer_failures: AtomicU64,
+    /// Attempts to reconnect to the camera after it dropped the session.
+    pub reconnects: AtomicU64,
+    /// Viewers closed for falling behind even on keyframes only.
+    pub viewers_evicted: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackCounts {
+    pub received: u64,
+    pub dropped: u64,
+    pub forwarded: u64,
+}
+
+impl TrackCounts {
+    pub const NAMES: [&str; 3] = ["received", "dropped", "forwarded"];
+
+    pub fn values(&self) -> [u64; 3] {
+        [self.received, self.dropped, self.forwarded]
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameDropCounts {
+    pub non_reference: u64,
+    pub reference: u64,
+    pub superseded: u64,
+}
+
+impl FrameDropCounts {
+    pub const REASONS: [&str; 3] = ["non_reference", "reference", "superseded"];
+
+    pub fn values(&self) -> [u64; 3] {
+        [self.non_reference, self.reference, self.superseded]
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceStats {
+    #[serde(flatten)]
+    pub info: SourceInfo,
+    pub video: TrackCounts,
+    pub audio: TrackCounts,
+    pub video_frames_dropped: FrameDropCounts,
+    pub viewer_failures: u64,
+    pub reconnects: u64,
+    pub viewers_evicted: u64,
+    pub startup: StartupReport,
+}
+
+/// Everything the gateway knows at one point in time, for `GET
+/// /api/stats/snapshot` and the periodic snapshot files.
+#[derive(Debug, Clone, Serialize)]
+pub struct Snapshot {
+    /// Milliseconds since the Unix epoch.
+    pub taken_at: u64,
+    pub sources: Vec<SourceStats>,
+    pub sessions: Vec<SessionInfo>,
+    /// Viewer sessions the last restart ended, by source, with
+    /// `--session-state-file`.
+    pub sessions_ended_by_restart: BTreeMap<String, u64>,
+}
+
+impl Snapshot {
+    pub fn take(state: &AppState) -> Self {
+        Self {
+            taken_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            sources: state
+                .all_streams()
+                .iter()
+                .map(|stream| SourceStats {
+                    info: stream.info.clone(),
+                    video: stream.stats.video.snapshot(),
+                    audio: stream.stats.audio.snapshot(),
+                    video_frames_dropped: stream.stats.video_frames_dropped.snapshot(),
+                    viewer_failures: stream.stats.viewer_failures.load(Ordering::Relaxed),
+                    reconnects: stream.stats.reconnects.load(Ordering::Relaxed),
+                    viewers_evicted: stream.stats.viewers_evicted.load(Ordering::Relaxed),
+                    startup: stream.startup.report(),
+                })
+                .collect(),
+            sessions: state.sessions.list(),
+            sessions_ended_by_restart: state.ended_sessions.by_source().clone(),
+        }
+    }
+
+    /// Renders the counters in the OpenMetrics text format, with the labels
+    /// `labels` asks for.
+    pub fn to_openmetrics(&self, labels: &MetricLabels) -> String {
+        let source_label = |name: &str| {
+            let info = self
+                .sources
+                .iter()
+                .find(|source| source.info.name == name)
+                .map(|source| &source.info);
+            labels.source(name, info)
+        };
+
+        let mut sessions = Family::new("whep_sessions", "gauge");
+        for source in &self.sources {
+            let count = self
+                .sessions
+                .iter()
+                .filter(|session| session.source == source.info.name)
+                .count();
+            sessions.add(
+                labels,
+                &[(MetricLabel::Source, source_label(&source.info.name))],
+                count as u64,
+            );
+        }
+        let mut families = vec![sessions];
+
+        for (index, name) in TrackCounts::NAMES.into_iter().enumerate() {
+            let mut packets = Family::new(format!("rtp_packets_{}", name), "counter");
+            for source in &self.sources {
+                for (track, counts) in [("video", &source.video), ("audio", &source.audio)] {
+                    packets.add(
+                        labels,
+                        &[
+                            (MetricLabel::Source, source_label(&source.info.name)),
+                            (MetricLabel::Track, track.to_owned()),
+                        ],
+                        counts.values()[index],
+                    );
+                }
+            }
+            families.push(packets);
+        }
+
+        let mut frames_dropped = Family::new("video_frames_dropped", "counter");
+        for source in &self.sources {
+            let counts = source.video_frames_dropped.values();
+            for (reason, dropped) in FrameDropCounts::REASONS.into_iter().zip(counts) {
+                frames_dropped.add(
+                    labels,
+                    &[
+                        (MetricLabel::Source, source_label(&source.info.name)),
+                        (MetricLabel::Reason, reason.to_owned()),
+                    ],
+                    dropped,
+                );
+            }
+        }
+        families.push(frames_dropped);
+
+        let mut viewer_failures = Family::new("whep_viewer_failures", "counter");
+        for source in &self.sources {
+            viewer_failures.add(
+                labels,
+                &[(MetricLabel::Source, source_label(&source.info.name))],
+                source.viewer_failures,
+            );
+        }
+        families.push(viewer_failures);
+
+        let mut reconnects = Family::new("rtsp_reconnects", "counter");
+        for source in &self.sources {
+            reconnects.add(
+                labels,
+                &[(MetricLabel::Source, source_label(&source.info.name))],
+                source.reconnects,
+            );
+        }
+        families.push(reconnects);
+
+        let mut evicted = Family::new("whep_viewers_evicted", "counter");
+        for source in &self.sources {
+            evicted.add(
+                labels,
+                &[(MetricLabel::Source, source_label(&source.info.name))],
+                source.viewers_evicted,
+            );
+        }
+        families.push(evicted);
+
+        let mut ended_by_restart = Family::new("whep_sessions_ended_by_restart", "counter");
+        for (source, ended) in &self.sessions_ended_by_restart {
+            ended_by_restart.add(
+                labels,
+                &[(MetricLabel::Source, source_label(source))],
+                *ended,
+            );
+        }
+        families.push(ended_by_restart);
+
+        let mut out = String::new();
+        for family in &families {
+            family.render(&mut out);
+        }
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+/// A label of the `/metrics` series that `--metrics-drop-label` can leave
+/// out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MetricLabel {
+    /// The source a series belongs to.
+    Source,
+    /// `video` or `audio`.
+    Track,
+    /// Why video frames were dropped.
+    Reason,
+}
+
+impl MetricLabel {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Source => "source",
+            Self::Track => "track",
+            Self::Reason => "reason",
+        }
+    }
+}
+
+/// Which labels `/metrics` emits, to keep the number of series in check on
+/// large deployments.
+#[derive(Debug, Clone, Default)]
+pub struct MetricLabels {
+    /// Labels left out; the series that differed only in them are summed.
+    pub dropped: Vec<MetricLabel>,
+    /// Source tag (e.g. `site`) that labels series instead of `source`.
+    pub source_tag: Option<String>,
+}
+
+impl MetricLabels {
+    /// What `label` is called in the output: the source tag's key stands in
+    /// for `source` with `--metrics-source-tag`.
+    fn name(&self, label: MetricLabel) -> &str {
+        match (label, &self.source_tag) {
+            (MetricLabel::Source, Some(tag)) => tag,
+            _ => label.name(),
+        }
+    }
+
+    /// The `source` label's value for source `name`; sources without the tag
+    /// share an empty one.
+    fn source(&self, name: &str, info: Option<&SourceInfo>) -> String {
+        match &self.source_tag {
+            Some(tag) => info
+                .and_then(|info| info.tags.get(tag))
+                .cloned()
+                .unwrap_or_default(),
+            None => name.to_owned(),
+        }
+    }
+}
+
+/// Checks that a `--metrics-source-tag` key can be a label name.
+pub fn parse_label_name(name: &str) -> Result<String, String> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(name.to_owned())
+    } else {
+        Err("label names are letters, digits and `_`, not starting with a digit".to_owned())
+    }
+}
+
+/// The series of one metric, summed over the labels left out.
+struct Family {
+    name: String,
+    kind: &'static str,
+    series: BTreeMap<Vec<(String, String)>, u64>,
+}
+
+impl Family {
+    fn new(name: impl Into<String>, kind: &'static str) -> Self {
+        Self {
+            name: name.into(),
+            kind,
+            series: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, labels: &MetricLabels, series: &[(MetricLabel, String)], value: u64) {
+        let key = series
+            .iter()
+            .filter(|(label, _)| !labels.dropped.contains(label))
+            .map(|(label, value)| (labels.name(*label).to_owned(), value.clone()))
+            .collect();
+        *self.series.entry(key).or_default() += value;
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# TYPE {} {}", self.name, self.kind);
+        let suffix = if self.kind == "counter" { "_total" } else { "" };
+        for (labels, value) in &self.series {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, escape_label(value)))
+                .collect();
+            if labels.is_empty() {
+                let _ = writeln!(out, "{}{} {}", self.name, suffix, value);
+            } else {
+                let _ = writeln!(
+                    out,
+                    "{}{}{{{}}} {}",
+                    self.name,
+                    suffix,
+                    labels.join(","),
+                    value
+                );
+            }
+        }
+    }
+}
+
+// Label values may not contain raw backslashes, quotes or newlines
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Writes a JSON snapshot to `dir` every `interval` for postmortem analysis.
+pub fn spawn_snapshot_writer(state: AppState, dir: PathBuf, interval: Duration) {
+    if interval.is_zero() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            warn!("Cannot create snapshot directory {}: {}", dir.display(), e);
+            return;
+        }
+        info!("📊 Writing stats snapshots to {}", dir.display());
+
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+
+            let snapshot = Snapshot::take(&state);
+            let path = dir.join(format!("snapshot-{}.json", snapshot.taken_at));
+            let result = serde_json::to_vec_pretty(&snapshot)
+                .map_err(std::io::Error::from)
+                .and_then(|json| std::fs::write(&path, json));
+            if let Err(e) = result {
+                warn!("Failed to write snapshot {}: {}", path.display(), e);
+            }
+        }
+    });
+}
@@ -0,0 +1,127 @@
+use std::{sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use serde::Serialize;
+use tokio::time::Instant;
+use webrtc::peer_connection::RTCPeerConnection;
+
+use crate::{sframe::FrameKeys, trickle::LocalCandidates, watchdog::Activity};
+
+/// What a session is watching and on whose behalf.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub source: String,
+    /// Identity of the token that created the session; `None` without auth.
+    #[serde(skip)]
+    pub owner: Option<String>,
+    /// The session's SFrame key, with `--e2ee`.
+    #[serde(skip)]
+    pub frame_keys: Option<Arc<FrameKeys>>,
+    /// The gateway's candidates still to be trickled to the viewer, when
+    /// the answer went out before gathering finished.
+    #[serde(skip)]
+    pub local_candidates: Option<Arc<LocalCandidates>>,
+}
+
+/// A session looked up by id, with its connection.
+pub struct StoredSession {
+    pub info: SessionInfo,
+    pub pc: Arc<RTCPeerConnection>,
+    /// Time since the session was created.
+    pub uptime: Duration,
+}
+
+/// Registry of active WHEP sessions, keyed by resource id.
+///
+/// Handlers only talk to this trait so the registry can be backed by something
+/// other than process memory.
+pub trait SessionStore: Send + Sync {
+    fn insert(&self, info: SessionInfo, pc: Arc<RTCPeerConnection>);
+    fn remove(&self, id: &str) -> Option<Arc<RTCPeerConnection>>;
+    fn get(&self, id: &str) -> Option<StoredSession>;
+    fn len(&self) -> usize;
+    /// Records a heartbeat from the client; `false` if there is no such session.
+    fn touch(&self, id: &str) -> bool;
+    /// Ids of sessions without a heartbeat (or creation) for at least `timeout`.
+    fn idle(&self, timeout: Duration) -> Vec<String>;
+    fn list(&self) -> Vec<SessionInfo>;
+    /// Sessions created by `owner`.
+    fn owned_by(&self, owner: Option<&str>) -> Vec<SessionInfo>;
+}
+
+struct Entry {
+    info: SessionInfo,
+    pc: Arc<RTCPeerConnection>,
+    heartbeat: Activity,
+    created: Instant,
+}
+
+/// The default store, holding sessions in a concurrent map.
+#[derive(Default)]
+pub struct InMemorySessionStore {
+    sessions: DashMap<String, Entry>,
+}
+
+impl SessionStore for InMemorySessionStore {
+    fn insert(&self, info: SessionInfo, pc: Arc<RTCPeerConnection>) {
+        self.sessions.insert(
+            info.id.clone(),
+            Entry {
+                info,
+                pc,
+                heartbeat: Activity::new(),
+                created: Instant::now(),
+            },
+        );
+    }
+
+    fn remove(&self, id: &str) -> Option<Arc<RTCPeerConnection>> {
+        self.sessions.remove(id).map(|(_, entry)| entry.pc)
+    }
+
+    fn get(&self, id: &str) -> Option<StoredSession> {
+        self.sessions.get(id).map(|entry| StoredSession {
+            info: entry.info.clone(),
+            pc: entry.pc.clone(),
+            uptime: entry.created.elapsed(),
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    fn touch(&self, id: &str) -> bool {
+        match self.sessions.get(id) {
+            Some(entry) => {
+                entry.heartbeat.touch();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn idle(&self, timeout: Duration) -> Vec<String> {
+        self.sessions
+            .iter()
+            .filter(|entry| entry.heartbeat.idle() >= timeout)
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    fn list(&self) -> Vec<SessionInfo> {
+        self.sessions
+            .iter()
+            .map(|entry| entry.info.clone())
+            .collect()
+    }
+
+    fn owned_by(&self, owner: Option<&str>) -> Vec<SessionInfo> {
+        self.sessions
+            .iter()
+            .filter(|entry| entry.info.owner.as_deref() == owner)
+            .map(|entry| entry.info.clone())
+            .collect()
+    }
+}
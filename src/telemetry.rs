@@ -0,0 +1,108 @@
+use std::{
+    sync::{Arc, atomic::Ordering},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::sync::{broadcast, watch};
+use tracing::debug;
+use webrtc::data_channel::RTCDataChannel;
+
+use crate::{
+    abr::Estimates,
+    events::Event,
+    state::Stream,
+    stats::{FrameDropCounts, PipelineStats, TrackCounts},
+};
+
+/// Label of the data channel on which viewers receive everything there is to
+/// overlay on the video, as JSON: the source's events, its metadata documents
+/// and the gateway's stats.
+pub const TELEMETRY_LABEL: &str = "telemetry";
+
+// How often stats are sent
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A telemetry message, told apart by its `type`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    Event {
+        #[serde(flatten)]
+        event: Event,
+    },
+    Metadata {
+        document: String,
+    },
+    Stats(Stats),
+}
+
+#[derive(Serialize)]
+struct Stats {
+    viewers: usize,
+    video: TrackCounts,
+    audio: TrackCounts,
+    video_frames_dropped: FrameDropCounts,
+    viewer_failures: u64,
+    /// The viewer's estimated bandwidth, in bits per second, with `--abr`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bandwidth: Option<u64>,
+}
+
+/// Sends a telemetry channel the events and metadata documents of `stream`
+/// as they come, and stats every second.
+///
+/// `bandwidth` is where the viewer's estimate is found, and the SSRC it is
+/// filed under.
+pub fn serve(dc: Arc<RTCDataChannel>, stream: &Stream, bandwidth: Option<(Arc<Estimates>, u32)>) {
+    let mut events = stream.events.subscribe();
+    let mut metadata = stream.metadata.subscribe();
+    let stats = stream.stats.clone();
+    let viewers = stream.viewers.subscribe();
+
+    let dc_for_open = dc.clone();
+    dc.on_open(Box::new(move || {
+        Box::pin(async move {
+            debug!("Data channel '{}' open", dc_for_open.label());
+            let mut ticks = tokio::time::interval(STATS_INTERVAL);
+            loop {
+                let message = tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(event) => Message::Event { event },
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    document = metadata.recv() => match document {
+                        Ok(document) => Message::Metadata { document },
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    _ = ticks.tick() => Message::Stats(collect(&stats, &viewers, bandwidth.as_ref())),
+                };
+                let Ok(text) = serde_json::to_string(&message) else {
+                    continue;
+                };
+                if dc_for_open.send_text(text).await.is_err() {
+                    break;
+                }
+            }
+        })
+    }));
+}
+
+fn collect(
+    stats: &PipelineStats,
+    viewers: &watch::Receiver<usize>,
+    bandwidth: Option<&(Arc<Estimates>, u32)>,
+) -> Stats {
+    Stats {
+        viewers: *viewers.borrow(),
+        video: stats.video.snapshot(),
+        audio: stats.audio.snapshot(),
+        video_frames_dropped: stats.video_frames_dropped.snapshot(),
+        viewer_failures: stats.viewer_failures.load(Ordering::Relaxed),
+        bandwidth: bandwidth
+            .and_then(|(estimates, ssrc)| estimates.get(*ssrc))
+            .and_then(|estimator| estimator.estimate()),
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use dashmap::DashMap;
+use serde::Serialize;
+use tokio::time::Instant;
+use tracing::{info, warn};
+use webrtc::{peer_connection::RTCPeerConnection, stats::StatsReportType};
+
+use crate::{
+    api::{CandidatePair, nominated_pair},
+    store::SessionStore,
+};
+
+/// What happened to a session, as one line of its timeline.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TimelineEvent {
+    Join {
+        source: String,
+        owner: Option<String>,
+        relay_only: bool,
+    },
+    /// The peer connection changed state (`connected`, `disconnected`, ...).
+    State {
+        state: String,
+    },
+    /// ICE nominated a (new) candidate pair.
+    Route {
+        #[serde(flatten)]
+        pair: CandidatePair,
+    },
+    /// Delivery every `--session-log-interval` seconds.
+    Quality(Quality),
+    Leave {
+        reason: String,
+        duration_secs: u64,
+    },
+}
+
+/// The bitrate since the previous sample, and counters since the session
+/// started.
+#[derive(Debug, Default, Serialize)]
+pub struct Quality {
+    pub bitrate_kbps: u64,
+    pub packets_sent: u64,
+    /// Packets the viewer reported lost, in total.
+    pub packets_lost: i64,
+    /// Share of packets lost in the viewer's latest receiver report.
+    pub fraction_lost: f64,
+    pub round_trip_ms: Option<f64>,
+    pub nacks: u64,
+    /// PLIs and FIRs.
+    pub keyframe_requests: u64,
+}
+
+impl Quality {
+    /// The counters in a connection's stats reports, and the bytes it sent so
+    /// far; the bitrate is left for the caller to work out.
+    pub fn from_reports(reports: &HashMap<String, StatsReportType>) -> (Self, u64) {
+        let mut quality = Self::default();
+        let mut sent = 0;
+        for stats in reports.values() {
+            match stats {
+                StatsReportType::OutboundRTP(outbound) => {
+                    sent += outbound.bytes_sent;
+                    quality.packets_sent += outbound.packets_sent;
+                    quality.nacks += outbound.nack_count;
+                    quality.keyframe_requests +=
+                        outbound.pli_count.unwrap_or(0) + outbound.fir_count.unwrap_or(0);
+                }
+                StatsReportType::RemoteInboundRTP(remote) => {
+                    quality.packets_lost += remote.packets_lost;
+                    quality.fraction_lost = quality.fraction_lost.max(remote.fraction_lost);
+                    if let Some(rtt) = remote.round_trip_time {
+                        let rtt = rtt * 1000.0;
+                        quality.round_trip_ms =
+                            Some(quality.round_trip_ms.map_or(rtt, |ms| ms.max(rtt)));
+                    }
+                }
+                _ => {}
+            }
+        }
+        (quality, sent)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TimelineEntry<'a> {
+    pub session: &'a str,
+    /// Unix time in milliseconds.
+    pub at: u64,
+    #[serde(flatten)]
+    pub event: &'a TimelineEvent,
+}
+
+/// Keeps session timelines somewhere outside the process.
+///
+/// Sessions only talk to this trait so timelines can go to something other
+/// than files (a log pipeline, a database).
+pub trait TimelineSink: Send + Sync {
+    fn record(&self, entry: &TimelineEntry<'_>);
+}
+
+/// Appends each session's timeline as JSON Lines to
+/// `<dir>/<started>-<session>.jsonl`, `started` in Unix milliseconds.
+pub struct FileTimelineSink {
+    dir: PathBuf,
+    // Files of the sessions that haven't left yet
+    files: DashMap<String, PathBuf>,
+}
+
+impl FileTimelineSink {
+    pub fn new(dir: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        info!("🗒️ Writing session timelines to {}", dir.display());
+        Ok(Self {
+            dir,
+            files: DashMap::new(),
+        })
+    }
+}
+
+impl TimelineSink for FileTimelineSink {
+    fn record(&self, entry: &TimelineEntry<'_>) {
+        let path = match entry.event {
+            TimelineEvent::Join { .. } => {
+                let path = self
+                    .dir
+                    .join(format!("{}-{}.jsonl", entry.at, entry.session));
+                self.files.insert(entry.session.to_owned(), path.clone());
+                path
+            }
+            TimelineEvent::Leave { .. } => match self.files.remove(entry.session) {
+                Some((_, path)) => path,
+                None => return,
+            },
+            _ => match self.files.get(entry.session) {
+                Some(path) => path.clone(),
+                None => return,
+            },
+        };
+        let result = serde_json::to_vec(entry)
+            .map_err(std::io::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)?
+                    .write_all(&line)
+            });
+        if let Err(e) = result {
+            warn!("Failed to write session timeline {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// One session's timeline: what it records, and when it stops.
+pub struct SessionTimeline {
+    id: String,
+    sink: Arc<dyn TimelineSink>,
+    created: Instant,
+    left: AtomicBool,
+}
+
+impl SessionTimeline {
+    pub fn start(id: &str, sink: Arc<dyn TimelineSink>, join: TimelineEvent) -> Arc<Self> {
+        let timeline = Arc::new(Self {
+            id: id.to_owned(),
+            sink,
+            created: Instant::now(),
+            left: AtomicBool::new(false),
+        });
+        timeline.record(&join);
+        timeline
+    }
+
+    pub fn record(&self, event: &TimelineEvent) {
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.sink.record(&TimelineEntry {
+            session: &self.id,
+            at,
+            event,
+        });
+    }
+
+    /// Ends the timeline; later calls are ignored.
+    pub fn leave(&self, reason: &str) {
+        if self.left.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        self.record(&TimelineEvent::Leave {
+            reason: reason.to_owned(),
+            duration_secs: self.created.elapsed().as_secs(),
+        });
+    }
+
+    /// Samples the connection's delivery every `interval` until the session
+    /// is gone.
+    pub fn spawn_sampler(
+        self: &Arc<Self>,
+        pc: Arc<RTCPeerConnection>,
+        sessions: Arc<dyn SessionStore>,
+        interval: Duration,
+    ) {
+        if interval.is_zero() {
+            return;
+        }
+        let timeline = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            // The first tick is immediate
+            ticks.tick().await;
+            let mut bytes_sent = 0;
+            let mut route = None;
+            loop {
+                ticks.tick().await;
+                if timeline.left.load(Ordering::Relaxed) || sessions.get(&timeline.id).is_none() {
+                    break;
+                }
+                let reports = pc.get_stats().await.reports;
+
+                if let Some(pair) = nominated_pair(&reports) {
+                    let key = format!(
+                        "{}:{} {}:{}",
+                        pair.local.address, pair.local.port, pair.remote.address, pair.remote.port
+                    );
+                    if route.as_ref() != Some(&key) {
+                        route = Some(key);
+                        timeline.record(&TimelineEvent::Route { pair });
+                    }
+                }
+
+                let (mut quality, sent) = Quality::from_reports(&reports);
+                quality.bitrate_kbps =
+                    sent.saturating_sub(bytes_sent) * 8 / interval.as_millis().max(1) as u64;
+                bytes_sent = sent;
+                timeline.record(&TimelineEvent::Quality(quality));
+            }
+        });
+    }
+}
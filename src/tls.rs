@@ -0,0 +1,88 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    extract::Path as UrlPath,
+    http::{HeaderMap, StatusCode, Uri, header, uri::Authority},
+    response::{IntoResponse, Redirect, Response},
+};
+use tracing::info;
+
+use crate::acme::Challenges;
+
+const SELF_SIGNED_CERT: &str = "self-signed.crt";
+const SELF_SIGNED_KEY: &str = "self-signed.key";
+
+/// Returns the certificate and key paths of a self-signed certificate in `dir`,
+/// generating one for `localhost`, `127.0.0.1` and `names` if none exists yet.
+///
+/// The pair is kept across restarts so browsers only have to trust it once;
+/// delete it to regenerate with different names.
+pub fn self_signed(dir: &Path, names: &[String]) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let cert_path = dir.join(SELF_SIGNED_CERT);
+    let key_path = dir.join(SELF_SIGNED_KEY);
+    if cert_path.exists() && key_path.exists() {
+        return Ok((cert_path, key_path));
+    }
+
+    let mut subject_alt_names = vec!["localhost".to_owned(), "127.0.0.1".to_owned()];
+    subject_alt_names.extend(names.iter().cloned());
+    let certified = rcgen::generate_simple_self_signed(subject_alt_names.clone())?;
+
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(&cert_path, certified.cert.pem())?;
+    std::fs::write(&key_path, certified.signing_key.serialize_pem())?;
+    info!(
+        "🔐 Generated self-signed certificate for {} in {}",
+        subject_alt_names.join(", "),
+        dir.display()
+    );
+
+    Ok((cert_path, key_path))
+}
+
+/// Serves plain HTTP on `addr`, permanently redirecting every request to the
+/// same host and path over HTTPS on `https_port`, except the ACME CA's fetches
+/// of `challenges`.
+pub async fn serve_redirect(
+    addr: SocketAddr,
+    https_port: u16,
+    challenges: Arc<Challenges>,
+) -> std::io::Result<()> {
+    let app = axum::Router::new()
+        .route(
+            "/.well-known/acme-challenge/{token}",
+            axum::routing::get(move |UrlPath(token): UrlPath<String>| async move {
+                challenges
+                    .get(&token)
+                    .ok_or(StatusCode::NOT_FOUND)
+                    .into_response()
+            }),
+        )
+        .fallback(move |headers: HeaderMap, uri: Uri| async move {
+            redirect(&headers, &uri, https_port)
+        });
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("↪️ Redirecting http://{} to HTTPS", addr);
+    axum::serve(listener, app).await
+}
+
+fn redirect(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Response {
+    let Some(host) = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())
+    else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let location = match https_port {
+        443 => format!("https://{}{}", host.host(), path),
+        port => format!("https://{}:{}{}", host.host(), port, path),
+    };
+    Redirect::permanent(&location).into_response()
+}
@@ -0,0 +1,352 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use ring::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    auth::{Principal, Role},
+    cli::TokenCommand,
+};
+
+// Random bytes in a generated token
+const TOKEN_BYTES: usize = 32;
+// Characters of a token id
+const ID_LEN: usize = 12;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TokenFileError {
+    #[error("failed to access token file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid token file: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("no token with id '{0}'")]
+    UnknownId(String),
+    #[error("failed to generate a token")]
+    Random,
+}
+
+/// A token as kept in `--token-file`: its SHA-256 hash and what it grants,
+/// never the token itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredToken {
+    /// Names the token in listings, logs and `token revoke`.
+    pub id: String,
+    pub role: Role,
+    #[serde(default)]
+    pub relay_only: bool,
+    #[serde(default)]
+    pub substream_only: bool,
+    #[serde(default)]
+    pub av1: bool,
+    /// Hex SHA-256 of the token.
+    pub hash: String,
+    /// Unix time the token was created.
+    pub created_at: u64,
+    /// Unix time after which the token is no longer accepted.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+impl StoredToken {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// The tokens of `--token-file`, read again whenever the file changes, so
+/// `token create` and `token revoke` apply to a running gateway.
+pub struct TokenFile {
+    path: PathBuf,
+    // The tokens as of the file's modification time
+    cache: Mutex<Option<(SystemTime, Vec<StoredToken>)>>,
+}
+
+impl TokenFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            cache: Mutex::new(None),
+        }
+    }
+
+    /// The caller `token` stands for, if the file holds it and it has not
+    /// expired.
+    pub fn authenticate(&self, token: &str) -> Option<Principal> {
+        let modified = match std::fs::metadata(&self.path).and_then(|meta| meta.modified()) {
+            Ok(modified) => modified,
+            // No file yet means no tokens yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Failed to read {}: {}", self.path.display(), e);
+                return None;
+            }
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.as_ref().is_none_or(|(at, _)| *at != modified) {
+            match load(&self.path) {
+                Ok(tokens) => *cache = Some((modified, tokens)),
+                // Keep the last good tokens rather than locking everyone out
+                Err(e) => warn!("Failed to load {}: {}", self.path.display(), e),
+            }
+        }
+
+        let hash = hash(token);
+        let stored = cache
+            .as_ref()?
+            .1
+            .iter()
+            .find(|stored| stored.hash == hash && !stored.is_expired(unix_time()))?;
+        Some(Principal {
+            id: format!("token-file:{}", stored.id),
+            role: stored.role,
+            sources: None,
+            relay_only: stored.relay_only,
+            substream_only: stored.substream_only,
+            av1: stored.av1,
+        })
+    }
+}
+
+/// Carries out `rtsp-to-webrtc token ...` on the file at `path`, printing the
+/// outcome.
+pub fn run(command: TokenCommand, path: &Path) -> Result<(), TokenFileError> {
+    let mut tokens = load(path)?;
+    match command {
+        TokenCommand::Create {
+            role,
+            relay_only,
+            substream_only,
+            av1,
+            expires_in,
+            note,
+        } => {
+            let mut secret = [0u8; TOKEN_BYTES];
+            SystemRandom::new()
+                .fill(&mut secret)
+                .map_err(|_| TokenFileError::Random)?;
+            let token = BASE64_URL_SAFE_NO_PAD.encode(secret);
+            let now = unix_time();
+            let stored = StoredToken {
+                id: uuid::Uuid::new_v4().simple().to_string()[..ID_LEN].to_owned(),
+                role,
+                relay_only,
+                substream_only,
+                av1,
+                hash: hash(&token),
+                created_at: now,
+                expires_at: expires_in.map(|seconds| now + seconds),
+                note,
+            };
+            eprintln!(
+                "Created token {}; it is shown only once, keep it safe:",
+                stored.id
+            );
+            println!("{}", token);
+            tokens.push(stored);
+        }
+        TokenCommand::Revoke { id } => {
+            let count = tokens.len();
+            tokens.retain(|stored| stored.id != id);
+            if tokens.len() == count {
+                return Err(TokenFileError::UnknownId(id));
+            }
+            eprintln!("Revoked token {}", id);
+        }
+        TokenCommand::List => {
+            let now = unix_time();
+            println!(
+                "{:<12}  {:<8}  {:<10}  {:<10}  NOTE",
+                "ID", "ROLE", "CREATED", "EXPIRES"
+            );
+            for stored in &tokens {
+                let mut role = format!("{:?}", stored.role).to_lowercase();
+                if stored.relay_only {
+                    role.push_str("+relay");
+                }
+                if stored.substream_only {
+                    role.push_str("+sub");
+                }
+                if stored.av1 {
+                    role.push_str("+av1");
+                }
+                let expires = match stored.expires_at {
+                    _ if stored.is_expired(now) => "expired".to_owned(),
+                    Some(expires_at) => expires_at.to_string(),
+                    None => "never".to_owned(),
+                };
+                println!(
+                    "{:<12}  {:<8}  {:<10}  {:<10}  {}",
+                    stored.id,
+                    role,
+                    stored.created_at,
+                    expires,
+                    stored.note.as_deref().unwrap_or_default()
+                );
+            }
+            return Ok(());
+        }
+    }
+    save(path, &tokens)
+}
+
+fn load(path: &Path) -> Result<Vec<StoredToken>, TokenFileError> {
+    match std::fs::read(path) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Written next to the file and renamed over it, so a running gateway never
+// reads half a file
+fn save(path: &Path, tokens: &[StoredToken]) -> Result<(), TokenFileError> {
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, serde_json::to_vec_pretty(tokens)?)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Hex SHA-256 of `token`, which is all that is kept of it.
+pub fn hash(token: &str) -> String {
+    digest::digest(&digest::SHA256, token.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, time::Duration};
+
+    use super::*;
+
+    fn stored(id: &str, token: &str, expires_at: Option<u64>) -> StoredToken {
+        StoredToken {
+            id: id.to_owned(),
+            role: Role::Viewer,
+            relay_only: true,
+            substream_only: false,
+            av1: false,
+            hash: hash(token),
+            created_at: 0,
+            expires_at,
+            note: None,
+        }
+    }
+
+    // Writes `tokens` to `path` and dates the file `age` seconds after the
+    // epoch, so the cache sees a change however fast the test runs
+    fn write(path: &Path, tokens: &[StoredToken], age: u64) {
+        save(path, tokens).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(age))
+            .unwrap();
+    }
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("tokens-{}-{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn hashes_with_sha256() {
+        assert_eq!(
+            hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn authenticates_stored_tokens() {
+        let path = path("authenticate");
+        let token_file = TokenFile::new(path.clone());
+        assert!(token_file.authenticate("watch").is_none());
+
+        write(
+            &path,
+            &[
+                stored("a1", "watch", None),
+                stored("b2", "stale", Some(unix_time() - 1)),
+            ],
+            1,
+        );
+        let principal = token_file.authenticate("watch").unwrap();
+        assert_eq!(principal.id, "token-file:a1");
+        assert_eq!(principal.role, Role::Viewer);
+        assert!(principal.relay_only);
+        assert!(token_file.authenticate("stale").is_none());
+        assert!(token_file.authenticate("guess").is_none());
+
+        assert!(!path.with_extension("tmp").exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reloads_when_the_file_changes() {
+        let path = path("reload");
+        let token_file = TokenFile::new(path.clone());
+        write(&path, &[stored("a1", "watch", None)], 1);
+        assert!(token_file.authenticate("watch").is_some());
+
+        write(&path, &[stored("b2", "other", None)], 2);
+        assert!(token_file.authenticate("watch").is_none());
+        assert!(token_file.authenticate("other").is_some());
+
+        // A broken file keeps the tokens it last held
+        std::fs::write(&path, "[{").unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(3))
+            .unwrap();
+        assert!(token_file.authenticate("other").is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn revokes_by_id() {
+        let path = path("revoke");
+        write(
+            &path,
+            &[stored("a1", "watch", None), stored("b2", "other", None)],
+            1,
+        );
+        assert!(matches!(
+            run(TokenCommand::Revoke { id: "zz".to_owned() }, &path),
+            Err(TokenFileError::UnknownId(id)) if id == "zz"
+        ));
+        run(
+            TokenCommand::Revoke {
+                id: "a1".to_owned(),
+            },
+            &path,
+        )
+        .unwrap();
+        let ids: Vec<String> = load(&path).unwrap().into_iter().map(|t| t.id).collect();
+        assert_eq!(ids, ["b2"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
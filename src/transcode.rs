@@ -0,0 +1,565 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    pin::pin,
+    sync::{Arc, Weak, mpsc},
+    task::{Context, Waker},
+};
+
+use bytes::{Bytes, BytesMut};
+use ffmpeg_next as ffmpeg;
+use retina::{
+    ConnectionContext, StreamContext,
+    codec::{CodecItem, Depacketizer},
+    rtp::ReceivedPacket,
+};
+use tokio::sync::{Notify, broadcast};
+use tracing::{debug, info, warn};
+use webrtc::{
+    api::media_engine::MIME_TYPE_H264,
+    rtp::{
+        codecs::h264::{H264Packet, H264Payloader},
+        header::Header,
+        packet::Packet,
+        packetizer::{Depacketizer as _, Payloader},
+    },
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+};
+
+use crate::fanout::FanoutTrack;
+
+// H.264 encoders tried in order, with the options that make each fit for live
+// video
+const ENCODERS: &[(&str, &[(&str, &str)])] = &[
+    (
+        "libx264",
+        &[("preset", "veryfast"), ("tune", "zerolatency")],
+    ),
+    ("libopenh264", &[]),
+];
+const CLOCK_RATE: u32 = 90_000;
+// Pictures between keyframes; viewers joining in between ask for one
+const KEYFRAME_INTERVAL: u32 = 120;
+// Payload size of the H.264 RTP packets, leaving room for SRTP and extensions
+const MTU: usize = 1200;
+// JPEG frames waiting to be transcoded; past this, packets are dropped
+const MJPEG_QUEUE: usize = 256;
+// Watermark glyphs, 5 pixels wide and 7 high, one row per byte: 0-9 then A-F
+const GLYPHS: [[u8; 7]; 16] = [
+    [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+    [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+    [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+    [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+    [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+    [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+    [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+    [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+    [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+    [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+    [0x0e, 0x11, 0x11, 0x11, 0x1f, 0x11, 0x11],
+    [0x1e, 0x11, 0x11, 0x1e, 0x11, 0x11, 0x1e],
+    [0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e],
+    [0x1c, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1c],
+    [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f],
+    [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x10],
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum TranscodeError {
+    #[error("ffmpeg failed: {0}")]
+    Ffmpeg(#[from] ffmpeg::Error),
+    #[error("ffmpeg was built without an H.264 encoder (libx264 or libopenh264)")]
+    NoEncoder,
+    #[error("failed to depacketize JPEG: {0}")]
+    Depacketize(String),
+    #[error("failed to packetize H.264: {0}")]
+    Rtp(#[from] webrtc::rtp::Error),
+}
+
+/// Transcodes an MJPEG camera's video to H.264 for its viewers, on a thread of
+/// its own. The transcode stops when this is dropped.
+pub struct MjpegTranscoder {
+    packets: mpsc::SyncSender<ReceivedPacket>,
+}
+
+impl MjpegTranscoder {
+    /// Sends the transcode at `bitrate_kbps` on `track`, with keyframes where
+    /// viewers ask for them on `keyframe_requests`.
+    pub fn spawn(
+        name: &str,
+        track: Arc<FanoutTrack>,
+        keyframe_requests: Arc<Notify>,
+        bitrate_kbps: u32,
+    ) -> Self {
+        let (packets, rx) = mpsc::sync_channel(MJPEG_QUEUE);
+        let name = name.to_owned();
+        info!("🎞️ [{}] Transcoding MJPEG to H.264", name);
+        std::thread::spawn(move || {
+            match run_mjpeg(rx, &track, &keyframe_requests, bitrate_kbps as usize * 1000) {
+                Ok(()) => info!("🎞️ [{}] Stopped transcoding MJPEG", name),
+                Err(e) => warn!("[{}] MJPEG transcode failed: {}", name, e),
+            }
+        });
+        Self { packets }
+    }
+
+    /// Hands over a packet of the camera's MJPEG stream. `false` if it was
+    /// dropped because the transcode fell behind.
+    pub fn push(&self, rtp: ReceivedPacket) -> bool {
+        self.packets.try_send(rtp).is_ok()
+    }
+}
+
+/// Reassembles the JPEG pictures of `packets` (RFC 2435), decodes them and
+/// sends them re-encoded as H.264 on `track`, until the source ends.
+fn run_mjpeg(
+    packets: mpsc::Receiver<ReceivedPacket>,
+    track: &FanoutTrack,
+    keyframe_requests: &Notify,
+    bitrate: usize,
+) -> Result<(), TranscodeError> {
+    ffmpeg::init()?;
+    let codec =
+        ffmpeg::decoder::find(ffmpeg::codec::Id::MJPEG).ok_or(ffmpeg::Error::DecoderNotFound)?;
+    let mut decoder = ffmpeg::codec::Context::new_with_codec(codec)
+        .decoder()
+        .video()?;
+    let mut depacketizer = Depacketizer::new("video", "jpeg", CLOCK_RATE, None, None)
+        .map_err(TranscodeError::Depacketize)?;
+    // The JPEG depacketizer doesn't look at the contexts, which only serve
+    // in errors of other codecs
+    let (conn_ctx, stream_ctx) = (ConnectionContext::dummy(), StreamContext::dummy());
+    // Opened again when the picture size changes
+    let mut encoder: Option<H264Encoder> = None;
+    let mut decoded = ffmpeg::frame::Video::empty();
+
+    for rtp in packets {
+        if let Err(e) = depacketizer.push(rtp) {
+            debug!("Dropping a JPEG packet: {}", e);
+            continue;
+        }
+        loop {
+            let picture = match depacketizer.pull(&conn_ctx, &stream_ctx) {
+                Ok(Some(CodecItem::VideoFrame(picture))) => picture,
+                Ok(Some(_)) => continue,
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("Dropping a JPEG picture: {}", e);
+                    break;
+                }
+            };
+            let mut input = ffmpeg::Packet::copy(picture.data());
+            input.set_pts(Some(picture.timestamp().elapsed()));
+            if let Err(e) = decoder.send_packet(&input) {
+                debug!("JPEG decoder rejected a picture: {}", e);
+                continue;
+            }
+
+            while decoder.receive_frame(&mut decoded).is_ok() {
+                if encoder
+                    .as_ref()
+                    .is_none_or(|encoder| !encoder.fits(&decoded))
+                {
+                    encoder = Some(H264Encoder::open(&decoded, None, bitrate)?);
+                }
+                let Some(encoder) = encoder.as_mut() else {
+                    continue;
+                };
+                for pkt in encoder.encode(&decoded, keyframe_requested(keyframe_requests))? {
+                    track.send(pkt);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The video of a source re-encoded at another size or bitrate, e.g. for
+/// recordings that take less space than the live stream. Only H.264 is
+/// decoded; other video is passed through with a warning.
+///
+/// The transcode starts at the source's next keyframe, puts keyframes where
+/// the source does and where `keyframe_requests` asks for them, and ends with
+/// the source.
+pub fn reencode(
+    name: &str,
+    source: &Arc<FanoutTrack>,
+    height: Option<u32>,
+    bitrate_kbps: u32,
+    keyframe_requests: Arc<Notify>,
+) -> Arc<FanoutTrack> {
+    let mime_type = source.codec().mime_type;
+    if !mime_type.eq_ignore_ascii_case(MIME_TYPE_H264) {
+        warn!(
+            "[{}] Can't transcode {} video, only H.264; keeping its quality",
+            name, mime_type
+        );
+        return source.clone();
+    }
+    let track = Arc::new(source.transcoded(RTCRtpCodecCapability {
+        mime_type: MIME_TYPE_H264.to_owned(),
+        clock_rate: CLOCK_RATE,
+        ..Default::default()
+    }));
+    info!(
+        "🎞️ [{}] Transcoding H.264 to {} at {} kbit/s",
+        name,
+        height.map_or("the source's size".to_owned(), |height| format!(
+            "{}p",
+            height
+        )),
+        bitrate_kbps
+    );
+    let (name, packets, output) = (name.to_owned(), source.listen(), track.clone());
+    std::thread::spawn(move || {
+        let encoding = Encoding {
+            height,
+            bitrate: bitrate_kbps as usize * 1000,
+            mark: None,
+            keyframe_requests: Some(&keyframe_requests),
+        };
+        // The recording only listens to the track, so the thread keeps it
+        match run_reencode(packets, &Arc::downgrade(&output), encoding) {
+            Ok(()) => info!("🎞️ [{}] Stopped transcoding H.264", name),
+            Err(e) => warn!("[{}] H.264 transcode failed: {}", name, e),
+        }
+    });
+    track
+}
+
+/// The video of a source transcoded for a single viewer, with a code derived
+/// from its session id drawn in the top-left corner so a leaked recording of
+/// it can be traced back to the session (`--watermark`). `None` for video
+/// other than H.264, which can't be decoded.
+///
+/// The transcode starts at the source's next keyframe, puts keyframes where
+/// the source does and where the viewer asks for them on the returned
+/// `Notify`, and ends once the viewer drops the track.
+pub fn watermark(
+    name: &str,
+    source: &FanoutTrack,
+    session_id: &str,
+    bitrate_kbps: u32,
+) -> Option<(Arc<FanoutTrack>, Arc<Notify>)> {
+    let mime_type = source.codec().mime_type;
+    if !mime_type.eq_ignore_ascii_case(MIME_TYPE_H264) {
+        warn!(
+            "[{}] Can't watermark {} video, only H.264; sending it unmarked",
+            name, mime_type
+        );
+        return None;
+    }
+    let track = Arc::new(source.transcoded(RTCRtpCodecCapability {
+        mime_type: MIME_TYPE_H264.to_owned(),
+        clock_rate: CLOCK_RATE,
+        ..Default::default()
+    }));
+    let keyframe_requests = Arc::new(Notify::new());
+    let code = watermark_code(session_id);
+    info!(
+        "🔏 [{}] Watermarking session {} with {}",
+        name, session_id, code
+    );
+    let (name, packets, output, requests) = (
+        name.to_owned(),
+        source.listen(),
+        Arc::downgrade(&track),
+        keyframe_requests.clone(),
+    );
+    std::thread::spawn(move || {
+        let encoding = Encoding {
+            height: None,
+            bitrate: bitrate_kbps as usize * 1000,
+            mark: Some(&code),
+            keyframe_requests: Some(&requests),
+        };
+        match run_reencode(packets, &output, encoding) {
+            Ok(()) => debug!("[{}] Stopped watermarking {}", name, code),
+            Err(e) => warn!("[{}] Watermark transcode {} failed: {}", name, code, e),
+        }
+    });
+    Some((track, keyframe_requests))
+}
+
+/// The 8 hex digits drawn on a session's video. The log line pairing them with
+/// the session id is what traces footage back, so they only need to be stable
+/// within a run.
+fn watermark_code(session_id: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    session_id.hash(&mut hasher);
+    format!("{:08X}", hasher.finish() as u32)
+}
+
+/// How [`run_reencode`] encodes what it decodes.
+struct Encoding<'a> {
+    height: Option<u32>,
+    bitrate: usize,
+    mark: Option<&'a str>,
+    /// Keyframes are also put where these ask for them, besides where the
+    /// source has them.
+    keyframe_requests: Option<&'a Notify>,
+}
+
+/// Decodes the H.264 `packets` and sends them re-encoded on `track`, until the
+/// source ends or the track is dropped.
+fn run_reencode(
+    mut packets: broadcast::Receiver<Packet>,
+    track: &Weak<FanoutTrack>,
+    encoding: Encoding,
+) -> Result<(), TranscodeError> {
+    ffmpeg::init()?;
+    let codec =
+        ffmpeg::decoder::find(ffmpeg::codec::Id::H264).ok_or(ffmpeg::Error::DecoderNotFound)?;
+    let mut decoder = ffmpeg::codec::Context::new_with_codec(codec)
+        .decoder()
+        .video()?;
+    // Opened again when the picture size changes
+    let mut encoder: Option<H264Encoder> = None;
+
+    let mut depacketizer = H264Packet::default();
+    let mut access_unit = BytesMut::new();
+    let mut timestamps = Timestamps::default();
+    let mut decoded = ffmpeg::frame::Video::empty();
+
+    loop {
+        let pkt = match packets.blocking_recv() {
+            Ok(pkt) => pkt,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                debug!("H.264 transcode lagged, {} packets skipped", skipped);
+                access_unit.clear();
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        let Some(track) = track.upgrade() else {
+            return Ok(());
+        };
+
+        match depacketizer.depacketize(&pkt.payload) {
+            Ok(nalus) => access_unit.extend_from_slice(&nalus),
+            Err(e) => {
+                debug!("Dropping an H.264 access unit: {}", e);
+                access_unit.clear();
+                continue;
+            }
+        }
+        if !pkt.header.marker || access_unit.is_empty() {
+            continue;
+        }
+
+        let mut input = ffmpeg::Packet::copy(&access_unit);
+        access_unit.clear();
+        input.set_pts(Some(timestamps.unwrap(pkt.header.timestamp)));
+        // A damaged access unit; the decoder recovers at the next keyframe
+        if let Err(e) = decoder.send_packet(&input) {
+            debug!("H.264 decoder rejected an access unit: {}", e);
+            continue;
+        }
+
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            if encoder
+                .as_ref()
+                .is_none_or(|encoder| !encoder.fits(&decoded))
+            {
+                let opened = H264Encoder::open(&decoded, encoding.height, encoding.bitrate)?;
+                encoder = Some(match encoding.mark {
+                    Some(mark) => opened.marked(mark),
+                    None => opened,
+                });
+            }
+            let Some(encoder) = encoder.as_mut() else {
+                continue;
+            };
+            let keyframe =
+                decoded.is_key() || encoding.keyframe_requests.is_some_and(keyframe_requested);
+            for pkt in encoder.encode(&decoded, keyframe)? {
+                track.send(pkt);
+            }
+        }
+    }
+}
+
+/// An H.264 encoder with the converter from decoded pictures to its size and
+/// the planar 4:2:0 it takes, putting out RTP packets.
+pub struct H264Encoder {
+    encoder: ffmpeg::encoder::Video,
+    scaler: ffmpeg::software::scaling::Context,
+    scaled: ffmpeg::frame::Video,
+    encoded: ffmpeg::Packet,
+    payloader: H264Payloader,
+    sequence_number: u16,
+    mark: Option<String>,
+}
+
+impl H264Encoder {
+    /// An encoder for pictures like `frame`, scaled to `height` if given, at
+    /// `bitrate` bits per second. Frames' timestamps are on the 90 kHz RTP
+    /// clock.
+    pub fn open(
+        frame: &ffmpeg::frame::Video,
+        height: Option<u32>,
+        bitrate: usize,
+    ) -> Result<Self, TranscodeError> {
+        let format = ffmpeg::format::Pixel::YUV420P;
+        // Even sizes, as 4:2:0 needs
+        let (width, height) = match height {
+            Some(height) => ((frame.width() * height / frame.height()) & !1, height & !1),
+            None => (frame.width() & !1, frame.height() & !1),
+        };
+        let scaler = ffmpeg::software::scaling::Context::get(
+            frame.format(),
+            frame.width(),
+            frame.height(),
+            format,
+            width,
+            height,
+            ffmpeg::software::scaling::Flags::BILINEAR,
+        )?;
+
+        for (name, settings) in ENCODERS {
+            let Some(codec) = ffmpeg::encoder::find_by_name(name) else {
+                continue;
+            };
+            let mut encoder = ffmpeg::codec::Context::new_with_codec(codec)
+                .encoder()
+                .video()?;
+            encoder.set_width(width);
+            encoder.set_height(height);
+            encoder.set_format(format);
+            encoder.set_time_base((1, CLOCK_RATE as i32));
+            encoder.set_bit_rate(bitrate);
+            encoder.set_gop(KEYFRAME_INTERVAL);
+            encoder.set_max_b_frames(0);
+            let mut options = ffmpeg::Dictionary::new();
+            for (option, value) in *settings {
+                options.set(option, value);
+            }
+            debug!(
+                "Encoding {}x{} H.264 at {} kbit/s with {}",
+                width,
+                height,
+                bitrate / 1000,
+                name
+            );
+            return Ok(Self {
+                encoder: encoder.open_with(options)?,
+                scaler,
+                scaled: ffmpeg::frame::Video::empty(),
+                encoded: ffmpeg::Packet::empty(),
+                payloader: H264Payloader::default(),
+                sequence_number: 0,
+                mark: None,
+            });
+        }
+        Err(TranscodeError::NoEncoder)
+    }
+
+    /// Draws `mark`, hex digits, on every picture before encoding it.
+    pub fn marked(mut self, mark: &str) -> Self {
+        self.mark = Some(mark.to_owned());
+        self
+    }
+
+    /// Whether `frame` is what the encoder was opened for.
+    pub fn fits(&self, frame: &ffmpeg::frame::Video) -> bool {
+        let input = self.scaler.input();
+        (input.format, input.width, input.height) == (frame.format(), frame.width(), frame.height())
+    }
+
+    /// Encodes `frame`, as a keyframe if asked to, returning the RTP packets
+    /// of what the encoder put out. Keyframes carry their SPS and PPS.
+    pub fn encode(
+        &mut self,
+        frame: &ffmpeg::frame::Video,
+        keyframe: bool,
+    ) -> Result<Vec<Packet>, TranscodeError> {
+        self.scaler.run(frame, &mut self.scaled)?;
+        if let Some(mark) = &self.mark {
+            draw_mark(&mut self.scaled, mark);
+        }
+        self.scaled.set_pts(frame.pts());
+        self.scaled.set_kind(if keyframe {
+            ffmpeg::picture::Type::I
+        } else {
+            ffmpeg::picture::Type::None
+        });
+        self.encoder.send_frame(&self.scaled)?;
+
+        let mut packets = Vec::new();
+        while self.encoder.receive_packet(&mut self.encoded).is_ok() {
+            let Some(data) = self.encoded.data() else {
+                continue;
+            };
+            let timestamp = self.encoded.pts().unwrap_or_default() as u32;
+            let payloads = self.payloader.payload(MTU, &Bytes::copy_from_slice(data))?;
+            let last = payloads.len().saturating_sub(1);
+            for (index, payload) in payloads.into_iter().enumerate() {
+                packets.push(Packet {
+                    header: Header {
+                        version: 2,
+                        marker: index == last,
+                        sequence_number: self.sequence_number,
+                        timestamp,
+                        ..Default::default()
+                    },
+                    payload,
+                });
+                self.sequence_number = self.sequence_number.wrapping_add(1);
+            }
+        }
+        Ok(packets)
+    }
+}
+
+/// Draws `mark` white on black in the top-left corner of `frame`'s luma plane,
+/// at a size that stays legible once re-encoded: glyphs about a 40th of the
+/// picture high, and at least 7 pixels. Anything but hex digits is left blank.
+fn draw_mark(frame: &mut ffmpeg::frame::Video, mark: &str) {
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+    let stride = frame.stride(0);
+    let scale = (height / 40 / 7).max(1);
+    // A glyph cell is 6 by 9 pixels, with spacing and a border
+    let (box_width, box_height) = ((mark.len() * 6 + 1) * scale, 9 * scale);
+    let luma = frame.data_mut(0);
+    for y in 0..box_height.min(height) {
+        for x in 0..box_width.min(width) {
+            let (column, row) = (x / scale, y / scale);
+            let lit = column % 6 != 0
+                && (1..8).contains(&row)
+                && mark
+                    .chars()
+                    .nth(column / 6)
+                    .and_then(|c| c.to_digit(16))
+                    .is_some_and(|digit| {
+                        GLYPHS[digit as usize][row - 1] & (0x10 >> (column % 6 - 1)) != 0
+                    });
+            luma[y * stride + x] = if lit { 235 } else { 16 };
+        }
+    }
+}
+
+/// RTP timestamps extended to 64 bits, so they keep growing across wraps as
+/// the encoder wants.
+#[derive(Default)]
+struct Timestamps {
+    last: Option<u32>,
+    extended: i64,
+}
+
+impl Timestamps {
+    fn unwrap(&mut self, timestamp: u32) -> i64 {
+        if let Some(last) = self.last {
+            self.extended += timestamp.wrapping_sub(last) as i32 as i64;
+        }
+        self.last = Some(timestamp);
+        self.extended
+    }
+}
+
+/// Whether a viewer asked for a keyframe since the last frame, without
+/// waiting for one to.
+fn keyframe_requested(keyframe_requests: &Notify) -> bool {
+    let requested = pin!(keyframe_requests.notified());
+    requested
+        .poll(&mut Context::from_waker(Waker::noop()))
+        .is_ready()
+}
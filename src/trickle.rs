@@ -0,0 +1,111 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use tracing::warn;
+use webrtc::{ice_transport::ice_candidate::RTCIceCandidate, peer_connection::RTCPeerConnection};
+
+/// The gateway's own ICE candidates for a session whose answer went out
+/// before gathering finished, waiting for the viewer to collect them.
+#[derive(Debug, Default)]
+pub struct LocalCandidates {
+    state: Mutex<Gathered>,
+}
+
+#[derive(Debug, Default)]
+struct Gathered {
+    candidates: Vec<String>,
+    sent: HashSet<String>,
+    complete: bool,
+    ended: bool,
+}
+
+impl LocalCandidates {
+    /// Collects the candidates `pc` gathers from now on.
+    pub fn gather(pc: &RTCPeerConnection) -> Arc<Self> {
+        let gathered = Arc::new(Self::default());
+        let sink = gathered.clone();
+        pc.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+            match candidate.map(|candidate| candidate.to_json()) {
+                Some(Ok(init)) => sink.found(init.candidate),
+                Some(Err(e)) => warn!("Failed to encode a local ICE candidate: {}", e),
+                None => sink.state.lock().unwrap().complete = true,
+            }
+            Box::pin(async {})
+        }));
+        gathered
+    }
+
+    fn found(&self, candidate: String) {
+        self.state.lock().unwrap().candidates.push(candidate);
+    }
+
+    /// Marks the candidates already in `sdp`, the answer, as delivered.
+    pub fn sent_in(&self, sdp: &str) {
+        let mut state = self.state.lock().unwrap();
+        for line in sdp.lines() {
+            if let Some(candidate) = line.strip_prefix("a=")
+                && candidate.starts_with("candidate:")
+            {
+                state.sent.insert(candidate.to_owned());
+            }
+            if line == "a=end-of-candidates" {
+                state.ended = true;
+            }
+        }
+    }
+
+    /// The candidates not delivered yet, and whether gathering has finished
+    /// since the last call; `None` when there is nothing new to tell.
+    pub fn take(&self) -> Option<(Vec<String>, bool)> {
+        let mut state = self.state.lock().unwrap();
+        let Gathered {
+            candidates, sent, ..
+        } = &mut *state;
+        let new: Vec<String> = candidates
+            .iter()
+            .filter(|candidate| sent.insert((*candidate).clone()))
+            .cloned()
+            .collect();
+        let ends = state.complete && !state.ended;
+        state.ended |= ends;
+        (!new.is_empty() || ends).then_some((new, ends))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "candidate:1 1 udp 2130706431 192.0.2.1 5000 typ host";
+    const SRFLX: &str =
+        "candidate:2 1 udp 1694498815 198.51.100.7 5000 typ srflx raddr 0.0.0.0 rport 5000";
+
+    #[test]
+    fn hands_out_each_candidate_once() {
+        let gathered = LocalCandidates::default();
+        assert_eq!(gathered.take(), None);
+
+        gathered.found(HOST.to_owned());
+        assert_eq!(gathered.take(), Some((vec![HOST.to_owned()], false)));
+        assert_eq!(gathered.take(), None);
+
+        gathered.found(SRFLX.to_owned());
+        gathered.state.lock().unwrap().complete = true;
+        assert_eq!(gathered.take(), Some((vec![SRFLX.to_owned()], true)));
+        assert_eq!(gathered.take(), None);
+    }
+
+    #[test]
+    fn skips_what_the_answer_carried() {
+        let gathered = LocalCandidates::default();
+        gathered.found(HOST.to_owned());
+        gathered.sent_in(&format!("v=0\r\na={}\r\n", HOST));
+        assert_eq!(gathered.take(), None);
+
+        gathered.state.lock().unwrap().complete = true;
+        gathered.sent_in("v=0\r\na=end-of-candidates\r\n");
+        assert_eq!(gathered.take(), None);
+    }
+}
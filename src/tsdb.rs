@@ -0,0 +1,180 @@
+use std::{collections::HashMap, fmt::Write, time::Duration};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    state::AppState,
+    stats::{FrameDropCounts, Snapshot, TrackCounts},
+    timeline::Quality,
+};
+
+// Writes that take longer than this are abandoned; the next interval retries
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where `--stats-sink` rows go: an InfluxDB line protocol write endpoint.
+pub struct StatsSink {
+    pub url: url::Url,
+    /// Sent as `Authorization: Token <token>`.
+    pub token: Option<String>,
+    pub interval: Duration,
+}
+
+/// Writes a `gateway_source` row per source and a `gateway_session` row per
+/// viewer session to `sink` every interval.
+pub fn spawn_stats_sink(state: AppState, sink: StatsSink) {
+    if sink.interval.is_zero() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let client = reqwest::Client::builder()
+            .timeout(WRITE_TIMEOUT)
+            .build()
+            .expect("failed to build stats sink HTTP client");
+        info!("📈 Writing stats to {}", sink.url);
+
+        let mut interval = tokio::time::interval(sink.interval);
+        // Bytes each session had sent at the previous write, for its bitrate
+        let mut bytes_sent: HashMap<String, u64> = HashMap::new();
+        loop {
+            interval.tick().await;
+
+            let snapshot = Snapshot::take(&state);
+            let timestamp = snapshot.taken_at * 1_000_000;
+            let mut body = String::new();
+            for source in &snapshot.sources {
+                let viewers = snapshot
+                    .sessions
+                    .iter()
+                    .filter(|session| session.source == source.info.name)
+                    .count();
+                let mut line = Line::new("gateway_source");
+                line.tag("source", &source.info.name);
+                for (key, value) in &source.info.tags {
+                    if key != "source" {
+                        line.tag(key, value);
+                    }
+                }
+                for (track, counts) in [("video", &source.video), ("audio", &source.audio)] {
+                    for (name, value) in TrackCounts::NAMES.into_iter().zip(counts.values()) {
+                        line.int(&format!("{}_{}", track, name), value);
+                    }
+                }
+                let dropped = source.video_frames_dropped.values();
+                for (reason, value) in FrameDropCounts::REASONS.into_iter().zip(dropped) {
+                    line.int(&format!("video_frames_dropped_{}", reason), value);
+                }
+                line.int("viewer_failures", source.viewer_failures);
+                line.int("reconnects", source.reconnects);
+                line.int("viewers", viewers as u64);
+                line.finish(&mut body, timestamp);
+            }
+
+            let mut sent_now = HashMap::new();
+            for session in &snapshot.sessions {
+                let Some(stored) = state.sessions.get(&session.id) else {
+                    continue;
+                };
+                let reports = stored.pc.get_stats().await.reports;
+                let (mut quality, sent) = Quality::from_reports(&reports);
+                let before = bytes_sent.get(&session.id).copied().unwrap_or(sent);
+                quality.bitrate_kbps =
+                    sent.saturating_sub(before) * 8 / sink.interval.as_millis().max(1) as u64;
+                sent_now.insert(session.id.clone(), sent);
+
+                let mut line = Line::new("gateway_session");
+                line.tag("source", &session.source);
+                line.tag("session", &session.id);
+                line.int("bitrate_kbps", quality.bitrate_kbps);
+                line.int("packets_sent", quality.packets_sent);
+                line.int("packets_lost", quality.packets_lost);
+                line.float("fraction_lost", quality.fraction_lost);
+                if let Some(round_trip_ms) = quality.round_trip_ms {
+                    line.float("round_trip_ms", round_trip_ms);
+                }
+                line.int("nacks", quality.nacks);
+                line.int("keyframe_requests", quality.keyframe_requests);
+                line.int("uptime_secs", stored.uptime.as_secs());
+                line.finish(&mut body, timestamp);
+            }
+            bytes_sent = sent_now;
+
+            if body.is_empty() {
+                continue;
+            }
+            let mut request = client
+                .post(sink.url.clone())
+                .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(body);
+            if let Some(token) = &sink.token {
+                request =
+                    request.header(reqwest::header::AUTHORIZATION, format!("Token {}", token));
+            }
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    debug!("Stats sink accepted {} sources", snapshot.sources.len());
+                }
+                Ok(response) => {
+                    warn!("Stats sink {} returned {}", sink.url, response.status());
+                }
+                Err(e) => {
+                    warn!("Stats sink {} failed: {}", sink.url, e);
+                }
+            }
+        }
+    });
+}
+
+/// One row in the InfluxDB line protocol.
+struct Line {
+    head: String,
+    fields: Vec<String>,
+}
+
+impl Line {
+    fn new(measurement: &str) -> Self {
+        Self {
+            head: escape(measurement, false),
+            fields: Vec::new(),
+        }
+    }
+
+    fn tag(&mut self, key: &str, value: &str) {
+        // Empty tag values are not allowed
+        if !value.is_empty() {
+            let _ = write!(self.head, ",{}={}", escape(key, true), escape(value, true));
+        }
+    }
+
+    fn int(&mut self, key: &str, value: impl std::fmt::Display) {
+        self.fields.push(format!("{}={}i", key, value));
+    }
+
+    fn float(&mut self, key: &str, value: f64) {
+        if value.is_finite() {
+            self.fields.push(format!("{}={}", key, value));
+        }
+    }
+
+    fn finish(self, out: &mut String, timestamp: u64) {
+        let _ = writeln!(out, "{} {} {}", self.head, self.fields.join(","), timestamp);
+    }
+}
+
+// Measurements escape commas and spaces; tag keys and values also `=`
+fn escape(value: &str, tag: bool) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ',' | ' ' => escaped.push('\\'),
+            '=' if tag => escaped.push('\\'),
+            '\n' | '\r' => {
+                escaped.push_str("\\ ");
+                continue;
+            }
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
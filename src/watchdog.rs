@@ -0,0 +1,306 @@
+use std::{
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::{broadcast, watch},
+    time::Instant,
+};
+use tracing::{info, warn};
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+
+use crate::{events::Event, store::SessionStore};
+
+/// Records when packets were last seen on a stream.
+pub struct Activity {
+    epoch: Instant,
+    // Milliseconds since `epoch` at which the last packet arrived
+    last: AtomicU64,
+    // No packets are expected, e.g. while an on-demand source has no viewers
+    paused: AtomicBool,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            last: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    pub fn touch(&self) {
+        self.last
+            .store(self.epoch.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// While paused, the stream never counts as idle; resuming restarts the
+    /// clock.
+    pub fn set_paused(&self, paused: bool) {
+        if !paused {
+            self.touch();
+        }
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    /// Time since the last packet, or since creation if none arrived yet.
+    pub fn idle(&self) -> Duration {
+        if self.paused.load(Ordering::Relaxed) {
+            return Duration::ZERO;
+        }
+        let now = self.epoch.elapsed().as_millis() as u64;
+        Duration::from_millis(now.saturating_sub(self.last.load(Ordering::Relaxed)))
+    }
+}
+
+/// Counts down the grace an on-demand session gets after its last viewer
+/// left.
+pub struct IdleGrace {
+    grace: Duration,
+    // When the last viewer left a session that is still up
+    since: Option<Instant>,
+}
+
+impl IdleGrace {
+    pub fn new(grace: Duration) -> Self {
+        Self { grace, since: None }
+    }
+
+    pub fn grace(&self) -> Duration {
+        self.grace
+    }
+
+    /// Starts the countdown when the last viewer of a live session leaves,
+    /// and stops it when a viewer joins.
+    pub fn viewers_changed(&mut self, viewers: usize, live: bool) {
+        self.since = (viewers == 0 && live).then(Instant::now);
+    }
+
+    pub fn cancel(&mut self) {
+        self.since = None;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.since.is_some()
+    }
+
+    /// Resolves once the grace ran out, never while no countdown runs.
+    pub async fn expired(&self) {
+        match self.since {
+            Some(since) => tokio::time::sleep_until(since + self.grace).await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Emits `VideoFrozen`/`VideoResumed` when video stops and restarts flowing,
+/// until `ended` is set.
+pub fn spawn_freeze_watchdog(
+    video: Arc<Activity>,
+    timeout: Duration,
+    events: broadcast::Sender<Event>,
+    mut ended: watch::Receiver<bool>,
+) {
+    if timeout.is_zero() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(timeout.min(Duration::from_secs(1)));
+        let mut frozen_at: Option<Instant> = None;
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = ended.wait_for(|&ended| ended) => break,
+            }
+            let idle = video.idle();
+
+            match frozen_at {
+                None if idle >= timeout => {
+                    warn!("🧊 Video frozen, no frames for {:?}", idle);
+                    frozen_at = Some(Instant::now() - idle);
+                    let _ = events.send(Event::VideoFrozen {
+                        idle_ms: idle.as_millis() as u64,
+                    });
+                }
+                Some(since) if idle < timeout => {
+                    let frozen = since.elapsed().saturating_sub(idle);
+                    info!("🎞️ Video resumed after {:?}", frozen);
+                    frozen_at = None;
+                    let _ = events.send(Event::VideoResumed {
+                        frozen_ms: frozen.as_millis() as u64,
+                    });
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+/// Closes sessions whose client stopped sending keepalive heartbeats, for as
+/// long as the store is in use.
+pub fn spawn_session_reaper(sessions: &Arc<dyn SessionStore>, timeout: Duration) {
+    if timeout.is_zero() {
+        return;
+    }
+
+    let sessions = Arc::downgrade(sessions);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(timeout.min(Duration::from_secs(1)));
+
+        loop {
+            interval.tick().await;
+            let Some(sessions) = sessions.upgrade() else {
+                break;
+            };
+
+            for id in sessions.idle(timeout) {
+                if let Some(pc) = sessions.remove(&id) {
+                    let _ = pc.close().await;
+                    info!(
+                        "💤 Session {} missed its keepalive | Remaining: {}",
+                        &id[..8],
+                        sessions.len()
+                    );
+                }
+            }
+        }
+    });
+}
+
+/// Closes sessions whose connection is still `new` or `connecting` `timeout`
+/// after they were created, e.g. because the client went away before ICE got
+/// anywhere. Stops once none of the stores is in use anymore.
+pub fn spawn_connect_reaper(stores: &[Arc<dyn SessionStore>], timeout: Duration) {
+    if timeout.is_zero() {
+        return;
+    }
+
+    let stores: Vec<Weak<dyn SessionStore>> = stores.iter().map(Arc::downgrade).collect();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(timeout.min(Duration::from_secs(1)));
+
+        loop {
+            interval.tick().await;
+            let stores: Vec<_> = stores.iter().filter_map(Weak::upgrade).collect();
+            if stores.is_empty() {
+                break;
+            }
+
+            for sessions in &stores {
+                for info in sessions.list() {
+                    let stuck = sessions.get(&info.id).is_some_and(|session| {
+                        session.uptime >= timeout
+                            && matches!(
+                                session.pc.connection_state(),
+                                RTCPeerConnectionState::New | RTCPeerConnectionState::Connecting
+                            )
+                    });
+                    if !stuck {
+                        continue;
+                    }
+                    if let Some(pc) = sessions.remove(&info.id) {
+                        let _ = pc.close().await;
+                        info!(
+                            "⌛ Session {} never connected | Remaining: {}",
+                            &info.id[..8],
+                            sessions.len()
+                        );
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn freeze_watchdog_reports_freeze_and_resume() {
+        let video = Arc::new(Activity::new());
+        let (events, mut rx) = broadcast::channel(8);
+        let (ended_tx, ended) = watch::channel(false);
+        let start = Instant::now();
+        spawn_freeze_watchdog(video.clone(), Duration::from_secs(3), events, ended);
+
+        // Ticks every second, so the freeze shows on the tick at 3 s
+        let Event::VideoFrozen { idle_ms } = rx.recv().await.unwrap() else {
+            panic!("expected VideoFrozen");
+        };
+        assert_eq!(idle_ms, 3000);
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        video.touch();
+        let Event::VideoResumed { frozen_ms } = rx.recv().await.unwrap() else {
+            panic!("expected VideoResumed");
+        };
+        assert_eq!(frozen_ms, 5500);
+        assert_eq!(start.elapsed(), Duration::from_secs(6));
+
+        ended_tx.send_replace(true);
+        assert!(matches!(
+            rx.recv().await,
+            Err(broadcast::error::RecvError::Closed)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn freeze_watchdog_ignores_paused_video() {
+        let video = Arc::new(Activity::new());
+        video.set_paused(true);
+        let (events, mut rx) = broadcast::channel(8);
+        let (_ended_tx, ended) = watch::channel(false);
+        spawn_freeze_watchdog(video, Duration::from_secs(3), events, ended);
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(matches!(
+            rx.try_recv(),
+            Err(broadcast::error::TryRecvError::Empty)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_grace_runs_out_after_last_viewer_left() {
+        let mut idle = IdleGrace::new(Duration::from_secs(30));
+        let start = Instant::now();
+        idle.viewers_changed(0, true);
+        assert!(idle.is_running());
+
+        idle.expired().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_grace_stops_when_viewer_joins() {
+        let mut idle = IdleGrace::new(Duration::from_secs(30));
+        idle.viewers_changed(0, true);
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        idle.viewers_changed(1, true);
+        assert!(!idle.is_running());
+        let expired = tokio::time::timeout(Duration::from_secs(3600), idle.expired()).await;
+        assert!(expired.is_err());
+
+        // The countdown starts over when the viewer leaves again
+        let start = Instant::now();
+        idle.viewers_changed(0, true);
+        idle.expired().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_grace_waits_for_no_session() {
+        let mut idle = IdleGrace::new(Duration::from_secs(30));
+        idle.viewers_changed(0, false);
+        assert!(!idle.is_running());
+        let expired = tokio::time::timeout(Duration::from_secs(3600), idle.expired()).await;
+        assert!(expired.is_err());
+    }
+}
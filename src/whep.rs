@@ -1,12 +1,25 @@
-use std::sync::Arc;
+use std::{
+    sync::{Arc, atomic::Ordering},
+    time::Duration,
+};
 
 use axum::{
-    extract::{FromRequest, State},
+    Extension,
+    extract::{FromRef, FromRequest, State},
     response::IntoResponse,
 };
+use base64::{Engine, prelude::BASE64_STANDARD};
+use serde::Deserialize;
+use tokio::sync::{Notify, broadcast, watch};
 use tracing::{debug, error, info, warn};
 use webrtc::{
-    peer_connection::sdp::session_description::RTCSessionDescription,
+    data_channel::RTCDataChannel,
+    ice_transport::ice_candidate::RTCIceCandidateInit,
+    peer_connection::{
+        RTCPeerConnection, configuration::RTCConfiguration,
+        policy::ice_transport_policy::RTCIceTransportPolicy,
+        sdp::session_description::RTCSessionDescription,
+    },
     rtcp::{
         goodbye::Goodbye,
         payload_feedbacks::{
@@ -19,22 +32,55 @@ use webrtc::{
             transport_layer_cc::TransportLayerCc,
         },
     },
+    rtp_transceiver::rtp_sender::RTCRtpSender,
     track::track_local::TrackLocal,
 };
 
-use crate::state::AppState;
+use crate::{
+    abr,
+    auth::{Principal, Role},
+    candidates::CandidatePreference,
+    errors::AppError,
+    fanout::{OnEvicted, Pace},
+    ids::new_session_id,
+    metadata::METADATA_LABEL,
+    persist::EndedSessions,
+    pool::PooledBuffer,
+    redact::redact_sdp,
+    sdp::{
+        answer_simulcast, ice_fragment, label_session, parse_ice_fragment, simulcast_recv_rids,
+        trickles_candidates,
+    },
+    sframe::FrameKeys,
+    speedtest::{self, SPEEDTEST_LABEL},
+    sse,
+    state::{AppState, Viewer},
+    stats::PipelineStats,
+    store::{SessionInfo, SessionStore, StoredSession},
+    telemetry::{self, TELEMETRY_LABEL},
+    timeline::{SessionTimeline, TimelineEvent},
+    trickle::LocalCandidates,
+};
+
+// Media type of trickled ICE candidates (RFC 8840)
+const TRICKLE_ICE_CONTENT_TYPE: &str = "application/trickle-ice-sdpfrag";
+// Shortest SFrame base key accepted, as much as the AES-128 key it derives
+const MIN_BASE_KEY_LEN: usize = 16;
+// Seconds turned-away viewers are told to wait before offering again
+const VIEWER_LIMIT_RETRY_AFTER: &str = "10";
 
 pub struct SDPOffer(pub RTCSessionDescription);
 
 impl<S> FromRequest<S> for SDPOffer
 where
     S: Send + Sync,
+    AppState: FromRef<S>,
 {
     type Rejection = axum::http::StatusCode;
 
     async fn from_request(
         req: axum::http::Request<axum::body::Body>,
-        _state: &S,
+        state: &S,
     ) -> Result<Self, Self::Rejection> {
         let ct = req
             .headers()
@@ -54,24 +100,28 @@ where
                 axum::http::StatusCode::BAD_REQUEST
             })?;
 
-        Ok(SDPOffer(
-            RTCSessionDescription::offer(String::from_utf8_lossy(bytes.as_ref()).to_string())
-                .map_err(|e| {
-                    error!("Failed to parse SDP: {}", e);
-                    axum::http::StatusCode::BAD_REQUEST
-                })?,
-        ))
+        let sdp = String::from_utf8_lossy(bytes.as_ref()).to_string();
+        if AppState::from_ref(state).log_sdp {
+            info!("📝 SDP offer:\n{}", redact_sdp(&sdp));
+        }
+
+        Ok(SDPOffer(RTCSessionDescription::offer(sdp).map_err(
+            |e| {
+                error!("Failed to parse SDP: {}", e);
+                axum::http::StatusCode::BAD_REQUEST
+            },
+        )?))
     }
 }
 
-pub struct SDPAnswer(pub RTCSessionDescription, String);
+/// An SDP answer and the `Location` of the session resource it created.
+pub struct SDPAnswer(pub RTCSessionDescription, pub String);
 
 impl IntoResponse for SDPAnswer {
     fn into_response(self) -> axum::response::Response {
         let sdp_str = self.0.sdp;
-        let id = self.1;
+        let location_value = self.1;
 
-        let location_value = format!("/resource/{}", id);
         axum::response::Response::builder()
             .header(axum::http::header::CONTENT_TYPE, "application/sdp")
             .header(axum::http::header::LOCATION, location_value)
@@ -81,104 +131,511 @@ impl IntoResponse for SDPAnswer {
     }
 }
 
+/// `POST /whep`: watch the default source.
 pub async fn whep_offer(
-    State(AppState {
-        api,
-        peer_connections,
-        video_track,
-        audio_track,
-    }): State<AppState>,
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    offer: SDPOffer,
+) -> Result<axum::response::Response, axum::response::Response> {
+    let stream = state.default_stream.clone();
+    offer_stream(state, &stream, principal, offer, true)
+        .await
+        .map(sse::advertise)
+}
+
+/// `POST /whep/{stream}`: watch a source by name.
+pub async fn whep_stream_offer(
+    State(state): State<AppState>,
+    axum::extract::Path(stream): axum::extract::Path<String>,
+    principal: Option<Extension<Principal>>,
+    offer: SDPOffer,
+) -> Result<axum::response::Response, axum::response::Response> {
+    offer_stream(state, &stream, principal, offer, true)
+        .await
+        .map(sse::advertise)
+}
+
+/// Answers a viewer's offer for the source named `stream`. With `trickle`,
+/// a viewer that trickles its own candidates is answered before gathering
+/// finishes and collects the gateway's with its `PATCH` requests.
+pub async fn offer_stream(
+    state: AppState,
+    stream: &str,
+    principal: Option<Extension<Principal>>,
     SDPOffer(offer): SDPOffer,
-) -> SDPAnswer {
+    trickle: bool,
+) -> Result<SDPAnswer, axum::response::Response> {
+    let Some(stream) = state.stream(stream) else {
+        if state.is_connecting(stream) {
+            warn!("Source '{}' is not up yet, turning a viewer away", stream);
+            return Err((
+                axum::http::StatusCode::SERVICE_UNAVAILABLE,
+                [(
+                    axum::http::header::RETRY_AFTER,
+                    state.options.startup_retry_interval.to_string(),
+                )],
+                "source connecting",
+            )
+                .into_response());
+        }
+        warn!("Unknown source '{}'", stream);
+        return Err(axum::http::StatusCode::NOT_FOUND.into_response());
+    };
+    if let Some(Extension(principal)) = &principal
+        && !principal.can_view(&stream.info.name)
+    {
+        warn!("Viewer not allowed to watch source '{}'", stream.info.name);
+        return Err(axum::http::StatusCode::FORBIDDEN.into_response());
+    }
+    // Substream-only tokens are served the lighter stream where there is one
+    let stream = match &stream.info.substream {
+        Some(substream)
+            if principal
+                .as_ref()
+                .is_some_and(|Extension(principal)| principal.substream_only) =>
+        {
+            let Some(substream) = state.stream(substream) else {
+                warn!(
+                    "Substream '{}' of source '{}' is not running",
+                    substream, stream.info.name
+                );
+                return Err((
+                    axum::http::StatusCode::SERVICE_UNAVAILABLE,
+                    "substream unavailable",
+                )
+                    .into_response());
+            };
+            substream
+        }
+        _ => stream,
+    };
+    if stream.control.is_suspended() {
+        warn!(
+            "Source '{}' is disabled, turning a viewer away",
+            stream.info.name
+        );
+        return Err((
+            axum::http::StatusCode::SERVICE_UNAVAILABLE,
+            "source disabled",
+        )
+            .into_response());
+    }
+    if let Some(retry_in) = stream.control.reconnecting_in() {
+        warn!(
+            "Source '{}' lost its camera, turning a viewer away",
+            stream.info.name
+        );
+        return Err((
+            axum::http::StatusCode::SERVICE_UNAVAILABLE,
+            [(
+                axum::http::header::RETRY_AFTER,
+                retry_in.as_secs().max(1).to_string(),
+            )],
+            "source connecting",
+        )
+            .into_response());
+    }
+    // With --abr, the viewer moves between the source's video and its
+    // substream's as its bandwidth allows, and with --simulcast receivers
+    // that take simulcast get both; either only if both carry the same codec
+    let low_stream = match (&stream.info.substream, &stream.video_track) {
+        (Some(substream), Some((_, main))) if state.options.abr || state.options.simulcast => {
+            state.stream(substream).filter(|low| {
+                low.video_track.as_ref().is_some_and(|(_, low)| {
+                    low.codec()
+                        .mime_type
+                        .eq_ignore_ascii_case(&main.codec().mime_type)
+                })
+            })
+        }
+        _ => None,
+    };
+    let api = state.api_for(&stream.info).map_err(AppError::WebRtc)?;
+    let AppState {
+        sessions,
+        rtcp_buffers,
+        log_sdp,
+        candidate_preference,
+        ice_servers,
+        options,
+        timelines,
+        viewer_limit,
+        bandwidth,
+        #[cfg(feature = "av1")]
+            av1: transcoders,
+        ..
+    } = state;
+    let source = &stream.info;
+
+    let Some(slot) = viewer_limit.admit() else {
+        warn!(
+            "Viewer limit of {} reached, turning away a viewer of '{}'",
+            viewer_limit.max().unwrap_or_default(),
+            source.name
+        );
+        return Err((
+            axum::http::StatusCode::SERVICE_UNAVAILABLE,
+            [(axum::http::header::RETRY_AFTER, VIEWER_LIMIT_RETRY_AFTER)],
+            "viewer limit reached",
+        )
+            .into_response());
+    };
+
+    // Hide the server's addresses / force a deterministic path through TURN
+    let relay_only = source.relay_only
+        || principal
+            .as_ref()
+            .is_some_and(|Extension(principal)| principal.relay_only);
+    let ice_transport_policy = if relay_only {
+        RTCIceTransportPolicy::Relay
+    } else {
+        RTCIceTransportPolicy::All
+    };
+
     let pc = api
-        .new_peer_connection(webrtc::peer_connection::configuration::RTCConfiguration::default())
+        .new_peer_connection(RTCConfiguration {
+            ice_servers: ice_servers.to_vec(),
+            ice_transport_policy,
+            ..Default::default()
+        })
         .await
-        .unwrap();
+        .map_err(AppError::WebRtc)?;
 
     let pc = Arc::new(pc);
+    let abandoned = CloseOnError(Some(pc.clone()));
 
-    let rtp_video_sender = pc
-        .add_track(video_track.1.clone() as Arc<dyn TrackLocal + Send + Sync>)
-        .await
-        .unwrap();
+    let id = new_session_id(
+        options.session_id_format,
+        options.session_id_policy,
+        &source.name,
+    );
 
-    tokio::spawn(async move {
-        let mut rtcp_buf = vec![0u8; 1500];
-        while let Ok((_, _)) = rtp_video_sender.read(&mut rtcp_buf).await {}
+    let timeline = timelines.map(|sink| {
+        SessionTimeline::start(
+            &id,
+            sink,
+            TimelineEvent::Join {
+                source: source.name.clone(),
+                owner: principal
+                    .as_ref()
+                    .map(|Extension(principal)| principal.id.clone()),
+                relay_only,
+            },
+        )
     });
 
-    if let Some((_, audio_track)) = audio_track {
+    // Each viewer gets tracks of its own, so it can start with the cached GOP
+    let mut subscriptions = Vec::new();
+    let frame_keys = options.e2ee.then(|| Arc::new(FrameKeys::default()));
+    let evict = evict_slow_viewer(&pc, &id, &sessions, &stream.stats, timeline.clone());
+
+    // With --watermark, every viewer gets video transcoded for it alone
+    #[cfg(feature = "transcode")]
+    let watermarked = options.watermark;
+    #[cfg(not(feature = "transcode"))]
+    let watermarked = false;
+
+    // The first two rids the receiver takes name the source's encoding and
+    // the substream's
+    let offered_rids = simulcast_recv_rids(&offer.sdp);
+    let simulcast = match (&low_stream, offered_rids.as_slice()) {
+        (Some(low_stream), [high, low, ..]) if options.simulcast && !watermarked => low_stream
+            .video_track
+            .clone()
+            .map(|(_, low_track)| (low_stream.clone(), low_track, [high.clone(), low.clone()])),
+        _ => None,
+    };
+
+    // `+av1` viewers whose offer takes AV1 get the source's video transcoded
+    // to it, in builds with the `av1` feature; with --simulcast, one rung of
+    // the ladder per rid the receiver takes
+    #[cfg(feature = "av1")]
+    let av1 = principal
+        .as_ref()
+        .filter(|Extension(principal)| {
+            !watermarked && principal.av1 && offer.sdp.to_ascii_lowercase().contains(" av1/90000")
+        })
+        .and_then(|_| {
+            let rungs = if options.simulcast {
+                offered_rids.len()
+            } else {
+                1
+            };
+            transcoders.subscribe(&stream, rungs)
+        });
+    #[cfg(not(feature = "av1"))]
+    let av1: Option<(Vec<Arc<crate::fanout::FanoutTrack>>, Arc<Notify>)> = None;
+
+    // The rids of the simulcast encodings sent, best first
+    let mut answer_rids = Vec::new();
+    if let Some((av1_tracks, av1_keyframe_requests)) = &av1 {
+        let rids = if av1_tracks.len() > 1 {
+            &offered_rids[..av1_tracks.len()]
+        } else {
+            &[]
+        };
+        let mut rtp_video_sender = None;
+        for (index, av1_track) in av1_tracks.iter().enumerate() {
+            let mut subscription = av1_track
+                .subscribe()
+                .encrypted(frame_keys.clone())
+                .evictable(evict.clone());
+            let rid = rids.get(index);
+            if let Some(rid) = rid {
+                subscription = subscription.encoding(rid, &av1_tracks[0]);
+            }
+            let sender = match &rtp_video_sender {
+                None => {
+                    let sender = pc
+                        .add_track(subscription.track() as Arc<dyn TrackLocal + Send + Sync>)
+                        .await
+                        .map_err(AppError::WebRtc)?;
+                    rtp_video_sender.insert(sender).clone()
+                }
+                Some(sender) => {
+                    sender
+                        .add_encoding(subscription.track() as Arc<dyn TrackLocal + Send + Sync>)
+                        .await
+                        .map_err(AppError::WebRtc)?;
+                    sender.clone()
+                }
+            };
+            let pace = subscription.pace();
+            subscriptions.push(subscription);
+            spawn_rtcp_reader(
+                sender,
+                rtcp_buffers.get(),
+                id.clone(),
+                sessions.clone(),
+                av1_keyframe_requests.clone(),
+                Some(pace),
+                rid.filter(|_| index > 0).cloned(),
+            );
+        }
+        answer_rids.extend(rids.iter().cloned());
+    } else if let Some((_, video_track)) = &stream.video_track
+        && let Some((low_stream, low_track, [high_rid, low_rid])) = &simulcast
+    {
+        let high = video_track
+            .subscribe()
+            .encrypted(frame_keys.clone())
+            .evictable(evict.clone())
+            .encoding(high_rid, video_track);
+        let low = low_track
+            .subscribe()
+            .encrypted(frame_keys.clone())
+            .evictable(evict.clone())
+            .encoding(low_rid, video_track);
+        let rtp_video_sender = pc
+            .add_track(high.track() as Arc<dyn TrackLocal + Send + Sync>)
+            .await
+            .map_err(AppError::WebRtc)?;
+        rtp_video_sender
+            .add_encoding(low.track() as Arc<dyn TrackLocal + Send + Sync>)
+            .await
+            .map_err(AppError::WebRtc)?;
+        let (high_pace, low_pace) = (high.pace(), low.pace());
+        subscriptions.extend([high, low]);
+        answer_rids.extend([high_rid.clone(), low_rid.clone()]);
+        spawn_rtcp_reader(
+            rtp_video_sender.clone(),
+            rtcp_buffers.get(),
+            id.clone(),
+            sessions.clone(),
+            stream.keyframe_requests.clone(),
+            Some(high_pace),
+            None,
+        );
+        spawn_rtcp_reader(
+            rtp_video_sender,
+            rtcp_buffers.get(),
+            id.clone(),
+            sessions.clone(),
+            low_stream.keyframe_requests.clone(),
+            Some(low_pace),
+            Some(low_rid.clone()),
+        );
+    } else if let Some((_, video_track)) = &stream.video_track {
+        #[cfg(feature = "transcode")]
+        let marked = watermarked
+            .then(|| {
+                crate::transcode::watermark(
+                    &stream.info.name,
+                    video_track,
+                    &id,
+                    options.watermark_bitrate,
+                )
+            })
+            .flatten();
+        #[cfg(not(feature = "transcode"))]
+        let marked: Option<(Arc<crate::fanout::FanoutTrack>, Arc<Notify>)> = None;
+        let (video_track, mut keyframe_requests) = match &marked {
+            Some((track, keyframe_requests)) => (track, keyframe_requests.clone()),
+            None => (video_track, stream.keyframe_requests.clone()),
+        };
+        let mut subscription = video_track
+            .subscribe()
+            .encrypted(frame_keys.clone())
+            .evictable(evict.clone());
+        let mut controller = None;
+        if let Some(low_stream) = &low_stream
+            && let Some((_, low_track)) = &low_stream.video_track
+            && options.abr
+            && !watermarked
+        {
+            let (selected, selection) = watch::channel(false);
+            subscription = subscription.switchable(low_track.clone(), selection);
+            // The controller passes the viewer's keyframe requests on to
+            // whichever stream it watches
+            keyframe_requests = Arc::new(Notify::new());
+            let layers = [
+                abr::Layer {
+                    name: stream.info.name.clone(),
+                    track: video_track.clone(),
+                    keyframe_requests: stream.keyframe_requests.clone(),
+                },
+                abr::Layer {
+                    name: low_stream.info.name.clone(),
+                    track: low_track.clone(),
+                    keyframe_requests: low_stream.keyframe_requests.clone(),
+                },
+            ];
+            controller = Some((layers, selected, keyframe_requests.clone()));
+        }
+        let rtp_video_sender = pc
+            .add_track(subscription.track() as Arc<dyn TrackLocal + Send + Sync>)
+            .await
+            .map_err(AppError::WebRtc)?;
+        if let Some((layers, selected, keyframe_requests)) = controller
+            && let Some(encoding) = rtp_video_sender.get_parameters().await.encodings.first()
+        {
+            abr::spawn_controller(
+                id.clone(),
+                bandwidth.clone(),
+                encoding.ssrc,
+                layers,
+                selected,
+                keyframe_requests,
+            );
+        }
+        let pace = subscription.pace();
+        subscriptions.push(subscription);
+        spawn_rtcp_reader(
+            rtp_video_sender,
+            rtcp_buffers.get(),
+            id.clone(),
+            sessions.clone(),
+            keyframe_requests,
+            Some(pace),
+            None,
+        );
+    }
+
+    if let Some((_, audio_track)) = &stream.audio_track {
+        let subscription = audio_track
+            .subscribe()
+            .encrypted(frame_keys.clone())
+            .evictable(evict.clone());
         let rtp_audio_sender = pc
-            .add_track(audio_track.clone() as Arc<dyn TrackLocal + Send + Sync>)
+            .add_track(subscription.track() as Arc<dyn TrackLocal + Send + Sync>)
             .await
-            .unwrap();
-
-        tokio::spawn(async move {
-            let mut rtcp_buf = [0u8; 1500];
-            while let Ok((rtcp, _atr)) = rtp_audio_sender.read(&mut rtcp_buf).await {
-                for pkt in rtcp {
-                    if let Some(_sr) = pkt.as_any().downcast_ref::<SenderReport>() {
-                        debug!("RTCP: Sender Report (SR)");
-                        continue;
-                    }
-                    if let Some(_rr) = pkt.as_any().downcast_ref::<ReceiverReport>() {
-                        debug!("RTCP: Receiver Report (RR)");
-                        continue;
-                    }
-                    if let Some(_pli) = pkt.as_any().downcast_ref::<PictureLossIndication>() {
-                        debug!("RTCP: PLI (Picture Loss Indication)");
-                        continue;
-                    }
-                    if let Some(_fir) = pkt.as_any().downcast_ref::<FullIntraRequest>() {
-                        debug!("RTCP: FIR (Full Intra Request)");
-                        continue;
-                    }
-                    if let Some(_tcc) = pkt.as_any().downcast_ref::<TransportLayerCc>() {
-                        debug!("RTCP: TCC (Transport-wide Congestion Control)");
-                        continue;
-                    }
-                    if let Some(_rrr) = pkt.as_any().downcast_ref::<RapidResynchronizationRequest>()
-                    {
-                        debug!("RTCP: Rapid Resync Request (RRR)");
-                        continue;
-                    }
-                    if let Some(_bye) = pkt.as_any().downcast_ref::<Goodbye>() {
-                        debug!("RTCP: BYE");
-                        continue;
-                    }
+            .map_err(AppError::WebRtc)?;
+        subscriptions.push(subscription);
+        spawn_rtcp_reader(
+            rtp_audio_sender,
+            rtcp_buffers.get(),
+            id.clone(),
+            sessions.clone(),
+            stream.keyframe_requests.clone(),
+            None,
+            None,
+        );
+    }
 
-                    debug!("RTCP: Unknown / Raw packet");
-                }
-            }
-        });
+    // With --abr, the telemetry channel carries the viewer's bandwidth
+    // estimate, filed under the SSRC of its first track
+    let mut estimate = None;
+    if options.abr
+        && let Some(sender) = pc.get_senders().await.first()
+        && let Some(encoding) = sender.get_parameters().await.encodings.first()
+    {
+        estimate = Some((bandwidth.clone(), encoding.ssrc));
     }
 
-    let id = uuid::Uuid::new_v4().to_string();
+    // Push source events to any data channel the viewer opens, except the
+    // speedtest channel which answers downlink test requests, the metadata
+    // channel which carries the source's metadata documents and the telemetry
+    // channel which carries both and stats
+    let events = stream.events.clone();
+    let metadata = stream.metadata.clone();
+    let stream_for_channels = stream.clone();
+    pc.on_data_channel(Box::new(move |dc: Arc<RTCDataChannel>| {
+        match dc.label() {
+            SPEEDTEST_LABEL => speedtest::serve(dc),
+            METADATA_LABEL => forward_to_channel(dc, metadata.subscribe(), Some),
+            TELEMETRY_LABEL => telemetry::serve(dc, &stream_for_channels, estimate.clone()),
+            _ => forward_to_channel(dc, events.subscribe(), |event| {
+                serde_json::to_string(&event).ok()
+            }),
+        }
+        Box::pin(async {})
+    }));
 
     // Set up peer connection state change handler
     let id_for_handler = id.clone();
-    let peer_connections_clone = peer_connections.clone();
+    let sessions_for_handler = sessions.clone();
+    let stats_for_handler = stream.stats.clone();
+    let viewer = Arc::new(std::sync::Mutex::new(None::<Viewer>));
+    let slot = Arc::new(std::sync::Mutex::new(Some(slot)));
+    let viewer_for_handler = viewer.clone();
+    let subscriptions = Arc::new(std::sync::Mutex::new(subscriptions));
+    let timeline_for_handler = timeline.clone();
     pc.on_peer_connection_state_change(Box::new(move |state| {
         let id = id_for_handler.clone();
-        let peer_connections = peer_connections_clone.clone();
+        let sessions = sessions_for_handler.clone();
+        let stats = stats_for_handler.clone();
+        let viewer = viewer_for_handler.clone();
+        let subscriptions = subscriptions.clone();
+        let timeline = timeline_for_handler.clone();
+        let slot = slot.clone();
 
         Box::pin(async move {
             use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 
+            if state == RTCPeerConnectionState::Failed {
+                stats.viewer_failures.fetch_add(1, Ordering::Relaxed);
+            }
+            if let Some(timeline) = &timeline {
+                timeline.record(&TimelineEvent::State {
+                    state: state.to_string(),
+                });
+            }
+
             match state {
+                RTCPeerConnectionState::Connected => {
+                    for subscription in subscriptions.lock().unwrap().iter() {
+                        subscription.start();
+                    }
+                }
                 RTCPeerConnectionState::Disconnected
                 | RTCPeerConnectionState::Failed
                 | RTCPeerConnectionState::Closed => {
                     info!("🔌 Connection {} state: {:?}, cleaning up", &id[..8], state);
+                    viewer.lock().unwrap().take();
+                    slot.lock().unwrap().take();
+                    subscriptions.lock().unwrap().clear();
+                    if let Some(timeline) = &timeline {
+                        timeline.leave(&state.to_string());
+                    }
 
-                    if let Some((_, pc)) = peer_connections.remove(&id) {
+                    if let Some(pc) = sessions.remove(&id) {
                         let _ = pc.close().await;
                     }
 
                     info!(
                         "🧹 Session {} auto-removed | Remaining: {}",
                         &id[..8],
-                        peer_connections.len()
+                        sessions.len()
                     );
                 }
                 _ => {}
@@ -186,41 +643,501 @@ pub async fn whep_offer(
         })
     }));
 
-    pc.set_remote_description(offer).await.unwrap();
+    let trickle = trickle && trickles_candidates(&offer.sdp);
+    pc.set_remote_description(offer)
+        .await
+        .map_err(AppError::BadOffer)?;
 
-    let answer = pc.create_answer(None).await.unwrap();
+    let answer = pc.create_answer(None).await.map_err(AppError::BadOffer)?;
 
-    pc.set_local_description(answer.clone()).await.unwrap();
+    // A viewer that trickles is answered right away and collects our
+    // candidates as they come; anyone else waits for ICE gathering so the
+    // answer carries them all
+    let local_candidates = trickle.then(|| LocalCandidates::gather(&pc));
+    let mut gathering_complete = pc.gathering_complete_promise().await;
+    pc.set_local_description(answer)
+        .await
+        .map_err(AppError::WebRtc)?;
+    if !trickle {
+        let _ = gathering_complete.recv().await;
+    }
 
-    peer_connections.insert(id.clone(), pc);
+    let mut answer = pc
+        .local_description()
+        .await
+        .ok_or(AppError::NoLocalDescription)?;
+    if let Some(local_candidates) = &local_candidates {
+        local_candidates.sent_in(&answer.sdp);
+    }
+    if !candidate_preference.is_empty() {
+        answer.sdp = candidate_preference.apply(&answer.sdp);
+    }
+    answer.sdp = label_session(&answer.sdp, source.title(), source.description.as_deref());
+    if !answer_rids.is_empty() {
+        let rids: Vec<&str> = answer_rids.iter().map(String::as_str).collect();
+        answer.sdp = answer_simulcast(&answer.sdp, &rids);
+    }
+
+    if log_sdp {
+        info!(
+            "📝 SDP answer for {}:\n{}",
+            &id[..8],
+            redact_sdp(&answer.sdp)
+        );
+    }
+
+    abandoned.disarm();
+    sessions.insert(
+        SessionInfo {
+            id: id.clone(),
+            source: source.name.clone(),
+            owner: principal.map(|Extension(principal)| principal.id),
+            frame_keys,
+            local_candidates,
+        },
+        pc.clone(),
+    );
+    if let Some(timeline) = &timeline {
+        timeline.spawn_sampler(
+            pc,
+            sessions.clone(),
+            Duration::from_secs(options.session_log_interval),
+        );
+    }
 
     info!(
         "✅ Session created: {} | Sessions: {}",
         &id[..8],
-        peer_connections.len()
+        sessions.len()
     );
 
-    SDPAnswer(answer, id)
+    *viewer.lock().unwrap() = Some(Viewer::new(&stream.viewers));
+
+    // Don't leave the new viewer waiting for the next natural keyframe, unless
+    // it starts with the cached one anyway; a watermark transcode starts at
+    // the source's next one
+    if let Some((_, av1_keyframe_requests)) = &av1 {
+        av1_keyframe_requests.notify_one();
+    } else if watermarked
+        || stream
+            .video_track
+            .as_ref()
+            .is_none_or(|(_, video_track)| !video_track.has_keyframe())
+    {
+        stream.keyframe_requests.notify_one();
+    }
+    if let Some((low_stream, low_track, _)) = &simulcast
+        && !low_track.has_keyframe()
+    {
+        low_stream.keyframe_requests.notify_one();
+    }
+
+    Ok(SDPAnswer(answer, format!("/whep/resource/{}", id)))
+}
+
+/// Sends everything received on `messages` as text over `dc` once it opens,
+/// until either side goes away.
+fn forward_to_channel<T: Clone + Send + 'static>(
+    dc: Arc<RTCDataChannel>,
+    mut messages: broadcast::Receiver<T>,
+    encode: fn(T) -> Option<String>,
+) {
+    let dc_for_open = dc.clone();
+    dc.on_open(Box::new(move || {
+        Box::pin(async move {
+            debug!("Data channel '{}' open", dc_for_open.label());
+            loop {
+                let message = match messages.recv().await {
+                    Ok(message) => message,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let Some(text) = encode(message) else {
+                    continue;
+                };
+                if dc_for_open.send_text(text).await.is_err() {
+                    break;
+                }
+            }
+        })
+    }));
 }
 
+/// Drains RTCP from a viewer, passing keyframe requests (PLI/FIR) on to the
+/// source, the loss its receiver reports show on to the track's `pace`, and
+/// tearing the session down as soon as the viewer says goodbye, instead of
+/// waiting for ICE to time out.
+///
+/// With a `rid`, reads the RTCP of that simulcast encoding of the sender
+/// rather than of its first.
+fn spawn_rtcp_reader(
+    sender: Arc<RTCRtpSender>,
+    mut buf: PooledBuffer,
+    id: String,
+    sessions: Arc<dyn SessionStore>,
+    keyframe_requests: Arc<Notify>,
+    pace: Option<Arc<Pace>>,
+    rid: Option<String>,
+) {
+    tokio::spawn(async move {
+        // The SSRC the viewer's receiver reports on the read track go by
+        let encodings = sender.get_parameters().await.encodings;
+        let ssrc = match &rid {
+            Some(rid) => encodings
+                .iter()
+                .find(|encoding| encoding.rid == rid.as_str()),
+            None => encodings.first(),
+        }
+        .map(|encoding| encoding.ssrc);
+        loop {
+            let read = match &rid {
+                Some(rid) => sender.read_simulcast(&mut buf, rid).await,
+                None => sender.read(&mut buf).await,
+            };
+            let Ok((rtcp, _atr)) = read else {
+                break;
+            };
+            for pkt in rtcp {
+                if let Some(_sr) = pkt.as_any().downcast_ref::<SenderReport>() {
+                    debug!("RTCP: Sender Report (SR)");
+                    continue;
+                }
+                if let Some(rr) = pkt.as_any().downcast_ref::<ReceiverReport>() {
+                    debug!("RTCP: Receiver Report (RR)");
+                    if let Some(pace) = &pace {
+                        rr.reports
+                            .iter()
+                            .filter(|report| Some(report.ssrc) == ssrc)
+                            .for_each(|report| pace.report(report.fraction_lost));
+                    }
+                    continue;
+                }
+                if let Some(_pli) = pkt.as_any().downcast_ref::<PictureLossIndication>() {
+                    debug!("RTCP: PLI (Picture Loss Indication)");
+                    keyframe_requests.notify_one();
+                    continue;
+                }
+                if let Some(_fir) = pkt.as_any().downcast_ref::<FullIntraRequest>() {
+                    debug!("RTCP: FIR (Full Intra Request)");
+                    keyframe_requests.notify_one();
+                    continue;
+                }
+                if let Some(_tcc) = pkt.as_any().downcast_ref::<TransportLayerCc>() {
+                    debug!("RTCP: TCC (Transport-wide Congestion Control)");
+                    continue;
+                }
+                if let Some(_rrr) = pkt.as_any().downcast_ref::<RapidResynchronizationRequest>() {
+                    debug!("RTCP: Rapid Resync Request (RRR)");
+                    continue;
+                }
+                if let Some(_bye) = pkt.as_any().downcast_ref::<Goodbye>() {
+                    debug!("RTCP: BYE");
+                    if let Some(pc) = sessions.remove(&id) {
+                        let _ = pc.close().await;
+                        info!(
+                            "👋 Session {} said goodbye | Remaining: {}",
+                            &id[..8],
+                            sessions.len()
+                        );
+                    }
+                    return;
+                }
+
+                debug!("RTCP: Unknown / Raw packet");
+            }
+        }
+    });
+}
+
+/// Closes the session of a viewer too slow to keep up with its tracks, even
+/// on keyframes only; its timeline records why.
+fn evict_slow_viewer(
+    pc: &Arc<RTCPeerConnection>,
+    id: &str,
+    sessions: &Arc<dyn SessionStore>,
+    stats: &Arc<PipelineStats>,
+    timeline: Option<Arc<SessionTimeline>>,
+) -> OnEvicted {
+    let pc = Arc::downgrade(pc);
+    let id = id.to_owned();
+    let sessions = sessions.clone();
+    let stats = stats.clone();
+    Arc::new(move || {
+        // Every track of the viewer may give up on it
+        if sessions.remove(&id).is_none() {
+            return;
+        }
+        warn!("🐢 Session {} too slow to keep up, evicted", &id[..8]);
+        stats.viewers_evicted.fetch_add(1, Ordering::Relaxed);
+        if let Some(timeline) = &timeline {
+            timeline.leave("evicted: too slow");
+        }
+        if let Some(pc) = pc.upgrade() {
+            tokio::spawn(async move {
+                let _ = pc.close().await;
+            });
+        }
+    })
+}
+
+/// Closes a viewer's or publisher's peer connection when answering its offer
+/// fails midway, unless disarmed once the session is set up.
+pub struct CloseOnError(pub Option<Arc<RTCPeerConnection>>);
+
+impl CloseOnError {
+    pub fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for CloseOnError {
+    fn drop(&mut self) {
+        if let Some(pc) = self.0.take() {
+            tokio::spawn(async move {
+                let _ = pc.close().await;
+            });
+        }
+    }
+}
+
+/// `DELETE /whep/resource/{id}`: end a session. Only the session's owner (or
+/// an admin) may end it.
 pub async fn whep_delete(
     State(AppState {
-        peer_connections, ..
+        sessions,
+        ended_sessions,
+        ..
     }): State<AppState>,
     axum::extract::Path(id): axum::extract::Path<String>,
+    principal: Option<Extension<Principal>>,
 ) -> axum::http::StatusCode {
-    if let Some((_, pc)) = peer_connections.remove(&id) {
-        pc.close().await.unwrap();
+    // The id comes from the client, so it may be shorter than a real one
+    let short_id = id.get(..8).unwrap_or(&id);
+    if let Some(session) = sessions.get(&id)
+        && !may_manage(principal.as_deref(), session.info.owner.as_deref())
+    {
+        warn!("Refusing to delete session {} of another user", short_id);
+        return axum::http::StatusCode::FORBIDDEN;
+    }
+
+    if let Some(pc) = sessions.remove(&id) {
+        if let Err(e) = pc.close().await {
+            warn!("Failed to close session {}: {}", short_id, e);
+        }
 
         info!(
             "🗑️  Session deleted: {} | Remaining: {}",
-            &id[..8],
-            peer_connections.len()
+            short_id,
+            sessions.len()
         );
 
         axum::http::StatusCode::NO_CONTENT
+    } else if ended_sessions.forget(&id) {
+        debug!("Session {} was ended by a restart", short_id);
+        axum::http::StatusCode::GONE
     } else {
-        warn!("⚠️  Session not found: {}", &id[..8]);
+        warn!("⚠️  Session not found: {}", short_id);
         axum::http::StatusCode::NOT_FOUND
     }
 }
+
+/// Whether `principal` may manage a session owned by `owner`: only its owner
+/// and admins may, or anyone when authentication is off.
+fn may_manage(principal: Option<&Principal>, owner: Option<&str>) -> bool {
+    principal.is_none_or(|principal| {
+        principal.role >= Role::Admin || owner == Some(principal.id.as_str())
+    })
+}
+
+/// `410 Gone` for a session the last restart ended, `404 Not Found` for one
+/// that never existed.
+pub fn missing_session(ended_sessions: &EndedSessions, id: &str) -> axum::http::StatusCode {
+    if ended_sessions.contains(id) {
+        axum::http::StatusCode::GONE
+    } else {
+        axum::http::StatusCode::NOT_FOUND
+    }
+}
+
+/// Body of `PUT /whep/resource/{id}/key`.
+#[derive(Deserialize)]
+pub struct FrameKeyRequest {
+    /// SFrame key id, sent in every frame's header.
+    pub kid: u64,
+    /// Base64 base key, from which the AES key and salt are derived.
+    pub key: String,
+}
+
+/// `PUT /whep/resource/{id}/key`: the SFrame key the session's frames are
+/// encrypted with from now on, with `--e2ee`. Only the session's owner (or
+/// an admin) may set it.
+pub async fn whep_key(
+    State(state): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    principal: Option<Extension<Principal>>,
+    axum::Json(request): axum::Json<FrameKeyRequest>,
+) -> Result<axum::http::StatusCode, (axum::http::StatusCode, &'static str)> {
+    let Some(session) = state.sessions.get(&id) else {
+        return Err((
+            missing_session(&state.ended_sessions, &id),
+            "no such session",
+        ));
+    };
+    if !may_manage(principal.as_deref(), session.info.owner.as_deref()) {
+        return Err((axum::http::StatusCode::FORBIDDEN, "not your session"));
+    }
+    let Some(frame_keys) = session.info.frame_keys else {
+        return Err((
+            axum::http::StatusCode::CONFLICT,
+            "sessions are not encrypted without --e2ee",
+        ));
+    };
+    let key = match BASE64_STANDARD.decode(&request.key) {
+        Ok(key) if key.len() >= MIN_BASE_KEY_LEN => key,
+        _ => {
+            return Err((
+                axum::http::StatusCode::UNPROCESSABLE_ENTITY,
+                "key must be at least 16 bytes, base64 encoded",
+            ));
+        }
+    };
+
+    let rotated = frame_keys.set(request.kid, &key);
+    info!("🔑 Session {} encrypts with key {}", &id[..8], request.kid);
+    // Frames were dropped until now, so the viewer waits for a keyframe
+    if !rotated && let Some(stream) = state.stream(&session.info.source) {
+        stream.keyframe_requests.notify_one();
+    }
+    Ok(axum::http::StatusCode::NO_CONTENT)
+}
+
+/// `GET /whep/resources`: the caller's own active sessions, so a client can
+/// clean up sessions it leaked (e.g. after a page crash).
+pub async fn whep_resources(
+    State(AppState { sessions, .. }): State<AppState>,
+    principal: Option<Extension<Principal>>,
+) -> axum::Json<Vec<SessionInfo>> {
+    let owner = principal.map(|Extension(principal)| principal.id);
+    axum::Json(sessions.owned_by(owner.as_deref()))
+}
+
+/// `HEAD /whep/resource/{id}`: keepalive heartbeat for a session.
+pub async fn whep_keepalive(
+    State(AppState {
+        sessions,
+        ended_sessions,
+        ..
+    }): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> axum::http::StatusCode {
+    if sessions.touch(&id) {
+        debug!("💓 Session {} keepalive", &id[..8]);
+        axum::http::StatusCode::NO_CONTENT
+    } else {
+        missing_session(&ended_sessions, &id)
+    }
+}
+
+/// `PATCH /whep/resource/{id}`: trickled ICE candidates
+/// (`application/trickle-ice-sdpfrag`) for a session; an empty body is a
+/// keepalive heartbeat. Either is answered with the gateway's candidates
+/// gathered since, if the session trickles them.
+pub async fn whep_patch(
+    State(AppState {
+        sessions,
+        ended_sessions,
+        candidate_preference,
+        ..
+    }): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    principal: Option<Extension<Principal>>,
+    headers: axum::http::HeaderMap,
+    body: String,
+) -> axum::response::Response {
+    let Some(session) = sessions.get(&id) else {
+        return missing_session(&ended_sessions, &id).into_response();
+    };
+    if !may_manage(principal.as_deref(), session.info.owner.as_deref()) {
+        warn!(
+            "Refusing ICE candidates for session {} of another user",
+            &id[..8]
+        );
+        return axum::http::StatusCode::FORBIDDEN.into_response();
+    }
+    if !sessions.touch(&id) {
+        return missing_session(&ended_sessions, &id).into_response();
+    }
+    if body.is_empty() {
+        debug!("💓 Session {} keepalive", &id[..8]);
+        return local_candidates(&session, &candidate_preference).await;
+    }
+
+    let ct = headers
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if ct.split(';').next().map(|s| s.trim()) != Some(TRICKLE_ICE_CONTENT_TYPE) {
+        warn!("Invalid Content-Type: '{}'", ct);
+        return axum::http::StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+
+    let fragment = parse_ice_fragment(&body);
+
+    // A new ufrag means an ICE restart, which needs a new answer we can't send here
+    let remote_ufrag = session
+        .pc
+        .remote_description()
+        .await
+        .and_then(|remote| parse_ice_fragment(&remote.sdp).ufrag);
+    if fragment.ufrag.is_some() && fragment.ufrag != remote_ufrag {
+        warn!("Session {} attempted an ICE restart", &id[..8]);
+        return axum::http::StatusCode::UNPROCESSABLE_ENTITY.into_response();
+    }
+
+    for (mid, candidate) in fragment.candidates {
+        let init = RTCIceCandidateInit {
+            candidate,
+            sdp_mid: mid,
+            username_fragment: fragment.ufrag.clone(),
+            ..Default::default()
+        };
+        if let Err(e) = session.pc.add_ice_candidate(init).await {
+            warn!("Session {} sent a bad candidate: {}", &id[..8], e);
+            return axum::http::StatusCode::BAD_REQUEST.into_response();
+        }
+    }
+
+    debug!("🧊 Session {} trickled ICE candidates", &id[..8]);
+    local_candidates(&session, &candidate_preference).await
+}
+
+/// The gateway's candidates the viewer of `session` hasn't had yet, as a
+/// trickle ICE fragment, or `204 No Content` if there are none.
+async fn local_candidates(
+    session: &StoredSession,
+    candidate_preference: &CandidatePreference,
+) -> axum::response::Response {
+    let (Some(local_candidates), Some(local)) = (
+        &session.info.local_candidates,
+        session.pc.local_description().await,
+    ) else {
+        return axum::http::StatusCode::NO_CONTENT.into_response();
+    };
+    let Some((candidates, complete)) = local_candidates.take() else {
+        return axum::http::StatusCode::NO_CONTENT.into_response();
+    };
+    let mut fragment = ice_fragment(&local.sdp, &candidates, complete);
+    if !candidate_preference.is_empty() {
+        fragment = candidate_preference.apply(&fragment);
+    }
+    debug!(
+        "🧊 Trickling {} ICE candidates to session {}",
+        candidates.len(),
+        &session.info.id[..8]
+    );
+    (
+        [(axum::http::header::CONTENT_TYPE, TRICKLE_ICE_CONTENT_TYPE)],
+        fragment,
+    )
+        .into_response()
+}
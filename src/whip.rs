@@ -0,0 +1,371 @@
+use std::{
+    sync::{Arc, Mutex, atomic::Ordering},
+    time::Duration,
+};
+
+use axum::{Extension, extract::State, response::IntoResponse};
+use tokio::sync::{Notify, broadcast, watch};
+use tracing::{info, warn};
+use webrtc::{
+    peer_connection::{
+        RTCPeerConnection, configuration::RTCConfiguration,
+        sdp::session_description::RTCSessionDescription,
+    },
+    rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication,
+    rtp_transceiver::rtp_codec::RTPCodecType,
+    track::track_remote::TrackRemote,
+};
+
+use crate::{
+    auth::Principal,
+    errors::AppError,
+    fanout::FanoutTrack,
+    hls::HlsPlaylist,
+    ids::new_session_id,
+    sdp::strip_rtx,
+    startup::StartupTimer,
+    state::{AppState, AudioFormat, Capabilities, SourceControl, SourceInfo, Stream, VideoLayer},
+    stats::{PipelineStats, TrackStats},
+    store::SessionInfo,
+    whep::{CloseOnError, SDPAnswer, SDPOffer},
+};
+
+// Browsers only send keyframes on request; besides passing on viewers'
+// requests, ask regularly so decoders recover from unreported loss
+const PLI_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// Tracks received so far from a publisher, until the stream can be registered.
+#[derive(Default)]
+struct Pending {
+    video: Option<Arc<FanoutTrack>>,
+    audio: Option<Arc<FanoutTrack>>,
+}
+
+/// `POST /whip/{stream}`: accept a WebRTC publisher and serve its tracks to
+/// WHEP viewers as source `stream`.
+pub async fn whip_offer(
+    State(state): State<AppState>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    principal: Option<Extension<Principal>>,
+    SDPOffer(offer): SDPOffer,
+) -> Result<SDPAnswer, axum::response::Response> {
+    if state.stream(&name).is_some() {
+        warn!("Source '{}' already exists, rejecting publisher", name);
+        return Err(axum::http::StatusCode::CONFLICT.into_response());
+    }
+
+    // Only the first video and audio section are republished
+    let expect_video = offer.sdp.contains("\nm=video");
+    let expect_audio = offer.sdp.contains("\nm=audio");
+    if !expect_video && !expect_audio {
+        warn!("WHIP offer for '{}' carries no media", name);
+        return Err(axum::http::StatusCode::BAD_REQUEST.into_response());
+    }
+    // Receivers drop RTX packets, so the publisher has to resend on its media
+    // streams
+    let offer = RTCSessionDescription::offer(strip_rtx(&offer.sdp)).map_err(|e| {
+        warn!("Failed to parse WHIP offer without RTX: {}", e);
+        axum::http::StatusCode::BAD_REQUEST.into_response()
+    })?;
+
+    let pc = Arc::new(
+        state
+            .api
+            .new_peer_connection(RTCConfiguration {
+                ice_servers: state.ice_servers.to_vec(),
+                ..Default::default()
+            })
+            .await
+            .map_err(AppError::WebRtc)?,
+    );
+    let abandoned = CloseOnError(Some(pc.clone()));
+
+    let id = new_session_id(
+        state.options.session_id_format,
+        state.options.session_id_policy,
+        &name,
+    );
+    let stats = Arc::new(PipelineStats::default());
+    let pending = Arc::new(Mutex::new(Pending::default()));
+    let keyframe_requests = Arc::new(Notify::new());
+    let control = Arc::new(SourceControl::default());
+
+    let weak_pc = Arc::downgrade(&pc);
+    let state_for_track = state.clone();
+    let name_for_track = name.clone();
+    let stats_for_track = stats.clone();
+    let keyframe_requests_for_track = keyframe_requests.clone();
+    let control_for_track = control.clone();
+    // Only viewers' startup is measured; publishers connect on their own
+    let startup_for_track = Arc::new(StartupTimer::new("Publisher"));
+    pc.on_track(Box::new(move |remote, _receiver, _transceiver| {
+        let kind = remote.kind();
+        let local = if kind == RTPCodecType::Video {
+            FanoutTrack::new(
+                remote.codec().capability,
+                "video".to_owned(),
+                "webrtc-rs".to_owned(),
+            )
+            .with_startup(startup_for_track.clone())
+        } else {
+            FanoutTrack::new(
+                remote.codec().capability,
+                "audio".to_owned(),
+                "webrtc-rs".to_owned(),
+            )
+        };
+        let local = Arc::new(local);
+
+        let complete = {
+            let mut pending = pending.lock().unwrap();
+            match kind {
+                RTPCodecType::Video if pending.video.is_none() => {
+                    pending.video = Some(local.clone())
+                }
+                RTPCodecType::Audio if pending.audio.is_none() => {
+                    pending.audio = Some(local.clone())
+                }
+                _ => {
+                    warn!("Ignoring extra {} track from publisher", kind);
+                    return Box::pin(async {});
+                }
+            }
+            ((!expect_video || pending.video.is_some())
+                && (!expect_audio || pending.audio.is_some()))
+            .then(|| (pending.video.clone(), pending.audio.clone()))
+        };
+
+        if let Some((video, audio)) = complete {
+            let info = SourceInfo {
+                name: name_for_track.clone(),
+                url: url::Url::parse(&format!("whip:{}", name_for_track))
+                    .expect("whip: URL is valid"),
+                tags: Default::default(),
+                video_codec: video.as_ref().map(|track| track.codec().mime_type),
+                audio_codec: audio.as_ref().map(|track| track.codec().mime_type),
+                metadata_codec: None,
+                display_name: None,
+                description: None,
+                relay_only: false,
+                substream: None,
+                interface: None,
+            };
+            // Publishers negotiate codecs but not resolutions up front
+            let video_layer = video.as_ref().map(|track| VideoLayer {
+                codec: track.codec().mime_type,
+                width: None,
+                height: None,
+                framerate: None,
+            });
+            let capabilities = Capabilities {
+                video: video_layer.clone(),
+                audio: audio.as_ref().map(|track| {
+                    let codec = track.codec();
+                    AudioFormat {
+                        codec: codec.mime_type,
+                        clock_rate: codec.clock_rate,
+                        channels: Some(codec.channels).filter(|&channels| channels > 0),
+                    }
+                }),
+                layers: video_layer.into_iter().collect(),
+            };
+            let hls = state_for_track
+                .options
+                .hls
+                .then(|| {
+                    HlsPlaylist::spawn(
+                        &name_for_track,
+                        video.as_ref(),
+                        audio.as_ref(),
+                        Duration::from_secs(state_for_track.options.hls_segment),
+                        // Publishers answer them with a PLI's keyframe
+                        Some(keyframe_requests_for_track.clone()),
+                    )
+                })
+                .flatten();
+            let added = state_for_track.add_stream(Stream {
+                info,
+                capabilities,
+                video_track: video.map(|track| (0, track)),
+                audio_track: audio.map(|track| (1, track)),
+                events: broadcast::channel(16).0,
+                metadata: broadcast::channel(16).0,
+                stats: stats_for_track.clone(),
+                keyframe_requests: keyframe_requests_for_track.clone(),
+                control: control_for_track.clone(),
+                viewers: watch::Sender::new(0),
+                startup: startup_for_track.clone(),
+                hls,
+                faults: None,
+            });
+            if added {
+                info!("📥 Publisher now live as source '{}'", name_for_track);
+            } else {
+                warn!(
+                    "Source '{}' appeared meanwhile, not replacing it",
+                    name_for_track
+                );
+            }
+        }
+
+        let track_stats = stats_for_track.clone();
+        let weak_pc = weak_pc.clone();
+        let keyframe_requests = keyframe_requests_for_track.clone();
+        let control = control_for_track.clone();
+        Box::pin(async move {
+            let stats = if kind == RTPCodecType::Video {
+                spawn_pli_requests(weak_pc, remote.ssrc(), keyframe_requests);
+                &track_stats.video
+            } else {
+                &track_stats.audio
+            };
+            forward(remote, local, stats, &control).await;
+        })
+    }));
+
+    let state_for_handler = state.clone();
+    let id_for_handler = id.clone();
+    let name_for_handler = name.clone();
+    let stats_for_handler = stats.clone();
+    let control_for_handler = control.clone();
+    pc.on_peer_connection_state_change(Box::new(move |pc_state| {
+        let state = state_for_handler.clone();
+        let id = id_for_handler.clone();
+        let name = name_for_handler.clone();
+        let stats = stats_for_handler.clone();
+        let control = control_for_handler.clone();
+
+        Box::pin(async move {
+            use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+
+            match pc_state {
+                RTCPeerConnectionState::Disconnected
+                | RTCPeerConnectionState::Failed
+                | RTCPeerConnectionState::Closed => {
+                    if let Some(pc) = state.publishers.remove(&id) {
+                        let _ = pc.close().await;
+                    }
+
+                    // Leave a source that took over the name in the meantime alone
+                    if state
+                        .stream(&name)
+                        .is_some_and(|stream| Arc::ptr_eq(&stream.stats, &stats))
+                    {
+                        state.remove_stream(&name);
+                        info!("📤 Publisher of '{}' gone, source removed", name);
+                    }
+                    control.stop.notify_one();
+                }
+                _ => {}
+            }
+        })
+    }));
+
+    pc.set_remote_description(offer)
+        .await
+        .map_err(AppError::BadOffer)?;
+
+    let answer = pc.create_answer(None).await.map_err(AppError::BadOffer)?;
+
+    // Wait for ICE gathering so the answer carries our candidates
+    let mut gathering_complete = pc.gathering_complete_promise().await;
+    pc.set_local_description(answer)
+        .await
+        .map_err(AppError::WebRtc)?;
+    let _ = gathering_complete.recv().await;
+
+    let answer = pc
+        .local_description()
+        .await
+        .ok_or(AppError::NoLocalDescription)?;
+
+    let weak_pc = Arc::downgrade(&pc);
+    abandoned.disarm();
+    state.publishers.insert(
+        SessionInfo {
+            id: id.clone(),
+            source: name.clone(),
+            owner: principal.map(|Extension(principal)| principal.id),
+            frame_keys: None,
+            local_candidates: None,
+        },
+        pc,
+    );
+
+    info!("✅ Publisher session created: {} for '{}'", &id[..8], name);
+
+    // Stopping a published source means hanging up on its publisher
+    tokio::spawn(async move {
+        control.stop.notified().await;
+        if let Some(pc) = weak_pc.upgrade() {
+            let _ = pc.close().await;
+        }
+        control.ended.send_replace(true);
+        control.stopped.notify_one();
+    });
+
+    Ok(SDPAnswer(answer, format!("/whip/resource/{}", id)))
+}
+
+/// Copies RTP from the publisher to the track viewers subscribe to.
+async fn forward(
+    remote: Arc<TrackRemote>,
+    local: Arc<FanoutTrack>,
+    stats: &TrackStats,
+    control: &SourceControl,
+) {
+    while let Ok((pkt, _)) = remote.read_rtp().await {
+        stats.received.fetch_add(1, Ordering::Relaxed);
+        if control.is_private() {
+            continue;
+        }
+        local.send(pkt);
+        stats.forwarded.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Sends PLIs to the publisher regularly and whenever a viewer asks for a
+/// keyframe.
+fn spawn_pli_requests(
+    pc: std::sync::Weak<RTCPeerConnection>,
+    media_ssrc: u32,
+    keyframe_requests: Arc<Notify>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PLI_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = keyframe_requests.notified() => {}
+            }
+            let Some(pc) = pc.upgrade() else {
+                break;
+            };
+            let pli = PictureLossIndication {
+                sender_ssrc: 0,
+                media_ssrc,
+            };
+            if pc.write_rtcp(&[Box::new(pli)]).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// `DELETE /whip/resource/{id}`: stop a publisher and remove its source.
+pub async fn whip_delete(
+    State(AppState { publishers, .. }): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> axum::http::StatusCode {
+    if let Some(pc) = publishers.remove(&id) {
+        let _ = pc.close().await;
+        info!("🗑️  Publisher session deleted: {}", &id[..8]);
+        axum::http::StatusCode::NO_CONTENT
+    } else {
+        warn!(
+            "⚠️  Publisher session not found: {}",
+            &id[..8.min(id.len())]
+        );
+        axum::http::StatusCode::NOT_FOUND
+    }
+}
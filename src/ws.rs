@@ -0,0 +1,554 @@
+use std::time::Duration;
+
+use axum::{
+    Extension,
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use hyper_util::rt::TokioIo;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+use tracing::{debug, info, warn};
+use webrtc::{
+    ice_transport::ice_candidate::RTCIceCandidateInit,
+    peer_connection::sdp::session_description::RTCSessionDescription,
+};
+
+use crate::{
+    auth::Principal,
+    errors,
+    redact::redact_sdp,
+    state::AppState,
+    whep::{SDPOffer, offer_stream},
+};
+
+// RFC 6455 section 1.3
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Offers are a few kilobytes; anything this big is not signaling
+const MAX_MESSAGE: usize = 256 * 1024;
+// How often the connection checks whether its session is still there
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Messages a client sends over `/ws`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Watch `stream`, or the default source.
+    Offer {
+        sdp: String,
+        #[serde(default)]
+        stream: Option<String>,
+    },
+    /// A trickled ICE candidate; an empty one ends the candidates.
+    Candidate {
+        candidate: String,
+        #[serde(default, rename = "sdpMid")]
+        sdp_mid: Option<String>,
+        #[serde(default, rename = "sdpMLineIndex")]
+        sdp_mline_index: Option<u16>,
+    },
+    /// Hang up.
+    Bye,
+}
+
+/// Messages the gateway sends over `/ws`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    /// The answer, with all of the gateway's candidates.
+    Answer { sdp: String, session: String },
+    /// What went wrong, as in the HTTP API's error bodies.
+    Error { code: String, message: String },
+    /// The session ended on the gateway's side.
+    Bye,
+}
+
+/// What the reader task passes on from the client.
+enum Incoming {
+    Text(String),
+    Ping(Vec<u8>),
+    Close,
+}
+
+/// `GET /ws`: WebSocket signaling for front-ends that don't speak WHEP. One
+/// viewer session per connection, which ends with the connection.
+pub async fn ws_signaling(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    mut request: Request,
+) -> Response {
+    let headers = request.headers();
+    let has_token = |name, token: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    if !has_token(header::UPGRADE, "websocket") || !has_token(header::CONNECTION, "upgrade") {
+        return (StatusCode::BAD_REQUEST, "expected a WebSocket upgrade").into_response();
+    }
+    if headers
+        .get(header::SEC_WEBSOCKET_VERSION)
+        .is_none_or(|version| version != "13")
+    {
+        return (
+            StatusCode::UPGRADE_REQUIRED,
+            [(header::SEC_WEBSOCKET_VERSION, "13")],
+            "unsupported WebSocket version",
+        )
+            .into_response();
+    }
+    let Some(accept) = headers.get(header::SEC_WEBSOCKET_KEY).map(accept_key) else {
+        return (StatusCode::BAD_REQUEST, "missing Sec-WebSocket-Key").into_response();
+    };
+
+    let upgrade = hyper::upgrade::on(&mut request);
+    tokio::spawn(async move {
+        match upgrade.await {
+            Ok(upgraded) => serve(TokioIo::new(upgraded), state, principal).await,
+            Err(e) => warn!("WebSocket upgrade failed: {}", e),
+        }
+    });
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(header::SEC_WEBSOCKET_ACCEPT, accept);
+    (StatusCode::SWITCHING_PROTOCOLS, headers).into_response()
+}
+
+fn accept_key(key: &HeaderValue) -> HeaderValue {
+    let mut context = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+    context.update(key.as_bytes());
+    context.update(ACCEPT_GUID.as_bytes());
+    HeaderValue::from_str(&BASE64_STANDARD.encode(context.finish()))
+        .expect("base64 is a valid header value")
+}
+
+async fn serve<S>(io: S, state: AppState, principal: Option<Extension<Principal>>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(io);
+    // Reads are not cancel safe, so they get a task of their own
+    let (incoming_tx, mut incoming) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut partial = None;
+        loop {
+            let message = match read_message(&mut reader, &mut partial).await {
+                Ok(message) => message,
+                Err(e) => {
+                    debug!("WebSocket read ended: {}", e);
+                    Incoming::Close
+                }
+            };
+            let closed = matches!(message, Incoming::Close);
+            if incoming_tx.send(message).await.is_err() || closed {
+                return;
+            }
+        }
+    });
+
+    let mut session: Option<String> = None;
+    let mut session_check = tokio::time::interval(SESSION_CHECK_INTERVAL);
+    loop {
+        let message = tokio::select! {
+            message = incoming.recv() => message.unwrap_or(Incoming::Close),
+            _ = session_check.tick() => {
+                if session.as_ref().is_some_and(|id| state.sessions.get(id).is_none()) {
+                    session = None;
+                    let _ = send(&mut writer, &ServerMessage::Bye).await;
+                    break;
+                }
+                continue;
+            }
+        };
+        let text = match message {
+            Incoming::Text(text) => text,
+            Incoming::Ping(payload) => {
+                if write_frame(&mut writer, OPCODE_PONG, &payload)
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+            Incoming::Close => break,
+        };
+
+        let reply = match serde_json::from_str::<ClientMessage>(&text) {
+            Ok(ClientMessage::Offer { sdp, stream }) => {
+                if session.is_some() {
+                    Some(error(
+                        StatusCode::CONFLICT,
+                        "connection already has a session",
+                    ))
+                } else {
+                    match offer(&state, stream, principal.clone(), sdp).await {
+                        Ok((id, answer)) => {
+                            session = Some(id);
+                            Some(answer)
+                        }
+                        Err(reply) => Some(reply),
+                    }
+                }
+            }
+            Ok(ClientMessage::Candidate {
+                candidate,
+                sdp_mid,
+                sdp_mline_index,
+            }) => {
+                let pc = session.as_ref().and_then(|id| state.sessions.get(id));
+                match pc {
+                    None => Some(error(
+                        StatusCode::CONFLICT,
+                        "no session to add candidates to",
+                    )),
+                    Some(_) if candidate.is_empty() => None,
+                    Some(stored) => stored
+                        .pc
+                        .add_ice_candidate(RTCIceCandidateInit {
+                            candidate,
+                            sdp_mid,
+                            sdp_mline_index,
+                            username_fragment: None,
+                        })
+                        .await
+                        .err()
+                        .map(|e| error(StatusCode::BAD_REQUEST, &e.to_string())),
+                }
+            }
+            Ok(ClientMessage::Bye) => break,
+            Err(e) => Some(error(StatusCode::BAD_REQUEST, &e.to_string())),
+        };
+        if let Some(reply) = reply
+            && send(&mut writer, &reply).await.is_err()
+        {
+            break;
+        }
+    }
+
+    if let Some(id) = session
+        && let Some(pc) = state.sessions.remove(&id)
+    {
+        let _ = pc.close().await;
+        info!(
+            "👋 WebSocket session {} closed | Remaining: {}",
+            &id[..8],
+            state.sessions.len()
+        );
+    }
+    let _ = write_frame(&mut writer, OPCODE_CLOSE, &1000u16.to_be_bytes()).await;
+    let _ = writer.shutdown().await;
+}
+
+/// Answers an offer like `POST /whep/{stream}`, returning the session id.
+async fn offer(
+    state: &AppState,
+    stream: Option<String>,
+    principal: Option<Extension<Principal>>,
+    sdp: String,
+) -> Result<(String, ServerMessage), ServerMessage> {
+    if state.log_sdp {
+        info!("📝 SDP offer:\n{}", redact_sdp(&sdp));
+    }
+    let offer = RTCSessionDescription::offer(sdp)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+    let stream = stream.unwrap_or_else(|| state.default_stream.clone());
+    match offer_stream(state.clone(), &stream, principal, SDPOffer(offer), false).await {
+        Ok(answer) => {
+            let id = answer.1.rsplit('/').next().unwrap_or_default().to_owned();
+            Ok((
+                id.clone(),
+                ServerMessage::Answer {
+                    sdp: answer.0.sdp,
+                    session: id,
+                },
+            ))
+        }
+        Err(response) => {
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), MAX_MESSAGE)
+                .await
+                .unwrap_or_default();
+            let message = String::from_utf8_lossy(&body).trim().to_owned();
+            Err(if message.is_empty() {
+                error(status, errors::reason(status))
+            } else {
+                error(status, &message)
+            })
+        }
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> ServerMessage {
+    ServerMessage::Error {
+        code: errors::code(status),
+        message: message.to_owned(),
+    }
+}
+
+async fn send<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &ServerMessage,
+) -> std::io::Result<()> {
+    let text = serde_json::to_string(message).map_err(std::io::Error::from)?;
+    write_frame(writer, OPCODE_TEXT, text.as_bytes()).await
+}
+
+/// Writes one unfragmented, unmasked frame, as servers send them.
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// Reads frames until a whole text message, a ping or a close arrives.
+/// Pings may come between the fragments of a message, which is kept in
+/// `partial` meanwhile.
+async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    partial: &mut Option<Vec<u8>>,
+) -> std::io::Result<Incoming> {
+    let invalid = |reason: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, reason);
+    loop {
+        let mut head = [0; 2];
+        reader.read_exact(&mut head).await?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        if head[1] & 0x80 == 0 {
+            return Err(invalid("unmasked client frame"));
+        }
+        let len = match head[1] & 0x7f {
+            126 => reader.read_u16().await? as usize,
+            127 => usize::try_from(reader.read_u64().await?).unwrap_or(usize::MAX),
+            len => len as usize,
+        };
+        if len > MAX_MESSAGE || partial.as_ref().map_or(0, Vec::len) + len > MAX_MESSAGE {
+            return Err(invalid("message too large"));
+        }
+        let mut mask = [0; 4];
+        reader.read_exact(&mut mask).await?;
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload).await?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        let message = match opcode {
+            OPCODE_PING => return Ok(Incoming::Ping(payload)),
+            OPCODE_PONG => continue,
+            OPCODE_CLOSE => return Ok(Incoming::Close),
+            OPCODE_TEXT | OPCODE_BINARY if partial.is_none() => partial.insert(Vec::new()),
+            OPCODE_CONTINUATION => partial
+                .as_mut()
+                .ok_or_else(|| invalid("unexpected continuation"))?,
+            _ => return Err(invalid("unexpected frame")),
+        };
+        message.extend_from_slice(&payload);
+        if fin {
+            return String::from_utf8(partial.take().unwrap_or_default())
+                .map(Incoming::Text)
+                .map_err(|_| invalid("message is not UTF-8"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A masked client frame
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    async fn read(bytes: &[u8]) -> std::io::Result<Incoming> {
+        let mut reader = bytes;
+        read_message(&mut reader, &mut None).await
+    }
+
+    #[test]
+    fn accepts_sample_key() {
+        // RFC 6455, section 1.3
+        let key = HeaderValue::from_static("dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(accept_key(&key), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[tokio::test]
+    async fn reads_masked_text() {
+        // RFC 6455, section 5.7
+        let hello = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        assert!(matches!(read(&hello).await, Ok(Incoming::Text(text)) if text == "Hello"));
+
+        let long = "a".repeat(300);
+        assert!(matches!(
+            read(&frame(true, OPCODE_TEXT, long.as_bytes())).await,
+            Ok(Incoming::Text(text)) if text == long
+        ));
+    }
+
+    #[tokio::test]
+    async fn joins_fragments_around_pings() {
+        let mut bytes = frame(false, OPCODE_TEXT, b"Hel");
+        bytes.extend(frame(true, OPCODE_PING, b"are you there"));
+        bytes.extend(frame(true, OPCODE_PONG, b""));
+        bytes.extend(frame(true, OPCODE_CONTINUATION, b"lo"));
+        let mut reader = &bytes[..];
+        let mut partial = None;
+
+        let ping = read_message(&mut reader, &mut partial).await;
+        assert!(matches!(ping, Ok(Incoming::Ping(payload)) if payload == b"are you there"));
+        assert_eq!(partial.as_deref(), Some(&b"Hel"[..]));
+        let text = read_message(&mut reader, &mut partial).await;
+        assert!(matches!(text, Ok(Incoming::Text(text)) if text == "Hello"));
+        assert!(partial.is_none());
+    }
+
+    #[tokio::test]
+    async fn reads_close() {
+        let close = frame(true, OPCODE_CLOSE, &1000u16.to_be_bytes());
+        assert!(matches!(read(&close).await, Ok(Incoming::Close)));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_frames() {
+        let invalid = |result: std::io::Result<Incoming>| {
+            result.is_err_and(|e| e.kind() == std::io::ErrorKind::InvalidData)
+        };
+        // Unmasked, as only servers may send
+        assert!(invalid(
+            read(&[0x81, 0x05, b'H', b'e', b'l', b'l', b'o']).await
+        ));
+        assert!(invalid(
+            read(&frame(true, OPCODE_CONTINUATION, b"lo")).await
+        ));
+        assert!(invalid(
+            read(&frame(true, OPCODE_TEXT, &[0xff, 0xfe])).await
+        ));
+        // Reserved opcode
+        assert!(invalid(read(&frame(true, 0x3, b"")).await));
+        assert!(invalid(
+            read(&frame(true, OPCODE_TEXT, &vec![b'a'; MAX_MESSAGE + 1])).await
+        ));
+        // Fragments adding up to too much
+        let mut bytes = frame(false, OPCODE_TEXT, &vec![b'a'; MAX_MESSAGE]);
+        bytes.extend(frame(true, OPCODE_CONTINUATION, b"a"));
+        assert!(invalid(read(&bytes).await));
+        // Cut off
+        let hello = frame(true, OPCODE_TEXT, b"Hello");
+        assert!(
+            read(&hello[..hello.len() - 1])
+                .await
+                .is_err_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_unmasked_frames() {
+        let mut out = Vec::new();
+        write_frame(&mut out, OPCODE_TEXT, b"Hello").await.unwrap();
+        assert_eq!(out, [0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+
+        let mut out = Vec::new();
+        write_frame(&mut out, OPCODE_BINARY, &[0; 256])
+            .await
+            .unwrap();
+        assert_eq!(out[..4], [0x82, 0x7e, 0x01, 0x00]);
+        assert_eq!(out.len(), 4 + 256);
+
+        let mut out = Vec::new();
+        write_frame(&mut out, OPCODE_BINARY, &[0; 0x10000])
+            .await
+            .unwrap();
+        assert_eq!(out[..10], [0x82, 0x7f, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(out.len(), 10 + 0x10000);
+    }
+
+    #[test]
+    fn parses_client_messages() {
+        let offer = serde_json::from_str(r#"{"type":"offer","sdp":"v=0"}"#);
+        assert!(matches!(
+            offer,
+            Ok(ClientMessage::Offer { sdp, stream: None }) if sdp == "v=0"
+        ));
+        let candidate = serde_json::from_str(
+            r#"{"type":"candidate","candidate":"candidate:1 1 udp 1 10.0.0.1 5000 typ host","sdpMid":"0","sdpMLineIndex":0}"#,
+        );
+        assert!(matches!(
+            candidate,
+            Ok(ClientMessage::Candidate { sdp_mid: Some(mid), sdp_mline_index: Some(0), .. }) if mid == "0"
+        ));
+        assert!(matches!(
+            serde_json::from_str(r#"{"type":"bye"}"#),
+            Ok(ClientMessage::Bye)
+        ));
+        assert!(serde_json::from_str::<ClientMessage>(r#"{"type":"answer","sdp":""}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn sends_json_messages() {
+        let mut out = Vec::new();
+        let message = error(StatusCode::CONFLICT, "connection already has a session");
+        send(&mut out, &message).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out[2..]).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "error",
+                "code": "conflict",
+                "message": "connection already has a session",
+            })
+        );
+    }
+}